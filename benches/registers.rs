@@ -0,0 +1,72 @@
+// compares the two register representations. the register file loops time Plain and Packed side by side,
+// the machine loop times whichever one the build uses: run it as `cargo bench` and again as
+// `cargo bench --features nanbox`
+use std::hint::black_box;
+use std::time::Instant;
+use tower::machine::Status;
+use tower::reader::assemble;
+use tower::register::{Packed, Plain, RegisterFile};
+use tower::{Machine, Value};
+
+const ROUNDS: usize = 1 << 22;
+const REGISTERS: usize = 254;
+
+fn report(name: &str, operations: usize, run: impl FnOnce()) {
+    let start = Instant::now();
+    run();
+    let nanos = start.elapsed().as_nanos() as f64 / operations as f64;
+    println!("{:<32} {:>8.2} ns/op", name, nanos);
+}
+
+fn moves<R: RegisterFile>(registers: &mut R) {
+    for i in 0..ROUNDS {
+        registers.copy(i % REGISTERS + 1, i % REGISTERS);
+    }
+}
+
+// every register is written from two others, the shape of ADD $a $b $c
+fn arithmetic<R: RegisterFile>(registers: &mut R, values: [Value; 2]) {
+    for reg in 0..=REGISTERS {
+        registers.set(reg, values[reg % 2]);
+    }
+    for i in 0..ROUNDS {
+        let (a, b) = (registers.get(i % REGISTERS), registers.get((i + 1) % REGISTERS));
+        registers.set((i + 2) % REGISTERS, (black_box(a) ^ black_box(b)).unwrap());
+    }
+}
+
+fn register_file<R: RegisterFile>(name: &str) {
+    let mut registers = R::new();
+    report(&format!("{} MOVE", name), ROUNDS, || moves(&mut registers));
+    report(&format!("{} XOR i32", name), ROUNDS, || arithmetic(&mut registers, [Value::I32(3), Value::I32(5)]));
+    // 64-bit integers do not fit in a box, Packed spills them to its side table
+    report(&format!("{} XOR i64", name), ROUNDS, || arithmetic(&mut registers, [Value::I64(3), Value::I64(5)]));
+    black_box(registers.get(0));
+}
+
+// the loop with its two constants given, 1 and 0.5f64 for the mix of types a program usually has
+fn machine(name: &str, constants: [&str; 2]) {
+    let representation = if cfg!(feature = "nanbox") { "packed" } else { "plain" };
+    let src = format!("CONST {}\nCONST {}\nLOAD $0 0\nLOAD $1 1\n\
+        ADD $2 $0 $0\nMOVE $3 $2\nMUL $4 $1 $1\nMOVE $5 $4\nXOR $6 $2 $3\nJMP 6\n", constants[0], constants[1]);
+    let mut machine = Machine::new();
+    machine.set_fuel(Some(ROUNDS as u64));
+    report(&format!("machine {} ({})", name, representation), ROUNDS, || {
+        assert_eq!(machine.run(assemble(&src)), Status::OutOfFuel);
+    });
+    black_box(machine.register(6));
+}
+
+fn main() {
+    println!("{:<32} {:>8} bytes", "size of Value", size_of::<Value>());
+    // plus a Value in Packed's side table for each register that spilled
+    println!("{:<32} {:>8} bytes", "size of Plain", size_of::<Plain>());
+    println!("{:<32} {:>8} bytes", "size of Packed", size_of::<Packed>());
+    register_file::<Plain>("plain");
+    register_file::<Packed>("packed");
+    machine("mixed", ["1", "0.5f64"]);
+    // 128-bit integers are inline, which is what makes Value 24 bytes rather than 16. the big
+    // integer loop is the same arithmetic with every result allocated on the heap instead
+    machine("i128", ["1i128", "3i128"]);
+    machine("big", ["1big", "3big"]);
+}
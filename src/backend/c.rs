@@ -0,0 +1,462 @@
+// translates bytecode into a standalone C program with the same output as Machine::run
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use crate::{code::Code, opcode::Opcode, value::{Value, ValueType}};
+use super::{analyze, Analysis};
+
+const PRELUDE: &str = "\
+#include <inttypes.h>
+#include <math.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+";
+
+// prints the shortest round-tripping digits produced by %e in positional notation,
+// which is how Rust's Display writes floats
+const PRINT_FLOAT: &str = "
+static void tower_print_digits(const char *buf) {
+    char digits[32];
+    size_t n = 0;
+    const char *p = buf;
+    if (*p == '-') {
+        putchar('-');
+        p++;
+    }
+    for (; *p != 'e'; p++) {
+        if (*p != '.') {
+            digits[n++] = *p;
+        }
+    }
+    int point = atoi(p + 1) + 1;
+    while (n > 1 && digits[n - 1] == '0') {
+        n--;
+    }
+    if (point <= 0) {
+        fputs(\"0.\", stdout);
+        for (int i = point; i < 0; i++) {
+            putchar('0');
+        }
+        fwrite(digits, 1, n, stdout);
+    } else if ((size_t)point >= n) {
+        fwrite(digits, 1, n, stdout);
+        for (size_t i = n; i < (size_t)point; i++) {
+            putchar('0');
+        }
+    } else {
+        fwrite(digits, 1, point, stdout);
+        putchar('.');
+        fwrite(digits + point, 1, n - point, stdout);
+    }
+}
+
+static int tower_print_special(double x) {
+    if (isnan(x)) {
+        fputs(\"NaN\", stdout);
+    } else if (isinf(x)) {
+        fputs(x < 0 ? \"-inf\" : \"inf\", stdout);
+    } else {
+        return 0;
+    }
+    return 1;
+}
+";
+
+const PRINT_F32: &str = "
+static void tower_print_f32(float x) {
+    char buf[64];
+    if (tower_print_special(x)) {
+        return;
+    }
+    for (int precision = 0; precision < 9; precision++) {
+        snprintf(buf, sizeof buf, \"%.*e\", precision, x);
+        if ((float)strtod(buf, NULL) == x) {
+            break;
+        }
+    }
+    tower_print_digits(buf);
+}
+";
+
+const PRINT_F64: &str = "
+static void tower_print_f64(double x) {
+    char buf[64];
+    if (tower_print_special(x)) {
+        return;
+    }
+    for (int precision = 0; precision < 17; precision++) {
+        snprintf(buf, sizeof buf, \"%.*e\", precision, x);
+        if (strtod(buf, NULL) == x) {
+            break;
+        }
+    }
+    tower_print_digits(buf);
+}
+";
+
+fn c_type(ty: ValueType) -> &'static str {
+    match ty {
+        ValueType::Bool => "bool",
+        ValueType::I8 => "int8_t",
+        ValueType::I16 => "int16_t",
+        ValueType::I32 => "int32_t",
+        ValueType::I64 => "int64_t",
+        ValueType::U8 => "uint8_t",
+        ValueType::U16 => "uint16_t",
+        ValueType::U32 => "uint32_t",
+        ValueType::U64 => "uint64_t",
+        ValueType::F32 => "float",
+        ValueType::F64 => "double",
+        ValueType::Str | ValueType::Ref | ValueType::I128 | ValueType::U128 | ValueType::Big | ValueType::Char | ValueType::Bytes => {
+            unreachable!("text, references, 128-bit and big integers are rejected by analyze")
+        },
+    }
+}
+
+fn literal(value: &Value) -> String {
+    match value {
+        Value::Bool(b) => b.to_string(),
+        Value::I8(i8::MIN) => "INT8_MIN".to_string(),
+        Value::I16(i16::MIN) => "INT16_MIN".to_string(),
+        Value::I32(i32::MIN) => "INT32_MIN".to_string(),
+        Value::I64(i64::MIN) => "INT64_MIN".to_string(),
+        Value::I8(i) => i.to_string(),
+        Value::I16(i) => i.to_string(),
+        Value::I32(i) => i.to_string(),
+        Value::I64(i) => format!("INT64_C({})", i),
+        Value::U8(u) => u.to_string(),
+        Value::U16(u) => u.to_string(),
+        Value::U32(u) => format!("{}u", u),
+        Value::U64(u) => format!("UINT64_C({})", u),
+        Value::F32(f) if f.is_nan() => "NAN".to_string(),
+        Value::F32(f) if f.is_infinite() => format!("{}INFINITY", if *f < 0.0 { "-" } else { "" }),
+        Value::F32(f) => format!("{:?}f", f),
+        Value::F64(f) if f.is_nan() => "(double)NAN".to_string(),
+        Value::F64(f) if f.is_infinite() => format!("{}(double)INFINITY", if *f < 0.0 { "-" } else { "" }),
+        Value::F64(f) => format!("{:?}", f),
+        Value::Str(_) | Value::Ref(_) | Value::I128(_) | Value::U128(_) | Value::Big(_) | Value::Char(_) | Value::Bytes(_) => {
+            unreachable!("text, references, 128-bit and big integers are rejected by analyze")
+        },
+    }
+}
+
+fn register(reg: u8, ty: ValueType) -> String {
+    format!("r{}_{}", reg, ty)
+}
+
+fn global(global: u8, ty: ValueType) -> String {
+    format!("g{}_{}", global, ty)
+}
+
+// integer arithmetic goes through uint64_t so that overflow wraps instead of being undefined,
+// integer DIV and shifts call the checked helpers from emit_checked
+fn binary(opcode: Opcode, ty: ValueType, a: &str, b: &str) -> String {
+    let op = match opcode {
+        Opcode::ADD => "+",
+        Opcode::SUB => "-",
+        Opcode::MUL => "*",
+        Opcode::DIV => "/",
+        Opcode::AND => "&",
+        Opcode::OR => "|",
+        Opcode::XOR => "^",
+        Opcode::SHR => ">>",
+        Opcode::SHL => "<<",
+        _ => unreachable!("{} is not a binary operator", opcode),
+    };
+    match (opcode, ty) {
+        (_, ValueType::F32) | (_, ValueType::F64) => format!("{} {} {}", a, op, b),
+        (_, ValueType::Bool) => format!("(bool)({} {} {})", a, op, b),
+        (Opcode::DIV, _) | (Opcode::SHR, _) | (Opcode::SHL, _) => format!("tower_{}_{}({}, {})", opcode.to_string().to_lowercase(), ty, a, b),
+        _ => format!("({})((uint64_t){} {} (uint64_t){})", c_type(ty), a, op, b),
+    }
+}
+
+// the helpers binary calls, which abort where the interpreter panics: dividing by zero, dividing the most
+// negative value by -1 and shifting by a negative amount or by the width of the type or more
+// what was printed before the abort is flushed first
+fn emit_checked(out: &mut String, opcode: Opcode, ty: ValueType) {
+    let name = opcode.to_string().to_lowercase();
+    let signed = matches!(ty, ValueType::I8 | ValueType::I16 | ValueType::I32 | ValueType::I64);
+    let bits = ty.size().unwrap() * 8;
+    let shift = match signed {
+        true => format!("b < 0 || b >= {}", bits),
+        false => format!("b >= {}", bits),
+    };
+    let (guard, result) = match opcode {
+        Opcode::DIV if signed => (format!("b == 0 || (b == -1 && a == INT{}_MIN)", bits), "a / b"),
+        Opcode::DIV => ("b == 0".to_string(), "a / b"),
+        Opcode::SHR => (shift, "a >> b"),
+        _ => (shift, "(uint64_t)a << b"),
+    };
+    let c_ty = c_type(ty);
+    write!(
+        out,
+        "\nstatic {c_ty} tower_{name}_{ty}({c_ty} a, {c_ty} b) {{\n    if ({guard}) {{\n        fflush(stdout);\n        abort();\n    }}\n    return ({c_ty})({result});\n}}\n"
+    )
+    .unwrap();
+}
+
+fn emit_instruction(
+    out: &mut String,
+    code: &Code,
+    analysis: &Analysis,
+    offset: usize,
+    printed: &mut BTreeSet<ValueType>,
+    checked: &mut Vec<(Opcode, ValueType)>,
+) {
+    let opcode = Opcode::from(code.raw[offset]);
+    let operands = &code.raw[offset + 1..offset + 1 + opcode.get_offset()];
+    let reg = |r: u8| register(r, analysis.register(offset, r));
+    let line = match opcode {
+        Opcode::PRINT => {
+            let ty = analysis.register(offset, operands[0]);
+            printed.insert(ty);
+            format!("tower_print_{}({});", ty, reg(operands[0]))
+        }
+        Opcode::TPRINT => {
+            let ty = analysis.register(offset, operands[0]);
+            printed.insert(ty);
+            match ty {
+                ValueType::Bool => format!("tower_print_bool({});", reg(operands[0])),
+                _ => format!("tower_print_{}({}); fputs(\"{}\", stdout);", ty, reg(operands[0]), ty),
+            }
+        }
+        Opcode::MOVE => {
+            let ty = analysis.register(offset, operands[1]);
+            format!("{} = {};", register(operands[0], ty), reg(operands[1]))
+        }
+        Opcode::LOAD => {
+            let value = &code.const_pool[operands[1] as usize];
+            format!("{} = {};", register(operands[0], value.value_type()), literal(value))
+        }
+        Opcode::GETGLOBAL => {
+            let ty = analysis.global(offset, operands[1]);
+            format!("{} = {};", register(operands[0], ty), global(operands[1], ty))
+        }
+        Opcode::SETGLOBAL => {
+            let ty = analysis.register(offset, operands[0]);
+            format!("{} = {};", global(operands[1], ty), reg(operands[0]))
+        }
+        // there is no host to hand the value to, so execution just carries on
+        Opcode::YIELD => format!("(void){};", reg(operands[0])),
+        Opcode::JMP => format!("goto L{};", operands[0]),
+        Opcode::JMPIF => format!("if ({}) goto L{};", reg(operands[0]), operands[1]),
+        Opcode::HALT => "return 0;".to_string(),
+        _ => {
+            let ty = analysis.register(offset, operands[1]);
+            if matches!(opcode, Opcode::DIV | Opcode::SHR | Opcode::SHL) && ty.is_integer() && !checked.contains(&(opcode, ty)) {
+                checked.push((opcode, ty));
+            }
+            let value = binary(opcode, ty, &reg(operands[1]), &reg(operands[2]));
+            format!("{} = {};", register(operands[0], ty), value)
+        }
+    };
+    writeln!(out, "    {} /* {}: line {} */", line, offset, code.lines[offset]).unwrap();
+}
+
+fn emit_print(out: &mut String, ty: ValueType) {
+    let format = match ty {
+        ValueType::Bool => {
+            out.push_str("\nstatic void tower_print_bool(bool x) {\n    fputs(x ? \"true\" : \"false\", stdout);\n}\n");
+            return;
+        }
+        ValueType::F32 => {
+            out.push_str(PRINT_F32);
+            return;
+        }
+        ValueType::F64 => {
+            out.push_str(PRINT_F64);
+            return;
+        }
+        ValueType::I8 => "PRId8",
+        ValueType::I16 => "PRId16",
+        ValueType::I32 => "PRId32",
+        ValueType::I64 => "PRId64",
+        ValueType::U8 => "PRIu8",
+        ValueType::U16 => "PRIu16",
+        ValueType::U32 => "PRIu32",
+        ValueType::U64 => "PRIu64",
+        ValueType::Str | ValueType::Ref | ValueType::I128 | ValueType::U128 | ValueType::Big | ValueType::Char | ValueType::Bytes => {
+            unreachable!("text, references, 128-bit and big integers are rejected by analyze")
+        },
+    };
+    write!(out, "\nstatic void tower_print_{}({} x) {{\n    printf(\"%\" {}, x);\n}}\n", ty, c_type(ty), format).unwrap();
+}
+
+pub fn emit(code: &Code) -> Result<String, String> {
+    let analysis = analyze(code)?;
+    let mut body = String::new();
+    let mut printed = BTreeSet::new();
+    let mut checked = Vec::new();
+    for (reg, ty) in &analysis.register_types {
+        writeln!(body, "    {} {} = 0;", c_type(*ty), register(*reg, *ty)).unwrap();
+    }
+    for (index, ty) in &analysis.global_types {
+        let value = &code.globals[*index as usize];
+        let init = if value.value_type() == *ty { literal(value) } else { "0".to_string() };
+        writeln!(body, "    {} {} = {};", c_type(*ty), global(*index, *ty), init).unwrap();
+    }
+    for &offset in analysis.states.keys() {
+        if analysis.jump_targets.contains(&offset) {
+            writeln!(body, "L{}:;", offset).unwrap();
+        }
+        emit_instruction(&mut body, code, &analysis, offset, &mut printed, &mut checked);
+    }
+
+    let mut out = String::from(PRELUDE);
+    if printed.contains(&ValueType::F32) || printed.contains(&ValueType::F64) {
+        out.push_str(PRINT_FLOAT);
+    }
+    for ty in printed {
+        emit_print(&mut out, ty);
+    }
+    for (opcode, ty) in checked {
+        emit_checked(&mut out, opcode, ty);
+    }
+    write!(out, "\nint main(void) {{\n{}}}\n", body).unwrap();
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::assemble;
+    use std::process::Command;
+
+    // compiles the generated source with the system C compiler and returns what it prints,
+    // or None if there is no compiler to test with
+    fn compile_and_run(code: &Code, name: &str) -> Option<String> {
+        compile_and_execute(code, name).map(|output| String::from_utf8(output.stdout).unwrap())
+    }
+
+    fn compile_and_execute(code: &Code, name: &str) -> Option<std::process::Output> {
+        let source = emit(code).unwrap();
+        if Command::new("cc").arg("--version").output().is_err() {
+            return None;
+        }
+        let dir = std::env::temp_dir().join(format!("tower-c-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("main.c");
+        let exe = dir.join("main");
+        std::fs::write(&src, source).unwrap();
+        let status = Command::new("cc")
+            .args(["-std=c99", "-Wall", "-Werror", "-o"])
+            .arg(&exe)
+            .arg(&src)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let output = Command::new(&exe).output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        Some(output)
+    }
+
+    #[test]
+    fn test_emit_add() {
+        let code = assemble("CONST 10.5\nCONST 30.5\nLOAD 1 0\nLOAD 2 1\nADD 3 1 2\nPRINT 3\nHALT\n");
+        if let Some(output) = compile_and_run(&code, "add") {
+            assert_eq!(output, "41");
+        }
+    }
+
+    #[test]
+    fn test_emit_typed_print() {
+        let code = assemble("CONST 10.5\nCONST true\nLOAD 0 0\nLOAD 1 1\nTPRINT 0\nTPRINT 1\nPRINT 0\nHALT\n");
+        if let Some(output) = compile_and_run(&code, "typed") {
+            assert_eq!(output, "10.5f32true10.5");
+        }
+    }
+
+    #[test]
+    fn test_emit_branch() {
+        let src = "CONST true\nCONST 1u8\nLOAD 1 0\nLOAD 2 0\nLOAD 3 1\nADD 0 0 3\nXOR 1 1 2\nJMPIF 1 24\nPRINT 0\nJMP 9\nPRINT 3\nHALT\n";
+        if let Some(output) = compile_and_run(&assemble(src), "branch") {
+            assert_eq!(output, "11");
+        }
+    }
+
+    #[test]
+    fn test_emit_integers() {
+        let mut code = Code::new();
+        code.add_const(Value::I8(100));
+        code.add_const(Value::I64(i64::MIN));
+        code.add_const(Value::U64(u64::MAX));
+        code.add_const(Value::Bool(true));
+        for byte in [
+            Opcode::LOAD as u8, 0, 0,
+            Opcode::SUB as u8, 1, 0, 0,
+            Opcode::SUB as u8, 1, 1, 0,
+            Opcode::PRINT as u8, 1,
+            Opcode::LOAD as u8, 2, 1,
+            Opcode::PRINT as u8, 2,
+            Opcode::LOAD as u8, 3, 2,
+            Opcode::PRINT as u8, 3,
+            Opcode::LOAD as u8, 4, 3,
+            Opcode::XOR as u8, 4, 4, 4,
+            Opcode::PRINT as u8, 4,
+            // registers start out as u8 zero
+            Opcode::PRINT as u8, 9,
+            Opcode::HALT as u8,
+        ] {
+            code.write_code(byte, 0);
+        }
+        if let Some(output) = compile_and_run(&code, "integers") {
+            assert_eq!(output, "-100-922337203685477580818446744073709551615false0");
+        }
+    }
+
+    #[test]
+    fn test_emit_floats() {
+        let mut code = Code::new();
+        let values = [
+            Value::F32(0.1), Value::F32(1e-7), Value::F32(-0.0), Value::F32(f32::NEG_INFINITY),
+            Value::F64(0.1), Value::F64(1e21), Value::F64(123.456), Value::F64(f64::NAN),
+        ];
+        for (i, value) in values.iter().enumerate() {
+            code.add_const(*value);
+            for byte in [Opcode::LOAD as u8, 0, i as u8, Opcode::PRINT as u8, 0] {
+                code.write_code(byte, i);
+            }
+        }
+        code.write_code(Opcode::HALT as u8, values.len());
+        let expected: String = values.iter().map(|v| v.to_string()).collect();
+        if let Some(output) = compile_and_run(&code, "floats") {
+            assert_eq!(output, expected);
+        }
+    }
+
+    #[test]
+    fn test_emit_jump_and_globals() {
+        // jumps over the first PRINT, then overwrites global 0 and prints it
+        let src = ".global 1\nCONST 2\nJMP 4\nPRINT 0\nLOAD 0 0\nSETGLOBAL 0 0\nGETGLOBAL 1 0\nPRINT 1\nHALT\n";
+        let code = assemble(src);
+        let c = emit(&code).unwrap();
+        assert!(c.contains("L4:;"));
+        assert!(!c.contains("/* 2: "));
+        if let Some(output) = compile_and_run(&code, "jump") {
+            assert_eq!(output, "2");
+        }
+    }
+
+    #[test]
+    fn test_emit_checked_division_and_shifts() {
+        let src = "CONST 7u8\nCONST -128i8\nCONST -1i8\nLOAD 0 0\nLOAD 1 1\nLOAD 2 2\n\
+            SHL 3 0 0\nSHR 3 0 0\nPRINT 3\nDIV 4 1 2\nPRINT 4\nHALT\n";
+        let code = assemble(src);
+        let c = emit(&code).unwrap();
+        assert!(c.contains("if (b >= 8) {\n        fflush(stdout);"));
+        assert!(c.contains("if (b == 0 || (b == -1 && a == INT8_MIN)) {"));
+        if let Some(output) = compile_and_execute(&code, "checked") {
+            assert!(!output.status.success());
+            assert_eq!(output.stdout, b"0");
+        }
+    }
+
+    #[test]
+    fn test_emit_rejects_unverified() {
+        let code = assemble("JMP 1\nHALT\n");
+        assert!(emit(&code).is_err());
+    }
+}
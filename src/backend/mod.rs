@@ -0,0 +1,278 @@
+// ahead-of-time backends that translate verified bytecode into other languages
+use std::collections::{BTreeMap, BTreeSet};
+use crate::{code::Code, opcode::Opcode, value::ValueType, machine::REGISTER_MAX};
+
+pub mod c;
+pub mod wasm;
+
+// static type of every register and global on entry to an instruction,
+// None means the slot can hold different types depending on the path taken
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TypeState {
+    pub registers: Vec<Option<ValueType>>,
+    pub globals: Vec<Option<ValueType>>,
+}
+
+impl TypeState {
+    fn entry(code: &Code) -> TypeState {
+        TypeState {
+            // Machine::new fills the register file with U8(0)
+            registers: vec![Some(ValueType::U8); REGISTER_MAX],
+            globals: code.globals.iter().map(|v| Some(v.value_type())).collect(),
+        }
+    }
+
+    // returns true if merging changed self
+    fn merge(&mut self, other: &TypeState) -> bool {
+        let mut changed = false;
+        let slots = self.registers.iter_mut().zip(&other.registers)
+            .chain(self.globals.iter_mut().zip(&other.globals));
+        for (mine, theirs) in slots {
+            if mine.is_some() && mine != theirs {
+                *mine = None;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn register(&self, reg: u8, offset: usize) -> Result<ValueType, String> {
+        self.registers[reg as usize]
+            .ok_or_else(|| format!("Register ${} has no single type at {}", reg, offset))
+    }
+
+    fn global(&self, global: u8, offset: usize) -> Result<ValueType, String> {
+        self.globals[global as usize]
+            .ok_or_else(|| format!("Global {} has no single type at {}", global, offset))
+    }
+}
+
+pub(crate) struct Analysis {
+    // type state on entry to every reachable instruction
+    pub states: BTreeMap<usize, TypeState>,
+    pub jump_targets: BTreeSet<usize>,
+    // every (register, type) pair that is read or written
+    pub register_types: BTreeSet<(u8, ValueType)>,
+    // every (global, type) pair that is read or written
+    pub global_types: BTreeSet<(u8, ValueType)>,
+}
+
+impl Analysis {
+    pub fn register(&self, offset: usize, reg: u8) -> ValueType {
+        self.states[&offset].registers[reg as usize].expect("register type checked by analyze")
+    }
+
+    pub fn global(&self, offset: usize, global: u8) -> ValueType {
+        self.states[&offset].globals[global as usize].expect("global type checked by analyze")
+    }
+}
+
+pub(crate) fn binary_type(opcode: Opcode, a: ValueType, b: ValueType) -> Result<ValueType, String> {
+    let allowed = match opcode {
+        Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => a != ValueType::Bool,
+        Opcode::AND | Opcode::OR | Opcode::XOR => !a.is_float(),
+        Opcode::SHR | Opcode::SHL => a.is_integer(),
+        _ => false,
+    };
+    if a != b || !allowed {
+        return Err(format!("Cannot {} types {} and {}", opcode, a, b));
+    }
+    Ok(a)
+}
+
+// applies the instruction at offset to state and returns its successors
+fn transfer(code: &Code, offset: usize, state: &mut TypeState, analysis: &mut Analysis) -> Result<Vec<usize>, String> {
+    let opcode = Opcode::from(code.raw[offset]);
+    let operands = &code.raw[offset + 1..offset + 1 + opcode.get_offset()];
+    let next = offset + opcode.get_offset() + 1;
+    let written = match opcode {
+        Opcode::PRINT | Opcode::TPRINT | Opcode::YIELD => {
+            let ty = state.register(operands[0], offset)?;
+            analysis.register_types.insert((operands[0], ty));
+            None
+        }
+        Opcode::MOVE => {
+            let ty = state.register(operands[1], offset)?;
+            analysis.register_types.insert((operands[1], ty));
+            Some((operands[0], ty))
+        }
+        Opcode::LOAD => Some((operands[0], code.const_pool[operands[1] as usize].value_type())),
+        Opcode::GETGLOBAL => {
+            let ty = state.global(operands[1], offset)?;
+            analysis.global_types.insert((operands[1], ty));
+            Some((operands[0], ty))
+        }
+        Opcode::SETGLOBAL => {
+            let ty = state.register(operands[0], offset)?;
+            analysis.register_types.insert((operands[0], ty));
+            analysis.global_types.insert((operands[1], ty));
+            state.globals[operands[1] as usize] = Some(ty);
+            None
+        }
+        Opcode::JMP => {
+            analysis.jump_targets.insert(operands[0] as usize);
+            return Ok(vec![operands[0] as usize]);
+        }
+        Opcode::JMPIF => {
+            let ty = state.register(operands[0], offset)?;
+            if ty != ValueType::Bool {
+                return Err(format!("Cannot branch on type {} at {}", ty, offset));
+            }
+            analysis.register_types.insert((operands[0], ty));
+            analysis.jump_targets.insert(operands[1] as usize);
+            return Ok(vec![next, operands[1] as usize]);
+        }
+        Opcode::HALT => return Ok(vec![]),
+        Opcode::CALLHOST => return Err(format!("CALLHOST at {} needs a host and cannot be compiled", offset)),
+        Opcode::MLOAD | Opcode::MSTORE | Opcode::MEMSIZE | Opcode::GROW => {
+            return Err(format!("{} at {} uses linear memory, which the backends do not support", opcode, offset));
+        }
+        Opcode::PUSH
+        | Opcode::POP
+        | Opcode::PEEK
+        | Opcode::DUP
+        | Opcode::SWAP
+        | Opcode::SLOAD
+        | Opcode::SSTORE => {
+            return Err(format!("{} at {} uses the stack, which the backends do not support", opcode, offset));
+        }
+        Opcode::CONCAT
+        | Opcode::STRLEN
+        | Opcode::SUBSTR
+        | Opcode::STRCMP
+        | Opcode::STREQ
+        | Opcode::TOSTR
+        | Opcode::PARSE
+        | Opcode::CHARAT
+        | Opcode::ISDIGIT
+        | Opcode::ISALPHA
+        | Opcode::ISSPACE => {
+            return Err(format!("{} at {} uses strings, which the backends do not support", opcode, offset));
+        }
+        Opcode::NEWARRAY
+        | Opcode::NEWRECORD
+        | Opcode::GETELEM
+        | Opcode::SETELEM
+        | Opcode::LEN
+        | Opcode::APPEND => {
+            return Err(format!("{} at {} uses heap objects, which the backends do not support", opcode, offset));
+        }
+        Opcode::CLOSURE | Opcode::CALL | Opcode::RET | Opcode::GETUPVAL | Opcode::SETUPVAL => {
+            return Err(format!("{} at {} uses functions, which the backends do not support", opcode, offset));
+        }
+        Opcode::NEG | Opcode::NOT | Opcode::ABS | Opcode::REM | Opcode::MOD | Opcode::MATH | Opcode::CONVERT => {
+            return Err(format!("{} at {} is not supported by the backends", opcode, offset));
+        }
+        Opcode::READ => return Err(format!("READ at {} needs an input source and cannot be compiled", offset)),
+        _ => {
+            let a = state.register(operands[1], offset)?;
+            let b = state.register(operands[2], offset)?;
+            analysis.register_types.insert((operands[1], a));
+            analysis.register_types.insert((operands[2], b));
+            Some((operands[0], binary_type(opcode, a, b).map_err(|e| format!("{} at {}", e, offset))?))
+        }
+    };
+    if let Some((reg, ty)) = written {
+        match ty {
+            ValueType::Str | ValueType::Bytes => {
+                return Err(format!("{} at {} uses strings, which the backends do not support", opcode, offset));
+            }
+            ValueType::Char => return Err(format!("{} at {} uses chars, which the backends do not support", opcode, offset)),
+            ValueType::Ref => {
+                return Err(format!("{} at {} uses heap objects, which the backends do not support", opcode, offset));
+            }
+            ValueType::I128 | ValueType::U128 => {
+                return Err(format!("{} at {} uses 128-bit integers, which the backends do not support", opcode, offset));
+            }
+            ValueType::Big => return Err(format!("{} at {} uses big integers, which the backends do not support", opcode, offset)),
+            _ => {}
+        }
+        analysis.register_types.insert((reg, ty));
+        state.registers[reg as usize] = Some(ty);
+    }
+    Ok(vec![next])
+}
+
+// infers the type of every register at every reachable instruction
+pub(crate) fn analyze(code: &Code) -> Result<Analysis, String> {
+    code.verify()?;
+    let mut analysis = Analysis {
+        states: BTreeMap::new(),
+        jump_targets: BTreeSet::new(),
+        register_types: BTreeSet::new(),
+        global_types: BTreeSet::new(),
+    };
+    analysis.states.insert(0, TypeState::entry(code));
+    let mut worklist = vec![0];
+    while let Some(offset) = worklist.pop() {
+        let mut state = analysis.states[&offset].clone();
+        for successor in transfer(code, offset, &mut state, &mut analysis)? {
+            match analysis.states.get_mut(&successor) {
+                Some(existing) => {
+                    if existing.merge(&state) {
+                        worklist.push(successor);
+                    }
+                }
+                None => {
+                    analysis.states.insert(successor, state.clone());
+                    worklist.push(successor);
+                }
+            }
+        }
+    }
+    Ok(analysis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::assemble;
+
+    #[test]
+    fn test_analyze_types() {
+        let code = assemble("CONST 10\nCONST 0.5\nLOAD 0 0\nLOAD 1 1\nADD 2 0 0\nHALT\n");
+        let analysis = analyze(&code).unwrap();
+        assert_eq!(analysis.register(6, 2), ValueType::U8);
+        assert_eq!(analysis.register(10, 2), ValueType::I8);
+        assert_eq!(analysis.register(10, 1), ValueType::F32);
+    }
+
+    #[test]
+    fn test_analyze_mismatch() {
+        let code = assemble("CONST 10\nCONST 0.5\nLOAD 0 0\nLOAD 1 1\nADD 2 0 1\nHALT\n");
+        assert!(analyze(&code).is_err());
+    }
+
+    #[test]
+    fn test_analyze_conflict() {
+        // $0 is u8 on the first pass through the loop and i8 afterwards
+        let code = assemble("CONST 10\nPRINT 0\nLOAD 0 0\nJMP 0\n");
+        assert!(analyze(&code).is_err());
+    }
+
+    #[test]
+    fn test_analyze_yield() {
+        let code = assemble("CONST 10\nLOAD 0 0\nYIELD 0\nMOVE 1 0\nHALT\n");
+        let analysis = analyze(&code).unwrap();
+        assert_eq!(analysis.register(5, 0), ValueType::I8);
+        assert_eq!(analysis.register(8, 1), ValueType::I8);
+    }
+
+    #[test]
+    fn test_analyze_rejects_128_bit() {
+        let code = assemble("CONST 1u128\nLOAD 0 0\nHALT\n");
+        assert_eq!(analyze(&code).err().unwrap(), "LOAD at 0 uses 128-bit integers, which the backends do not support");
+    }
+
+    #[test]
+    fn test_analyze_rejects_big_integers() {
+        let code = assemble("CONST 0xffbig\nLOAD 0 0\nHALT\n");
+        assert_eq!(analyze(&code).err().unwrap(), "LOAD at 0 uses big integers, which the backends do not support");
+    }
+
+    #[test]
+    fn test_analyze_rejects_strings() {
+        let code = assemble("CONST \"hi\"\nLOAD 0 0\nPRINT 0\nHALT\n");
+        assert_eq!(analyze(&code).err().unwrap(), "LOAD at 0 uses strings, which the backends do not support");
+    }
+}
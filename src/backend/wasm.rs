@@ -0,0 +1,970 @@
+// translates bytecode into a binary WebAssembly module
+//
+// the module exports a single function `main` and imports one function per printed type from
+// the `tower` module (`print_i8`, `print_f32`, ...) which the host is expected to format the
+// same way as Value's Display
+use std::collections::{BTreeMap, BTreeSet};
+use crate::{code::Code, opcode::Opcode, value::{Value, ValueType}};
+use super::{analyze, Analysis};
+
+const MAGIC: &[u8] = b"\0asm";
+const VERSION: &[u8] = &[1, 0, 0, 0];
+
+const SECTION_TYPE: u8 = 1;
+const SECTION_IMPORT: u8 = 2;
+const SECTION_FUNCTION: u8 = 3;
+const SECTION_EXPORT: u8 = 7;
+const SECTION_CODE: u8 = 10;
+
+const I32: u8 = 0x7F;
+const I64: u8 = 0x7E;
+const F32: u8 = 0x7D;
+const F64: u8 = 0x7C;
+
+const BLOCK: u8 = 0x02;
+const LOOP: u8 = 0x03;
+const IF: u8 = 0x04;
+const ELSE: u8 = 0x05;
+const END: u8 = 0x0B;
+const BR: u8 = 0x0C;
+const RETURN: u8 = 0x0F;
+const CALL: u8 = 0x10;
+const LOCAL_GET: u8 = 0x20;
+const LOCAL_SET: u8 = 0x21;
+const I32_CONST: u8 = 0x41;
+const I64_CONST: u8 = 0x42;
+const F32_CONST: u8 = 0x43;
+const F64_CONST: u8 = 0x44;
+const I32_AND: u8 = 0x71;
+const I32_EXTEND8_S: u8 = 0xC0;
+const I32_EXTEND16_S: u8 = 0xC1;
+const EMPTY_BLOCK: u8 = 0x40;
+
+fn wasm_type(ty: ValueType) -> u8 {
+    match ty {
+        ValueType::I64 | ValueType::U64 => I64,
+        ValueType::F32 => F32,
+        ValueType::F64 => F64,
+        _ => I32,
+    }
+}
+
+fn write_u32(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_i64(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    write_u32(out, name.len() as u32);
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn write_section(out: &mut Vec<u8>, id: u8, count: usize, contents: &[u8]) {
+    let mut section = Vec::new();
+    write_u32(&mut section, count as u32);
+    section.extend_from_slice(contents);
+    out.push(id);
+    write_u32(out, section.len() as u32);
+    out.extend_from_slice(&section);
+}
+
+fn write_const(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Bool(b) => {
+            out.push(I32_CONST);
+            write_i64(out, *b as i64);
+        }
+        Value::I8(i) => {
+            out.push(I32_CONST);
+            write_i64(out, *i as i64);
+        }
+        Value::I16(i) => {
+            out.push(I32_CONST);
+            write_i64(out, *i as i64);
+        }
+        Value::I32(i) => {
+            out.push(I32_CONST);
+            write_i64(out, *i as i64);
+        }
+        Value::U8(u) => {
+            out.push(I32_CONST);
+            write_i64(out, *u as i64);
+        }
+        Value::U16(u) => {
+            out.push(I32_CONST);
+            write_i64(out, *u as i64);
+        }
+        Value::U32(u) => {
+            out.push(I32_CONST);
+            write_i64(out, *u as i32 as i64);
+        }
+        Value::I64(i) => {
+            out.push(I64_CONST);
+            write_i64(out, *i);
+        }
+        Value::U64(u) => {
+            out.push(I64_CONST);
+            write_i64(out, *u as i64);
+        }
+        Value::F32(f) => {
+            out.push(F32_CONST);
+            out.extend_from_slice(&f.to_le_bytes());
+        }
+        Value::F64(f) => {
+            out.push(F64_CONST);
+            out.extend_from_slice(&f.to_le_bytes());
+        }
+        Value::Str(_) | Value::Ref(_) | Value::I128(_) | Value::U128(_) | Value::Big(_) | Value::Char(_) | Value::Bytes(_) => {
+            unreachable!("text, references, 128-bit and big integers are rejected by analyze")
+        },
+    }
+}
+
+fn binary_op(opcode: Opcode, ty: ValueType) -> u8 {
+    let signed = matches!(ty, ValueType::I8 | ValueType::I16 | ValueType::I32 | ValueType::I64);
+    match (wasm_type(ty), opcode) {
+        (I32, Opcode::ADD) => 0x6A,
+        (I32, Opcode::SUB) => 0x6B,
+        (I32, Opcode::MUL) => 0x6C,
+        (I32, Opcode::DIV) => if signed { 0x6D } else { 0x6E },
+        (I32, Opcode::AND) => I32_AND,
+        (I32, Opcode::OR) => 0x72,
+        (I32, Opcode::XOR) => 0x73,
+        (I32, Opcode::SHL) => 0x74,
+        (I32, Opcode::SHR) => if signed { 0x75 } else { 0x76 },
+        (I64, Opcode::ADD) => 0x7C,
+        (I64, Opcode::SUB) => 0x7D,
+        (I64, Opcode::MUL) => 0x7E,
+        (I64, Opcode::DIV) => if signed { 0x7F } else { 0x80 },
+        (I64, Opcode::AND) => 0x83,
+        (I64, Opcode::OR) => 0x84,
+        (I64, Opcode::XOR) => 0x85,
+        (I64, Opcode::SHL) => 0x86,
+        (I64, Opcode::SHR) => if signed { 0x87 } else { 0x88 },
+        (F32, Opcode::ADD) => 0x92,
+        (F32, Opcode::SUB) => 0x93,
+        (F32, Opcode::MUL) => 0x94,
+        (F32, Opcode::DIV) => 0x95,
+        (F64, Opcode::ADD) => 0xA0,
+        (F64, Opcode::SUB) => 0xA1,
+        (F64, Opcode::MUL) => 0xA2,
+        (F64, Opcode::DIV) => 0xA3,
+        _ => unreachable!("{} is not defined on {}", opcode, ty),
+    }
+}
+
+// i8, i16, u8 and u16 live in i32 locals and are re-wrapped after every operation
+fn write_wrap(out: &mut Vec<u8>, ty: ValueType) {
+    match ty {
+        ValueType::I8 => out.push(I32_EXTEND8_S),
+        ValueType::I16 => out.push(I32_EXTEND16_S),
+        ValueType::U8 | ValueType::U16 => {
+            out.push(I32_CONST);
+            write_i64(out, if ty == ValueType::U8 { 0xFF } else { 0xFFFF });
+            out.push(I32_AND);
+        }
+        _ => {}
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Exit {
+    Halt,
+    Goto(usize),
+    // JMPIF at offset, taken to the first target and falling through to the second
+    Branch(usize, usize, usize),
+}
+
+// a straight run of instructions, identified by the offset of its first instruction
+struct Block {
+    instructions: Vec<usize>,
+    exit: Exit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Frame {
+    // a `block` whose end is where control for the merge node starts
+    Block(usize),
+    // a `loop` whose start is the loop header
+    Loop(usize),
+    // an `if` arm, which is never branched to but still counts towards label depth
+    If,
+}
+
+struct Emitter<'a> {
+    code: &'a Code,
+    analysis: &'a Analysis,
+    blocks: BTreeMap<usize, Block>,
+    rpo: BTreeMap<usize, usize>,
+    children: BTreeMap<usize, Vec<usize>>,
+    loop_headers: BTreeSet<usize>,
+    merge_nodes: BTreeSet<usize>,
+    registers: BTreeMap<(u8, ValueType), u32>,
+    globals: BTreeMap<(u8, ValueType), u32>,
+    prints: BTreeMap<ValueType, u32>,
+}
+
+fn build_blocks(code: &Code, analysis: &Analysis) -> BTreeMap<usize, Block> {
+    let mut leaders: BTreeSet<usize> = analysis.jump_targets.clone();
+    leaders.insert(0);
+    let mut blocks = BTreeMap::new();
+    let mut current: Option<(usize, Vec<usize>)> = None;
+    for &offset in analysis.states.keys() {
+        let opcode = Opcode::from(code.raw[offset]);
+        let (start, mut instructions) = match current.take() {
+            Some(block) if !leaders.contains(&offset) => block,
+            Some((start, instructions)) => {
+                blocks.insert(start, Block { instructions, exit: Exit::Goto(offset) });
+                (offset, Vec::new())
+            }
+            None => (offset, Vec::new()),
+        };
+        match opcode {
+            Opcode::HALT => {
+                blocks.insert(start, Block { instructions, exit: Exit::Halt });
+            }
+            Opcode::JMP => {
+                let target = code.raw[offset + 1] as usize;
+                blocks.insert(start, Block { instructions, exit: Exit::Goto(target) });
+            }
+            Opcode::JMPIF => {
+                let target = code.raw[offset + 2] as usize;
+                blocks.insert(start, Block { instructions, exit: Exit::Branch(offset, target, offset + 3) });
+            }
+            _ => {
+                instructions.push(offset);
+                current = Some((start, instructions));
+            }
+        }
+    }
+    blocks
+}
+
+fn successors(block: &Block) -> Vec<usize> {
+    match block.exit {
+        Exit::Halt => vec![],
+        Exit::Goto(target) => vec![target],
+        Exit::Branch(_, target, next) if target == next => vec![target],
+        Exit::Branch(_, target, next) => vec![target, next],
+    }
+}
+
+impl<'a> Emitter<'a> {
+    fn new(code: &'a Code, analysis: &'a Analysis) -> Result<Emitter<'a>, String> {
+        let blocks = build_blocks(code, analysis);
+
+        // reverse postorder numbering from the entry block
+        let mut postorder = Vec::new();
+        let mut visited = BTreeSet::new();
+        let mut stack = vec![(0, false)];
+        while let Some((node, done)) = stack.pop() {
+            if done {
+                postorder.push(node);
+                continue;
+            }
+            if !visited.insert(node) {
+                continue;
+            }
+            stack.push((node, true));
+            for successor in successors(&blocks[&node]).into_iter().rev() {
+                if !visited.contains(&successor) {
+                    stack.push((successor, false));
+                }
+            }
+        }
+        let order: Vec<usize> = postorder.into_iter().rev().collect();
+        let rpo: BTreeMap<usize, usize> = order.iter().enumerate().map(|(i, &node)| (node, i)).collect();
+
+        let mut predecessors: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for &node in &order {
+            for successor in successors(&blocks[&node]) {
+                predecessors.entry(successor).or_default().push(node);
+            }
+        }
+
+        // immediate dominators using the iterative algorithm of Cooper, Harvey and Kennedy
+        let mut idom: BTreeMap<usize, usize> = BTreeMap::new();
+        idom.insert(0, 0);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &node in order.iter().skip(1) {
+                let mut new_idom: Option<usize> = None;
+                for &pred in &predecessors[&node] {
+                    if !idom.contains_key(&pred) {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => pred,
+                        Some(mut other) => {
+                            let mut finger = pred;
+                            while finger != other {
+                                while rpo[&finger] > rpo[&other] {
+                                    finger = idom[&finger];
+                                }
+                                while rpo[&other] > rpo[&finger] {
+                                    other = idom[&other];
+                                }
+                            }
+                            finger
+                        }
+                    });
+                }
+                let new_idom = new_idom.expect("reachable block has a processed predecessor");
+                if idom.get(&node) != Some(&new_idom) {
+                    idom.insert(node, new_idom);
+                    changed = true;
+                }
+            }
+        }
+        let dominates = |a: usize, mut b: usize| loop {
+            if a == b {
+                return true;
+            }
+            if b == 0 {
+                return false;
+            }
+            b = idom[&b];
+        };
+
+        let mut children: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for &node in order.iter().skip(1) {
+            children.entry(idom[&node]).or_default().push(node);
+        }
+        let mut loop_headers = BTreeSet::new();
+        let mut merge_nodes = BTreeSet::new();
+        for (&node, preds) in &predecessors {
+            let mut forward = 0;
+            for &pred in preds {
+                if rpo[&pred] >= rpo[&node] {
+                    if !dominates(node, pred) {
+                        return Err(format!("Irreducible control flow into {}", node));
+                    }
+                    loop_headers.insert(node);
+                } else {
+                    forward += 1;
+                }
+            }
+            if forward > 1 {
+                merge_nodes.insert(node);
+            }
+        }
+
+        // only types that are actually printed get an import, numbered in type order
+        let printed: BTreeSet<ValueType> = analysis.states.keys()
+            .filter(|&&offset| code.raw[offset] == Opcode::PRINT as u8)
+            .map(|&offset| analysis.register(offset, code.raw[offset + 1]))
+            .collect();
+        let prints = printed.into_iter().zip(0..).collect();
+        let mut local = 0;
+        let mut registers = BTreeMap::new();
+        for &pair in &analysis.register_types {
+            registers.insert(pair, local);
+            local += 1;
+        }
+        let mut globals = BTreeMap::new();
+        for &pair in &analysis.global_types {
+            globals.insert(pair, local);
+            local += 1;
+        }
+
+        Ok(Emitter {
+            code,
+            analysis,
+            blocks,
+            rpo,
+            children,
+            loop_headers,
+            merge_nodes,
+            registers,
+            globals,
+            prints,
+        })
+    }
+
+    fn get_register(&self, out: &mut Vec<u8>, offset: usize, reg: u8) -> ValueType {
+        let ty = self.analysis.register(offset, reg);
+        out.push(LOCAL_GET);
+        write_u32(out, self.registers[&(reg, ty)]);
+        ty
+    }
+
+    fn set_register(&self, out: &mut Vec<u8>, reg: u8, ty: ValueType) {
+        out.push(LOCAL_SET);
+        write_u32(out, self.registers[&(reg, ty)]);
+    }
+
+    fn write_instruction(&self, out: &mut Vec<u8>, offset: usize) {
+        let opcode = Opcode::from(self.code.raw[offset]);
+        let operands = &self.code.raw[offset + 1..offset + 1 + opcode.get_offset()];
+        match opcode {
+            Opcode::PRINT => {
+                let ty = self.get_register(out, offset, operands[0]);
+                out.push(CALL);
+                write_u32(out, self.prints[&ty]);
+            }
+            Opcode::MOVE => {
+                let ty = self.get_register(out, offset, operands[1]);
+                self.set_register(out, operands[0], ty);
+            }
+            Opcode::LOAD => {
+                let value = &self.code.const_pool[operands[1] as usize];
+                write_const(out, value);
+                self.set_register(out, operands[0], value.value_type());
+            }
+            // globals live in locals since main is the only function
+            Opcode::GETGLOBAL => {
+                let ty = self.analysis.global(offset, operands[1]);
+                out.push(LOCAL_GET);
+                write_u32(out, self.globals[&(operands[1], ty)]);
+                self.set_register(out, operands[0], ty);
+            }
+            Opcode::SETGLOBAL => {
+                let ty = self.get_register(out, offset, operands[0]);
+                out.push(LOCAL_SET);
+                write_u32(out, self.globals[&(operands[1], ty)]);
+            }
+            // there is no host to hand the value to, so execution just carries on
+            Opcode::YIELD => {}
+            Opcode::JMP | Opcode::JMPIF | Opcode::HALT => unreachable!("{} ends a block", opcode),
+            _ => {
+                let ty = self.get_register(out, offset, operands[1]);
+                self.get_register(out, offset, operands[2]);
+                out.push(binary_op(opcode, ty));
+                write_wrap(out, ty);
+                self.set_register(out, operands[0], ty);
+            }
+        }
+    }
+
+    fn write_tree(&self, out: &mut Vec<u8>, node: usize, context: &mut Vec<Frame>) {
+        let mut merges: Vec<usize> = self.children.get(&node).into_iter().flatten()
+            .copied()
+            .filter(|child| self.merge_nodes.contains(child))
+            .collect();
+        merges.sort_by_key(|child| self.rpo[child]);
+        if self.loop_headers.contains(&node) {
+            out.extend_from_slice(&[LOOP, EMPTY_BLOCK]);
+            context.push(Frame::Loop(node));
+            self.write_within(out, node, &merges, context);
+            context.pop();
+            out.push(END);
+        } else {
+            self.write_within(out, node, &merges, context);
+        }
+    }
+
+    // the merge nodes dominated by node follow it, each wrapped in a block that
+    // branches to it can break out of
+    fn write_within(&self, out: &mut Vec<u8>, node: usize, merges: &[usize], context: &mut Vec<Frame>) {
+        match merges.split_last() {
+            Some((&merge, rest)) => {
+                out.extend_from_slice(&[BLOCK, EMPTY_BLOCK]);
+                context.push(Frame::Block(merge));
+                self.write_within(out, node, rest, context);
+                context.pop();
+                out.push(END);
+                self.write_tree(out, merge, context);
+            }
+            None => {
+                let block = &self.blocks[&node];
+                for &offset in &block.instructions {
+                    self.write_instruction(out, offset);
+                }
+                match block.exit {
+                    Exit::Halt => out.push(RETURN),
+                    Exit::Goto(target) => self.write_branch(out, node, target, context),
+                    Exit::Branch(offset, target, next) => {
+                        self.get_register(out, offset, self.code.raw[offset + 1]);
+                        out.extend_from_slice(&[IF, EMPTY_BLOCK]);
+                        context.push(Frame::If);
+                        self.write_branch(out, node, target, context);
+                        out.push(ELSE);
+                        self.write_branch(out, node, next, context);
+                        context.pop();
+                        out.push(END);
+                    }
+                }
+            }
+        }
+    }
+
+    fn write_branch(&self, out: &mut Vec<u8>, source: usize, target: usize, context: &mut Vec<Frame>) {
+        let frame = if self.rpo[&target] <= self.rpo[&source] {
+            Frame::Loop(target)
+        } else if self.merge_nodes.contains(&target) {
+            Frame::Block(target)
+        } else {
+            self.write_tree(out, target, context);
+            return;
+        };
+        let depth = context.iter().rev().position(|f| *f == frame).expect("branch target is in scope");
+        out.push(BR);
+        write_u32(out, depth as u32);
+    }
+
+    fn write_body(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        let locals: Vec<u8> = self.registers.keys().map(|&(_, ty)| wasm_type(ty))
+            .chain(self.globals.keys().map(|&(_, ty)| wasm_type(ty)))
+            .collect();
+        write_u32(&mut body, locals.len() as u32);
+        for ty in locals {
+            write_u32(&mut body, 1);
+            body.push(ty);
+        }
+        for (&(global, ty), &local) in &self.globals {
+            let value = &self.code.globals[global as usize];
+            if value.value_type() == ty {
+                write_const(&mut body, value);
+                body.push(LOCAL_SET);
+                write_u32(&mut body, local);
+            }
+        }
+        self.write_tree(&mut body, 0, &mut Vec::new());
+        body.push(END);
+        body
+    }
+}
+
+pub fn emit(code: &Code) -> Result<Vec<u8>, String> {
+    let analysis = analyze(code)?;
+    // the host only learns the type from the import, so it cannot tell typed prints apart
+    if let Some(offset) = analysis.states.keys().find(|&&offset| code.raw[offset] == Opcode::TPRINT as u8) {
+        return Err(format!("TPRINT at {} is not supported by the wasm backend", offset));
+    }
+    let emitter = Emitter::new(code, &analysis)?;
+
+    // type 0 is main, followed by one type per printed type
+    let mut types = vec![0x60, 0, 0];
+    for ty in emitter.prints.keys() {
+        types.extend_from_slice(&[0x60, 1, wasm_type(*ty), 0]);
+    }
+    let mut imports = Vec::new();
+    for (i, ty) in emitter.prints.keys().enumerate() {
+        write_name(&mut imports, "tower");
+        write_name(&mut imports, &format!("print_{}", ty));
+        imports.push(0x00);
+        write_u32(&mut imports, i as u32 + 1);
+    }
+    let mut exports = Vec::new();
+    write_name(&mut exports, "main");
+    exports.push(0x00);
+    write_u32(&mut exports, emitter.prints.len() as u32);
+    let body = emitter.write_body();
+    let mut bodies = Vec::new();
+    write_u32(&mut bodies, body.len() as u32);
+    bodies.extend_from_slice(&body);
+
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(VERSION);
+    write_section(&mut out, SECTION_TYPE, emitter.prints.len() + 1, &types);
+    write_section(&mut out, SECTION_IMPORT, emitter.prints.len(), &imports);
+    write_section(&mut out, SECTION_FUNCTION, 1, &[0]);
+    write_section(&mut out, SECTION_EXPORT, 1, &exports);
+    write_section(&mut out, SECTION_CODE, 1, &bodies);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::assemble;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Instr {
+        Block,
+        Loop,
+        If,
+        Else,
+        End,
+        Br(u32),
+        Return,
+        Call(u32),
+        LocalGet(u32),
+        LocalSet(u32),
+        Const(Value),
+        Op(u8),
+    }
+
+    struct Module {
+        types: Vec<(Vec<u8>, Vec<u8>)>,
+        imports: Vec<(String, String, u32)>,
+        functions: Vec<u32>,
+        exports: Vec<(String, u32)>,
+        locals: Vec<u8>,
+        body: Vec<Instr>,
+    }
+
+    struct Reader<'a> {
+        bytes: &'a [u8],
+        pos: usize,
+    }
+
+    impl Reader<'_> {
+        fn byte(&mut self) -> u8 {
+            self.pos += 1;
+            self.bytes[self.pos - 1]
+        }
+
+        fn take(&mut self, n: usize) -> &[u8] {
+            self.pos += n;
+            &self.bytes[self.pos - n..self.pos]
+        }
+
+        fn u32(&mut self) -> u32 {
+            let (mut result, mut shift) = (0, 0);
+            loop {
+                let byte = self.byte();
+                result |= ((byte & 0x7F) as u32) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    return result;
+                }
+            }
+        }
+
+        fn i64(&mut self) -> i64 {
+            let (mut result, mut shift) = (0i64, 0);
+            loop {
+                let byte = self.byte();
+                result |= ((byte & 0x7F) as i64) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    if shift < 64 && byte & 0x40 != 0 {
+                        result |= -1 << shift;
+                    }
+                    return result;
+                }
+            }
+        }
+
+        fn name(&mut self) -> String {
+            let len = self.u32() as usize;
+            String::from_utf8(self.take(len).to_vec()).unwrap()
+        }
+
+        fn vec<T>(&mut self, mut item: impl FnMut(&mut Self) -> T) -> Vec<T> {
+            let count = self.u32();
+            (0..count).map(|_| item(self)).collect()
+        }
+    }
+
+    // decodes the instruction stream, checking that blocks nest and branches stay in scope
+    fn decode_body(reader: &mut Reader) -> Vec<Instr> {
+        let mut body = Vec::new();
+        let mut depth = 1;
+        while depth > 0 {
+            let instr = match reader.byte() {
+                BLOCK | LOOP | IF => {
+                    assert_eq!(reader.byte(), EMPTY_BLOCK);
+                    depth += 1;
+                    match reader.bytes[reader.pos - 2] {
+                        BLOCK => Instr::Block,
+                        LOOP => Instr::Loop,
+                        _ => Instr::If,
+                    }
+                }
+                ELSE => Instr::Else,
+                END => {
+                    depth -= 1;
+                    Instr::End
+                }
+                BR => {
+                    let label = reader.u32();
+                    assert!(label < depth - 1, "branch out of the function body");
+                    Instr::Br(label)
+                }
+                RETURN => Instr::Return,
+                CALL => Instr::Call(reader.u32()),
+                LOCAL_GET => Instr::LocalGet(reader.u32()),
+                LOCAL_SET => Instr::LocalSet(reader.u32()),
+                I32_CONST => Instr::Const(Value::I32(reader.i64() as i32)),
+                I64_CONST => Instr::Const(Value::I64(reader.i64())),
+                F32_CONST => Instr::Const(Value::F32(f32::from_le_bytes(reader.take(4).try_into().unwrap()))),
+                F64_CONST => Instr::Const(Value::F64(f64::from_le_bytes(reader.take(8).try_into().unwrap()))),
+                op => Instr::Op(op),
+            };
+            body.push(instr);
+        }
+        body
+    }
+
+    fn decode(bytes: &[u8]) -> Module {
+        assert_eq!(&bytes[..4], MAGIC);
+        assert_eq!(&bytes[4..8], VERSION);
+        let mut reader = Reader { bytes, pos: 8 };
+        let mut module = Module {
+            types: vec![],
+            imports: vec![],
+            functions: vec![],
+            exports: vec![],
+            locals: vec![],
+            body: vec![],
+        };
+        let mut last_id = 0;
+        while reader.pos < bytes.len() {
+            let id = reader.byte();
+            assert!(id > last_id, "sections out of order");
+            last_id = id;
+            let size = reader.u32() as usize;
+            let end = reader.pos + size;
+            match id {
+                SECTION_TYPE => {
+                    module.types = reader.vec(|r| {
+                        assert_eq!(r.byte(), 0x60);
+                        let params = r.vec(|r| r.byte());
+                        let results = r.vec(|r| r.byte());
+                        (params, results)
+                    });
+                }
+                SECTION_IMPORT => {
+                    module.imports = reader.vec(|r| {
+                        let (module, name) = (r.name(), r.name());
+                        assert_eq!(r.byte(), 0x00);
+                        (module, name, r.u32())
+                    });
+                }
+                SECTION_FUNCTION => module.functions = reader.vec(|r| r.u32()),
+                SECTION_EXPORT => {
+                    module.exports = reader.vec(|r| {
+                        let name = r.name();
+                        assert_eq!(r.byte(), 0x00);
+                        (name, r.u32())
+                    });
+                }
+                SECTION_CODE => {
+                    assert_eq!(reader.u32(), 1);
+                    let body_size = reader.u32() as usize;
+                    let body_end = reader.pos + body_size;
+                    let groups = reader.vec(|r| (r.u32(), r.byte()));
+                    for (count, ty) in groups {
+                        module.locals.extend(std::iter::repeat_n(ty, count as usize));
+                    }
+                    module.body = decode_body(&mut reader);
+                    assert_eq!(reader.pos, body_end);
+                }
+                _ => panic!("unexpected section {}", id),
+            }
+            assert_eq!(reader.pos, end, "section {} has the wrong size", id);
+        }
+        module
+    }
+
+    // index of the else or end that closes the construct opened at start
+    fn matching(body: &[Instr], start: usize, stop_at_else: bool) -> usize {
+        let mut depth = 0;
+        let mut pc = start;
+        loop {
+            pc += 1;
+            match body[pc] {
+                Instr::Block | Instr::Loop | Instr::If => depth += 1,
+                Instr::Else if depth == 0 && stop_at_else => return pc,
+                Instr::End if depth == 0 => return pc,
+                Instr::End => depth -= 1,
+                _ => {}
+            }
+        }
+    }
+
+    // interprets the decoded body for at most fuel instructions and returns what was printed
+    fn run(module: &Module, fuel: usize) -> String {
+        let mut output = String::new();
+        let mut locals: Vec<Value> = module.locals.iter().map(|&ty| match ty {
+            I32 => Value::I32(0),
+            I64 => Value::I64(0),
+            F32 => Value::F32(0.0),
+            _ => Value::F64(0.0),
+        }).collect();
+        let mut stack: Vec<Value> = vec![];
+        let mut labels: Vec<(Instr, usize)> = vec![];
+        let mut pc = 0;
+        for _ in 0..fuel {
+            match module.body[pc] {
+                Instr::Block | Instr::Loop => labels.push((module.body[pc], pc)),
+                Instr::If => {
+                    let start = pc;
+                    if stack.pop().unwrap() == Value::I32(0) {
+                        pc = matching(&module.body, start, true);
+                    }
+                    if module.body[pc] != Instr::End {
+                        labels.push((Instr::If, start));
+                    }
+                }
+                // the then arm finished without branching
+                Instr::Else => {
+                    let (_, start) = labels.pop().unwrap();
+                    pc = matching(&module.body, start, false);
+                }
+                Instr::End => {
+                    if labels.pop().is_none() {
+                        break;
+                    }
+                }
+                Instr::Br(label) => {
+                    let (kind, start) = labels[labels.len() - 1 - label as usize];
+                    labels.truncate(labels.len() - 1 - label as usize);
+                    if kind == Instr::Loop {
+                        labels.push((kind, start));
+                        pc = start;
+                    } else {
+                        pc = matching(&module.body, start, false);
+                    }
+                }
+                Instr::Return => break,
+                Instr::Call(function) => {
+                    let (_, name, _) = &module.imports[function as usize];
+                    let value = match (name.as_str(), stack.pop().unwrap()) {
+                        ("print_bool", Value::I32(v)) => Value::Bool(v != 0),
+                        ("print_i8", Value::I32(v)) => Value::I8(v as i8),
+                        ("print_i16", Value::I32(v)) => Value::I16(v as i16),
+                        ("print_u8", Value::I32(v)) => Value::U8(v as u8),
+                        ("print_u16", Value::I32(v)) => Value::U16(v as u16),
+                        ("print_u32", Value::I32(v)) => Value::U32(v as u32),
+                        ("print_u64", Value::I64(v)) => Value::U64(v as u64),
+                        (_, v) => v,
+                    };
+                    output.push_str(&value.to_string());
+                }
+                Instr::LocalGet(local) => stack.push(locals[local as usize]),
+                Instr::LocalSet(local) => locals[local as usize] = stack.pop().unwrap(),
+                Instr::Const(value) => stack.push(value),
+                Instr::Op(op) => {
+                    let b = stack.pop().unwrap();
+                    let value = match (op, b) {
+                        (I32_EXTEND8_S, Value::I32(v)) => Value::I32(v as i8 as i32),
+                        (I32_EXTEND16_S, Value::I32(v)) => Value::I32(v as i16 as i32),
+                        (_, b) => match (op, stack.pop().unwrap(), b) {
+                            (0x6A, Value::I32(a), Value::I32(b)) => Value::I32(a.wrapping_add(b)),
+                            (0x6B, Value::I32(a), Value::I32(b)) => Value::I32(a.wrapping_sub(b)),
+                            (0x6C, Value::I32(a), Value::I32(b)) => Value::I32(a.wrapping_mul(b)),
+                            (0x6D, Value::I32(a), Value::I32(b)) => Value::I32(a / b),
+                            (I32_AND, Value::I32(a), Value::I32(b)) => Value::I32(a & b),
+                            (0x73, Value::I32(a), Value::I32(b)) => Value::I32(a ^ b),
+                            (0x75, Value::I32(a), Value::I32(b)) => Value::I32(a >> b),
+                            (0x7C, Value::I64(a), Value::I64(b)) => Value::I64(a.wrapping_add(b)),
+                            (0x92, Value::F32(a), Value::F32(b)) => Value::F32(a + b),
+                            (0xA2, Value::F64(a), Value::F64(b)) => Value::F64(a * b),
+                            (op, a, b) => panic!("test interpreter does not support {:#x} on {:?} {:?}", op, a, b),
+                        },
+                    };
+                    stack.push(value);
+                }
+            }
+            pc += 1;
+        }
+        output
+    }
+
+    #[test]
+    fn test_emit_module() {
+        let code = assemble("CONST 10.5\nCONST 30.5\nLOAD 1 0\nLOAD 2 1\nADD 3 1 2\nPRINT 3\nHALT\n");
+        let module = decode(&emit(&code).unwrap());
+        assert_eq!(module.types, vec![(vec![], vec![]), (vec![F32], vec![])]);
+        assert_eq!(module.imports, vec![("tower".to_string(), "print_f32".to_string(), 1)]);
+        assert_eq!(module.functions, vec![0]);
+        assert_eq!(module.exports, vec![("main".to_string(), 1)]);
+        assert_eq!(module.locals, vec![F32, F32, F32]);
+        assert_eq!(run(&module, 100), "41");
+    }
+
+    #[test]
+    fn test_emit_integers() {
+        let mut code = Code::new();
+        code.add_const(Value::I8(-128));
+        code.add_const(Value::I8(1));
+        code.add_const(Value::U64(u64::MAX));
+        code.add_const(Value::U64(1));
+        for byte in [
+            Opcode::LOAD as u8, 0, 0,
+            Opcode::LOAD as u8, 1, 1,
+            Opcode::SHR as u8, 2, 0, 1,
+            Opcode::PRINT as u8, 2,
+            Opcode::SUB as u8, 2, 0, 1,
+            Opcode::PRINT as u8, 2,
+            Opcode::LOAD as u8, 3, 2,
+            Opcode::LOAD as u8, 4, 3,
+            Opcode::ADD as u8, 3, 3, 4,
+            Opcode::PRINT as u8, 3,
+            Opcode::HALT as u8,
+        ] {
+            code.write_code(byte, 0);
+        }
+        let module = decode(&emit(&code).unwrap());
+        let names: Vec<&str> = module.imports.iter().map(|i| i.1.as_str()).collect();
+        assert_eq!(names, vec!["print_i8", "print_u64"]);
+        // i8 subtraction wraps around through i32.extend8_s
+        assert_eq!(run(&module, 100), "-641270");
+    }
+
+    #[test]
+    fn test_emit_loop() {
+        // counts up from the u8 zero every register starts with
+        let mut code = Code::new();
+        code.add_const(Value::U8(1));
+        for byte in [
+            Opcode::LOAD as u8, 1, 0,
+            Opcode::ADD as u8, 0, 0, 1,
+            Opcode::PRINT as u8, 0,
+            Opcode::JMP as u8, 3,
+        ] {
+            code.write_code(byte, 0);
+        }
+        let module = decode(&emit(&code).unwrap());
+        assert_eq!(module.body.iter().filter(|&&i| i == Instr::Loop).count(), 1);
+        assert!(run(&module, 200).starts_with("12345678910"));
+    }
+
+    #[test]
+    fn test_emit_loop_entered_in_the_middle() {
+        // the loop is entered at its second block, so the header is the jump target
+        let src = "CONST 1\nCONST 2\nLOAD 0 0\nLOAD 1 1\nJMP 10\nPRINT 1\nPRINT 0\nJMP 8\n";
+        let code = assemble(src);
+        let module = decode(&emit(&code).unwrap());
+        assert!(run(&module, 100).starts_with("1212121"));
+    }
+
+    // counts up, printing the count on odd iterations and 1 on even ones
+    const BRANCH_SRC: &str = "CONST true\nCONST 1u8\nLOAD 1 0\nLOAD 2 0\nLOAD 3 1\nADD 0 0 3\nXOR 1 1 2\n\
+        JMPIF 1 24\nPRINT 0\nJMP 9\nPRINT 3\nJMP 9\n";
+
+    #[test]
+    fn test_emit_branch() {
+        let module = decode(&emit(&assemble(BRANCH_SRC)).unwrap());
+        assert_eq!(module.body.iter().filter(|&&i| i == Instr::If).count(), 1);
+        assert!(run(&module, 300).starts_with("11315171"));
+    }
+
+    #[test]
+    fn test_emit_rejects_non_bool_branch() {
+        let code = assemble("CONST 1\nLOAD 0 0\nJMPIF 0 0\nHALT\n");
+        assert_eq!(emit(&code).err().unwrap(), "Cannot branch on type i8 at 3");
+    }
+
+    #[test]
+    fn test_emit_rejects_type_conflict() {
+        let code = assemble("CONST 10\nPRINT 0\nLOAD 0 0\nJMP 0\n");
+        assert!(emit(&code).is_err());
+    }
+}
@@ -0,0 +1,590 @@
+// arbitrary-precision integers as a sign and a magnitude of 32-bit limbs, least significant first.
+// a value holds a Big handle so it stays Copy. like strings, constants are interned for the rest of
+// the process and numbers computed at run time are objects on the machine's heap
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use crate::{heap::Ref, string::ON_HEAP};
+
+// normalized: the top limb is never zero and zero is never negative,
+// so equal numbers are equal structs
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    negative: bool,
+    limbs: Vec<u32>,
+}
+
+fn trim(mut limbs: Vec<u32>) -> Vec<u32> {
+    while limbs.last() == Some(&0) {
+        limbs.pop();
+    }
+    limbs
+}
+
+fn cmp_magnitude(a: &[u32], b: &[u32]) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let (long, short) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let mut out = Vec::with_capacity(long.len() + 1);
+    let mut carry = 0;
+    for (i, &limb) in long.iter().enumerate() {
+        let sum = limb as u64 + *short.get(i).unwrap_or(&0) as u64 + carry;
+        out.push(sum as u32);
+        carry = sum >> 32;
+    }
+    out.push(carry as u32);
+    trim(out)
+}
+
+// a - b, a must not be smaller than b
+fn sub_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = Vec::with_capacity(a.len());
+    let mut borrow = 0;
+    for (i, &limb) in a.iter().enumerate() {
+        let (diff, under) = limb.overflowing_sub(*b.get(i).unwrap_or(&0));
+        let (diff, under_borrow) = diff.overflowing_sub(borrow);
+        out.push(diff);
+        borrow = (under || under_borrow) as u32;
+    }
+    trim(out)
+}
+
+fn mul_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = vec![0; a.len() + b.len()];
+    for (i, &x) in a.iter().enumerate() {
+        let mut carry = 0;
+        for (j, &y) in b.iter().enumerate() {
+            let product = x as u64 * y as u64 + out[i + j] as u64 + carry;
+            out[i + j] = product as u32;
+            carry = product >> 32;
+        }
+        out[i + b.len()] = carry as u32;
+    }
+    trim(out)
+}
+
+// quotient and remainder, b must not be zero. a single limb divisor divides a limb at a time,
+// longer ones fall back to shifting in a bit at a time
+fn divmod_magnitude(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    let mut quotient = vec![0; a.len()];
+    if let [divisor] = *b {
+        let mut rem = 0;
+        for i in (0..a.len()).rev() {
+            let current = rem << 32 | a[i] as u64;
+            quotient[i] = (current / divisor as u64) as u32;
+            rem = current % divisor as u64;
+        }
+        return (trim(quotient), trim(vec![rem as u32]));
+    }
+    let mut rem: Vec<u32> = Vec::with_capacity(b.len() + 1);
+    for bit in (0..a.len() * 32).rev() {
+        let mut carry = a[bit / 32] >> (bit % 32) & 1;
+        for limb in rem.iter_mut() {
+            let top = *limb >> 31;
+            *limb = *limb << 1 | carry;
+            carry = top;
+        }
+        if carry != 0 {
+            rem.push(carry);
+        }
+        if cmp_magnitude(&rem, b) != Ordering::Less {
+            rem = sub_magnitude(&rem, b);
+            quotient[bit / 32] |= 1 << (bit % 32);
+        }
+    }
+    (trim(quotient), rem)
+}
+
+fn shl_magnitude(a: &[u32], n: usize) -> Vec<u32> {
+    if a.is_empty() {
+        return Vec::new();
+    }
+    let (words, bits) = (n / 32, n % 32);
+    let mut out = vec![0; words];
+    let mut carry = 0;
+    for &limb in a {
+        out.push(limb << bits | carry);
+        carry = if bits == 0 { 0 } else { limb >> (32 - bits) };
+    }
+    out.push(carry);
+    trim(out)
+}
+
+fn shr_magnitude(a: &[u32], n: usize) -> Vec<u32> {
+    let (words, bits) = (n / 32, n % 32);
+    let a = a.get(words..).unwrap_or(&[]);
+    let out = (0..a.len())
+        .map(|i| {
+            let high = match a.get(i + 1) {
+                Some(&high) if bits != 0 => high << (32 - bits),
+                _ => 0,
+            };
+            a[i] >> bits | high
+        })
+        .collect();
+    trim(out)
+}
+
+// negates a two's complement number in place
+fn negate_limbs(limbs: &mut [u32]) {
+    let mut carry = 1;
+    for limb in limbs.iter_mut() {
+        let sum = !*limb as u64 + carry;
+        *limb = sum as u32;
+        carry = sum >> 32;
+    }
+}
+
+impl BigInt {
+    fn new(negative: bool, limbs: Vec<u32>) -> BigInt {
+        let limbs = trim(limbs);
+        BigInt {
+            negative: negative && !limbs.is_empty(),
+            limbs,
+        }
+    }
+
+    pub fn zero() -> BigInt {
+        BigInt::default()
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.limbs.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    // the number of 32-bit limbs in the magnitude
+    pub fn limb_count(&self) -> usize {
+        self.limbs.len()
+    }
+
+    pub fn abs(&self) -> BigInt {
+        BigInt::new(false, self.limbs.clone())
+    }
+
+    // truncated like the fixed-width integers: the quotient rounds toward zero and the remainder
+    // takes the sign of the dividend. None when dividing by zero
+    pub fn divmod(&self, other: &BigInt) -> Option<(BigInt, BigInt)> {
+        if other.is_zero() {
+            return None;
+        }
+        let (quotient, rem) = divmod_magnitude(&self.limbs, &other.limbs);
+        Some((BigInt::new(self.negative != other.negative, quotient), BigInt::new(self.negative, rem)))
+    }
+
+    // the remainder is never negative
+    pub fn rem_euclid(&self, other: &BigInt) -> Option<BigInt> {
+        let (_, rem) = self.divmod(other)?;
+        match rem.negative {
+            true => Some(&rem + &other.abs()),
+            false => Some(rem),
+        }
+    }
+
+    // the number as len limbs of two's complement, len must leave room for the sign bit
+    fn twos_complement(&self, len: usize) -> Vec<u32> {
+        let mut limbs = self.limbs.clone();
+        limbs.resize(len, 0);
+        if self.negative {
+            negate_limbs(&mut limbs);
+        }
+        limbs
+    }
+
+    fn from_twos_complement(mut limbs: Vec<u32>) -> BigInt {
+        let negative = limbs.last().is_some_and(|&top| top >> 31 == 1);
+        if negative {
+            negate_limbs(&mut limbs);
+        }
+        BigInt::new(negative, limbs)
+    }
+
+    // bitwise operations act on the infinite two's complement, as if sign extended
+    fn bitwise(&self, other: &BigInt, op: fn(u32, u32) -> u32) -> BigInt {
+        let len = self.limbs.len().max(other.limbs.len()) + 1;
+        let (a, b) = (self.twos_complement(len), other.twos_complement(len));
+        BigInt::from_twos_complement(a.iter().zip(&b).map(|(&a, &b)| op(a, b)).collect())
+    }
+
+    // the shortest little-endian two's complement, empty for zero
+    pub fn to_le_bytes(&self) -> Vec<u8> {
+        let limbs = self.twos_complement(self.limbs.len() + 1);
+        let mut bytes: Vec<u8> = limbs.iter().flat_map(|limb| limb.to_le_bytes()).collect();
+        let sign = if self.negative { 0xFF } else { 0 };
+        while bytes.last() == Some(&sign) && bytes.len() > 1 && (bytes[bytes.len() - 2] ^ sign) >> 7 == 0 {
+            bytes.pop();
+        }
+        if bytes == [0] {
+            bytes.pop();
+        }
+        bytes
+    }
+
+    pub fn from_le_bytes(bytes: &[u8]) -> BigInt {
+        let sign = match bytes.last() {
+            Some(&top) if top >> 7 == 1 => 0xFF,
+            _ => 0,
+        };
+        let mut bytes = bytes.to_vec();
+        bytes.resize(bytes.len().div_ceil(4) * 4, sign);
+        let mut limbs: Vec<u32> = bytes.chunks(4).map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap())).collect();
+        // an extra limb so a positive number with its top bit set is not read as negative
+        limbs.push(if sign == 0 { 0 } else { u32::MAX });
+        BigInt::from_twos_complement(limbs)
+    }
+
+    fn magnitude_u128(&self) -> Option<u128> {
+        if self.limbs.len() > 4 {
+            return None;
+        }
+        Some(self.limbs.iter().rev().fold(0, |acc, &limb| acc << 32 | limb as u128))
+    }
+
+    pub fn to_u128(&self) -> Option<u128> {
+        self.magnitude_u128().filter(|_| !self.negative)
+    }
+
+    pub fn to_i128(&self) -> Option<i128> {
+        let magnitude = self.magnitude_u128()?;
+        match self.negative {
+            true if magnitude <= 1 << 127 => Some((magnitude as i128).wrapping_neg()),
+            true => None,
+            false => i128::try_from(magnitude).ok(),
+        }
+    }
+
+    // multiplies by a small factor and adds a digit, for parsing
+    fn mul_add_small(&mut self, factor: u32, digit: u32) {
+        let mut carry = digit as u64;
+        for limb in self.limbs.iter_mut() {
+            let product = *limb as u64 * factor as u64 + carry;
+            *limb = product as u32;
+            carry = product >> 32;
+        }
+        self.limbs.push(carry as u32);
+        self.limbs = trim(std::mem::take(&mut self.limbs));
+    }
+}
+
+impl From<u128> for BigInt {
+    fn from(u: u128) -> BigInt {
+        BigInt::new(false, (0..4).map(|i| (u >> (32 * i)) as u32).collect())
+    }
+}
+
+impl From<i128> for BigInt {
+    fn from(i: i128) -> BigInt {
+        let magnitude = BigInt::from(i.unsigned_abs());
+        BigInt::new(i < 0, magnitude.limbs)
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &BigInt) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_magnitude(&self.limbs, &other.limbs),
+            (true, true) => cmp_magnitude(&other.limbs, &self.limbs),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &BigInt) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl std::ops::Add for &BigInt {
+    type Output = BigInt;
+
+    fn add(self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::new(self.negative, add_magnitude(&self.limbs, &other.limbs));
+        }
+        match cmp_magnitude(&self.limbs, &other.limbs) {
+            Ordering::Less => BigInt::new(other.negative, sub_magnitude(&other.limbs, &self.limbs)),
+            _ => BigInt::new(self.negative, sub_magnitude(&self.limbs, &other.limbs)),
+        }
+    }
+}
+
+impl std::ops::Neg for &BigInt {
+    type Output = BigInt;
+
+    fn neg(self) -> BigInt {
+        BigInt::new(!self.negative, self.limbs.clone())
+    }
+}
+
+impl std::ops::Sub for &BigInt {
+    type Output = BigInt;
+
+    fn sub(self, other: &BigInt) -> BigInt {
+        self + &-other
+    }
+}
+
+impl std::ops::Mul for &BigInt {
+    type Output = BigInt;
+
+    fn mul(self, other: &BigInt) -> BigInt {
+        BigInt::new(self.negative != other.negative, mul_magnitude(&self.limbs, &other.limbs))
+    }
+}
+
+// !x is -x - 1, as for the fixed-width signed integers
+impl std::ops::Not for &BigInt {
+    type Output = BigInt;
+
+    fn not(self) -> BigInt {
+        &-self - &BigInt::from(1u128)
+    }
+}
+
+impl std::ops::BitAnd for &BigInt {
+    type Output = BigInt;
+
+    fn bitand(self, other: &BigInt) -> BigInt {
+        self.bitwise(other, |a, b| a & b)
+    }
+}
+
+impl std::ops::BitOr for &BigInt {
+    type Output = BigInt;
+
+    fn bitor(self, other: &BigInt) -> BigInt {
+        self.bitwise(other, |a, b| a | b)
+    }
+}
+
+impl std::ops::BitXor for &BigInt {
+    type Output = BigInt;
+
+    fn bitxor(self, other: &BigInt) -> BigInt {
+        self.bitwise(other, |a, b| a ^ b)
+    }
+}
+
+impl std::ops::Shl<usize> for &BigInt {
+    type Output = BigInt;
+
+    fn shl(self, n: usize) -> BigInt {
+        BigInt::new(self.negative, shl_magnitude(&self.limbs, n))
+    }
+}
+
+// arithmetic, negative numbers round toward negative infinity like i128 >> n
+impl std::ops::Shr<usize> for &BigInt {
+    type Output = BigInt;
+
+    fn shr(self, n: usize) -> BigInt {
+        if !self.negative {
+            return BigInt::new(false, shr_magnitude(&self.limbs, n));
+        }
+        let below = sub_magnitude(&self.limbs, &[1]);
+        BigInt::new(true, add_magnitude(&shr_magnitude(&below, n), &[1]))
+    }
+}
+
+impl std::fmt::Display for BigInt {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // nine decimal digits at a time, least significant first
+        let mut chunks = Vec::new();
+        let mut rest = self.limbs.clone();
+        while !rest.is_empty() {
+            let (quotient, rem) = divmod_magnitude(&rest, &[1_000_000_000]);
+            chunks.push(rem.first().copied().unwrap_or(0));
+            rest = quotient;
+        }
+        let mut digits = chunks.pop().unwrap_or(0).to_string();
+        for chunk in chunks.iter().rev() {
+            digits.push_str(&format!("{:09}", chunk));
+        }
+        f.pad_integral(!self.negative, "", &digits)
+    }
+}
+
+// decimal, or hexadecimal after 0x, with an optional leading minus
+impl FromStr for BigInt {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<BigInt, String> {
+        let (negative, unsigned) = match s.strip_prefix('-') {
+            Some(unsigned) => (true, unsigned),
+            None => (false, s),
+        };
+        let (radix, digits) = match unsigned.strip_prefix("0x").or_else(|| unsigned.strip_prefix("0X")) {
+            Some(digits) => (16, digits),
+            None => (10, unsigned),
+        };
+        if digits.is_empty() {
+            return Err(format!("Cannot parse {} into big", s));
+        }
+        let mut big = BigInt::zero();
+        for c in digits.chars() {
+            let digit = c.to_digit(radix).ok_or(format!("Cannot parse {} into big", s))?;
+            big.mul_add_small(radix, digit);
+        }
+        Ok(BigInt::new(negative, big.limbs))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Big(u32);
+
+#[derive(Default)]
+struct Interner {
+    ids: HashMap<&'static BigInt, u32>,
+    numbers: Vec<&'static BigInt>,
+}
+
+fn interner() -> &'static Mutex<Interner> {
+    static INTERNER: OnceLock<Mutex<Interner>> = OnceLock::new();
+    INTERNER.get_or_init(|| Mutex::new(Interner::default()))
+}
+
+impl Big {
+    pub fn intern(big: BigInt) -> Big {
+        let mut interner = interner().lock().unwrap();
+        if let Some(&id) = interner.ids.get(&big) {
+            return Big(id);
+        }
+        let big: &'static BigInt = Box::leak(Box::new(big));
+        let id = u32::try_from(interner.numbers.len()).expect("Too many interned big integers");
+        interner.numbers.push(big);
+        interner.ids.insert(big, id);
+        Big(id)
+    }
+
+    pub(crate) fn on_heap(object: Ref) -> Big {
+        Big(ON_HEAP | object.index())
+    }
+
+    // the number of an interned big integer, None for one on the heap, which only the heap can read
+    pub fn interned(&self) -> Option<&'static BigInt> {
+        match self.object() {
+            Some(_) => None,
+            None => Some(interner().lock().unwrap().numbers[self.0 as usize]),
+        }
+    }
+
+    pub fn object(&self) -> Option<Ref> {
+        (self.0 & ON_HEAP != 0).then(|| Ref::new(self.0 & !ON_HEAP))
+    }
+
+    // the handle as a number, for register files that store it without its type
+    pub(crate) fn id(&self) -> u32 {
+        self.0
+    }
+
+    pub(crate) fn from_id(id: u32) -> Big {
+        Big(id)
+    }
+}
+
+// like strings, interned numbers by value and before numbers on the heap, which compare by slot
+impl Ord for Big {
+    fn cmp(&self, other: &Big) -> Ordering {
+        match (self.interned(), other.interned()) {
+            (Some(a), Some(b)) => a.cmp(b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => self.0.cmp(&other.0),
+        }
+    }
+}
+
+impl PartialOrd for Big {
+    fn partial_cmp(&self, other: &Big) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl std::fmt::Debug for Big {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.interned(), self.object()) {
+            (Some(big), _) => write!(f, "{}", big),
+            (None, object) => write!(f, "<big {}>", object.map_or(0, |object| object.index())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn big(s: &str) -> BigInt {
+        BigInt::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let a = big("123456789012345678901234567890");
+        let b = big("-987654321098765432109876543210");
+        assert_eq!((&a + &b).to_string(), "-864197532086419753208641975320");
+        assert_eq!((&a - &b).to_string(), "1111111110111111111011111111100");
+        assert_eq!((&a * &b).to_string(), "-121932631137021795226185032733622923332237463801111263526900");
+        assert_eq!((&a * &b).divmod(&b), Some((a.clone(), BigInt::zero())));
+        let (quotient, rem) = b.divmod(&big("1000000000000")).unwrap();
+        assert_eq!((quotient.to_string(), rem.to_string()), ("-987654321098765432".to_string(), "-109876543210".to_string()));
+        assert_eq!(big("-7").rem_euclid(&big("3")), Some(big("2")));
+        assert_eq!(a.divmod(&BigInt::zero()), None);
+        assert_eq!(big("-0").to_string(), "0");
+        assert!(!big("-0").is_negative());
+        assert_eq!(format!("{:>5}", big("-12")), "  -12");
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(big("0xffffffffffffffffffffffffffffffff").to_u128(), Some(u128::MAX));
+        assert_eq!(big("-0X10"), BigInt::from(-16i128));
+        assert!(BigInt::from_str("12a").is_err());
+        assert!(BigInt::from_str("0x").is_err());
+        assert!(BigInt::from_str("-").is_err());
+    }
+
+    #[test]
+    fn test_shifts_and_bitwise() {
+        let one = BigInt::from(1u128);
+        assert_eq!((&one << 200).to_string(), "1606938044258990275541962092341162602522202993782792835301376");
+        assert_eq!(&(&one << 200) >> 199, BigInt::from(2u128));
+        for (a, n) in [(-5i128, 1), (-1, 40), (-(1 << 70), 3), (-(1 << 70) - 1, 70), (12345, 4)] {
+            assert_eq!(&BigInt::from(a) >> n, BigInt::from(a >> n));
+        }
+        for (a, b) in [(-6i128, 3i128), (5, -3), (-1, -(1 << 100)), (1 << 90, (1 << 91) - 1)] {
+            let (x, y) = (BigInt::from(a), BigInt::from(b));
+            assert_eq!(&x & &y, BigInt::from(a & b));
+            assert_eq!(&x | &y, BigInt::from(a | b));
+            assert_eq!(&x ^ &y, BigInt::from(a ^ b));
+            assert_eq!(!&x, BigInt::from(!a));
+        }
+    }
+
+    #[test]
+    fn test_conversions_and_bytes() {
+        for i in [0, 1, -1, 127, 128, -128, -129, i128::MIN, i128::MAX, 1 << 64, -(1 << 95)] {
+            let big = BigInt::from(i);
+            assert_eq!(big.to_i128(), Some(i));
+            assert_eq!(BigInt::from_le_bytes(&big.to_le_bytes()), big);
+        }
+        assert_eq!(BigInt::zero().to_le_bytes(), Vec::<u8>::new());
+        assert_eq!(BigInt::from(128i128).to_le_bytes(), vec![0x80, 0]);
+        assert_eq!(BigInt::from(-128i128).to_le_bytes(), vec![0x80]);
+        assert_eq!(BigInt::from(u128::MAX).to_i128(), None);
+        assert_eq!(BigInt::from(-1i128).to_u128(), None);
+        assert_eq!((&BigInt::from(u128::MAX) + &BigInt::from(1u128)).to_u128(), None);
+        assert!(BigInt::from(-3i128) < BigInt::from(-2i128) && BigInt::from(-2i128) < BigInt::from(1u128 << 100));
+        assert_eq!(Big::intern(big("5")), Big::intern(BigInt::from(5u128)));
+        assert_eq!(Big::intern(big("5")).interned(), Some(&big("5")));
+        assert_eq!(Big::on_heap(Ref::new(1)).object(), Some(Ref::new(1)));
+        assert!(Big::intern(big("7")) < Big::on_heap(Ref::new(0)));
+    }
+}
@@ -1,9 +1,19 @@
-use crate::{value::Value, opcode::Opcode};
+use crate::{value::{Value, ValueType}, opcode::Opcode, machine::REGISTER_MAX, string, function::Function, math};
 
 pub struct Code {
     pub raw: Vec<u8>,
     pub lines: Vec<usize>,
     pub const_pool: Vec<Value>,
+    // initial values, each machine running the code gets its own copy to modify
+    pub globals: Vec<Value>,
+    // CLOSURE refers to these by index
+    pub functions: Vec<Function>,
+}
+
+impl Default for Code {
+    fn default() -> Self {
+        Code::new()
+    }
 }
 
 impl Code {
@@ -12,6 +22,8 @@ impl Code {
             raw: Vec::new(),
             lines: Vec::new(),
             const_pool: Vec::new(),
+            globals: Vec::new(),
+            functions: Vec::new(),
         }
     }
 
@@ -20,123 +32,381 @@ impl Code {
         self.const_pool.len() - 1
     }
 
+    pub fn add_global(&mut self, value: Value) -> usize {
+        self.globals.push(value);
+        self.globals.len() - 1
+    }
+
+    // the function starts at the next instruction written
+    pub fn add_function(&mut self, name: &str, arity: u8, registers: u8) -> usize {
+        self.functions.push(Function {
+            name: name.to_string(),
+            entry: self.raw.len(),
+            arity,
+            registers,
+        });
+        self.functions.len() - 1
+    }
+
+    pub fn function_index(&self, name: &str) -> Option<usize> {
+        self.functions.iter().position(|function| function.name == name)
+    }
+
+    // the function whose first instruction is at offset
+    pub fn function_at(&self, offset: usize) -> Option<&Function> {
+        self.functions.iter().find(|function| function.entry == offset)
+    }
+
     pub fn write_code(&mut self, code: u8, line: usize) {
         self.raw.push(code);
         self.lines.push(line);
     }
 
-    fn disassemble_instruction(&self, offset: usize) -> usize {
-        print!("{}", offset);
-        print!(" {} ", self.lines[offset]);
+    // checks that the bytecode can be executed without running off the rails:
+    // every opcode is valid, operands are in bounds, jumps land on an instruction
+    // and the last instruction does not fall through past the end
+    pub fn verify(&self) -> Result<(), String> {
+        if self.lines.len() != self.raw.len() {
+            return Err(format!("Line table has {} entries for {} bytes", self.lines.len(), self.raw.len()));
+        }
+        // code outlives any one heap, so its strings, byte strings and big integers have to be interned
+        if let Some(constant) = self.const_pool.iter().position(|value| value.as_object().is_some()) {
+            return Err(format!("Constant {} is not interned", constant));
+        }
+        if let Some(global) = self.globals.iter().position(|value| value.as_object().is_some()) {
+            return Err(format!("Global {} is not interned", global));
+        }
+        let mut boundaries = Vec::new();
+        let mut last = None;
+        let mut offset = 0;
+        while offset < self.raw.len() {
+            let opcode = match Opcode::from_byte(self.raw[offset]) {
+                Some(Opcode::CONST) | None => {
+                    return Err(format!("Invalid opcode {} at {}", self.raw[offset], offset));
+                }
+                Some(opcode) => opcode,
+            };
+            let next = offset + opcode.get_offset() + 1;
+            if next > self.raw.len() {
+                return Err(format!("Truncated {} at {}", opcode, offset));
+            }
+            let registers = match opcode {
+                Opcode::JMP | Opcode::HALT => &self.raw[offset + 1..offset + 1],
+                Opcode::LOAD => {
+                    let constant = self.raw[offset + 2] as usize;
+                    if constant >= self.const_pool.len() {
+                        return Err(format!("Constant {} out of range at {}", constant, offset));
+                    }
+                    &self.raw[offset + 1..offset + 2]
+                }
+                Opcode::GETGLOBAL | Opcode::SETGLOBAL => {
+                    let global = self.raw[offset + 2] as usize;
+                    if global >= self.globals.len() {
+                        return Err(format!("Global {} out of range at {}", global, offset));
+                    }
+                    &self.raw[offset + 1..offset + 2]
+                }
+                // the second operand is a stack depth or a field count
+                Opcode::JMPIF | Opcode::SLOAD | Opcode::SSTORE | Opcode::NEWRECORD => &self.raw[offset + 1..offset + 2],
+                Opcode::READ | Opcode::MLOAD => {
+                    let ty = self.raw[offset + 3];
+                    match ValueType::from_byte(ty) {
+                        None => return Err(format!("Invalid type {} at {}", ty, offset)),
+                        // memory only holds types with a fixed width, the same check decode makes
+                        Some(ty) if opcode == Opcode::MLOAD && ty.size().is_none() => {
+                            return Err(format!("Cannot load type {} from memory at {}", ty, offset));
+                        }
+                        _ => {}
+                    }
+                    &self.raw[offset + 1..offset + 3]
+                }
+                Opcode::PARSE | Opcode::CONVERT => {
+                    let ty = self.raw[offset + 4];
+                    match ValueType::from_byte(ty) {
+                        None => return Err(format!("Invalid type {} at {}", ty, offset)),
+                        Some(ty) if opcode == Opcode::CONVERT && !ty.is_integer() && ty != ValueType::Char => {
+                            return Err(format!("Cannot convert to type {} at {}", ty, offset));
+                        }
+                        _ => {}
+                    }
+                    &self.raw[offset + 1..offset + 4]
+                }
+                Opcode::CALLHOST | Opcode::CLOSURE | Opcode::CALL => {
+                    let (first, count) = (self.raw[offset + 3] as usize, self.raw[offset + 4] as usize);
+                    if first + count > REGISTER_MAX {
+                        return Err(format!("Argument registers ${}..${} out of range at {}", first, first + count, offset));
+                    }
+                    match opcode {
+                        Opcode::CALL => &self.raw[offset + 1..offset + 3],
+                        Opcode::CLOSURE if self.raw[offset + 2] as usize >= self.functions.len() => {
+                            return Err(format!("Function {} out of range at {}", self.raw[offset + 2], offset));
+                        }
+                        _ => &self.raw[offset + 1..offset + 2],
+                    }
+                }
+                // the second operand is an upvalue index
+                Opcode::GETUPVAL | Opcode::SETUPVAL => &self.raw[offset + 1..offset + 2],
+                // the second operand selects the function, unary functions ignore the last register
+                Opcode::MATH => {
+                    if math::name(self.raw[offset + 2]).is_none() {
+                        return Err(format!("Invalid math function {} at {}", self.raw[offset + 2], offset));
+                    }
+                    if self.raw[offset + 1] as usize >= REGISTER_MAX {
+                        return Err(format!("Register ${} out of range at {}", self.raw[offset + 1], offset));
+                    }
+                    &self.raw[offset + 3..offset + 5]
+                }
+                _ => &self.raw[offset + 1..next],
+            };
+            if let Some(register) = registers.iter().find(|&&r| r as usize >= REGISTER_MAX) {
+                return Err(format!("Register ${} out of range at {}", register, offset));
+            }
+            boundaries.push(offset);
+            last = Some(opcode);
+            offset = next;
+        }
+        for &offset in &boundaries {
+            let target = match Opcode::from(self.raw[offset]) {
+                Opcode::JMP => Some(self.raw[offset + 1] as usize),
+                Opcode::JMPIF => Some(self.raw[offset + 2] as usize),
+                _ => None,
+            };
+            if let Some(target) = target {
+                if boundaries.binary_search(&target).is_err() {
+                    return Err(format!("Jump to {} at {} does not land on an instruction", target, offset));
+                }
+            }
+        }
+        // CLOSURE names a function by a byte, and by its name in assembly
+        if self.functions.len() > u8::MAX as usize + 1 {
+            return Err(format!("{} functions are more than CLOSURE can name", self.functions.len()));
+        }
+        for (index, function) in self.functions.iter().enumerate() {
+            if self.function_index(&function.name) != Some(index) {
+                return Err(format!("Duplicate function {}", function.name));
+            }
+            if boundaries.binary_search(&function.entry).is_err() {
+                return Err(format!("Function {} at {} does not start on an instruction", function.name, function.entry));
+            }
+            if function.registers < function.arity || function.registers as usize > REGISTER_MAX {
+                return Err(format!("Function {} has {} registers for {} arguments", function.name, function.registers, function.arity));
+            }
+        }
+        match last {
+            Some(Opcode::HALT) | Some(Opcode::JMP) | Some(Opcode::RET) => Ok(()),
+            _ => Err("Code does not end with HALT, JMP or RET".to_string()),
+        }
+    }
+
+    pub fn next_instruction(&self, offset: usize) -> usize {
         let instruction: Opcode = self.raw[offset].into();
+        offset + instruction.get_offset() + 1
+    }
+
+    // whether an instruction starts at offset rather than one of its operands
+    pub fn is_instruction(&self, offset: usize) -> bool {
+        let mut current = 0;
+        while current < offset {
+            current = self.next_instruction(current);
+        }
+        current == offset && offset < self.raw.len()
+    }
+
+    // offset of the first instruction generated from the given source line
+    pub fn line_offset(&self, line: usize) -> Option<usize> {
+        let mut offset = 0;
+        while offset < self.raw.len() {
+            if self.lines[offset] == line {
+                return Some(offset);
+            }
+            offset = self.next_instruction(offset);
+        }
+        None
+    }
+
+    // register written by the instruction at offset, if any
+    pub fn destination_register(&self, offset: usize) -> Option<u8> {
+        match Opcode::from(self.raw[offset]) {
+            Opcode::PRINT
+            | Opcode::TPRINT
+            | Opcode::SETGLOBAL
+            | Opcode::JMP
+            | Opcode::JMPIF
+            | Opcode::HALT
+            | Opcode::CONST
+            | Opcode::YIELD
+            | Opcode::MSTORE
+            | Opcode::PUSH
+            | Opcode::DUP
+            | Opcode::SWAP
+            | Opcode::SSTORE
+            | Opcode::SETELEM
+            | Opcode::APPEND
+            // CALL writes its destination when the callee returns
+            | Opcode::CALL
+            | Opcode::RET
+            | Opcode::SETUPVAL => None,
+            _ => Some(self.raw[offset + 1]),
+        }
+    }
+
+    // assembly text for the instruction at offset
+    pub fn format_instruction(&self, offset: usize) -> String {
+        let instruction = match Opcode::from_byte(self.raw[offset]) {
+            Some(instruction) => instruction,
+            None => return "Unknown opcode".to_string(),
+        };
 
         match instruction {
-            Opcode::PRINT => {
+            Opcode::PRINT | Opcode::TPRINT | Opcode::YIELD | Opcode::PUSH | Opcode::POP | Opcode::PEEK | Opcode::RET => {
                 let register = self.raw[offset + 1];
-                println!("PRINT ${}", register);
-                offset + instruction.get_offset() + 1
+                format!("{} ${}", instruction, register)
             }
             Opcode::MOVE => {
                 let r1 = self.raw[offset + 1];
                 let r2 = self.raw[offset + 2];
-                println!("MOVE ${} ${}", r1, r2);
-                offset + instruction.get_offset() + 1
+                format!("MOVE ${} ${}", r1, r2)
             }
             Opcode::LOAD => {
                 let register = self.raw[offset + 1];
                 let constant = self.raw[offset + 2];
-                println!("LOAD ${} {}", register, self.const_pool[constant as usize]);
-                offset + instruction.get_offset() + 1
+                match self.const_pool[constant as usize] {
+                    Value::Str(s) => format!("LOAD ${} {}", register, s.interned().map_or(format!("{:?}", s), string::quote)),
+                    Value::Char(c) => format!("LOAD ${} {}", register, string::quote_char(c)),
+                    Value::Bytes(bytes) => format!("LOAD ${} {}", register, bytes.interned().map_or(format!("{:?}", bytes), string::quote_bytes)),
+                    value => format!("LOAD ${} {}", register, value),
+                }
             }
-            Opcode::STORE => {
+            Opcode::GETGLOBAL
+            | Opcode::SETGLOBAL
+            | Opcode::SLOAD
+            | Opcode::SSTORE
+            | Opcode::NEWRECORD
+            | Opcode::GETUPVAL
+            | Opcode::SETUPVAL => {
                 let register = self.raw[offset + 1];
-                let constant = self.raw[offset + 2];
-                println!("STORE ${} {}", register, self.const_pool[constant as usize]);
-                offset + instruction.get_offset() + 1
+                let global = self.raw[offset + 2];
+                format!("{} ${} {}", instruction, register, global)
             }
-            Opcode::ADD => {
+            Opcode::ADD
+            | Opcode::SUB
+            | Opcode::MUL
+            | Opcode::DIV
+            | Opcode::AND
+            | Opcode::OR
+            | Opcode::XOR
+            | Opcode::SHR
+            | Opcode::SHL
+            | Opcode::CONCAT
+            | Opcode::STRCMP
+            | Opcode::STREQ
+            | Opcode::CHARAT
+            | Opcode::GETELEM
+            | Opcode::SETELEM
+            | Opcode::REM
+            | Opcode::MOD => {
                 let r1 = self.raw[offset + 1];
                 let r2 = self.raw[offset + 2];
                 let r3 = self.raw[offset + 3];
-                println!("ADD ${} ${} ${}", r1, r2, r3);
-                offset + instruction.get_offset() + 1
+                format!("{} ${} ${} ${}", instruction, r1, r2, r3)
             }
-            Opcode::SUB => {
-                let r1 = self.raw[offset + 1];
-                let r2 = self.raw[offset + 2];
-                let r3 = self.raw[offset + 3];
-                println!("SUB ${} ${} ${}", r1, r2, r3);
-                offset + instruction.get_offset() + 1
-            }
-            Opcode::MUL => {
-                let r1 = self.raw[offset + 1];
-                let r2 = self.raw[offset + 2];
-                let r3 = self.raw[offset + 3];
-                println!("MUL ${} ${} ${}", r1, r2, r3);
-                offset + instruction.get_offset() + 1
-            }
-            Opcode::DIV => {
-                let r1 = self.raw[offset + 1];
-                let r2 = self.raw[offset + 2];
-                let r3 = self.raw[offset + 3];
-                println!("DIV ${} ${} ${}", r1, r2, r3);
-                offset + instruction.get_offset() + 1
+            Opcode::JMP => {
+                let register = self.raw[offset + 1];
+                format!("JMP {}", register)
             }
-            Opcode::AND => {
-                let r1 = self.raw[offset + 1];
-                let r2 = self.raw[offset + 2];
-                let r3 = self.raw[offset + 3];
-                println!("AND ${} ${} ${}", r1, r2, r3);
-                offset + instruction.get_offset() + 1
+            Opcode::JMPIF => {
+                let register = self.raw[offset + 1];
+                let target = self.raw[offset + 2];
+                format!("JMPIF ${} {}", register, target)
             }
-            Opcode::OR => {
+            Opcode::HALT | Opcode::DUP | Opcode::SWAP => instruction.to_string(),
+            Opcode::READ | Opcode::MLOAD => {
                 let r1 = self.raw[offset + 1];
                 let r2 = self.raw[offset + 2];
-                let r3 = self.raw[offset + 3];
-                println!("OR ${} ${} ${}", r1, r2, r3);
-                offset + instruction.get_offset() + 1
+                match ValueType::from_byte(self.raw[offset + 3]) {
+                    Some(ty) => format!("{} ${} ${} {}", instruction, r1, r2, ty),
+                    None => format!("{} ${} ${} {}", instruction, r1, r2, self.raw[offset + 3]),
+                }
             }
-            Opcode::XOR => {
+            Opcode::MSTORE
+            | Opcode::GROW
+            | Opcode::STRLEN
+            | Opcode::TOSTR
+            | Opcode::NEWARRAY
+            | Opcode::LEN
+            | Opcode::APPEND
+            | Opcode::NEG
+            | Opcode::NOT
+            | Opcode::ABS
+            | Opcode::ISDIGIT
+            | Opcode::ISALPHA
+            | Opcode::ISSPACE => {
                 let r1 = self.raw[offset + 1];
                 let r2 = self.raw[offset + 2];
-                let r3 = self.raw[offset + 3];
-                println!("XOR ${} ${} ${}", r1, r2, r3);
-                offset + instruction.get_offset() + 1
+                format!("{} ${} ${}", instruction, r1, r2)
             }
-            Opcode::SHR => {
-                let r1 = self.raw[offset + 1];
-                let r2 = self.raw[offset + 2];
-                let r3 = self.raw[offset + 3];
-                println!("SHR ${} ${} ${}", r1, r2, r3);
-                offset + instruction.get_offset() + 1
+            Opcode::MEMSIZE => format!("MEMSIZE ${}", self.raw[offset + 1]),
+            Opcode::SUBSTR => {
+                let operands = &self.raw[offset + 1..offset + 5];
+                format!("SUBSTR ${} ${} ${} ${}", operands[0], operands[1], operands[2], operands[3])
             }
-            Opcode::SHL => {
-                let r1 = self.raw[offset + 1];
-                let r2 = self.raw[offset + 2];
-                let r3 = self.raw[offset + 3];
-                println!("SHL ${} ${} ${}", r1, r2, r3);
-                offset + instruction.get_offset() + 1
+            Opcode::PARSE | Opcode::CONVERT => {
+                let operands = &self.raw[offset + 1..offset + 5];
+                match ValueType::from_byte(operands[3]) {
+                    Some(ty) => format!("{} ${} ${} ${} {}", instruction, operands[0], operands[1], operands[2], ty),
+                    None => format!("{} ${} ${} ${} {}", instruction, operands[0], operands[1], operands[2], operands[3]),
+                }
             }
-            Opcode::JMP => {
+            // arguments are written as the first and last register of the range
+            Opcode::CALLHOST | Opcode::CLOSURE | Opcode::CALL => {
                 let register = self.raw[offset + 1];
-                println!("JMP {}", register);
-                offset + instruction.get_offset() + 1
-            }
-            Opcode::HALT => {
-                println!("HALT");
-                offset + 1
+                let callee = match instruction {
+                    Opcode::CALL => format!("${}", self.raw[offset + 2]),
+                    Opcode::CLOSURE => match self.functions.get(self.raw[offset + 2] as usize) {
+                        Some(function) => function.name.clone(),
+                        None => self.raw[offset + 2].to_string(),
+                    },
+                    _ => self.raw[offset + 2].to_string(),
+                };
+                let (first, count) = (self.raw[offset + 3] as usize, self.raw[offset + 4] as usize);
+                match count {
+                    0 => format!("{} ${} {}", instruction, register, callee),
+                    _ => format!("{} ${} {} ${} ${}", instruction, register, callee, first, first + count - 1),
+                }
             }
-            _ => {
-                println!("Unknown opcode");
-                offset + 1
+            Opcode::MATH => {
+                let operands = &self.raw[offset + 1..offset + 5];
+                let function = match math::name(operands[1]) {
+                    Some(name) => name.to_string(),
+                    None => operands[1].to_string(),
+                };
+                match math::is_binary(operands[1]) {
+                    true => format!("MATH ${} {} ${} ${}", operands[0], function, operands[2], operands[3]),
+                    false => format!("MATH ${} {} ${}", operands[0], function, operands[2]),
+                }
             }
+            _ => "Unknown opcode".to_string(),
         }
     }
 
+    // the directive that declared the function starting at offset, if any
+    pub fn format_function(&self, offset: usize) -> Option<String> {
+        let function = self.function_at(offset)?;
+        Some(format!(".function {} {} {}", function.name, function.arity, function.registers))
+    }
+
+    pub fn disassemble_instruction(&self, offset: usize) -> usize {
+        if let Some(function) = self.format_function(offset) {
+            println!("{}", function);
+        }
+        println!("{} {} {}", offset, self.lines[offset], self.format_instruction(offset));
+        self.next_instruction(offset)
+    }
+
     pub fn disassemble(&self) {
         let mut offset = 0;
         while offset < self.raw.len() {
             offset = self.disassemble_instruction(offset);
         }
     }
-}
\ No newline at end of file
+}
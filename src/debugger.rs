@@ -0,0 +1,357 @@
+// interactive terminal front end for stepping through a program on a Machine
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+use crate::{heap::Object, machine::{Machine, Status, REGISTER_MAX}, output::display, string, value::Value};
+
+const HELP: &str = "\
+break <pc>           set a breakpoint at the instruction starting at an offset
+break line <n>       set a breakpoint at the first instruction of source line n
+delete <pc>          remove a breakpoint
+step                 execute one instruction
+next                 execute until the source line changes
+continue             run until a breakpoint or HALT
+back                 undo the last executed instruction
+reverse              run backwards until a breakpoint or the start of history
+last $r              find the last instruction that wrote a register
+regs [$r ...]        show registers (all that are not the initial u8 0 by default)
+consts               show the constant pool
+globals              show the globals
+stack                show the stack, top first
+heap                 show the live heap objects
+backtrace            show where each running function returns to, innermost first
+mem <addr> [<len>]   dump linear memory in hex
+set $r <value>       overwrite a register
+set global <k> <value>
+                     overwrite a global
+disasm               disassemble the program
+quit                 leave the debugger";
+
+fn parse_register(word: &str) -> Option<u8> {
+    word.strip_prefix('$')?.parse().ok()
+}
+
+fn location(machine: &Machine) -> String {
+    let code = machine.code();
+    format!("{} {} {}", machine.pc(), code.lines[machine.pc()], code.format_instruction(machine.pc()))
+}
+
+fn report(machine: &Machine, status: Status, output: &mut impl Write) -> io::Result<()> {
+    match status {
+        Status::Halted => writeln!(output, "Program halted at {}", machine.pc()),
+        Status::Breakpoint => writeln!(output, "Breakpoint at {}", location(machine)),
+        Status::OutOfFuel => writeln!(output, "Out of fuel at {}", location(machine)),
+        Status::Yielded(value) => writeln!(output, "Yielded {} before {}", display(&value, machine.heap()), location(machine)),
+        Status::Trapped(trap) => writeln!(output, "Trapped at {}: {}", machine.pc(), trap),
+        Status::Running | Status::Paused => writeln!(output, "{}", location(machine)),
+    }
+}
+
+fn step_line(machine: &mut Machine) -> Status {
+    let line = machine.code().lines[machine.pc()];
+    loop {
+        let status = machine.step();
+        if status != Status::Running {
+            return status;
+        }
+        if machine.breakpoints().any(|pc| pc == machine.pc()) {
+            return Status::Breakpoint;
+        }
+        if machine.code().lines[machine.pc()] != line {
+            return status;
+        }
+    }
+}
+
+fn disasm(machine: &Machine, output: &mut impl Write) -> io::Result<()> {
+    let code = machine.code();
+    let mut offset = 0;
+    while offset < code.raw.len() {
+        let marker = if offset == machine.pc() { "=>" } else { "  " };
+        let breakpoint = if machine.breakpoints().any(|pc| pc == offset) { "*" } else { " " };
+        if let Some(function) = code.format_function(offset) {
+            writeln!(output, "{}", function)?;
+        }
+        writeln!(output, "{}{} {} {} {}", marker, breakpoint, offset, code.lines[offset], code.format_instruction(offset))?;
+        offset = code.next_instruction(offset);
+    }
+    Ok(())
+}
+
+// sixteen bytes to a row, prefixed with the address of the first
+fn dump(machine: &Machine, addr: &str, len: &str, output: &mut impl Write) -> io::Result<()> {
+    let bytes = machine.memory().bytes();
+    let (addr, len): (usize, usize) = match (addr.parse(), len.parse()) {
+        (Ok(addr), Ok(len)) if addr <= bytes.len() => (addr, len),
+        _ => return writeln!(output, "Invalid range {} {} for {} bytes of memory", addr, len, bytes.len()),
+    };
+    let end = addr.saturating_add(len).min(bytes.len());
+    for (row, chunk) in bytes[addr..end].chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        writeln!(output, "{:>8}: {}", addr + row * 16, hex.join(" "))?;
+    }
+    Ok(())
+}
+
+fn command(machine: &mut Machine, words: &[&str], output: &mut impl Write) -> io::Result<()> {
+    match words {
+        ["break", "line", line] => match line.parse().ok().and_then(|line| machine.add_line_breakpoint(line)) {
+            Some(pc) => writeln!(output, "Breakpoint at {}", pc),
+            None => writeln!(output, "No code for line {}", line),
+        },
+        ["break", pc] => match pc.parse() {
+            Ok(pc) if machine.code().is_instruction(pc) => {
+                machine.add_breakpoint(pc);
+                writeln!(output, "Breakpoint at {}", pc)
+            }
+            _ => writeln!(output, "Invalid offset {}", pc),
+        },
+        ["delete", pc] => match pc.parse() {
+            Ok(pc) if machine.remove_breakpoint(pc) => writeln!(output, "Deleted breakpoint at {}", pc),
+            _ => writeln!(output, "No breakpoint at {}", pc),
+        },
+        ["step"] | ["s"] => {
+            let status = machine.step();
+            report(machine, status, output)
+        }
+        ["next"] | ["n"] => {
+            let status = step_line(machine);
+            report(machine, status, output)
+        }
+        ["continue"] | ["c"] => {
+            let status = machine.run_until_breakpoint();
+            report(machine, status, output)
+        }
+        ["back"] | ["b"] => match machine.step_back() {
+            true => writeln!(output, "{}", location(machine)),
+            false => writeln!(output, "No history to step back through"),
+        },
+        ["reverse"] | ["rc"] => match machine.run_back_until_breakpoint() {
+            true => writeln!(output, "Breakpoint at {}", location(machine)),
+            false => writeln!(output, "Start of history at {}", location(machine)),
+        },
+        ["last", reg] => match (parse_register(reg), machine.history()) {
+            (_, None) => writeln!(output, "History is not being recorded"),
+            (Some(reg), Some(history)) => match history.last_write(reg) {
+                Some((pc, steps)) => {
+                    let code = machine.code();
+                    writeln!(output, "${} written {} steps ago by {} {} {}", reg, steps, pc, code.lines[pc], code.format_instruction(pc))
+                }
+                None => writeln!(output, "${} not written in recorded history", reg),
+            },
+            (None, _) => writeln!(output, "Invalid register {}", reg),
+        },
+        ["regs"] => {
+            for reg in 0..u8::MAX {
+                if machine.register(reg) != Value::U8(0) {
+                    writeln!(output, "${} = {}", reg, display(&machine.register(reg), machine.heap()))?;
+                }
+            }
+            Ok(())
+        }
+        ["regs", regs @ ..] => {
+            for word in regs {
+                match parse_register(word) {
+                    Some(reg) if (reg as usize) < REGISTER_MAX => {
+                        writeln!(output, "${} = {}", reg, display(&machine.register(reg), machine.heap()))?
+                    }
+                    _ => writeln!(output, "Invalid register {}", word)?,
+                }
+            }
+            Ok(())
+        }
+        ["consts"] => {
+            for (i, value) in machine.code().const_pool.iter().enumerate() {
+                writeln!(output, "{} = {}", i, value)?;
+            }
+            Ok(())
+        }
+        ["globals"] => {
+            for (i, value) in machine.globals().iter().enumerate() {
+                writeln!(output, "{} = {}", i, display(value, machine.heap()))?;
+            }
+            Ok(())
+        }
+        ["stack"] => {
+            for (depth, value) in machine.stack().values().iter().rev().enumerate() {
+                writeln!(output, "{} = {}", depth, display(value, machine.heap()))?;
+            }
+            Ok(())
+        }
+        ["heap"] => {
+            for (i, slot) in machine.heap().slots().iter().enumerate() {
+                let values = |values: &[Value]| values.iter().map(|value| display(value, machine.heap())).collect::<Vec<_>>().join(", ");
+                match slot {
+                    Some(Object::Array(array)) => writeln!(output, "{} = array [{}]", i, values(array))?,
+                    Some(Object::Record(fields)) => writeln!(output, "{} = record {{{}}}", i, values(fields))?,
+                    Some(Object::Closure(function, upvalues)) => {
+                        let name = machine.code().functions.get(*function as usize).map_or("?", |function| &function.name);
+                        writeln!(output, "{} = closure {} [{}]", i, name, values(upvalues))?
+                    }
+                    Some(Object::Str(s, _)) => writeln!(output, "{} = str {}", i, string::quote(s))?,
+                    Some(Object::Bytes(bytes)) => writeln!(output, "{} = bytes {}", i, string::quote_bytes(bytes))?,
+                    Some(Object::Big(big)) => writeln!(output, "{} = big {}", i, big)?,
+                    None => {}
+                }
+            }
+            Ok(())
+        }
+        ["backtrace"] | ["bt"] => {
+            writeln!(output, "{}", location(machine))?;
+            for frame in machine.frames().iter().rev() {
+                let code = machine.code();
+                writeln!(output, "{} {} {}", frame.return_pc, code.lines[frame.return_pc], code.format_instruction(frame.return_pc))?;
+            }
+            Ok(())
+        }
+        ["mem", addr] => dump(machine, addr, "16", output),
+        ["mem", addr, len] => dump(machine, addr, len, output),
+        ["set", "global", global, value] => {
+            match (global.parse(), Value::from_str(value)) {
+                (Ok(global), Ok(value)) if machine.set_global(global, value) => Ok(()),
+                (_, Err(e)) => writeln!(output, "{}", e),
+                _ => writeln!(output, "Invalid global {}", global),
+            }
+        }
+        ["set", reg, value] => match (parse_register(reg), Value::from_str(value)) {
+            (Some(reg), Ok(value)) if (reg as usize) < REGISTER_MAX => {
+                machine.set_register(reg, value);
+                Ok(())
+            }
+            (_, Err(e)) => writeln!(output, "{}", e),
+            _ => writeln!(output, "Invalid register {}", reg),
+        },
+        ["disasm"] => disasm(machine, output),
+        ["help"] => writeln!(output, "{}", HELP),
+        _ => writeln!(output, "Unknown command, try help"),
+    }
+}
+
+// reads commands until quit or end of input
+pub fn run(machine: &mut Machine, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+    writeln!(output, "{}", location(machine))?;
+    let mut lines = input.lines();
+    loop {
+        write!(output, "(tower) ")?;
+        output.flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => return Ok(()),
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {}
+            ["quit"] | ["q"] => return Ok(()),
+            words => command(machine, words, &mut output)?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{history::History, reader::assemble};
+
+    fn debug(src: &str, commands: &str) -> (Machine, String) {
+        let mut machine = Machine::new();
+        machine.load(assemble(src)).unwrap();
+        let mut output = Vec::new();
+        run(&mut machine, commands.as_bytes(), &mut output).unwrap();
+        (machine, String::from_utf8(output).unwrap())
+    }
+
+    const SRC: &str = "CONST 10\nCONST 20\nLOAD 0 0\nLOAD 1 1\nADD 2 0 1\nHALT\n";
+
+    #[test]
+    fn test_step_and_regs() {
+        let (machine, output) = debug(SRC, "step\nstep\nregs\n");
+        assert_eq!(machine.pc(), 6);
+        assert!(output.contains("6 5 ADD $2 $0 $1"));
+        assert!(output.contains("$0 = 10\n$1 = 20\n"));
+    }
+
+    #[test]
+    fn test_break_line_and_continue() {
+        let (machine, output) = debug(SRC, "break line 5\ncontinue\ncontinue\n");
+        assert!(output.contains("Breakpoint at 6 5 ADD $2 $0 $1"));
+        assert!(output.contains("Program halted at 10"));
+        assert_eq!(machine.register(2), Value::I8(30));
+    }
+
+    #[test]
+    fn test_globals() {
+        let src = ".global 1\n.global 2u16\nGETGLOBAL 0 1\nHALT\n";
+        let (machine, output) = debug(src, "set global 1 7u16\nglobals\nstep\nset global 2 1\n");
+        assert!(output.contains("0 = 1\n1 = 7\n"));
+        assert!(output.contains("Invalid global 2"));
+        assert_eq!(machine.register(0), Value::U16(7));
+    }
+
+    #[test]
+    fn test_stack() {
+        let src = "CONST 1\nCONST 2u16\nLOAD $0 0\nLOAD $1 1\nPUSH $0\nPUSH $1\nHALT\n";
+        let (_, output) = debug(src, "continue\nstack\n");
+        assert!(output.contains("0 = 2\n1 = 1\n"));
+    }
+
+    #[test]
+    fn test_heap() {
+        let src = "CONST 1\nLOAD $0 0\nNEWARRAY $1 $0\nNEWRECORD $2 2\nSETELEM $2 $0 $1\nHALT\n";
+        let (_, output) = debug(src, "continue\nheap\n");
+        assert!(output.contains("0 = array [0]\n1 = record {0, <ref 0>}\n"));
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let src = "CLOSURE $0 f\nCALL $1 $0\nHALT\n.function f 0 1\nRET $0\n";
+        let (_, output) = debug(src, "step\nstep\nbacktrace\nheap\ndisasm\n");
+        assert!(output.contains("11 5 RET $0\n10 3 HALT\n"));
+        assert!(output.contains("0 = closure f []\n"));
+        assert!(output.contains("   10 3 HALT\n.function f 0 1\n=>  11 5 RET $0\n"));
+    }
+
+    #[test]
+    fn test_set_register() {
+        let (machine, _) = debug(SRC, "next\nnext\nset $0 1\nnext\n");
+        assert_eq!(machine.register(2), Value::I8(21));
+    }
+
+    #[test]
+    fn test_reverse() {
+        let mut machine = Machine::new();
+        machine.load(assemble(SRC)).unwrap();
+        machine.set_history(History::new());
+        let mut output = Vec::new();
+        run(&mut machine, "c\nlast $2\nback\nback\nlast $0\nreverse\n".as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("$2 written 1 steps ago by 6 5 ADD $2 $0 $1\n"));
+        assert!(output.contains("$0 written 1 steps ago by 0 3 LOAD $0 10\n"));
+        assert!(output.contains("Start of history at 0 3 LOAD $0 10\n"));
+        assert_eq!(machine.register(2), Value::U8(0));
+    }
+
+    #[test]
+    fn test_mem() {
+        let (_, output) = debug(
+            "CONST 20\nCONST 258u16\nCONST 16\nLOAD $0 0\nLOAD $1 1\nLOAD $2 2\nGROW $3 $0\nMSTORE $2 $1\nHALT\n",
+            "c\nmem 16 8\nmem 30\n",
+        );
+        assert!(output.contains("      16: 02 01 00 00\n"));
+        assert!(output.contains("Invalid range 30 16 for 20 bytes of memory\n"));
+    }
+
+    #[test]
+    fn test_disasm_marks_pc_and_breakpoints() {
+        let (_, output) = debug(SRC, "break 3\ndisasm\n");
+        assert!(output.contains("=>  0 3 LOAD $0 10\n  * 3 4 LOAD $1 20\n"));
+    }
+
+    #[test]
+    fn test_break_rejects_operand_offsets() {
+        let (machine, output) = debug(SRC, "break 4
+break 10
+break 11
+");
+        assert!(output.contains("Invalid offset 4\n(tower) Breakpoint at 10\n(tower) Invalid offset 11\n"));
+        assert_eq!(machine.breakpoints().collect::<Vec<_>>(), vec![10]);
+    }
+}
@@ -0,0 +1,35 @@
+// per opcode fuel costs for metering untrusted programs
+use crate::opcode::Opcode;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CostTable {
+    costs: Vec<u64>,
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        CostTable::uniform(1)
+    }
+}
+
+impl CostTable {
+    // every instruction costs the same, so fuel is an instruction budget
+    pub fn uniform(cost: u64) -> CostTable {
+        CostTable {
+            costs: vec![cost; u8::MAX as usize + 1],
+        }
+    }
+
+    pub fn with_cost(mut self, opcode: Opcode, cost: u64) -> CostTable {
+        self.set_cost(opcode, cost);
+        self
+    }
+
+    pub fn set_cost(&mut self, opcode: Opcode, cost: u64) {
+        self.costs[opcode as usize] = cost;
+    }
+
+    pub fn cost(&self, opcode: Opcode) -> u64 {
+        self.costs[opcode as usize]
+    }
+}
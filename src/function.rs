@@ -0,0 +1,24 @@
+// functions declared in the code and the call frames of the functions running on a machine
+use crate::{heap::Ref, value::Value};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub entry: usize,
+    pub arity: u8,
+    // the callee's register window, $0 up to but not including registers, saved across the call
+    pub registers: u8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub return_pc: usize,
+    // the caller's register that receives the result
+    pub dst: u8,
+    // the closure being run, GETUPVAL and SETUPVAL use its upvalues
+    pub closure: Ref,
+    // the caller's values of the callee's register window
+    pub saved: Vec<Value>,
+}
+
+pub const DEFAULT_CALL_LIMIT: usize = 1 << 10;
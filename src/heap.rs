@@ -0,0 +1,317 @@
+// garbage collected objects that registers refer to by handle, bounded by a limit set by the host
+use crate::{bigint::{Big, BigInt}, string::{Bytes, CharIndex, Str, ON_HEAP}};
+use crate::value::Value;
+
+// counted in values, each object also takes one for its header
+pub const DEFAULT_HEAP_LIMIT: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Ref(u32);
+
+impl Ref {
+    pub fn new(index: u32) -> Ref {
+        Ref(index)
+    }
+
+    pub fn index(&self) -> u32 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Object {
+    // grows with APPEND
+    Array(Vec<Value>),
+    // keeps the number of fields it was created with
+    Record(Vec<Value>),
+    // a function by its index in the code, with the upvalues it captured
+    Closure(u8, Vec<Value>),
+    // text built at run time and the index of its chars, a str value refers to it by a handle with
+    // ON_HEAP set
+    Str(String, CharIndex),
+    // a byte string built at run time, referred to the same way
+    Bytes(Vec<u8>),
+    // a big integer computed at run time, referred to the same way
+    Big(BigInt),
+}
+
+impl Object {
+    pub fn str(s: String) -> Object {
+        let index = CharIndex::new(&s);
+        Object::Str(s, index)
+    }
+
+    pub fn values(&self) -> &[Value] {
+        match self {
+            Object::Array(values) | Object::Record(values) | Object::Closure(_, values) => values,
+            Object::Str(..) | Object::Bytes(_) | Object::Big(_) => &[],
+        }
+    }
+
+    fn values_mut(&mut self) -> Option<&mut Vec<Value>> {
+        match self {
+            Object::Array(values) | Object::Record(values) | Object::Closure(_, values) => Some(values),
+            Object::Str(..) | Object::Bytes(_) | Object::Big(_) => None,
+        }
+    }
+
+    // text, char offsets, bytes and limbs are charged in whole values
+    pub(crate) fn size(&self) -> usize {
+        match self {
+            Object::Str(s, index) => 1 + (s.len() + index.size()).div_ceil(size_of::<Value>()),
+            Object::Bytes(bytes) => 1 + bytes.len().div_ceil(size_of::<Value>()),
+            Object::Big(big) => 1 + big_size(big.limb_count()),
+            _ => 1 + self.values().len(),
+        }
+    }
+}
+
+// the values that many limbs take up, before the header
+pub(crate) fn big_size(limbs: usize) -> usize {
+    limbs.saturating_mul(size_of::<u32>()).div_ceil(size_of::<Value>())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heap {
+    objects: Vec<Option<Object>>,
+    free: Vec<u32>,
+    used: usize,
+    limit: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap::with_limit(DEFAULT_HEAP_LIMIT)
+    }
+
+    pub fn with_limit(limit: usize) -> Heap {
+        Heap {
+            objects: Vec::new(),
+            free: Vec::new(),
+            used: 0,
+            limit,
+        }
+    }
+
+    // rebuilds a heap from its slots, None being a free slot
+    pub fn from_slots(objects: Vec<Option<Object>>, limit: usize) -> Heap {
+        let free = (0..objects.len() as u32).rev().filter(|&i| objects[i as usize].is_none()).collect();
+        let used = objects.iter().flatten().map(Object::size).sum();
+        Heap {
+            objects,
+            free,
+            used,
+            limit,
+        }
+    }
+
+    pub fn slots(&self) -> &[Option<Object>] {
+        &self.objects
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    // lowering the limit below what is used only stops further allocation
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
+    // number of live objects
+    pub fn len(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // None if the object does not fit under the limit
+    pub fn alloc(&mut self, object: Object) -> Option<Ref> {
+        if self.used + object.size() > self.limit {
+            return None;
+        }
+        self.used += object.size();
+        match self.free.pop() {
+            Some(index) => {
+                self.objects[index as usize] = Some(object);
+                Some(Ref(index))
+            }
+            None => {
+                let index = u32::try_from(self.objects.len()).ok().filter(|&index| index < ON_HEAP)?;
+                self.objects.push(Some(object));
+                Some(Ref(index))
+            }
+        }
+    }
+
+    pub fn get(&self, object: Ref) -> Option<&Object> {
+        self.objects.get(object.0 as usize)?.as_ref()
+    }
+
+    // the text of an interned string or of one on this heap
+    pub fn str(&self, s: Str) -> Option<&str> {
+        match s.object() {
+            Some(object) => match self.get(object)? {
+                Object::Str(s, _) => Some(s),
+                _ => None,
+            },
+            None => s.interned(),
+        }
+    }
+
+    // the text of a string with the index of its chars
+    pub fn chars(&self, s: Str) -> Option<(&str, &CharIndex)> {
+        match s.object() {
+            Some(object) => match self.get(object)? {
+                Object::Str(s, index) => Some((s, index)),
+                _ => None,
+            },
+            None => Some((s.interned()?, s.char_index()?)),
+        }
+    }
+
+    // the bytes of an interned byte string or of one on this heap
+    pub fn bytes(&self, bytes: Bytes) -> Option<&[u8]> {
+        match bytes.object() {
+            Some(object) => match self.get(object)? {
+                Object::Bytes(bytes) => Some(bytes),
+                _ => None,
+            },
+            None => bytes.interned(),
+        }
+    }
+
+    // the number of an interned big integer or of one on this heap
+    pub fn big(&self, big: Big) -> Option<&BigInt> {
+        match big.object() {
+            Some(object) => match self.get(object)? {
+                Object::Big(big) => Some(big),
+                _ => None,
+            },
+            None => big.interned(),
+        }
+    }
+
+    pub fn set(&mut self, object: Ref, index: usize, value: Value) -> bool {
+        match self.objects.get_mut(object.0 as usize).and_then(Option::as_mut) {
+            Some(object) => match object.values_mut().and_then(|values| values.get_mut(index)) {
+                Some(slot) => {
+                    *slot = value;
+                    true
+                }
+                None => false,
+            },
+            None => false,
+        }
+    }
+
+    // false if the array does not fit under the limit with one more value
+    pub fn push(&mut self, array: Ref, value: Value) -> bool {
+        if self.used >= self.limit {
+            return false;
+        }
+        match self.objects.get_mut(array.0 as usize).and_then(Option::as_mut) {
+            Some(Object::Array(values)) => {
+                values.push(value);
+                self.used += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // undoes pushes
+    pub fn truncate(&mut self, object: Ref, len: usize) {
+        if let Some(values) = self.objects.get_mut(object.0 as usize).and_then(Option::as_mut).and_then(Object::values_mut) {
+            self.used -= values.len().saturating_sub(len);
+            values.truncate(len);
+        }
+    }
+
+    // frees every object that cannot be reached from the roots, returns how many were freed
+    pub fn collect(&mut self, roots: impl IntoIterator<Item = Value>) -> usize {
+        let mut marked = vec![false; self.objects.len()];
+        let mut pending: Vec<Ref> = roots.into_iter().filter_map(|value| value.as_object()).collect();
+        while let Some(object) = pending.pop() {
+            let index = object.0 as usize;
+            if index >= marked.len() || marked[index] {
+                continue;
+            }
+            marked[index] = true;
+            if let Some(object) = &self.objects[index] {
+                pending.extend(object.values().iter().filter_map(|value| value.as_object()));
+            }
+        }
+        let mut freed = 0;
+        for (index, slot) in self.objects.iter_mut().enumerate() {
+            if !marked[index] {
+                if let Some(object) = slot.take() {
+                    self.used -= object.size();
+                    self.free.push(index as u32);
+                    freed += 1;
+                }
+            }
+        }
+        freed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alloc_and_collect() {
+        let mut heap = Heap::with_limit(8);
+        let a = heap.alloc(Object::Array(vec![Value::I8(1)])).unwrap();
+        let b = heap.alloc(Object::Record(vec![Value::Ref(a), Value::U8(0)])).unwrap();
+        let c = heap.alloc(Object::Array(vec![])).unwrap();
+        assert_eq!(heap.used(), 6);
+        assert!(heap.push(c, Value::I8(2)));
+        assert!(!heap.push(b, Value::I8(2)));
+        assert!(heap.alloc(Object::Array(vec![Value::I8(0)])).is_none());
+
+        // b keeps a alive, c is garbage
+        assert_eq!(heap.collect([Value::Ref(b), Value::I8(3)]), 1);
+        assert_eq!((heap.len(), heap.used()), (2, 5));
+        assert!(heap.get(c).is_none());
+        assert_eq!(heap.alloc(Object::Array(vec![])), Some(c));
+        heap.truncate(a, 0);
+        assert_eq!(heap.get(a), Some(&Object::Array(vec![])));
+        assert_eq!(Heap::from_slots(heap.slots().to_vec(), 8), heap);
+        assert_eq!(heap.collect([]), 3);
+        assert!(heap.is_empty());
+    }
+
+    #[test]
+    fn test_strings() {
+        let mut heap = Heap::with_limit(16);
+        let s = Str::on_heap(heap.alloc(Object::str("x".repeat(size_of::<Value>() + 1))).unwrap());
+        assert_eq!(heap.used(), 3);
+        assert_eq!(heap.str(s).map(str::len), Some(size_of::<Value>() + 1));
+        assert_eq!(heap.str(Str::intern("constant")), Some("constant"));
+        assert!(!heap.set(s.object().unwrap(), 0, Value::I8(0)));
+        assert_eq!(heap.collect([Value::Str(s)]), 0);
+        assert_eq!(heap.collect([]), 1);
+        assert_eq!(heap.str(s), None);
+
+        let s = Str::on_heap(heap.alloc(Object::str("é".repeat(65))).unwrap());
+        assert_eq!(heap.used(), 1 + (130 + 2 * size_of::<usize>()).div_ceil(size_of::<Value>()));
+        assert_eq!(heap.chars(s).map(|(s, index)| index.offset(s, 64)), Some(Some(128)));
+        let bytes = Bytes::on_heap(heap.alloc(Object::Bytes(b"\xff".to_vec())).unwrap());
+        assert_eq!((heap.bytes(bytes), heap.bytes(Bytes::intern(b"c"))), (Some(&b"\xff"[..]), Some(&b"c"[..])));
+        assert_eq!(heap.str(Str::on_heap(bytes.object().unwrap())), None);
+    }
+}
@@ -0,0 +1,136 @@
+// undo log of executed instructions so a machine can be stepped backwards
+use std::collections::VecDeque;
+use crate::{function::Frame, heap::Ref, value::Value};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Overwrite {
+    Register(u8, Value),
+    Global(usize, Value),
+    // the old bytes at an address, read back with the type of the value stored over them
+    Memory(u64, Value),
+    MemorySize(usize),
+    // a value pushed onto the stack, undone by popping it
+    Pushed,
+    // a value popped off the stack, undone by pushing it back
+    Popped(Value),
+    // the old value of the stack slot at an index from the bottom
+    Stack(usize, Value),
+    // the old value of an array or record element
+    Element(Ref, usize, Value),
+    // the old length of an array, undoes APPEND
+    Length(Ref, usize),
+    // the number of frames before a CALL, undone by popping the frame it pushed and restoring the caller's registers
+    Call(usize),
+    // the frame RET popped and the callee's registers it replaced
+    Return(Box<Frame>, Vec<Value>),
+}
+
+// what an instruction changed: the pc it ran at and the old contents of the slots it wrote,
+// no instruction writes more than two
+#[derive(Debug, Clone, PartialEq)]
+pub struct Undo {
+    pub pc: usize,
+    pub overwrites: [Option<Overwrite>; 2],
+}
+
+pub struct History {
+    entries: VecDeque<Undo>,
+    limit: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new()
+    }
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            entries: VecDeque::new(),
+            limit: usize::MAX,
+        }
+    }
+
+    // keep only the most recent instructions, older ones can no longer be undone
+    pub fn with_limit(mut self, limit: usize) -> History {
+        self.limit = limit;
+        self
+    }
+
+    pub fn push(&mut self, undo: Undo) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.entries.len() == self.limit {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(undo);
+    }
+
+    pub fn pop(&mut self) -> Option<Undo> {
+        self.entries.pop_back()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // every value an undo could bring back, the garbage collector keeps what they refer to
+    pub fn values(&self) -> impl Iterator<Item = Value> + '_ {
+        let overwrites = self.entries.iter().flat_map(|undo| undo.overwrites.iter().flatten());
+        overwrites.flat_map(|overwrite| match overwrite {
+            Overwrite::Register(_, value)
+            | Overwrite::Global(_, value)
+            | Overwrite::Popped(value)
+            | Overwrite::Stack(_, value) => vec![*value],
+            // the element's old value is kept alive by the object being kept alive
+            Overwrite::Element(object, _, _) | Overwrite::Length(object, _) => vec![Value::Ref(*object)],
+            Overwrite::Return(frame, registers) => {
+                frame.saved.iter().chain(registers).copied().chain([Value::Ref(frame.closure)]).collect()
+            }
+            _ => Vec::new(),
+        })
+    }
+
+    // offset of the most recent instruction that wrote reg, and how many steps back it was
+    pub fn last_write(&self, reg: u8) -> Option<(usize, usize)> {
+        self.entries
+            .iter()
+            .rev()
+            .enumerate()
+            .find(|(_, undo)| {
+                undo.overwrites.iter().any(|overwrite| matches!(overwrite, Some(Overwrite::Register(r, _)) if *r == reg))
+            })
+            .map(|(steps, undo)| (undo.pc, steps + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit_and_last_write() {
+        let mut history = History::new().with_limit(3);
+        for pc in 0..5 {
+            history.push(Undo {
+                pc,
+                overwrites: [Some(Overwrite::Register(pc as u8 % 2, Value::U8(0))), None],
+            });
+        }
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.last_write(0), Some((4, 1)));
+        assert_eq!(history.last_write(1), Some((3, 2)));
+        assert_eq!(history.last_write(2), None);
+        assert_eq!(history.pop().map(|undo| undo.pc), Some(4));
+    }
+}
@@ -0,0 +1,78 @@
+// native functions a program can call with CALLHOST, looked up by index at run time and by name when assembling
+use crate::{heap::Heap, value::Value};
+
+// the heap is passed along to read the strings a program built at run time
+pub type HostFunction = Box<dyn FnMut(&[Value], &Heap) -> Result<Value, String>>;
+
+pub struct Hosts {
+    names: Vec<String>,
+    functions: Vec<HostFunction>,
+}
+
+impl Default for Hosts {
+    fn default() -> Self {
+        Hosts::new()
+    }
+}
+
+impl Hosts {
+    pub fn new() -> Hosts {
+        Hosts {
+            names: Vec::new(),
+            functions: Vec::new(),
+        }
+    }
+
+    // returns the index CALLHOST uses, registering a name twice replaces the function
+    pub fn register(&mut self, name: &str, function: impl FnMut(&[Value], &Heap) -> Result<Value, String> + 'static) -> u8 {
+        if let Some(index) = self.index(name) {
+            self.functions[index as usize] = Box::new(function);
+            return index;
+        }
+        assert!(self.names.len() <= u8::MAX as usize, "Too many host functions");
+        self.names.push(name.to_string());
+        self.functions.push(Box::new(function));
+        (self.names.len() - 1) as u8
+    }
+
+    pub fn index(&self, name: &str) -> Option<u8> {
+        self.names.iter().position(|n| n == name).map(|index| index as u8)
+    }
+
+    pub fn name(&self, index: u8) -> Option<&str> {
+        self.names.get(index as usize).map(|name| name.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    // None when nothing is registered at index
+    pub fn call(&mut self, index: u8, args: &[Value], heap: &Heap) -> Option<Result<Value, String>> {
+        self.functions.get_mut(index as usize).map(|function| function(args, heap))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_and_call() {
+        let mut hosts = Hosts::new();
+        let heap = Heap::new();
+        assert_eq!(hosts.register("sum", |args, _| Ok(args.iter().fold(Value::I8(0), |a, &b| (a + b).unwrap()))), 0);
+        assert_eq!(hosts.register("fail", |_, _| Err("no".to_string())), 1);
+        assert_eq!(hosts.index("fail"), Some(1));
+        assert_eq!(hosts.name(0), Some("sum"));
+        assert_eq!(hosts.call(0, &[Value::I8(2), Value::I8(3)], &heap), Some(Ok(Value::I8(5))));
+        assert_eq!(hosts.call(1, &[], &heap), Some(Err("no".to_string())));
+        assert_eq!(hosts.call(2, &[], &heap), None);
+        assert_eq!(hosts.register("sum", |_, _| Ok(Value::Bool(true))), 0);
+        assert_eq!(hosts.call(0, &[], &heap), Some(Ok(Value::Bool(true))));
+    }
+}
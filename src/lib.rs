@@ -0,0 +1,27 @@
+pub mod opcode;
+pub mod value;
+pub mod code;
+pub mod machine;
+pub mod reader;
+pub mod backend;
+pub mod debugger;
+pub mod trace;
+pub mod profile;
+pub mod fuel;
+pub mod snapshot;
+pub mod history;
+pub mod host;
+pub mod output;
+pub mod memory;
+pub mod stack;
+pub mod string;
+pub mod heap;
+pub mod function;
+pub mod math;
+pub mod bigint;
+pub mod register;
+
+pub use crate::code::Code;
+pub use crate::opcode::Opcode;
+pub use crate::value::Value;
+pub use crate::machine::Machine;
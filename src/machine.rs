@@ -1,189 +1,1512 @@
-use crate::{code::Code, value::Value};
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::io::{self, BufRead};
+use std::time::Instant;
+use crate::{code::Code, opcode::Opcode, value::{Value, ValueType}, trace::{TraceRecord, TracedValue, Tracer}, profile::Profiler, fuel::CostTable, snapshot::{self, StateRef}, history::{History, Overwrite, Undo}, host::Hosts, output::{self, Output}, memory::Memory, stack::Stack, string::{Bytes, CharIndex, Str}, heap::{self, Heap, Object, Ref}, bigint::{Big, BigInt}, function::{Frame, DEFAULT_CALL_LIMIT}, math, register::{RegisterFile, Registers}};
 
-const REGISTER_MAX: usize = u8::MAX as usize;
+pub const REGISTER_MAX: usize = u8::MAX as usize;
+
+// faults detected by the machine itself, pc is left on the faulting instruction
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trap {
+    InvalidOpcode(u8),
+    PcOutOfBounds(usize),
+    UnknownHost(u8),
+    RegisterOutOfBounds(usize),
+    // a host function returned an error
+    Host(String),
+    // PRINT could not write to the output
+    Output(String),
+    // the tracer could not write its record
+    Trace(String),
+    // READ could not read or parse its input
+    Input(String),
+    // a register used as an address or size is negative or not an integer
+    InvalidIndex(Value),
+    MemoryOutOfBounds(u64, usize),
+    // a push with the stack at its limit
+    StackOverflow(usize),
+    // an instruction needed more values than the stack holds
+    StackUnderflow(usize),
+    // an index past the end of a sequence of the given length
+    IndexOutOfBounds(u64, usize),
+    // an allocation did not fit under the heap limit even after collecting garbage
+    HeapExhausted(usize),
+    // CALL passed a different number of arguments than the function takes
+    Arity(u8, u8),
+    // a CALL with the given number of frames already running
+    CallStackOverflow(usize),
+    // RET or an upvalue access outside of any function
+    NoFrame,
+    // an operand of a type the instruction does not take
+    Type(String),
+    // an integer result that does not fit its type
+    Overflow(ValueType),
+    // an integer division or remainder by zero
+    DivideByZero,
+    // a shift by a negative amount, or by at least the width of a fixed width integer
+    InvalidShift(String),
+    // load was given code that does not verify
+    InvalidCode(String),
+}
+
+impl std::fmt::Display for Trap {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Trap::InvalidOpcode(byte) => write!(f, "Invalid opcode {}", byte),
+            Trap::PcOutOfBounds(pc) => write!(f, "Instruction at {} is past the end of the code", pc),
+            Trap::UnknownHost(index) => write!(f, "No host function registered at {}", index),
+            Trap::RegisterOutOfBounds(reg) => write!(f, "Register ${} out of range", reg),
+            Trap::Host(e) => write!(f, "Host function failed: {}", e),
+            Trap::Output(e) => write!(f, "Cannot write output: {}", e),
+            Trap::Trace(e) => write!(f, "Cannot write trace: {}", e),
+            Trap::Input(e) => write!(f, "Cannot read input: {}", e),
+            Trap::InvalidIndex(value) => write!(f, "Cannot use {} {} as an address or size", value.value_type(), value),
+            Trap::MemoryOutOfBounds(addr, len) => write!(f, "Memory access of {} bytes at {} is out of bounds", len, addr),
+            Trap::StackOverflow(limit) => write!(f, "Stack overflow past {} values", limit),
+            Trap::StackUnderflow(needed) => write!(f, "Stack holds fewer than {} values", needed),
+            Trap::IndexOutOfBounds(index, len) => write!(f, "Index {} is out of bounds for length {}", index, len),
+            Trap::HeapExhausted(limit) => write!(f, "Heap exhausted at its limit of {} values", limit),
+            Trap::Arity(arity, count) => write!(f, "Function takes {} arguments but was called with {}", arity, count),
+            Trap::CallStackOverflow(limit) => write!(f, "Call stack overflow past {} frames", limit),
+            Trap::NoFrame => write!(f, "No function is running"),
+            Trap::Type(e) => write!(f, "{}", e),
+            Trap::Overflow(ty) => write!(f, "Integer overflow in type {}", ty),
+            Trap::DivideByZero => write!(f, "Division by zero"),
+            Trap::InvalidShift(amount) => write!(f, "Cannot shift by {}", amount),
+            Trap::InvalidCode(e) => write!(f, "Invalid code: {}", e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    Running,
+    Breakpoint,
+    Halted,
+    // the next instruction costs more fuel than is left, add fuel and resume
+    OutOfFuel,
+    // YIELD handed a value to the host, resuming continues after the YIELD
+    Yielded(Value),
+    // run_for used up its step budget
+    Paused,
+    Trapped(Trap),
+}
+
+// a string or byte string operand of a string operation, strings come with the index of their chars
+#[derive(Clone, Copy)]
+enum Text<'a> {
+    Str(&'a str, &'a CharIndex),
+    Bytes(&'a [u8]),
+}
+
+impl Text<'_> {
+    fn len(self) -> usize {
+        match self {
+            Text::Str(_, index) => index.len(),
+            Text::Bytes(bytes) => bytes.len(),
+        }
+    }
+}
 
 pub struct Machine {
-    registers: [Value; REGISTER_MAX],
+    registers: Registers,
     pc: usize,
+    // pc is on a HALT that already ran, stepping again only reports Halted
+    halted: bool,
     code: Code,
+    breakpoints: BTreeSet<usize>,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    fuel: Option<u64>,
+    costs: CostTable,
+    history: Option<History>,
+    hosts: Hosts,
+    output: Output,
+    // None reads from stdin
+    input: Option<Box<dyn BufRead>>,
+    memory: Memory,
+    globals: Vec<Value>,
+    stack: Stack,
+    heap: Heap,
+    // innermost call last
+    frames: Vec<Frame>,
+    call_limit: usize,
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Machine::new()
+    }
 }
 
 impl Machine {
     pub fn new() -> Machine {
+        Machine::with_output(Output::stdout())
+    }
+
+    pub fn with_output(output: Output) -> Machine {
         Machine {
-            registers: [Value::U8(0); REGISTER_MAX],
+            registers: Registers::new(),
             pc: 0,
+            halted: false,
             code: Code::new(),
+            breakpoints: BTreeSet::new(),
+            tracer: None,
+            profiler: None,
+            fuel: None,
+            costs: CostTable::default(),
+            history: None,
+            hosts: Hosts::new(),
+            output,
+            input: None,
+            memory: Memory::new(),
+            globals: Vec::new(),
+            stack: Stack::new(),
+            heap: Heap::new(),
+            frames: Vec::new(),
+            call_limit: DEFAULT_CALL_LIMIT,
         }
     }
 
-    fn print(&self, reg: u8) {
-        print!("{}", self.registers[reg as usize]);
+    fn print(&mut self, reg: u8, typed: bool) -> Result<(), Trap> {
+        let value = self.registers.get(reg as usize);
+        let text = match typed {
+            true => output::typed(&value, &self.heap),
+            false => output::display(&value, &self.heap),
+        };
+        self.output.print(&text).map_err(|e| Trap::Output(e.to_string()))
     }
 
     fn move_reg(&mut self, r1: u8, r2: u8) {
-        self.registers[r1 as usize] = self.registers[r2 as usize];
+        self.registers.copy(r1 as usize, r2 as usize);
+
+    }
+
+    fn add(&mut self, r1: u8, r2: u8, r3: u8) -> Result<(), Trap> {
+        self.registers.set(r1 as usize, (self.registers.get(r2 as usize) + self.registers.get(r3 as usize))?);
+        Ok(())
+    }
+
+    fn sub(&mut self, r1: u8, r2: u8, r3: u8) -> Result<(), Trap> {
+        self.registers.set(r1 as usize, (self.registers.get(r2 as usize) - self.registers.get(r3 as usize))?);
+        Ok(())
+    }
+
+    fn mul(&mut self, r1: u8, r2: u8, r3: u8) -> Result<(), Trap> {
+        self.registers.set(r1 as usize, (self.registers.get(r2 as usize) * self.registers.get(r3 as usize))?);
+        Ok(())
+    }
+
+    fn div(&mut self, r1: u8, r2: u8, r3: u8) -> Result<(), Trap> {
+        self.registers.set(r1 as usize, (self.registers.get(r2 as usize) / self.registers.get(r3 as usize))?);
+        Ok(())
+    }
+
+    fn and(&mut self, r1: u8, r2: u8, r3: u8) -> Result<(), Trap> {
+        self.registers.set(r1 as usize, (self.registers.get(r2 as usize) & self.registers.get(r3 as usize))?);
+        Ok(())
+    }
+
+    fn or(&mut self, r1: u8, r2: u8, r3: u8) -> Result<(), Trap> {
+        self.registers.set(r1 as usize, (self.registers.get(r2 as usize) | self.registers.get(r3 as usize))?);
+        Ok(())
+    }
+
+    fn xor(&mut self, r1: u8, r2: u8, r3: u8) -> Result<(), Trap> {
+        self.registers.set(r1 as usize, (self.registers.get(r2 as usize) ^ self.registers.get(r3 as usize))?);
+        Ok(())
+    }
+
+    fn shr(&mut self, r1: u8, r2: u8, r3: u8) -> Result<(), Trap> {
+        self.registers.set(r1 as usize, (self.registers.get(r2 as usize) >> self.registers.get(r3 as usize))?);
+        Ok(())
+    }
+
+    fn shl(&mut self, r1: u8, r2: u8, r3: u8) -> Result<(), Trap> {
+        self.registers.set(r1 as usize, (self.registers.get(r2 as usize) << self.registers.get(r3 as usize))?);
+        Ok(())
+    }
+
+    // NEG, NOT and ABS take one source, REM and MOD two
+    fn arithmetic_op(&mut self, opcode: Opcode, operands: [u8; 3]) -> Result<(), Trap> {
+        let (a, b) = (self.registers.get(operands[1] as usize), self.registers.get(operands[2] as usize));
+        let value = match opcode {
+            Opcode::NEG => -a,
+            Opcode::NOT => !a,
+            Opcode::ABS => a.abs(),
+            Opcode::REM => a % b,
+            Opcode::MOD => a.rem_euclid(b),
+            _ => unreachable!("{} is not an arithmetic operation", opcode),
+        };
+        self.registers.set(operands[0] as usize, value?);
+        Ok(())
+    }
+
+    // whether either source of the arithmetic instruction at pc is a big integer, which takes it to big_op
+    fn has_big_source(&self, opcode: Opcode) -> bool {
+        let sources = &self.code.raw[self.pc + 2..self.pc + 1 + opcode.get_offset()];
+        sources.iter().any(|&reg| matches!(self.registers.get(reg as usize), Value::Big(_)))
+    }
+
+    // any of the arithmetic operations on big integers, which are read from the heap and give a new
+    // big integer on it. the result is charged against the heap limit before it is computed
+    fn big_op(&mut self, opcode: Opcode, operands: [u8; 3]) -> Result<(), Trap> {
+        let mut values = [self.registers.get(operands[1] as usize), self.registers.get(operands[2] as usize)];
+        let unary = matches!(opcode, Opcode::NEG | Opcode::NOT | Opcode::ABS);
+        if unary {
+            values[1] = values[0];
+        }
+        let [a, b] = values.map(|value| match value {
+            Value::Big(big) => Ok(self.heap.big(big).expect("Dangling big integer").clone()),
+            value => Err(Trap::Type(format!("Cannot apply {} to types big and {}", opcode, value.value_type()))),
+        });
+        let (a, b) = (a?, b?);
+        // shifting by more bits than the number has gives 0 or -1, so SHR amounts can be clamped
+        let shift = match opcode {
+            Opcode::SHL | Opcode::SHR if b.is_negative() => return Err(Trap::InvalidShift(b.to_string())),
+            Opcode::SHL => b.to_u128().and_then(|n| usize::try_from(n).ok()).unwrap_or(usize::MAX),
+            Opcode::SHR => b.to_u128().map_or(usize::MAX, |n| n as usize).min(a.limb_count() * 32 + 1),
+            _ => 0,
+        };
+        let limbs = match opcode {
+            Opcode::MUL => a.limb_count() + b.limb_count(),
+            Opcode::SHL => (shift / 32).saturating_add(a.limb_count() + 1),
+            Opcode::SHR => a.limb_count(),
+            _ => a.limb_count().max(b.limb_count()) + 1,
+        };
+        self.reserve(1 + heap::big_size(limbs) as u64)?;
+        let result = match opcode {
+            Opcode::ADD => &a + &b,
+            Opcode::SUB => &a - &b,
+            Opcode::MUL => &a * &b,
+            Opcode::AND => &a & &b,
+            Opcode::OR => &a | &b,
+            Opcode::XOR => &a ^ &b,
+            Opcode::SHL => &a << shift,
+            Opcode::SHR => &a >> shift,
+            Opcode::NEG => -&a,
+            Opcode::NOT => !&a,
+            Opcode::ABS => a.abs(),
+            Opcode::DIV => a.divmod(&b).ok_or(Trap::DivideByZero)?.0,
+            Opcode::REM => a.divmod(&b).ok_or(Trap::DivideByZero)?.1,
+            Opcode::MOD => a.rem_euclid(&b).ok_or(Trap::DivideByZero)?,
+            _ => unreachable!("{} is not an arithmetic operation", opcode),
+        };
+        let value = self.alloc_big(result)?;
+        self.registers.set(operands[0] as usize, value);
+        Ok(())
+    }
+
+    // the ok register gets whether the value fits the type, the destination is only written when it does
+    fn convert(&mut self, operands: [u8; 4]) -> Result<(), Trap> {
+        let ty = ValueType::from_byte(operands[3]).expect("type checked by decode");
+        let value = self.registers.get(operands[2] as usize);
+        let converted = match (value, ty) {
+            (Value::Big(_), ValueType::Big) => Some(value),
+            (Value::Big(big), _) => Value::from_big(self.heap.big(big).expect("Dangling big integer"), ty)?,
+            (_, ValueType::Big) => Some(self.alloc_big(value.to_big()?)?),
+            _ => value.convert(ty)?,
+        };
+        self.registers.set(operands[1] as usize, Value::Bool(converted.is_some()));
+        if let Some(value) = converted {
+            self.registers.set(operands[0] as usize, value);
+        }
+        Ok(())
+    }
+
+    fn load_const(&mut self, reg: u8, constant: u8) {
+        self.registers.set(reg as usize, self.code.const_pool[constant as usize]);
+    }
+
+    fn getglobal(&mut self, reg: u8, global: u8) {
+        self.registers.set(reg as usize, self.globals[global as usize]);
+    }
+
+    fn setglobal(&mut self, reg: u8, global: u8) {
+        self.globals[global as usize] = self.registers.get(reg as usize);
+    }
+
+    // the destination is only written when the host function succeeds
+    fn callhost(&mut self, reg: u8, host: u8, first: u8, count: u8) -> Result<(), Trap> {
+        let (first, count) = (first as usize, count as usize);
+        if first + count > REGISTER_MAX {
+            return Err(Trap::RegisterOutOfBounds(first + count - 1));
+        }
+        let result = self
+            .hosts
+            .call(host, &self.registers.read(first..first + count), &self.heap)
+            .ok_or(Trap::UnknownHost(host))?;
+        self.registers.set(reg as usize, result.map_err(Trap::Host)?);
+        Ok(())
+    }
+
+    // the line without its line ending, None at the end of input
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        let read = match self.input.as_mut() {
+            Some(input) => input.read_line(&mut line)?,
+            None => io::stdin().read_line(&mut line)?,
+        };
+        if read == 0 {
+            return Ok(None);
+        }
+        Ok(Some(line.trim_end_matches(['\n', '\r']).to_string()))
+    }
+
+    // ok is set to whether a value was read, reg is left alone at the end of input
+    fn read(&mut self, reg: u8, ok: u8, ty: ValueType) -> Result<(), Trap> {
+        let line = self.read_line().map_err(|e| Trap::Input(e.to_string()))?;
+        let read = line.is_some();
+        if let Some(line) = line {
+            let value = self.parse(line.trim(), ty)?.ok_or(Trap::Input(format!("Cannot parse {} into {}", line.trim(), ty)))?;
+            self.registers.set(reg as usize, value);
+        }
+        self.registers.set(ok as usize, Value::Bool(read));
+        Ok(())
+    }
 
+    // text as a value of the type, None when it does not parse. strings, byte strings and big integers
+    // go on the heap
+    fn parse(&mut self, s: &str, ty: ValueType) -> Result<Option<Value>, Trap> {
+        match ty {
+            ValueType::Str => self.alloc_str(s.to_string()).map(Some),
+            ValueType::Bytes => self.alloc_bytes(s.as_bytes().to_vec()).map(Some),
+            ValueType::Big => s.parse().ok().map(|big| self.alloc_big(big)).transpose(),
+            _ => Ok(Value::parse_as(s, ty).ok()),
+        }
+    }
+
+    fn index(&self, reg: u8) -> Result<u64, Trap> {
+        let value = self.registers.get(reg as usize);
+        let index = match value {
+            Value::Big(big) => self.heap.big(big).expect("Dangling big integer").to_u128().and_then(|u| u64::try_from(u).ok()),
+            _ => value.as_index(),
+        };
+        index.ok_or(Trap::InvalidIndex(value))
+    }
+
+    fn mload(&mut self, reg: u8, addr: u8, ty: ValueType) -> Result<(), Trap> {
+        let addr = self.index(addr)?;
+        let size = ty.size().expect("type checked by decode");
+        self.registers.set(reg as usize, self.memory.load(addr, ty).ok_or(Trap::MemoryOutOfBounds(addr, size))?);
+        Ok(())
+    }
+
+    fn mstore(&mut self, addr: u8, reg: u8) -> Result<(), Trap> {
+        let addr = self.index(addr)?;
+        let value = self.registers.get(reg as usize);
+        let size = match value.value_type().size() {
+            Some(size) => size,
+            None => return Err(Trap::Type(format!("Cannot store type {} in memory", value.value_type()))),
+        };
+        match self.memory.store(addr, value) {
+            true => Ok(()),
+            false => Err(Trap::MemoryOutOfBounds(addr, size)),
+        }
+    }
+
+    // reg gets the old size, or u64::MAX if the limit does not leave room
+    fn grow(&mut self, reg: u8, delta: u8) -> Result<(), Trap> {
+        let delta = self.index(delta)?;
+        let old = self.memory.grow(delta).map(|old| old as u64).unwrap_or(u64::MAX);
+        self.registers.set(reg as usize, Value::U64(old));
+        Ok(())
+    }
+
+    fn push(&mut self, value: Value) -> Result<(), Trap> {
+        match self.stack.push(value) {
+            true => Ok(()),
+            false => Err(Trap::StackOverflow(self.stack.limit())),
+        }
+    }
+
+    fn peek(&self, depth: u8) -> Result<Value, Trap> {
+        self.stack.peek(depth as usize).ok_or(Trap::StackUnderflow(depth as usize + 1))
+    }
+
+    // the stack operations, depth counts down from the top of the stack
+    fn stack_op(&mut self, opcode: Opcode, operands: [u8; 2]) -> Result<(), Trap> {
+        match opcode {
+            Opcode::PUSH => self.push(self.registers.get(operands[0] as usize))?,
+            Opcode::POP => {
+                self.registers.set(operands[0] as usize, self.stack.pop().ok_or(Trap::StackUnderflow(1))?);
+            }
+            Opcode::PEEK => self.registers.set(operands[0] as usize, self.peek(0)?),
+            Opcode::DUP => self.push(self.peek(0)?)?,
+            Opcode::SWAP => {
+                let (top, below) = (self.peek(0)?, self.peek(1)?);
+                self.stack.set(0, below);
+                self.stack.set(1, top);
+            }
+            Opcode::SLOAD => self.registers.set(operands[0] as usize, self.peek(operands[1])?),
+            Opcode::SSTORE => {
+                self.peek(operands[1])?;
+                self.stack.set(operands[1] as usize, self.registers.get(operands[0] as usize));
+            }
+            _ => unreachable!("{} is not a stack operation", opcode),
+        }
+        Ok(())
+    }
+
+    fn string(&self, reg: u8, operation: &str) -> Result<&str, Trap> {
+        match self.registers.get(reg as usize) {
+            Value::Str(s) => Ok(self.heap.str(s).expect("Dangling string")),
+            value => Err(Trap::Type(format!("Cannot {} type {}", operation, value.value_type()))),
+        }
+    }
+
+    fn text(&self, reg: u8, operation: &str) -> Result<Text<'_>, Trap> {
+        match self.registers.get(reg as usize) {
+            Value::Str(s) => {
+                let (s, index) = self.heap.chars(s).expect("Dangling string");
+                Ok(Text::Str(s, index))
+            }
+            Value::Bytes(bytes) => Ok(Text::Bytes(self.heap.bytes(bytes).expect("Dangling byte string"))),
+            value => Err(Trap::Type(format!("Cannot {} type {}", operation, value.value_type()))),
+        }
+    }
+
+    // two strings or two byte strings as bytes, utf-8 concatenates and orders the same way strings do
+    fn texts(&self, r1: u8, r2: u8, operation: &str) -> Result<(ValueType, &[u8], &[u8]), Trap> {
+        match (self.text(r1, operation)?, self.text(r2, operation)?) {
+            (Text::Str(a, _), Text::Str(b, _)) => Ok((ValueType::Str, a.as_bytes(), b.as_bytes())),
+            (Text::Bytes(a), Text::Bytes(b)) => Ok((ValueType::Bytes, a, b)),
+            _ => {
+                let (a, b) = (self.registers.get(r1 as usize), self.registers.get(r2 as usize));
+                Err(Trap::Type(format!("Cannot {} types {} and {}", operation, a.value_type(), b.value_type())))
+            }
+        }
     }
 
-    fn add(&mut self, r1: u8, r2: u8, r3: u8) {
-        self.registers[r1 as usize] = self.registers[r2 as usize] + self.registers[r3 as usize];
+    // lengths and offsets count characters in strings and bytes in byte strings
+    fn string_op(&mut self, opcode: Opcode, operands: [u8; 4]) -> Result<(), Trap> {
+        let value = match opcode {
+            Opcode::CONCAT => {
+                let (ty, a, b) = self.texts(operands[1], operands[2], "concat")?;
+                let joined = [a, b].concat();
+                match ty {
+                    ValueType::Str => self.alloc_str(String::from_utf8(joined).expect("two strings joined"))?,
+                    _ => self.alloc_bytes(joined)?,
+                }
+            }
+            Opcode::STRLEN => Value::U64(self.text(operands[1], "take the length of")?.len() as u64),
+            Opcode::SUBSTR => {
+                let text = self.text(operands[1], "take a substring of")?;
+                let (start, len) = (self.index(operands[2])?, self.index(operands[3])?);
+                let count = text.len();
+                let end = start.saturating_add(len);
+                if end > count as u64 {
+                    return Err(Trap::IndexOutOfBounds(end, count));
+                }
+                match text {
+                    Text::Str(s, index) => {
+                        let offset = |i| index.offset(s, i as usize).expect("bounds checked");
+                        let s = s[offset(start)..offset(end)].to_string();
+                        self.alloc_str(s)?
+                    }
+                    Text::Bytes(bytes) => {
+                        let bytes = bytes[start as usize..end as usize].to_vec();
+                        self.alloc_bytes(bytes)?
+                    }
+                }
+            }
+            // a string gives the char at the index, a byte string the byte as a u8
+            Opcode::CHARAT => {
+                let text = self.text(operands[1], "index")?;
+                let index = self.index(operands[2])?;
+                let value = match text {
+                    Text::Str(s, chars) => chars.offset(s, index as usize).and_then(|offset| s[offset..].chars().next()).map(Value::Char),
+                    Text::Bytes(bytes) => bytes.get(index as usize).copied().map(Value::U8),
+                };
+                value.ok_or(Trap::IndexOutOfBounds(index, text.len()))?
+            }
+            Opcode::STRCMP | Opcode::STREQ => {
+                let (_, a, b) = self.texts(operands[1], operands[2], "compare")?;
+                match opcode {
+                    Opcode::STREQ => Value::Bool(a == b),
+                    _ => Value::I8(match a.cmp(b) {
+                        Ordering::Less => -1,
+                        Ordering::Equal => 0,
+                        Ordering::Greater => 1,
+                    }),
+                }
+            }
+            Opcode::TOSTR => self.alloc_str(output::display(&self.registers.get(operands[1] as usize), &self.heap))?,
+            // like READ, ok is set to whether the text parsed and reg is left alone if it did not
+            Opcode::PARSE => {
+                let s = self.string(operands[2], "parse")?.trim().to_string();
+                let ty = ValueType::from_byte(operands[3]).expect("type checked by decode");
+                let parsed = self.parse(&s, ty)?;
+                self.registers.set(operands[1] as usize, Value::Bool(parsed.is_some()));
+                match parsed {
+                    Some(value) => value,
+                    None => return Ok(()),
+                }
+            }
+            _ => unreachable!("{} is not a string operation", opcode),
+        };
+        self.registers.set(operands[0] as usize, value);
+        Ok(())
+    }
+
+    // chars are classified by unicode except that digits are 0 to 9, bytes by ascii
+    fn classify(&mut self, opcode: Opcode, operands: [u8; 2]) -> Result<(), Trap> {
+        let class = match (opcode, self.registers.get(operands[1] as usize)) {
+            (Opcode::ISDIGIT, Value::Char(c)) => c.is_ascii_digit(),
+            (Opcode::ISALPHA, Value::Char(c)) => c.is_alphabetic(),
+            (Opcode::ISSPACE, Value::Char(c)) => c.is_whitespace(),
+            (Opcode::ISDIGIT, Value::U8(byte)) => byte.is_ascii_digit(),
+            (Opcode::ISALPHA, Value::U8(byte)) => byte.is_ascii_alphabetic(),
+            (Opcode::ISSPACE, Value::U8(byte)) => byte.is_ascii_whitespace(),
+            (_, value) => return Err(Trap::Type(format!("Cannot classify type {}", value.value_type()))),
+        };
+        self.registers.set(operands[0] as usize, Value::Bool(class));
+        Ok(())
     }
 
-    fn sub(&mut self, r1: u8, r2: u8, r3: u8) {
-        self.registers[r1 as usize] = self.registers[r2 as usize] - self.registers[r3 as usize];
+    fn object(&self, reg: u8) -> Result<Ref, Trap> {
+        match self.registers.get(reg as usize) {
+            Value::Ref(object) if matches!(self.heap.get(object), Some(Object::Closure(..))) => {
+                Err(Trap::Type("Cannot index a closure".to_string()))
+            }
+            Value::Ref(object) => Ok(object),
+            value => Err(Trap::Type(format!("Cannot index type {}", value.value_type()))),
+        }
     }
 
-    fn mul(&mut self, r1: u8, r2: u8, r3: u8) {
-        self.registers[r1 as usize] = self.registers[r2 as usize] * self.registers[r3 as usize];
+    // collects garbage if size more values do not fit under the heap limit
+    fn reserve(&mut self, size: u64) -> Result<(), Trap> {
+        if size > self.heap.limit().saturating_sub(self.heap.used()) as u64 {
+            self.collect();
+        }
+        match size > self.heap.limit().saturating_sub(self.heap.used()) as u64 {
+            true => Err(Trap::HeapExhausted(self.heap.limit())),
+            false => Ok(()),
+        }
     }
 
-    fn div(&mut self, r1: u8, r2: u8, r3: u8) {
-        self.registers[r1 as usize] = self.registers[r2 as usize] / self.registers[r3 as usize];
+    fn alloc(&mut self, object: Object) -> Value {
+        Value::Ref(self.heap.alloc(object).expect("space reserved before allocating"))
     }
 
-    fn and(&mut self, r1: u8, r2: u8, r3: u8) {
-        self.registers[r1 as usize] = self.registers[r2 as usize] & self.registers[r3 as usize];
+    // strings built at run time go on the heap, only constants are interned
+    fn alloc_str(&mut self, s: String) -> Result<Value, Trap> {
+        let object = Object::str(s);
+        self.reserve(object.size() as u64)?;
+        Ok(Value::Str(Str::on_heap(self.heap.alloc(object).expect("space reserved before allocating"))))
     }
 
-    fn or(&mut self, r1: u8, r2: u8, r3: u8) {
-        self.registers[r1 as usize] = self.registers[r2 as usize] | self.registers[r3 as usize];
+    fn alloc_bytes(&mut self, bytes: Vec<u8>) -> Result<Value, Trap> {
+        let object = Object::Bytes(bytes);
+        self.reserve(object.size() as u64)?;
+        Ok(Value::Bytes(Bytes::on_heap(self.heap.alloc(object).expect("space reserved before allocating"))))
     }
 
-    fn xor(&mut self, r1: u8, r2: u8, r3: u8) {
-        self.registers[r1 as usize] = self.registers[r2 as usize] ^ self.registers[r3 as usize];
+    // like strings, big integers computed at run time go on the heap
+    fn alloc_big(&mut self, big: BigInt) -> Result<Value, Trap> {
+        let object = Object::Big(big);
+        self.reserve(object.size() as u64)?;
+        Ok(Value::Big(Big::on_heap(self.heap.alloc(object).expect("space reserved before allocating"))))
     }
 
-    fn shr(&mut self, r1: u8, r2: u8, r3: u8) {
-        self.registers[r1 as usize] = self.registers[r2 as usize] >> self.registers[r3 as usize];
+    // new arrays and records start out filled with the initial register value
+    fn heap_op(&mut self, opcode: Opcode, operands: [u8; 3]) -> Result<(), Trap> {
+        let value = match opcode {
+            Opcode::NEWARRAY => {
+                let len = self.index(operands[1])?;
+                self.reserve(len.saturating_add(1))?;
+                self.alloc(Object::Array(vec![Value::U8(0); len as usize]))
+            }
+            Opcode::NEWRECORD => {
+                self.reserve(operands[1] as u64 + 1)?;
+                self.alloc(Object::Record(vec![Value::U8(0); operands[1] as usize]))
+            }
+            Opcode::GETELEM => {
+                let values = self.heap.get(self.object(operands[1])?).expect("Dangling reference").values();
+                let index = self.index(operands[2])?;
+                *values.get(index as usize).ok_or(Trap::IndexOutOfBounds(index, values.len()))?
+            }
+            Opcode::SETELEM => {
+                let object = self.object(operands[0])?;
+                let index = self.index(operands[1])?;
+                let value = self.registers.get(operands[2] as usize);
+                if !self.heap.set(object, index as usize, value) {
+                    let len = self.heap.get(object).expect("Dangling reference").values().len();
+                    return Err(Trap::IndexOutOfBounds(index, len));
+                }
+                return Ok(());
+            }
+            Opcode::LEN => Value::U64(self.heap.get(self.object(operands[1])?).expect("Dangling reference").values().len() as u64),
+            Opcode::APPEND => {
+                let object = self.object(operands[0])?;
+                if let Some(Object::Record(_)) = self.heap.get(object) {
+                    return Err(Trap::Type("Cannot append to a record".to_string()));
+                }
+                self.reserve(1)?;
+                self.heap.push(object, self.registers.get(operands[1] as usize));
+                return Ok(());
+            }
+            _ => unreachable!("{} is not a heap operation", opcode),
+        };
+        self.registers.set(operands[0] as usize, value);
+        Ok(())
     }
 
-    fn shl(&mut self, r1: u8, r2: u8, r3: u8) {
-        self.registers[r1 as usize] = self.registers[r2 as usize] << self.registers[r3 as usize];
+    // the closure in reg and the index of its function
+    fn closure(&self, reg: u8) -> Result<(Ref, usize), Trap> {
+        let object = match self.registers.get(reg as usize) {
+            Value::Ref(object) => object,
+            value => return Err(Trap::Type(format!("Cannot call type {}", value.value_type()))),
+        };
+        match self.heap.get(object) {
+            Some(Object::Closure(function, _)) => Ok((object, *function as usize)),
+            _ => Err(Trap::Type("Cannot call an object that is not a closure".to_string())),
+        }
     }
 
-    fn load(&mut self, reg: u8, constant: u8) {
-        self.registers[reg as usize] = self.code.const_pool[constant as usize];
+    // the closure of the innermost call, which holds the upvalues
+    fn upvalues(&self) -> Result<Ref, Trap> {
+        self.frames.last().map(|frame| frame.closure).ok_or(Trap::NoFrame)
     }
 
-    fn store(&mut self, reg: u8, constant: u8) {
-        self.code.const_pool[constant as usize] = self.registers[reg as usize];
+    // returns the pc to continue at, next being the following instruction
+    fn function_op(&mut self, opcode: Opcode, operands: [u8; 4], next: usize) -> Result<usize, Trap> {
+        let (first, count) = (operands[2] as usize, operands[3] as usize);
+        match opcode {
+            // upvalues are captured by value into the closure, SETUPVAL changes them for every later call
+            Opcode::CLOSURE => {
+                self.reserve(count as u64 + 1)?;
+                let upvalues = self.registers.read(first..first + count);
+                let closure = self.alloc(Object::Closure(operands[1], upvalues));
+                self.registers.set(operands[0] as usize, closure);
+            }
+            // the callee gets its arguments in $0 onwards and the rest of its register window zeroed
+            Opcode::CALL => {
+                let (closure, function) = self.closure(operands[1])?;
+                let function = &self.code.functions[function];
+                let (entry, arity, window) = (function.entry, function.arity, function.registers as usize);
+                if count != arity as usize {
+                    return Err(Trap::Arity(arity, operands[3]));
+                }
+                if self.frames.len() >= self.call_limit {
+                    return Err(Trap::CallStackOverflow(self.call_limit));
+                }
+                let arguments = self.registers.read(first..first + count);
+                let saved = self.registers.read(0..window);
+                self.frames.push(Frame {
+                    return_pc: next,
+                    dst: operands[0],
+                    closure,
+                    saved,
+                });
+                self.registers.write(0, &vec![Value::U8(0); window]);
+                self.registers.write(0, &arguments);
+                return Ok(entry);
+            }
+            Opcode::RET => {
+                let frame = self.frames.pop().ok_or(Trap::NoFrame)?;
+                let value = self.registers.get(operands[0] as usize);
+                self.registers.write(0, &frame.saved);
+                self.registers.set(frame.dst as usize, value);
+                return Ok(frame.return_pc);
+            }
+            Opcode::GETUPVAL => {
+                let upvalues = self.heap.get(self.upvalues()?).expect("Dangling reference").values();
+                let index = operands[1] as usize;
+                let value = *upvalues.get(index).ok_or(Trap::IndexOutOfBounds(index as u64, upvalues.len()))?;
+                self.registers.set(operands[0] as usize, value);
+            }
+            Opcode::SETUPVAL => {
+                let closure = self.upvalues()?;
+                let index = operands[1] as usize;
+                if !self.heap.set(closure, index, self.registers.get(operands[0] as usize)) {
+                    let len = self.heap.get(closure).expect("Dangling reference").values().len();
+                    return Err(Trap::IndexOutOfBounds(index as u64, len));
+                }
+            }
+            _ => unreachable!("{} is not a function operation", opcode),
+        }
+        Ok(next)
     }
 
     fn jmp(&mut self, addr: u8) {
         self.pc = addr as usize;
     }
 
-    pub fn run(&mut self, code: Code) {
+    fn jmpif(&mut self, reg: u8, addr: u8) -> Result<(), Trap> {
+        match self.registers.get(reg as usize) {
+            Value::Bool(true) => self.pc = addr as usize,
+            Value::Bool(false) => self.pc += Opcode::JMPIF.get_offset() + 1,
+            value => return Err(Trap::Type(format!("Cannot branch on type {}", value.value_type()))),
+        }
+        Ok(())
+    }
+
+    // code that does not verify is rejected, so decode only has to guard against what verify cannot see
+    // the stack and memory start out empty for the new program
+    pub fn load(&mut self, code: Code) -> Result<(), String> {
+        code.verify()?;
+        self.globals = code.globals.clone();
         self.code = code;
-        loop {
-            let instruction = self.code.raw[self.pc];
-            match instruction {
-                0 => {
-                    self.print(self.code.raw[self.pc + 1]);
-                    self.pc += 2;
+        self.pc = 0;
+        self.halted = false;
+        self.frames.clear();
+        self.stack.values_mut().clear();
+        self.memory.resize(0);
+        if let Some(history) = self.history.as_mut() {
+            history.clear();
+        }
+        Ok(())
+    }
+
+    // executes the instruction at pc
+    pub fn step(&mut self) -> Status {
+        // a halted machine is not charged fuel again for the HALT it is stopped on
+        if self.halted {
+            return Status::Halted;
+        }
+        let pc = self.pc;
+        let opcode = match self.decode() {
+            Ok(opcode) => opcode,
+            Err(trap) => return Status::Trapped(trap),
+        };
+        if let Some(fuel) = self.fuel {
+            let cost = self.costs.cost(opcode);
+            if cost > fuel {
+                return Status::OutOfFuel;
+            }
+            self.fuel = Some(fuel - cost);
+        }
+        let undo = self.history.as_ref().map(|_| self.undo(opcode));
+        let start = match self.profiler {
+            Some(ref profiler) if profiler.is_timed() => Some(Instant::now()),
+            _ => None,
+        };
+        let status = match self.tracer {
+            Some(ref tracer) if tracer.traces(pc) => self.traced_step(opcode),
+            _ => self.execute(opcode),
+        };
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.count(&self.code, pc, start.map(|start| start.elapsed()));
+        }
+        self.halted = status == Status::Halted;
+        // HALT changes nothing, so there is nothing to step back over
+        if let (Some(history), Some(undo)) = (self.history.as_mut(), undo) {
+            if status != Status::Halted {
+                history.push(undo);
+            }
+        }
+        // output is flushed whenever control goes back to the host
+        if status != Status::Running {
+            if let Err(e) = self.output.flush() {
+                return Status::Trapped(Trap::Output(e.to_string()));
+            }
+        }
+        status
+    }
+
+    // what the instruction at pc is about to overwrite
+    fn undo(&self, opcode: Opcode) -> Undo {
+        let register = |reg: u8| Some(Overwrite::Register(reg, self.registers.get(reg as usize)));
+        let slot = |depth: usize| {
+            let index = self.stack.index(depth)?;
+            Some(Overwrite::Stack(index, self.stack.values()[index]))
+        };
+        let overwrites = match opcode {
+            Opcode::SETGLOBAL => {
+                let global = self.code.raw[self.pc + 2] as usize;
+                [Some(Overwrite::Global(global, self.globals[global])), None]
+            }
+            Opcode::READ | Opcode::PARSE | Opcode::CONVERT => [register(self.code.raw[self.pc + 1]), register(self.code.raw[self.pc + 2])],
+            Opcode::MSTORE => {
+                let addr = self.registers.get(self.code.raw[self.pc + 1] as usize).as_index();
+                let ty = self.registers.get(self.code.raw[self.pc + 2] as usize).value_type();
+                let old = addr.and_then(|addr| Some(Overwrite::Memory(addr, self.memory.load(addr, ty)?)));
+                [old, None]
+            }
+            Opcode::GROW => [register(self.code.raw[self.pc + 1]), Some(Overwrite::MemorySize(self.memory.size()))],
+            // nothing is recorded for stack instructions that are about to trap
+            Opcode::PUSH => [(self.stack.len() < self.stack.limit()).then_some(Overwrite::Pushed), None],
+            Opcode::DUP => [(!self.stack.is_empty() && self.stack.len() < self.stack.limit()).then_some(Overwrite::Pushed), None],
+            Opcode::POP => match self.stack.peek(0) {
+                Some(top) => [register(self.code.raw[self.pc + 1]), Some(Overwrite::Popped(top))],
+                None => [None, None],
+            },
+            Opcode::SWAP => match (slot(0), slot(1)) {
+                (Some(top), Some(below)) => [Some(top), Some(below)],
+                _ => [None, None],
+            },
+            Opcode::SSTORE => [slot(self.code.raw[self.pc + 2] as usize), None],
+            Opcode::SETELEM => {
+                let (object, index) = (self.registers.get(self.code.raw[self.pc + 1] as usize), self.registers.get(self.code.raw[self.pc + 2] as usize));
+                let old = object.as_object().zip(index.as_index()).and_then(|(object, index)| {
+                    let old = *self.heap.get(object)?.values().get(usize::try_from(index).ok()?)?;
+                    Some(Overwrite::Element(object, index as usize, old))
+                });
+                [old, None]
+            }
+            Opcode::APPEND => {
+                let object = self.registers.get(self.code.raw[self.pc + 1] as usize).as_object();
+                let len = object.and_then(|object| Some(Overwrite::Length(object, self.heap.get(object)?.values().len())));
+                [len, None]
+            }
+            Opcode::CALL => [Some(Overwrite::Call(self.frames.len())), None],
+            // the destination is written after the caller's registers come back, so it is undone first
+            Opcode::RET => match self.frames.last() {
+                Some(frame) => {
+                    let registers = self.registers.read(0..frame.saved.len());
+                    [Some(Overwrite::Return(Box::new(frame.clone()), registers)), register(frame.dst)]
+                }
+                None => [None, None],
+            },
+            Opcode::SETUPVAL => {
+                let index = self.code.raw[self.pc + 2] as usize;
+                let old = self.frames.last().and_then(|frame| {
+                    let old = *self.heap.get(frame.closure)?.values().get(index)?;
+                    Some(Overwrite::Element(frame.closure, index, old))
+                });
+                [old, None]
+            }
+            _ => [self.code.destination_register(self.pc).and_then(register), None],
+        };
+        Undo { pc: self.pc, overwrites }
+    }
+
+    // undoes the last recorded instruction, false when there is no history left
+    pub fn step_back(&mut self) -> bool {
+        let undo = match self.history.as_mut().and_then(|history| history.pop()) {
+            Some(undo) => undo,
+            None => return false,
+        };
+        self.pc = undo.pc;
+        self.halted = false;
+        // in reverse, in case both slots are the same register
+        for overwrite in undo.overwrites.into_iter().rev() {
+            match overwrite {
+                Some(Overwrite::Register(reg, value)) => self.registers.set(reg as usize, value),
+                Some(Overwrite::Global(global, value)) => self.globals[global] = value,
+                Some(Overwrite::Memory(addr, value)) => {
+                    self.memory.store(addr, value);
+                }
+                Some(Overwrite::MemorySize(size)) => self.memory.resize(size),
+                Some(Overwrite::Pushed) => {
+                    self.stack.pop();
+                }
+                Some(Overwrite::Popped(value)) => self.stack.values_mut().push(value),
+                Some(Overwrite::Stack(index, value)) => self.stack.values_mut()[index] = value,
+                Some(Overwrite::Element(object, index, value)) => {
+                    self.heap.set(object, index, value);
+                }
+                Some(Overwrite::Length(object, len)) => self.heap.truncate(object, len),
+                // a CALL that trapped pushed no frame
+                Some(Overwrite::Call(depth)) if self.frames.len() > depth => {
+                    let frame = self.frames.pop().expect("more frames than depth");
+                    self.registers.write(0, &frame.saved);
+                }
+                Some(Overwrite::Return(frame, registers)) => {
+                    self.registers.write(0, &registers);
+                    self.frames.push(*frame);
+                }
+                Some(Overwrite::Call(_)) | None => {}
+            }
+        }
+        true
+    }
+
+    // steps back at least once and then until pc reaches a breakpoint, false if history ran out first
+    pub fn run_back_until_breakpoint(&mut self) -> bool {
+        while self.step_back() {
+            if self.breakpoints.contains(&self.pc) {
+                return true;
+            }
+        }
+        false
+    }
+
+    // checks that a whole instruction is at pc before anything looks at its operands
+    fn decode(&self) -> Result<Opcode, Trap> {
+        let byte = *self.code.raw.get(self.pc).ok_or(Trap::PcOutOfBounds(self.pc))?;
+        match Opcode::from_byte(byte) {
+            Some(Opcode::CONST) | None => Err(Trap::InvalidOpcode(byte)),
+            Some(opcode) if self.pc + opcode.get_offset() >= self.code.raw.len() => {
+                Err(Trap::PcOutOfBounds(self.pc + opcode.get_offset()))
+            }
+            // the type operand is part of the encoding, so a bad one makes the whole instruction invalid
+            Some(Opcode::READ) if ValueType::from_byte(self.code.raw[self.pc + 3]).is_none() => {
+                Err(Trap::InvalidOpcode(byte))
+            }
+            Some(Opcode::PARSE) if ValueType::from_byte(self.code.raw[self.pc + 4]).is_none() => {
+                Err(Trap::InvalidOpcode(byte))
+            }
+            Some(Opcode::CONVERT)
+                if !ValueType::from_byte(self.code.raw[self.pc + 4]).is_some_and(|ty| ty.is_integer() || ty == ValueType::Char) =>
+            {
+                Err(Trap::InvalidOpcode(byte))
+            }
+            Some(Opcode::MATH) if math::name(self.code.raw[self.pc + 2]).is_none() => Err(Trap::InvalidOpcode(byte)),
+            // every closure has to refer to a function that exists
+            Some(Opcode::CLOSURE) if self.code.raw[self.pc + 2] as usize >= self.code.functions.len() => {
+                Err(Trap::InvalidOpcode(byte))
+            }
+            // memory only holds types with a fixed width
+            Some(Opcode::MLOAD) if ValueType::from_byte(self.code.raw[self.pc + 3]).and_then(|ty| ty.size()).is_none() => {
+                Err(Trap::InvalidOpcode(byte))
+            }
+            Some(opcode) => Ok(opcode),
+        }
+    }
+
+    fn traced_step(&mut self, opcode: Opcode) -> Status {
+        let pc = self.pc;
+        let instruction = self.code.format_instruction(pc);
+        let destination = self.code.destination_register(pc);
+        let before = destination.map(|reg| TracedValue::new(&self.registers.get(reg as usize), &self.heap));
+        let status = self.execute(opcode);
+        let record = TraceRecord {
+            pc,
+            line: self.code.lines[pc],
+            instruction,
+            register: destination.zip(before).map(|(reg, before)| (reg, before, TracedValue::new(&self.registers.get(reg as usize), &self.heap))),
+        };
+        match self.tracer.as_mut().map(|tracer| tracer.record(&record)) {
+            Some(Err(e)) => Status::Trapped(Trap::Trace(e.to_string())),
+            _ => status,
+        }
+    }
+
+    fn execute(&mut self, opcode: Opcode) -> Status {
+        match opcode {
+            Opcode::PRINT | Opcode::TPRINT => {
+                if let Err(trap) = self.print(self.code.raw[self.pc + 1], opcode == Opcode::TPRINT) {
+                    return Status::Trapped(trap);
+                }
+                self.pc += 2;
+            }
+            Opcode::MOVE => {
+                self.move_reg(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2]);
+                self.pc += 3;
+            }
+            Opcode::LOAD => {
+                self.load_const(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2]);
+                self.pc += 3;
+            }
+            Opcode::GETGLOBAL => {
+                self.getglobal(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2]);
+                self.pc += 3;
+            }
+            Opcode::SETGLOBAL => {
+                self.setglobal(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2]);
+                self.pc += 3;
+            }
+            Opcode::ADD
+            | Opcode::SUB
+            | Opcode::MUL
+            | Opcode::DIV
+            | Opcode::AND
+            | Opcode::OR
+            | Opcode::XOR
+            | Opcode::SHR
+            | Opcode::SHL
+            | Opcode::NEG
+            | Opcode::NOT
+            | Opcode::ABS
+            | Opcode::REM
+            | Opcode::MOD
+                if self.has_big_source(opcode) =>
+            {
+                let mut operands = [0; 3];
+                operands[..opcode.get_offset()].copy_from_slice(&self.code.raw[self.pc + 1..self.pc + 1 + opcode.get_offset()]);
+                if let Err(trap) = self.big_op(opcode, operands) {
+                    return Status::Trapped(trap);
+                }
+                self.pc += opcode.get_offset() + 1;
+            }
+            Opcode::ADD => {
+                if let Err(trap) = self.add(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2], self.code.raw[self.pc + 3]) {
+                    return Status::Trapped(trap);
+                }
+                self.pc += 4;
+            }
+            Opcode::SUB => {
+                if let Err(trap) = self.sub(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2], self.code.raw[self.pc + 3]) {
+                    return Status::Trapped(trap);
+                }
+                self.pc += 4;
+            }
+            Opcode::MUL => {
+                if let Err(trap) = self.mul(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2], self.code.raw[self.pc + 3]) {
+                    return Status::Trapped(trap);
+                }
+                self.pc += 4;
+            }
+            Opcode::DIV => {
+                if let Err(trap) = self.div(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2], self.code.raw[self.pc + 3]) {
+                    return Status::Trapped(trap);
+                }
+                self.pc += 4;
+            }
+            Opcode::AND => {
+                if let Err(trap) = self.and(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2], self.code.raw[self.pc + 3]) {
+                    return Status::Trapped(trap);
+                }
+                self.pc += 4;
+            }
+            Opcode::OR => {
+                if let Err(trap) = self.or(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2], self.code.raw[self.pc + 3]) {
+                    return Status::Trapped(trap);
                 }
-                1 => {
-                    self.move_reg(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2]);
-                    self.pc += 3;
+                self.pc += 4;
+            }
+            Opcode::XOR => {
+                if let Err(trap) = self.xor(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2], self.code.raw[self.pc + 3]) {
+                    return Status::Trapped(trap);
                 }
-                2 => {
-                    self.load(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2]);
-                    self.pc += 3;
+                self.pc += 4;
+            }
+            Opcode::SHR => {
+                if let Err(trap) = self.shr(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2], self.code.raw[self.pc + 3]) {
+                    return Status::Trapped(trap);
                 }
-                3 => {
-                    self.store(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2]);
-                    self.pc += 3;
+                self.pc += 4;
+            }
+            Opcode::SHL => {
+                if let Err(trap) = self.shl(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2], self.code.raw[self.pc + 3]) {
+                    return Status::Trapped(trap);
                 }
-                4 => {
-                    self.add(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2], self.code.raw[self.pc + 3]);
-                    self.pc += 4;
+                self.pc += 4;
+            }
+            Opcode::NEG | Opcode::NOT | Opcode::ABS | Opcode::REM | Opcode::MOD => {
+                let mut operands = [0; 3];
+                operands[..opcode.get_offset()].copy_from_slice(&self.code.raw[self.pc + 1..self.pc + 1 + opcode.get_offset()]);
+                if let Err(trap) = self.arithmetic_op(opcode, operands) {
+                    return Status::Trapped(trap);
                 }
-                5 => {
-                    self.sub(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2], self.code.raw[self.pc + 3]);
-                    self.pc += 4;
+                self.pc += opcode.get_offset() + 1;
+            }
+            Opcode::MATH => {
+                let operands = &self.code.raw[self.pc + 1..self.pc + 5];
+                let (a, b) = (self.registers.get(operands[2] as usize), self.registers.get(operands[3] as usize));
+                match math::apply(operands[1], a, b) {
+                    Ok(value) => self.registers.set(operands[0] as usize, value),
+                    Err(trap) => return Status::Trapped(trap),
                 }
-                6 => {
-                    self.mul(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2], self.code.raw[self.pc + 3]);
-                    self.pc += 4;
+                self.pc += 5;
+            }
+            Opcode::CONVERT => {
+                let operands = &self.code.raw[self.pc + 1..self.pc + 5];
+                if let Err(trap) = self.convert([operands[0], operands[1], operands[2], operands[3]]) {
+                    return Status::Trapped(trap);
                 }
-                7 => {
-                    self.div(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2], self.code.raw[self.pc + 3]);
-                    self.pc += 4;
+                self.pc += 5;
+            }
+            Opcode::ISDIGIT | Opcode::ISALPHA | Opcode::ISSPACE => {
+                if let Err(trap) = self.classify(opcode, [self.code.raw[self.pc + 1], self.code.raw[self.pc + 2]]) {
+                    return Status::Trapped(trap);
                 }
-                8 => {
-                    self.and(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2], self.code.raw[self.pc + 3]);
-                    self.pc += 4;
+                self.pc += 3;
+            }
+            Opcode::JMP => {
+                self.jmp(self.code.raw[self.pc + 1]);
+            }
+            Opcode::CALLHOST => {
+                let operands = &self.code.raw[self.pc + 1..self.pc + 5];
+                let (reg, host, first, count) = (operands[0], operands[1], operands[2], operands[3]);
+                if let Err(trap) = self.callhost(reg, host, first, count) {
+                    return Status::Trapped(trap);
                 }
-                9 => {
-                    self.or(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2], self.code.raw[self.pc + 3]);
-                    self.pc += 4;
+                self.pc += 5;
+            }
+            Opcode::JMPIF => {
+                if let Err(trap) = self.jmpif(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2]) {
+                    return Status::Trapped(trap);
                 }
-                10 => {
-                    self.xor(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2], self.code.raw[self.pc + 3]);
-                    self.pc += 4;
+            }
+            Opcode::READ => {
+                let operands = &self.code.raw[self.pc + 1..self.pc + 4];
+                let (reg, ok) = (operands[0], operands[1]);
+                let ty = ValueType::from_byte(operands[2]).expect("type checked by decode");
+                if let Err(trap) = self.read(reg, ok, ty) {
+                    return Status::Trapped(trap);
                 }
-                11 => {
-                    self.shr(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2], self.code.raw[self.pc + 3]);
-                    self.pc += 4;
+                self.pc += 4;
+            }
+            Opcode::MLOAD | Opcode::MSTORE | Opcode::GROW => {
+                let operands = &self.code.raw[self.pc + 1..self.pc + 1 + opcode.get_offset()];
+                let result = match opcode {
+                    Opcode::MLOAD => {
+                        let ty = ValueType::from_byte(operands[2]).expect("type checked by decode");
+                        self.mload(operands[0], operands[1], ty)
+                    }
+                    Opcode::MSTORE => self.mstore(operands[0], operands[1]),
+                    _ => self.grow(operands[0], operands[1]),
+                };
+                if let Err(trap) = result {
+                    return Status::Trapped(trap);
                 }
-                12 => {
-                    self.shl(self.code.raw[self.pc + 1], self.code.raw[self.pc + 2], self.code.raw[self.pc + 3]);
-                    self.pc += 4;
+                self.pc += opcode.get_offset() + 1;
+            }
+            Opcode::PUSH
+            | Opcode::POP
+            | Opcode::PEEK
+            | Opcode::DUP
+            | Opcode::SWAP
+            | Opcode::SLOAD
+            | Opcode::SSTORE => {
+                // DUP and SWAP have no operands and read as zeros
+                let mut operands = [0; 2];
+                operands[..opcode.get_offset()].copy_from_slice(&self.code.raw[self.pc + 1..self.pc + 1 + opcode.get_offset()]);
+                if let Err(trap) = self.stack_op(opcode, operands) {
+                    return Status::Trapped(trap);
                 }
-                13 => {
-                    self.jmp(self.code.raw[self.pc + 1]);
+                self.pc += opcode.get_offset() + 1;
+            }
+            Opcode::CONCAT
+            | Opcode::STRLEN
+            | Opcode::SUBSTR
+            | Opcode::STRCMP
+            | Opcode::STREQ
+            | Opcode::TOSTR
+            | Opcode::PARSE
+            | Opcode::CHARAT => {
+                let mut operands = [0; 4];
+                operands[..opcode.get_offset()].copy_from_slice(&self.code.raw[self.pc + 1..self.pc + 1 + opcode.get_offset()]);
+                if let Err(trap) = self.string_op(opcode, operands) {
+                    return Status::Trapped(trap);
                 }
-                14 => {
-                    break;
+                self.pc += opcode.get_offset() + 1;
+            }
+            Opcode::NEWARRAY
+            | Opcode::NEWRECORD
+            | Opcode::GETELEM
+            | Opcode::SETELEM
+            | Opcode::LEN
+            | Opcode::APPEND => {
+                let mut operands = [0; 3];
+                operands[..opcode.get_offset()].copy_from_slice(&self.code.raw[self.pc + 1..self.pc + 1 + opcode.get_offset()]);
+                if let Err(trap) = self.heap_op(opcode, operands) {
+                    return Status::Trapped(trap);
                 }
-                _ => {
-                    panic!("Invalid opcode {:?} at {}", instruction, self.pc);
+                self.pc += opcode.get_offset() + 1;
+            }
+            Opcode::CLOSURE | Opcode::CALL | Opcode::RET | Opcode::GETUPVAL | Opcode::SETUPVAL => {
+                let mut operands = [0; 4];
+                operands[..opcode.get_offset()].copy_from_slice(&self.code.raw[self.pc + 1..self.pc + 1 + opcode.get_offset()]);
+                match self.function_op(opcode, operands, self.pc + opcode.get_offset() + 1) {
+                    Ok(pc) => self.pc = pc,
+                    Err(trap) => return Status::Trapped(trap),
                 }
             }
+            Opcode::MEMSIZE => {
+                self.registers.set(self.code.raw[self.pc + 1] as usize, Value::U64(self.memory.size() as u64));
+                self.pc += 2;
+            }
+            Opcode::YIELD => {
+                let value = self.registers.get(self.code.raw[self.pc + 1] as usize);
+                self.pc += 2;
+                return Status::Yielded(value);
+            }
+            Opcode::HALT => {
+                return Status::Halted;
+            }
+            Opcode::CONST => unreachable!("CONST is rejected by decode"),
         }
+        Status::Running
     }
 
-}
+    // steps at least once and then until the program halts or pc reaches a breakpoint
+    pub fn run_until_breakpoint(&mut self) -> Status {
+        loop {
+            match self.step() {
+                Status::Running if self.breakpoints.contains(&self.pc) => return Status::Breakpoint,
+                Status::Running => {}
+                status => return status,
+            }
+        }
+    }
 
-// tests for machine
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::opcode::Opcode;
+    pub fn run(&mut self, code: Code) -> Status {
+        match self.load(code) {
+            Ok(()) => self.resume(),
+            Err(e) => Status::Trapped(Trap::InvalidCode(e)),
+        }
+    }
 
-    #[test]
-    fn test_load() {
-        let mut machine = Machine::new();
-        let mut code = Code::new();
-        code.add_const(Value::from(1i8));
-        code.write_code(Opcode::LOAD as u8, 0);
-        code.write_code(0, 0);
-        code.write_code(0, 0);   
-        code.write_code(Opcode::HALT as u8, 1); 
-        machine.run(code);
-        assert_eq!(machine.registers[0], Value::I8(1));
+    // executes at most steps instructions, returning Paused if none of them stopped the machine
+    pub fn run_for(&mut self, steps: usize) -> Status {
+        for _ in 0..steps {
+            match self.step() {
+                Status::Running => {}
+                status => return status,
+            }
+        }
+        Status::Paused
     }
 
-    #[test]
-    fn test_store() {
-        let mut machine = Machine::new();
-        let mut code = Code::new();
-        code.add_const(Value::from(1i8));
-        code.write_code(Opcode::LOAD as u8, 0);
-        code.write_code(0, 0);
-        code.write_code(0, 0);   
-        code.write_code(Opcode::STORE as u8, 1); 
-        code.write_code(0, 1);
-        code.write_code(0, 1);   
-        code.write_code(Opcode::HALT as u8, 2); 
-        machine.run(code);
-        assert_eq!(machine.code.const_pool[0], Value::I8(1));
+    // continues from pc until the program halts, yields, traps or runs out of fuel
+    pub fn resume(&mut self) -> Status {
+        let status = loop {
+            match self.step() {
+                Status::Running => {}
+                status => break status,
+            }
+        };
+        match self.tracer.as_mut().map(|tracer| tracer.flush()) {
+            Some(Err(e)) => Status::Trapped(Trap::Trace(e.to_string())),
+            _ => status,
+        }
     }
 
-    #[test]
-    fn test_move() {
-        let mut machine = Machine::new();
+    // limits execution to a budget spent according to the cost table, None removes the limit
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    pub fn add_fuel(&mut self, fuel: u64) {
+        self.fuel = Some(self.fuel.unwrap_or(0).saturating_add(fuel));
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn set_costs(&mut self, costs: CostTable) {
+        self.costs = costs;
+    }
+
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn set_profiler(&mut self, profiler: Profiler) {
+        self.profiler = Some(profiler);
+    }
+
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    // resume flushes the tracer before returning, after single steps the caller flushes it
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    // recording makes every step undoable, at the cost of one entry per executed instruction
+    pub fn set_history(&mut self, history: History) {
+        self.history = Some(history);
+    }
+
+    pub fn take_history(&mut self) -> Option<History> {
+        self.history.take()
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    // where READ takes its lines from instead of stdin
+    pub fn set_input(&mut self, input: impl BufRead + 'static) {
+        self.input = Some(Box::new(input));
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    pub fn stack(&self) -> &Stack {
+        &self.stack
+    }
+
+    pub fn stack_mut(&mut self) -> &mut Stack {
+        &mut self.stack
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+
+    // innermost call last
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn call_limit(&self) -> usize {
+        self.call_limit
+    }
+
+    // lowering the limit below the current depth only stops further calls
+    pub fn set_call_limit(&mut self, limit: usize) {
+        self.call_limit = limit;
+    }
+
+    // frees the heap objects nothing can reach, returns how many were freed.
+    // registers, the stack, globals, call frames and the undo history are the roots
+    pub fn collect(&mut self) -> usize {
+        let history = self.history.iter().flat_map(|history| history.values());
+        let frames = self.frames.iter().flat_map(|frame| frame.saved.iter().copied().chain([Value::Ref(frame.closure)]));
+        let registers = self.registers.read(0..REGISTER_MAX);
+        let roots = registers.iter().chain(self.stack.values()).chain(&self.globals).copied().chain(frames).chain(history);
+        self.heap.collect(roots)
+    }
+
+    pub fn set_output(&mut self, output: Output) {
+        self.output = output;
+    }
+
+    pub fn register_host(&mut self, name: &str, function: impl FnMut(&[Value], &Heap) -> Result<Value, String> + 'static) -> u8 {
+        self.hosts.register(name, function)
+    }
+
+    pub fn hosts(&self) -> &Hosts {
+        &self.hosts
+    }
+
+    pub fn add_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(pc);
+    }
+
+    // sets a breakpoint on the first instruction of a source line
+    pub fn add_line_breakpoint(&mut self, line: usize) -> Option<usize> {
+        let pc = self.code.line_offset(line)?;
+        self.breakpoints.insert(pc);
+        Some(pc)
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn code(&self) -> &Code {
+        &self.code
+    }
+
+    pub fn register(&self, reg: u8) -> Value {
+        self.registers.get(reg as usize)
+    }
+
+    pub fn set_register(&mut self, reg: u8, value: Value) {
+        self.registers.set(reg as usize, value);
+    }
+
+    pub fn global(&self, global: usize) -> Option<Value> {
+        self.globals.get(global).copied()
+    }
+
+    pub fn set_global(&mut self, global: usize, value: Value) -> bool {
+        match self.globals.get_mut(global) {
+            Some(slot) => {
+                *slot = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn globals(&self) -> &[Value] {
+        &self.globals
+    }
+
+    pub fn constant(&self, constant: usize) -> Option<Value> {
+        self.code.const_pool.get(constant).copied()
+    }
+
+    // registers, pc, memory, globals, the stack, the heap, call frames and the code
+    pub fn snapshot(&self) -> Vec<u8> {
+        snapshot::write(&StateRef {
+            registers: &self.registers.read(0..REGISTER_MAX),
+            pc: self.pc,
+            code: &self.code,
+            memory: self.memory.bytes(),
+            globals: &self.globals,
+            stack: self.stack.values(),
+            heap: self.heap.slots(),
+            frames: &self.frames,
+        })
+    }
+
+    // replaces the program state, breakpoints, fuel and attached tools are kept
+    pub fn restore(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let state = snapshot::read(bytes)?;
+        state.code.verify()?;
+        self.registers.write(0, &state.registers);
+        self.pc = state.pc;
+        self.halted = false;
+        self.code = state.code;
+        self.globals = state.globals;
+        *self.stack.values_mut() = state.stack;
+        self.heap = Heap::from_slots(state.heap, self.heap.limit());
+        self.frames = state.frames;
+        self.memory.resize(state.memory.len());
+        self.memory.bytes_mut().copy_from_slice(&state.memory);
+        if let Some(history) = self.history.as_mut() {
+            history.clear();
+        }
+        Ok(())
+    }
+}
+
+// tests for machine
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{reader::{assemble, assemble_with_hosts}, trace::TraceFormat};
+
+    #[test]
+    fn test_load() {
+        let mut machine = Machine::new();
+        let mut code = Code::new();
+        code.add_const(Value::from(1i8));
+        code.write_code(Opcode::LOAD as u8, 0);
+        code.write_code(0, 0);
+        code.write_code(0, 0);   
+        code.write_code(Opcode::HALT as u8, 1); 
+        machine.run(code);
+        assert_eq!(machine.register(0), Value::I8(1));
+    }
+
+    #[test]
+    fn test_globals() {
+        let mut machine = Machine::new();
+        let mut code = Code::new();
+        code.add_const(Value::from(1i8));
+        code.add_global(Value::from(0i8));
+        code.write_code(Opcode::LOAD as u8, 0);
+        code.write_code(0, 0);
+        code.write_code(0, 0);
+        code.write_code(Opcode::SETGLOBAL as u8, 1);
+        code.write_code(0, 1);
+        code.write_code(0, 1);
+        code.write_code(Opcode::GETGLOBAL as u8, 2);
+        code.write_code(1, 2);
+        code.write_code(0, 2);
+        code.write_code(Opcode::HALT as u8, 3);
+        machine.run(code);
+        assert_eq!(machine.global(0), Some(Value::I8(1)));
+        assert_eq!(machine.register(1), Value::I8(1));
+        // the code keeps the initial value, so running it again starts over
+        assert_eq!(machine.code.globals[0], Value::I8(0));
+        assert_eq!(machine.code.const_pool[0], Value::I8(1));
+    }
+
+    #[test]
+    fn test_move() {
+        let mut machine = Machine::new();
         let mut code = Code::new();
         code.add_const(Value::from(10i8));
         code.write_code(Opcode::LOAD as u8, 0);
@@ -194,7 +1517,7 @@ mod tests {
         code.write_code(0, 1);   
         code.write_code(Opcode::HALT as u8, 2); 
         machine.run(code);
-        assert_eq!(machine.registers[1], Value::I8(10));
+        assert_eq!(machine.register(1), Value::I8(10));
     }
 
     #[test]
@@ -215,6 +1538,858 @@ mod tests {
         code.write_code(1, 2);
         code.write_code(Opcode::HALT as u8, 3);
         machine.run(code);
-        assert_eq!(machine.registers[2], Value::I8(30));
+        assert_eq!(machine.register(2), Value::I8(30));
+    }
+
+    #[test]
+    fn test_step() {
+        let mut machine = Machine::new();
+        let mut code = Code::new();
+        code.add_const(Value::from(10i8));
+        code.write_code(Opcode::LOAD as u8, 0);
+        code.write_code(0, 0);
+        code.write_code(0, 0);
+        code.write_code(Opcode::HALT as u8, 1);
+        machine.load(code).unwrap();
+        assert_eq!(machine.step(), Status::Running);
+        assert_eq!(machine.pc(), 3);
+        assert_eq!(machine.register(0), Value::I8(10));
+        assert_eq!(machine.step(), Status::Halted);
+        assert_eq!(machine.step(), Status::Halted);
+        assert_eq!(machine.pc(), 3);
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        let mut machine = Machine::new();
+        let mut code = Code::new();
+        code.add_const(Value::from(10i8));
+        code.write_code(Opcode::LOAD as u8, 0);
+        code.write_code(0, 0);
+        code.write_code(0, 0);
+        code.write_code(Opcode::MOVE as u8, 1);
+        code.write_code(1, 1);
+        code.write_code(0, 1);
+        code.write_code(Opcode::HALT as u8, 2);
+        machine.load(code).unwrap();
+        assert_eq!(machine.add_line_breakpoint(1), Some(3));
+        assert_eq!(machine.run_until_breakpoint(), Status::Breakpoint);
+        assert_eq!(machine.pc(), 3);
+        assert_eq!(machine.register(1), Value::U8(0));
+        assert_eq!(machine.run_until_breakpoint(), Status::Halted);
+        assert_eq!(machine.register(1), Value::I8(10));
+    }
+
+    // a sink the test can read back after handing ownership to the tracer
+    #[derive(Clone, Default)]
+    struct Buffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl std::io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    // the register as PRINT shows it, strings built at run time are on the heap
+    fn text(machine: &Machine, reg: u8) -> String {
+        output::display(&machine.register(reg), machine.heap())
+    }
+
+    fn add_program() -> Code {
+        let mut code = Code::new();
+        code.add_const(Value::from(10i8));
+        code.add_const(Value::from(20i8));
+        for (byte, line) in [
+            (Opcode::LOAD as u8, 1), (0, 1), (0, 1),
+            (Opcode::LOAD as u8, 2), (1, 2), (1, 2),
+            (Opcode::ADD as u8, 3), (2, 3), (0, 3), (1, 3),
+            (Opcode::HALT as u8, 4),
+        ] {
+            code.write_code(byte, line);
+        }
+        code
+    }
+
+    #[test]
+    fn test_trace_text() {
+        let buffer = Buffer::default();
+        let mut machine = Machine::new();
+        machine.set_tracer(Tracer::new(buffer.clone(), TraceFormat::Text));
+        machine.run(add_program());
+        assert_eq!(
+            buffer.contents(),
+            "0 1 LOAD $0 10  $0: 0 -> 10\n3 2 LOAD $1 20  $1: 0 -> 20\n6 3 ADD $2 $0 $1  $2: 0 -> 30\n10 4 HALT\n"
+        );
+    }
+
+    #[test]
+    fn test_trace_json_range() {
+        let buffer = Buffer::default();
+        let mut machine = Machine::new();
+        machine.set_tracer(Tracer::new(buffer.clone(), TraceFormat::JsonLines).with_range(3..7));
+        machine.run(add_program());
+        let lines: Vec<String> = buffer.contents().lines().map(String::from).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[1],
+            r#"{"pc":6,"line":3,"instruction":"ADD $2 $0 $1","register":2,"before":{"type":"u8","value":"0"},"after":{"type":"i8","value":"30"}}"#
+        );
+        assert!(machine.take_tracer().is_some());
+    }
+
+    struct Broken;
+
+    impl std::io::Write for Broken {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace_write_error_traps() {
+        let mut machine = Machine::new();
+        machine.set_tracer(Tracer::new(Broken, TraceFormat::Text));
+        assert_eq!(machine.run(add_program()), Status::Trapped(Trap::Trace("disk full".to_string())));
+        assert_eq!(machine.pc(), 3);
+    }
+
+    fn loop_program() -> Code {
+        let mut code = Code::new();
+        code.add_const(Value::from(1i8));
+        code.add_const(Value::from(0i8));
+        for byte in [
+            Opcode::LOAD as u8, 0, 0,
+            Opcode::LOAD as u8, 1, 1,
+            Opcode::ADD as u8, 1, 1, 0,
+            Opcode::JMP as u8, 6,
+        ] {
+            code.write_code(byte, 0);
+        }
+        code
+    }
+
+    #[test]
+    fn test_out_of_fuel() {
+        let mut machine = Machine::new();
+        machine.set_fuel(Some(22));
+        assert_eq!(machine.run(loop_program()), Status::OutOfFuel);
+        assert_eq!(machine.fuel(), Some(0));
+        assert_eq!(machine.pc(), 6);
+        assert_eq!(machine.register(1), Value::I8(10));
+        machine.add_fuel(1);
+        assert_eq!(machine.resume(), Status::OutOfFuel);
+        assert_eq!(machine.register(1), Value::I8(11));
+        assert_eq!(machine.pc(), 10);
+    }
+
+    #[test]
+    fn test_halted_machine_spends_no_fuel() {
+        let mut machine = Machine::new();
+        machine.set_fuel(Some(4));
+        assert_eq!(machine.run(add_program()), Status::Halted);
+        assert_eq!(machine.fuel(), Some(0));
+        assert_eq!(machine.resume(), Status::Halted);
+        assert_eq!(machine.step(), Status::Halted);
+        assert_eq!(machine.fuel(), Some(0));
+    }
+
+    #[test]
+    fn test_weighted_fuel() {
+        let mut machine = Machine::new();
+        machine.set_costs(CostTable::uniform(1).with_cost(Opcode::ADD, 5).with_cost(Opcode::JMP, 0));
+        machine.set_fuel(Some(13));
+        assert_eq!(machine.run(loop_program()), Status::OutOfFuel);
+        // each LOAD costs 1 and each ADD 5, leaving 1 which is not enough for a third ADD
+        assert_eq!(machine.register(1), Value::I8(2));
+        assert_eq!(machine.fuel(), Some(1));
+    }
+
+    fn yield_program() -> Code {
+        let mut code = Code::new();
+        code.add_const(Value::from(1i8));
+        code.add_const(Value::from(0i8));
+        for byte in [
+            Opcode::LOAD as u8, 0, 0,
+            Opcode::LOAD as u8, 1, 1,
+            Opcode::ADD as u8, 1, 1, 0,
+            Opcode::YIELD as u8, 1,
+            Opcode::JMP as u8, 6,
+        ] {
+            code.write_code(byte, 0);
+        }
+        code
+    }
+
+    #[test]
+    fn test_yield_and_resume() {
+        let mut machine = Machine::new();
+        assert_eq!(machine.run(yield_program()), Status::Yielded(Value::I8(1)));
+        assert_eq!(machine.pc(), 12);
+        assert_eq!(machine.resume(), Status::Yielded(Value::I8(2)));
+        assert_eq!(machine.run_for(2), Status::Paused);
+        assert_eq!(machine.pc(), 10);
+        assert_eq!(machine.run_for(2), Status::Yielded(Value::I8(3)));
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut machines = [Machine::new(), Machine::new()];
+        for machine in machines.iter_mut() {
+            machine.load(yield_program()).unwrap();
+        }
+        // the second machine gets a smaller slice and falls behind
+        let mut yielded = vec![];
+        for _ in 0..6 {
+            for (i, machine) in machines.iter_mut().enumerate() {
+                if let Status::Yielded(value) = machine.run_for(if i == 0 { 10 } else { 2 }) {
+                    yielded.push((i, value));
+                }
+            }
+        }
+        let first = yielded.iter().filter(|y| y.0 == 0).count();
+        let second = yielded.iter().filter(|y| y.0 == 1).count();
+        assert_eq!((first, second), (6, 3));
+        assert_eq!(machines[1].register(1), Value::I8(3));
+    }
+
+    #[test]
+    fn test_trap() {
+        let mut machine = Machine::new();
+        let mut code = Code::new();
+        code.write_code(Opcode::CONST as u8, 0);
+        code.write_code(0, 0);
+        let invalid = format!("Invalid opcode {} at 0", Opcode::CONST as u8);
+        assert_eq!(machine.run(code), Status::Trapped(Trap::InvalidCode(invalid)));
+        assert_eq!(machine.pc(), 0);
+
+        let mut code = Code::new();
+        code.write_code(Opcode::JMP as u8, 0);
+        code.write_code(9, 0);
+        let invalid = "Jump to 9 at 0 does not land on an instruction".to_string();
+        assert_eq!(machine.run(code), Status::Trapped(Trap::InvalidCode(invalid)));
+
+        let mut code = Code::new();
+        code.write_code(Opcode::PRINT as u8, 0);
+        assert_eq!(machine.load(code), Err("Truncated PRINT at 0".to_string()));
+        assert_eq!(machine.resume(), Status::Trapped(Trap::PcOutOfBounds(0)));
+
+        // registers past the last one, constants and globals that do not exist
+        for src in ["MOVE $255 $0\nHALT\n", "LOAD $0 0\nHALT\n"] {
+            assert!(matches!(machine.run(assemble(src)), Status::Trapped(Trap::InvalidCode(_))));
+        }
+        let mut code = assemble(".global 1\nGETGLOBAL $0 0\nHALT\n");
+        code.raw[2] = 1;
+        assert_eq!(machine.load(code), Err("Global 1 out of range at 0".to_string()));
+        // a string on the heap would dangle once the code outlives it
+        let mut code = assemble("HALT\n");
+        code.add_const(Value::Str(Str::on_heap(Ref::new(0))));
+        assert_eq!(machine.load(code), Err("Constant 0 is not interned".to_string()));
+    }
+
+    #[test]
+    fn test_load_clears_stack_and_memory() {
+        let mut machine = Machine::new();
+        let src = "CONST 8\nLOAD $0 0\nGROW $1 $0\nPUSH $0\nHALT\n";
+        assert_eq!(machine.run(assemble(src)), Status::Halted);
+        assert_eq!((machine.memory().size(), machine.stack().len()), (8, 1));
+        machine.load(assemble("HALT\n")).unwrap();
+        assert_eq!((machine.memory().size(), machine.stack().len()), (0, 0));
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut machine = Machine::new();
+        let mut code = yield_program();
+        // keep the running total in a global so the snapshot has to carry it
+        code.add_global(Value::I8(0));
+        code.raw[3] = Opcode::GETGLOBAL as u8;
+        code.raw[5] = 0;
+        code.raw.splice(12..12, [Opcode::SETGLOBAL as u8, 1, 0]);
+        code.lines.splice(12..12, [0, 0, 0]);
+        code.raw[16] = 3;
+        machine.load(code).unwrap();
+        assert_eq!(machine.resume(), Status::Yielded(Value::I8(1)));
+        assert_eq!(machine.resume(), Status::Yielded(Value::I8(2)));
+        assert_eq!(machine.run_for(2), Status::Paused);
+        let snapshot = machine.snapshot();
+
+        let mut restored = Machine::new();
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.pc(), machine.pc());
+        assert_eq!(restored.global(0), Some(Value::I8(2)));
+        for _ in 0..3 {
+            assert_eq!(restored.resume(), machine.resume());
+        }
+        assert_eq!(restored.snapshot(), machine.snapshot());
+        assert!(restored.restore(&snapshot[..10]).is_err());
+    }
+
+    #[test]
+    fn test_callhost() {
+        let mut machine = Machine::new();
+        let calls = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let log = calls.clone();
+        machine.register_host("log", move |args, _| {
+            log.borrow_mut().push(args.to_vec());
+            Ok(args.iter().fold(Value::I8(0), |a, &b| (a + b).unwrap()))
+        });
+        machine.register_host("fail", |_, _| Err("disk full".to_string()));
+        let src = "CONST 10\nCONST 20\nLOAD $1 0\nLOAD $2 1\nCALLHOST $0, \"log\", $1, $2\nCALLHOST $3 \"fail\"\nHALT\n";
+        let code = assemble_with_hosts(src, machine.hosts());
+        assert_eq!(machine.run(code), Status::Trapped(Trap::Host("disk full".to_string())));
+        assert_eq!(machine.pc(), 11);
+        assert_eq!(machine.register(0), Value::I8(30));
+        assert_eq!(*calls.borrow(), vec![vec![Value::I8(10), Value::I8(20)]]);
+
+        let code = assemble("CALLHOST $0 7\nHALT\n");
+        assert_eq!(machine.run(code), Status::Trapped(Trap::UnknownHost(7)));
+
+        // a string built at run time is read through the heap
+        machine.register_host("len", |args, heap| match args {
+            [Value::Str(s)] => Ok(Value::U64(heap.str(*s).map_or(0, str::len) as u64)),
+            _ => Err("len takes a string".to_string()),
+        });
+        let src = "CONST \"ab\"\nLOAD $1 0\nCONCAT $1 $1 $1\nCALLHOST $0 \"len\" $1 1\nHALT\n";
+        assert_eq!(machine.run(assemble_with_hosts(src, machine.hosts())), Status::Halted);
+        assert_eq!(machine.register(0), Value::U64(4));
+    }
+
+    #[test]
+    fn test_output() {
+        let buffer = Buffer::default();
+        let mut machine = Machine::with_output(Output::new(buffer.clone()).with_separator("\n"));
+        let code = assemble("CONST 10.5\nCONST 2u64\nLOAD $0 0\nLOAD $1 1\nPRINT $0\nTPRINT $0\nTPRINT $1\nHALT\n");
+        assert_eq!(machine.run(code), Status::Halted);
+        assert_eq!(buffer.contents(), "10.5\n10.5f32\n2u64\n");
+    }
+
+    // sums i32 lines until the end of input
+    const SUM_SRC: &str = "CONST 0i32\nLOAD $0 0\nREAD $1 $2 i32\nJMPIF $2 13\nPRINT $0\nHALT\nADD $0 $0 $1\nJMP 3\n";
+
+    #[test]
+    fn test_read() {
+        let buffer = Buffer::default();
+        let mut machine = Machine::with_output(Output::new(buffer.clone()));
+        machine.set_input("5\n 7 \r\n-2".as_bytes());
+        assert_eq!(machine.run(assemble(SUM_SRC)), Status::Halted);
+        assert_eq!(buffer.contents(), "10");
+        assert_eq!(machine.register(2), Value::Bool(false));
+
+        machine.set_input("5\nabc\n".as_bytes());
+        assert_eq!(machine.run(assemble(SUM_SRC)), Status::Trapped(Trap::Input("Cannot parse abc into i32".to_string())));
+        assert_eq!(machine.pc(), 3);
+    }
+
+    #[test]
+    fn test_step_back_over_read() {
+        let mut machine = Machine::new();
+        machine.set_input("4\n".as_bytes());
+        machine.load(assemble(SUM_SRC)).unwrap();
+        machine.set_history(History::new());
+        assert_eq!(machine.run_for(2), Status::Paused);
+        assert_eq!((machine.register(1), machine.register(2)), (Value::I32(4), Value::Bool(true)));
+        assert!(machine.step_back());
+        assert_eq!((machine.register(1), machine.register(2)), (Value::U8(0), Value::U8(0)));
+    }
+
+    #[test]
+    fn test_memory() {
+        let buffer = Buffer::default();
+        let mut machine = Machine::with_output(Output::new(buffer.clone()).with_separator(" "));
+        machine.memory_mut().set_limit(16);
+        let src = "CONST 12\nCONST -2i16\nCONST 10\nCONST 4\n\
+            LOAD $0 0\nGROW $1 $0\nLOAD $2 1\nLOAD $3 2\nMSTORE $3 $2\nMLOAD $4 $3 u16\nPRINT $4\n\
+            MEMSIZE $5\nPRINT $5\nGROW $6 $0\nPRINT $6\nLOAD $3 3\nMLOAD $4 $3 u16\nPRINT $4\nMLOAD $4 $5 u8\nHALT\n";
+        machine.set_history(History::new());
+        assert_eq!(machine.run(assemble(src)), Status::Trapped(Trap::MemoryOutOfBounds(12, 1)));
+        assert_eq!(buffer.contents(), "65534 12 18446744073709551615 0 ");
+        assert_eq!(&machine.memory().bytes()[10..12], &[0xFE, 0xFF]);
+
+        let mut restored = Machine::new();
+        restored.restore(&machine.snapshot()).unwrap();
+        assert_eq!(restored.memory().bytes(), machine.memory().bytes());
+
+        // back past the MSTORE, then the first GROW
+        while machine.pc() > 12 {
+            assert!(machine.step_back());
+        }
+        assert_eq!(machine.memory().bytes(), &[0; 12]);
+        while machine.pc() > 3 {
+            assert!(machine.step_back());
+        }
+        assert_eq!(machine.memory().size(), 0);
+
+        // verify turns away every type without a fixed width, as decode does
+        for ty in [ValueType::Str, ValueType::Ref, ValueType::Big, ValueType::Bytes] {
+            let mut code = assemble("MLOAD $0 $1 u8\nHALT\n");
+            code.raw[3] = ty as u8;
+            let message = format!("Cannot load type {} from memory at 0", ty);
+            assert_eq!(code.verify(), Err(message.clone()));
+            assert_eq!(Machine::new().run(code), Status::Trapped(Trap::InvalidCode(message)));
+        }
+    }
+
+    #[test]
+    fn test_stack() {
+        let mut machine = Machine::new();
+        machine.stack_mut().set_limit(3);
+        machine.set_history(History::new());
+        let src = "CONST 1\nCONST 2\nCONST 3\nLOAD $0 0\nLOAD $1 1\nLOAD $2 2\nPUSH $0\nPUSH $1\nPUSH $2\n\
+            SWAP\nSLOAD $3 2\nSSTORE $0 0\nDUP\nPOP $4\nPOP $5\nPEEK $6\nHALT\n";
+        assert_eq!(machine.run(assemble(src)), Status::Trapped(Trap::StackOverflow(3)));
+        assert_eq!(machine.pc(), 22);
+        assert_eq!(machine.stack().values(), &[Value::I8(1), Value::I8(3), Value::I8(1)]);
+        assert_eq!(machine.register(3), Value::I8(1));
+
+        let mut restored = Machine::new();
+        restored.restore(&machine.snapshot()).unwrap();
+        assert_eq!(restored.stack().values(), machine.stack().values());
+
+        machine.stack_mut().set_limit(4);
+        assert_eq!(machine.resume(), Status::Halted);
+        assert_eq!((machine.register(4), machine.register(5), machine.register(6)), (Value::I8(1), Value::I8(1), Value::I8(3)));
+        assert_eq!(machine.stack().values(), &[Value::I8(1), Value::I8(3)]);
+        while machine.step_back() {}
+        assert_eq!(machine.pc(), 0);
+        assert!(machine.stack().is_empty());
+
+        assert_eq!(machine.run(assemble("SLOAD $0 1\nHALT\n")), Status::Trapped(Trap::StackUnderflow(2)));
+    }
+
+    #[test]
+    fn test_strings() {
+        let buffer = Buffer::default();
+        let mut machine = Machine::with_output(Output::new(buffer.clone()).with_separator(" "));
+        let src = "CONST \"x = \"\nCONST 42\nCONST \"h\u{e9}llo\"\nCONST 1\nCONST 3\nCONST \" 7 \"\nCONST \"abc\"\n\
+            LOAD $0 0\nLOAD $1 1\nTOSTR $1 $1\nCONCAT $2 $0 $1\nPRINT $2\n\
+            LOAD $3 2\nSTRLEN $4 $3\nLOAD $5 3\nLOAD $6 4\nSUBSTR $7 $3 $5 $6\nPRINT $7\n\
+            STRCMP $8 $3 $7\nSTREQ $9 $7 $7\nLOAD $10 5\nPARSE $11 $12 $10 i32\nLOAD $13 6\nPARSE $11 $12 $13 i32\n\
+            SUBSTR $7 $3 $6 $6\nHALT\n";
+        assert_eq!(machine.run(assemble(src)), Status::Trapped(Trap::IndexOutOfBounds(6, 5)));
+        assert_eq!(machine.pc(), 58);
+        assert_eq!(buffer.contents(), "x = 42 éll ");
+        assert_eq!(machine.register(4), Value::U64(5));
+        assert_eq!((machine.register(8), machine.register(9)), (Value::I8(-1), Value::Bool(true)));
+        assert_eq!((machine.register(11), machine.register(12)), (Value::I32(7), Value::Bool(false)));
+
+        // strings survive a snapshot even when the text was built at run time
+        let mut restored = Machine::new();
+        restored.restore(&machine.snapshot()).unwrap();
+        assert_eq!(text(&restored, 2), "x = 42");
+    }
+
+    #[test]
+    fn test_strings_on_the_heap() {
+        // each string takes two values, the collector frees the ones no register holds any more
+        let src = "CONST \"ab\"\nLOAD $0 0\nCONCAT $1 $0 $0\nCONCAT $1 $0 $0\nCONCAT $1 $0 $0\nCONCAT $2 $1 $0\nHALT\n";
+        let mut machine = Machine::new();
+        machine.heap_mut().set_limit(4);
+        assert_eq!(machine.run(assemble(src)), Status::Halted);
+        assert_eq!((text(&machine, 1), text(&machine, 2)), ("abab".to_string(), "ababab".to_string()));
+        assert_eq!((machine.heap().len(), machine.heap().used()), (2, 4));
+        assert_eq!(machine.register(0), Value::from("ab"));
+        machine.set_register(2, Value::U8(0));
+        assert_eq!(machine.collect(), 1);
+
+        let src = "CONST \"ab\"\nLOAD $0 0\nCONCAT $1 $0 $0\nTOSTR $2 $1\nCONCAT $3 $0 $0\nHALT\n";
+        let mut machine = Machine::new();
+        machine.heap_mut().set_limit(4);
+        assert_eq!(machine.run(assemble(src)), Status::Trapped(Trap::HeapExhausted(4)));
+        assert_eq!(text(&machine, 2), "abab");
+    }
+
+    #[test]
+    fn test_concat_type_mismatch() {
+        let status = Machine::new().run(assemble("CONST \"a\"\nCONST 1\nLOAD $0 0\nLOAD $1 1\nCONCAT $2 $0 $1\nHALT\n"));
+        assert_eq!(status, Status::Trapped(Trap::Type("Cannot concat type i8".to_string())));
+    }
+
+    const HEAP_SRC: &str = "CONST 2\nCONST 7\nCONST 1\n\
+        LOAD $0 0\nNEWARRAY $1 $0\nLOAD $2 1\nLOAD $3 2\nSETELEM $1 $3 $2\nAPPEND $1 $2\n\
+        NEWRECORD $4 2\nSETELEM $4 $3 $1\nGETELEM $5 $1 $3\nLEN $6 $1\nMOVE $4 $0\nNEWRECORD $4 2\nGETELEM $7 $1 $6\nHALT\n";
+
+    #[test]
+    fn test_heap() {
+        let mut machine = Machine::new();
+        // the second record only fits once the first, no longer in $4, is collected
+        machine.heap_mut().set_limit(8);
+        assert_eq!(machine.run(assemble(HEAP_SRC)), Status::Trapped(Trap::IndexOutOfBounds(3, 3)));
+        assert_eq!(machine.pc(), 39);
+        assert_eq!((machine.register(5), machine.register(6)), (Value::I8(7), Value::U64(3)));
+        assert_eq!(machine.heap().len(), 2);
+        let array = machine.register(1).as_object().unwrap();
+        assert_eq!(machine.heap().get(array), Some(&Object::Array(vec![Value::U8(0), Value::I8(7), Value::I8(7)])));
+
+        let mut restored = Machine::new();
+        restored.restore(&machine.snapshot()).unwrap();
+        assert_eq!(restored.heap().slots(), machine.heap().slots());
+
+        machine.set_register(1, Value::U8(0));
+        assert_eq!(machine.collect(), 1);
+        assert_eq!(machine.run(assemble("CONST 8\nLOAD $0 0\nNEWARRAY $1 $0\nHALT\n")), Status::Trapped(Trap::HeapExhausted(8)));
+    }
+
+    #[test]
+    fn test_step_back_over_heap() {
+        let mut machine = Machine::new();
+        machine.set_history(History::new());
+        machine.run(assemble(HEAP_SRC));
+        // objects an undo can bring back stay alive
+        assert_eq!(machine.collect(), 0);
+        while machine.pc() > 12 {
+            assert!(machine.step_back());
+        }
+        let array = machine.register(1).as_object().unwrap();
+        assert_eq!(machine.heap().get(array).unwrap().values(), &[Value::U8(0), Value::U8(0)]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_append_to_record() {
+        let status = Machine::new().run(assemble("NEWRECORD $0 1\nAPPEND $0 $1\nHALT\n"));
+        assert_eq!(status, Status::Trapped(Trap::Type("Cannot append to a record".to_string())));
+    }
+
+    // add keeps a running total in its upvalue, starting from the 10 it captured
+    const CLOSURE_SRC: &str = "CONST 10\nCONST 5\n\
+        LOAD $0 0\nCLOSURE $1 add $0\nLOAD $2 1\nCALL $3 $1 $2\nCALL $4 $1 $3\nHALT\n\
+        .function add 1 2\nGETUPVAL $1 0\nADD $0 $0 $1\nSETUPVAL $0 0\nRET $0\n";
+
+    #[test]
+    fn test_closures() {
+        let mut machine = Machine::new();
+        assert_eq!(machine.run(assemble(CLOSURE_SRC)), Status::Halted);
+        assert_eq!((machine.register(3), machine.register(4)), (Value::I8(15), Value::I8(30)));
+        // the callee's window is given back to the caller
+        assert_eq!(machine.register(0), Value::I8(10));
+        let closure = machine.register(1).as_object().unwrap();
+        assert_eq!(machine.heap().get(closure), Some(&Object::Closure(0, vec![Value::I8(30)])));
+        assert!(machine.frames().is_empty());
+
+        assert_eq!(machine.run(assemble("CONST 1\nLOAD $0 0\nCLOSURE $1 f\nCALL $2 $1\nHALT\n.function f 1 1\nRET $0\n")), Status::Trapped(Trap::Arity(1, 0)));
+        assert_eq!(machine.run(assemble("RET $0\n")), Status::Trapped(Trap::NoFrame));
+    }
+
+    #[test]
+    fn test_call_limit() {
+        let mut machine = Machine::new();
+        machine.set_call_limit(8);
+        let src = ".global 0u8\nCLOSURE $0 recurse\nSETGLOBAL $0 0\nCALL $1 $0\nHALT\n\
+            .function recurse 0 1\nGETGLOBAL $0 0\nCALL $0 $0\nRET $0\n";
+        assert_eq!(machine.run(assemble(src)), Status::Trapped(Trap::CallStackOverflow(8)));
+        assert_eq!(machine.frames().len(), 8);
+        assert_eq!(machine.frames()[1].return_pc, 22);
+
+        // the frames keep the closure alive even with every register cleared
+        machine.set_register(0, Value::U8(0));
+        machine.set_global(0, Value::U8(0));
+        assert_eq!(machine.collect(), 0);
+        let mut restored = Machine::new();
+        restored.restore(&machine.snapshot()).unwrap();
+        assert_eq!(restored.frames(), machine.frames());
+    }
+
+    #[test]
+    fn test_step_back_over_calls() {
+        let mut machine = Machine::new();
+        machine.set_history(History::new());
+        machine.run(assemble(CLOSURE_SRC));
+        // inside the second call, just before it stores its total
+        while machine.pc() != 29 || machine.frames().is_empty() {
+            assert!(machine.step_back());
+        }
+        assert_eq!(machine.register(0), Value::I8(30));
+        assert_eq!(machine.register(3), Value::I8(15));
+        let closure = machine.frames()[0].closure;
+        assert_eq!(machine.heap().get(closure).unwrap().values(), &[Value::I8(15)]);
+        assert!(machine.step_back() && machine.step_back() && machine.step_back());
+        assert_eq!((machine.pc(), machine.register(0)), (16, Value::I8(10)));
+        assert!(machine.frames().is_empty());
+        assert_eq!(machine.resume(), Status::Halted);
+        assert_eq!(machine.register(4), Value::I8(30));
+    }
+
+    #[test]
+    fn test_index_closure() {
+        let status = Machine::new().run(assemble("CLOSURE $0 f\nLEN $1 $0\nHALT\n.function f 0 0\nRET $0\n"));
+        assert_eq!(status, Status::Trapped(Trap::Type("Cannot index a closure".to_string())));
+    }
+
+    #[test]
+    fn test_unary_and_remainder() {
+        let mut machine = Machine::new();
+        let src = "CONST -7\nCONST 3\nCONST true\nLOAD $0 0\nLOAD $1 1\nLOAD $2 2\n\
+            REM $3 $0 $1\nMOD $4 $0 $1\nNEG $5 $0\nABS $6 $0\nNOT $7 $2\nNOT $8 $1\nHALT\n";
+        assert_eq!(machine.run(assemble(src)), Status::Halted);
+        assert_eq!((machine.register(3), machine.register(4)), (Value::I8(-1), Value::I8(2)));
+        assert_eq!((machine.register(5), machine.register(6)), (Value::I8(7), Value::I8(7)));
+        assert_eq!((machine.register(7), machine.register(8)), (Value::Bool(false), Value::I8(-4)));
+        assert_eq!(machine.code().format_instruction(9), "REM $3 $0 $1");
+        assert_eq!(machine.code().format_instruction(17), "NEG $5 $0");
+    }
+
+    #[test]
+    fn test_unary_and_remainder_traps() {
+        for (src, trap) in [
+            ("CONST 0.5\nLOAD $0 0\nNOT $1 $0\nHALT\n", Trap::Type("Cannot not type f32".to_string())),
+            ("CONST 1u8\nLOAD $0 0\nNEG $1 $0\nHALT\n", Trap::Type("Cannot negate type u8".to_string())),
+            ("CONST -128\nLOAD $0 0\nNEG $1 $0\nHALT\n", Trap::Overflow(ValueType::I8)),
+            ("CONST -128\nLOAD $0 0\nABS $1 $0\nHALT\n", Trap::Overflow(ValueType::I8)),
+            ("CONST 7\nCONST 0\nLOAD $0 0\nLOAD $1 1\nREM $2 $0 $1\nHALT\n", Trap::DivideByZero),
+            ("CONST 7u16\nCONST 0u16\nLOAD $0 0\nLOAD $1 1\nMOD $2 $0 $1\nHALT\n", Trap::DivideByZero),
+            ("CONST 7\nCONST 1.0\nLOAD $0 0\nLOAD $1 1\nMOD $2 $0 $1\nHALT\n", Trap::Type("Cannot take the modulus of types i8 and f32".to_string())),
+        ] {
+            assert_eq!(Machine::new().run(assemble(src)), Status::Trapped(trap));
+        }
+    }
+
+    #[test]
+    fn test_128_bit_memory() {
+        let mut machine = Machine::new();
+        let src = "CONST 32\nCONST -170141183460469231731687303715884105728i128\nCONST 0\n\
+            LOAD $0 0\nGROW $1 $0\nLOAD $2 1\nLOAD $3 2\nMSTORE $3 $2\nMLOAD $4 $3 i128\nMLOAD $5 $3 u128\nTOSTR $6 $5\nHALT\n";
+        assert_eq!(machine.run(assemble(src)), Status::Halted);
+        assert_eq!(machine.register(4), Value::from(i128::MIN));
+        assert_eq!(machine.register(5), Value::from(1u128 << 127));
+        assert_eq!(text(&machine, 6), (1u128 << 127).to_string());
+        assert_eq!(machine.code().format_instruction(19), "MLOAD $5 $3 u128");
+    }
+
+    #[test]
+    fn test_big_integers() {
+        let mut machine = Machine::new();
+        let src = "CONST 0xffffffffffffffffffffffffffffffffbig\nCONST 1big\nCONST 300u16\n\
+            LOAD $0 0\nLOAD $1 1\nADD $2 $0 $1\nMUL $3 $2 $2\nCONVERT $4 $5 $2 u128\nCONVERT $6 $7 $0 u128\n\
+            LOAD $8 2\nCONVERT $9 $10 $8 big\nCONVERT $11 $12 $9 u8\nTOSTR $13 $3\nHALT\n";
+        assert_eq!(machine.run(assemble(src)), Status::Halted);
+        assert_eq!((machine.register(4), machine.register(5)), (Value::U8(0), Value::Bool(false)));
+        assert_eq!((machine.register(6), machine.register(7)), (Value::from(u128::MAX), Value::Bool(true)));
+        assert_eq!((text(&machine, 9), machine.register(10)), ("300".to_string(), Value::Bool(true)));
+        assert_eq!(machine.register(12), Value::Bool(false));
+        let expected = "115792089237316195423570985008687907853269984665640564039457584007913129639936";
+        assert_eq!(text(&machine, 13), expected);
+        assert_eq!(machine.code().format_instruction(14), "CONVERT $4 $5 $2 u128");
+
+        let mut code = assemble("CONVERT $0 $1 $2 i8\nHALT\n");
+        code.raw[4] = ValueType::F32 as u8;
+        assert_eq!(code.verify(), Err("Cannot convert to type f32 at 0".to_string()));
+        assert_eq!(machine.run(code), Status::Trapped(Trap::InvalidCode("Cannot convert to type f32 at 0".to_string())));
+    }
+
+    #[test]
+    fn test_big_arithmetic() {
+        let mut machine = Machine::new();
+        let src = "CONST 0x100000000000000000000000000000000big\nCONST 1big\nCONST 7big\nCONST 127big\nCONST -1big\nCONST 3big\n\
+            LOAD $0 0\nLOAD $1 1\nLOAD $2 2\nLOAD $3 3\nLOAD $4 4\nLOAD $5 5\n\
+            SUB $6 $0 $1\nMUL $7 $0 $0\nDIV $7 $7 $0\nREM $7 $7 $2\nSHR $8 $0 $3\nAND $9 $4 $0\nNEG $10 $0\nMOD $10 $10 $5\n\
+            NOT $11 $4\nSHL $12 $1 $3\nHALT\n";
+        assert_eq!(machine.run(assemble(src)), Status::Halted);
+        let texts = (6..13).map(|reg| text(&machine, reg)).collect::<Vec<_>>();
+        let max = "340282366920938463463374607431768211456";
+        assert_eq!(texts, ["340282366920938463463374607431768211455", "4", "2", max, "2", "0", "170141183460469231731687303715884105728"]);
+        assert_eq!(output::typed(&machine.register(7), machine.heap()), "4big");
+        // computed numbers are on the heap, only constants are interned
+        assert!(machine.register(6).as_object().is_some() && machine.register(0).as_object().is_none());
+
+        let status = Machine::new().run(assemble("CONST 1big\nCONST 1\nLOAD $0 0\nLOAD $1 1\nADD $2 $0 $1\nHALT\n"));
+        assert_eq!(status, Status::Trapped(Trap::Type("Cannot apply ADD to types big and i8".to_string())));
+    }
+
+    #[test]
+    fn test_big_shifts_and_limit() {
+        let src = "CONST 1big\nCONST 0x10000000000big\nCONST -1big\nLOAD $0 0\nLOAD $1 1\nLOAD $2 2\n\
+            SHR $3 $0 $1\nSHL $4 $0 $1\nHALT\n";
+        let mut machine = Machine::new();
+        assert_eq!(machine.run(assemble(src)), Status::Trapped(Trap::HeapExhausted(machine.heap().limit())));
+        assert_eq!((machine.pc(), text(&machine, 3)), (13, "0".to_string()));
+        let status = Machine::new().run(assemble("CONST 1big\nCONST -1big\nLOAD $0 0\nLOAD $1 1\nSHL $2 $0 $1\nHALT\n"));
+        assert_eq!(status, Status::Trapped(Trap::InvalidShift("-1".to_string())));
+
+        // the first sum is collected once $1 holds the second
+        let mut machine = Machine::new();
+        machine.heap_mut().set_limit(4);
+        assert_eq!(machine.run(assemble("CONST 1big\nLOAD $0 0\nADD $1 $0 $0\nADD $1 $1 $0\nADD $1 $1 $0\nHALT\n")), Status::Halted);
+        assert_eq!((text(&machine, 1), machine.heap().len(), machine.heap().used()), ("4".to_string(), 2, 4));
+    }
+
+    #[test]
+    fn test_big_traps() {
+        let mixed = |op: &str| Trap::Type(format!("Cannot apply {} to types big and i8", op));
+        for (a, b, op, trap) in [
+            ("1big", "0big", "DIV", Trap::DivideByZero),
+            ("1big", "0big", "REM", Trap::DivideByZero),
+            ("-1big", "0big", "MOD", Trap::DivideByZero),
+            // a big integer in either source goes to the big integer operations
+            ("1big", "2", "ADD", mixed("ADD")),
+            ("2", "1big", "ADD", mixed("ADD")),
+            ("2", "1big", "SHL", mixed("SHL")),
+        ] {
+            let src = format!("CONST {}\nCONST {}\nLOAD $0 0\nLOAD $1 1\n{} $2 $0 $1\nHALT\n", a, b, op);
+            assert_eq!(Machine::new().run(assemble(&src)), Status::Trapped(trap));
+        }
+        for (constant, ty, message) in [
+            ("1.5", "big", "Cannot convert type f32 to an integer"),
+            ("true", "big", "Cannot convert type bool to an integer"),
+            ("\"1\"", "big", "Cannot convert type str to an integer"),
+            ("1.5", "i8", "Cannot convert type f32 to an integer"),
+        ] {
+            let src = format!("CONST {}\nLOAD $0 0\nCONVERT $1 $2 $0 {}\nHALT\n", constant, ty);
+            assert_eq!(Machine::new().run(assemble(&src)), Status::Trapped(Trap::Type(message.to_string())));
+        }
+    }
+
+    #[test]
+    fn test_chars_and_bytes() {
+        let mut machine = Machine::new();
+        let src = "CONST \"hé y\"\nCONST b\"a b\\xff\"\nCONST 1\nCONST 2\nCONST '7'\n\
+            LOAD $0 0\nLOAD $1 1\nLOAD $2 2\nLOAD $3 3\n\
+            CHARAT $4 $0 $2\nISALPHA $5 $4\nCHARAT $6 $0 $3\nISSPACE $7 $6\nCHARAT $8 $1 $2\nISSPACE $9 $8\nISDIGIT $10 $8\n\
+            CONVERT $11 $12 $4 u32\nCONVERT $13 $14 $11 char\nCONCAT $15 $1 $1\nSTRLEN $16 $1\nSUBSTR $17 $1 $2 $3\n\
+            STRCMP $18 $17 $1\nLOAD $19 4\nISDIGIT $20 $19\nHALT\n";
+        assert_eq!(machine.run(assemble(src)), Status::Halted);
+        assert_eq!((machine.register(4), machine.register(5)), (Value::Char('é'), Value::Bool(true)));
+        assert_eq!((machine.register(6), machine.register(7)), (Value::Char(' '), Value::Bool(true)));
+        assert_eq!(machine.register(8), Value::U8(b' '));
+        assert_eq!((machine.register(9), machine.register(10)), (Value::Bool(true), Value::Bool(false)));
+        assert_eq!((machine.register(11), machine.register(13)), (Value::U32(0xE9), Value::Char('é')));
+        let typed = |reg| output::typed(&machine.register(reg), machine.heap());
+        assert_eq!((typed(15), typed(17)), ("b\"a b\\xffa b\\xff\"".to_string(), "b\" b\"".to_string()));
+        assert_eq!(machine.register(16), Value::U64(4));
+        assert_eq!((machine.register(18), machine.register(20)), (Value::I8(-1), Value::Bool(true)));
+        assert_eq!(machine.code().format_instruction(12), "CHARAT $4 $0 $2");
+        assert_eq!(machine.code().format_instruction(16), "ISALPHA $5 $4");
+        assert_eq!(machine.code().format_instruction(3), "LOAD $1 b\"a b\\xff\"");
+
+        let src = "CONST \"ab\"\nCONST 2\nLOAD $0 0\nLOAD $1 1\nCHARAT $2 $0 $1\nHALT\n";
+        assert_eq!(machine.run(assemble(src)), Status::Trapped(Trap::IndexOutOfBounds(2, 2)));
+
+        // strings past the first run of indexed chars, built at run time and as constants
+        let src = format!("CONST \"{}\"\nCONST 70\nCONST 3\nCONST 66\nLOAD $0 0\nLOAD $1 1\nLOAD $2 2\nLOAD $8 3\n\
+            CONCAT $3 $0 $0\nCHARAT $4 $3 $1\nSUBSTR $5 $3 $1 $2\nSTRLEN $6 $3\nCHARAT $7 $0 $8\nHALT\n", "é".repeat(64) + "xyz");
+        assert_eq!(machine.run(assemble(&src)), Status::Halted);
+        assert_eq!((machine.register(4), text(&machine, 5)), (Value::Char('é'), "ééé".to_string()));
+        assert_eq!((machine.register(6), machine.register(7)), (Value::U64(134), Value::Char('z')));
+    }
+
+    #[test]
+    fn test_type_mismatch_traps() {
+        for (src, message) in [
+            ("CONST 1.5\nLOAD $0 0\nISDIGIT $1 $0\nHALT\n", "Cannot classify type f32"),
+            ("CONST \"a\"\nLOAD $1 0\nMSTORE $0 $1\nHALT\n", "Cannot store type str in memory"),
+            ("CALL $0 $1\nHALT\n", "Cannot call type u8"),
+            ("JMPIF $0 0\nHALT\n", "Cannot branch on type u8"),
+            ("CONST 1\nLOAD $0 0\nPARSE $1 $2 $0 i8\nHALT\n", "Cannot parse type i8"),
+        ] {
+            assert_eq!(Machine::new().run(assemble(src)), Status::Trapped(Trap::Type(message.to_string())));
+        }
+    }
+
+    #[test]
+    fn test_binary_arithmetic_traps() {
+        for (a, b, op, trap) in [
+            ("1", "1u8", "ADD", Trap::Type("Cannot add types i8 and u8".to_string())),
+            ("127", "1", "ADD", Trap::Overflow(ValueType::I8)),
+            ("0u8", "1u8", "SUB", Trap::Overflow(ValueType::U8)),
+            ("1", "0", "DIV", Trap::DivideByZero),
+            ("-128", "-1", "DIV", Trap::Overflow(ValueType::I8)),
+            ("true", "1", "XOR", Trap::Type("Cannot bitxor types bool and i8".to_string())),
+            ("1", "9", "SHL", Trap::InvalidShift("9".to_string())),
+            ("1", "-1", "SHR", Trap::InvalidShift("-1".to_string())),
+        ] {
+            let src = format!("CONST {}\nCONST {}\nLOAD $0 0\nLOAD $1 1\n{} $2 $0 $1\nHALT\n", a, b, op);
+            let mut machine = Machine::new();
+            assert_eq!(machine.run(assemble(&src)), Status::Trapped(trap));
+            assert_eq!(machine.pc(), 6);
+        }
+        let mut machine = Machine::new();
+        assert_eq!(machine.run(assemble("CONST -128\nCONST 7\nLOAD $0 0\nLOAD $1 1\nSHR $2 $0 $1\nHALT\n")), Status::Halted);
+        assert_eq!(machine.register(2), Value::I8(-1));
+    }
+
+    #[test]
+    fn test_concat_str_and_bytes() {
+        let status = Machine::new().run(assemble("CONST \"a\"\nCONST b\"b\"\nLOAD $0 0\nLOAD $1 1\nCONCAT $2 $0 $1\nHALT\n"));
+        assert_eq!(status, Status::Trapped(Trap::Type("Cannot concat types str and bytes".to_string())));
+
+        // byte strings built at run time go on the heap and are collected like strings
+        let mut machine = Machine::new();
+        machine.heap_mut().set_limit(4);
+        let src = "CONST b\"ab\"\nLOAD $0 0\nCONCAT $1 $0 $0\nCONCAT $1 $1 $0\nCONCAT $1 $1 $0\nPARSE $2 $3 $1 bytes\nHALT\n";
+        assert_eq!(machine.run(assemble(src)), Status::Trapped(Trap::Type("Cannot parse type bytes".to_string())));
+        assert_eq!((text(&machine, 1), machine.heap().len(), machine.heap().used()), ("abababab".to_string(), 2, 4));
+        assert!(machine.register(1).as_object().is_some());
+    }
+
+    #[test]
+    fn test_math() {
+        let mut machine = Machine::new();
+        let src = "CONST 2.0f64\nCONST -0.5f64\nLOAD $0 0\nLOAD $1 1\n\
+            MATH $2 sqrt $0\nMATH $3 pow $0 $1\nMATH $4 round $1\nMATH $5 ln $1\nMATH $6 is_nan $5\nHALT\n";
+        assert_eq!(machine.run(assemble(src)), Status::Halted);
+        assert_eq!((machine.register(2), machine.register(3)), (Value::F64(2f64.sqrt()), Value::F64(2f64.powf(-0.5))));
+        assert_eq!((machine.register(4), machine.register(6)), (Value::F64(-1.0), Value::Bool(true)));
+
+        let mut code = assemble("MATH $1 sqrt $0\nHALT\n");
+        code.raw[2] = math::FUNCTIONS.len() as u8;
+        let invalid = format!("Invalid math function {} at 0", math::FUNCTIONS.len());
+        assert_eq!(machine.run(code), Status::Trapped(Trap::InvalidCode(invalid)));
+    }
+
+    #[test]
+    fn test_math_type_mismatch() {
+        let mut machine = Machine::new();
+        let status = machine.run(assemble("CONST 4\nLOAD $0 0\nMATH $1 sqrt $0\nHALT\n"));
+        assert_eq!((status, machine.pc()), (Status::Trapped(Trap::Type("Cannot sqrt type i8".to_string())), 3));
+        let status = Machine::new().run(assemble("CONST 2.0\nCONST 2.0f64\nLOAD $0 0\nLOAD $1 1\nMATH $2 pow $0 $1\nHALT\n"));
+        assert_eq!(status, Status::Trapped(Trap::Type("Cannot pow types f32 and f64".to_string())));
+    }
+
+    #[test]
+    fn test_invalid_address() {
+        let mut machine = Machine::new();
+        let code = assemble("CONST -1\nLOAD $0 0\nMLOAD $1 $0 i8\nHALT\n");
+        assert_eq!(machine.run(code), Status::Trapped(Trap::InvalidIndex(Value::I8(-1))));
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut machine = Machine::new();
+        let mut code = add_program();
+        code.add_global(Value::U8(0));
+        let halt = code.raw.pop().unwrap();
+        code.raw.extend([Opcode::SETGLOBAL as u8, 2, 0, halt]);
+        code.lines.extend([5, 5, 5]);
+        machine.load(code).unwrap();
+        machine.set_history(History::new());
+        assert_eq!(machine.resume(), Status::Halted);
+        assert_eq!(machine.global(0), Some(Value::I8(30)));
+        assert_eq!(machine.history().unwrap().last_write(2), Some((6, 2)));
+
+        assert!(machine.step_back());
+        assert_eq!(machine.global(0), Some(Value::U8(0)));
+        assert!(machine.step_back());
+        assert_eq!((machine.pc(), machine.register(2)), (6, Value::U8(0)));
+        machine.add_breakpoint(3);
+        assert!(machine.run_back_until_breakpoint());
+        assert_eq!((machine.pc(), machine.register(1)), (3, Value::U8(0)));
+        assert!(!machine.run_back_until_breakpoint());
+        assert_eq!((machine.pc(), machine.register(0)), (0, Value::U8(0)));
+
+        // replaying forward gives the same result
+        assert_eq!(machine.resume(), Status::Halted);
+        assert_eq!(machine.register(2), Value::I8(30));
+    }
+}
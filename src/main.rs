@@ -1,15 +1,101 @@
-mod opcode;
-mod value;
-mod code;
-mod machine;
-mod reader;
+use std::io::{self, BufRead, Read};
+use tower::{Code, Opcode, Value, Machine};
+use tower::machine::Status;
+use tower::backend::{c, wasm};
+use tower::debugger;
+use tower::trace::{TraceFormat, Tracer};
+use tower::profile::Profiler;
+use tower::history::History;
+use tower::reader::assemble;
 
-use crate::code::Code;
-use crate::opcode::Opcode;
-use crate::value::Value;
-use crate::machine::Machine;
+fn usage() -> ! {
+    eprintln!("usage: tower [run <file> [--fuel <n>] | debug <file> | trace <file> [--json] [<start>..<end>] | profile <file> [--time] [--top <n>] | c <file> | wasm <file> <output>]");
+    std::process::exit(2);
+}
 
-fn main() {
+fn read_code(path: Option<&String>) -> Code {
+    let path = path.unwrap_or_else(|| usage());
+    let src = std::fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("tower: cannot read {}: {}", path, e);
+        std::process::exit(1);
+    });
+    assemble(&src)
+}
+
+// trace options: --json switches to JSON lines, start..end limits the traced offsets
+fn tracer(options: &[String]) -> Tracer {
+    let mut format = TraceFormat::Text;
+    let mut range = 0..usize::MAX;
+    for option in options {
+        if option == "--json" {
+            format = TraceFormat::JsonLines;
+            continue;
+        }
+        let bounds = option.split_once("..").and_then(|(start, end)| Some((start.parse().ok()?, end.parse().ok()?)));
+        match bounds {
+            Some((start, end)) => range = start..end,
+            None => usage(),
+        }
+    }
+    Tracer::new(std::io::stderr(), format).with_range(range)
+}
+
+// there is no host to receive yielded values, so execution simply resumes
+fn complete(machine: &mut Machine, mut status: Status) -> Status {
+    while let Status::Yielded(_) = status {
+        status = machine.resume();
+    }
+    status
+}
+
+fn exit_unless_halted(machine: &Machine, status: Status) {
+    match status {
+        Status::OutOfFuel => {
+            eprintln!("tower: out of fuel at {}", machine.pc());
+            std::process::exit(1);
+        }
+        Status::Trapped(trap) => {
+            eprintln!("tower: trapped at {}: {}", machine.pc(), trap);
+            std::process::exit(1);
+        }
+        _ => {}
+    }
+}
+
+// reads stdin a line at a time without holding the lock, so debugger commands and
+// READ instructions can take turns
+#[derive(Default)]
+struct SharedStdin {
+    line: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for SharedStdin {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl BufRead for SharedStdin {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.line.len() {
+            self.line.clear();
+            self.pos = 0;
+            io::stdin().lock().read_until(b'\n', &mut self.line)?;
+        }
+        Ok(&self.line[self.pos..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.pos += amount;
+    }
+}
+
+fn demo() {
     let mut machine = Machine::new();
     let mut code = Code::new();
     let val = Value::F32(10.5);
@@ -31,4 +117,81 @@ fn main() {
     code.write_code(Opcode::HALT as u8, 4);
     code.disassemble();
     machine.run(code);
-}
\ No newline at end of file
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(|s| s.as_str()) {
+        None => demo(),
+        Some("run") => {
+            let mut machine = Machine::new();
+            match args.get(3..).unwrap_or_default() {
+                [] => {}
+                [option, fuel] if option == "--fuel" => {
+                    machine.set_fuel(Some(fuel.parse().unwrap_or_else(|_| usage())));
+                }
+                _ => usage(),
+            }
+            let status = machine.run(read_code(args.get(2)));
+            let status = complete(&mut machine, status);
+            exit_unless_halted(&machine, status);
+        }
+        Some("debug") => {
+            let mut machine = Machine::new();
+            if let Err(e) = machine.load(read_code(args.get(2))) {
+                eprintln!("tower: {}", e);
+                std::process::exit(1);
+            }
+            machine.set_history(History::new().with_limit(1 << 20));
+            if let Err(e) = debugger::run(&mut machine, SharedStdin::default(), std::io::stdout()) {
+                eprintln!("tower: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Some("trace") => {
+            let mut machine = Machine::new();
+            machine.set_tracer(tracer(args.get(3..).unwrap_or_default()));
+            let status = machine.run(read_code(args.get(2)));
+            let status = complete(&mut machine, status);
+            exit_unless_halted(&machine, status);
+        }
+        Some("profile") => {
+            let mut profiler = Profiler::new();
+            let mut top = 10;
+            let mut options = args.iter().skip(3);
+            while let Some(option) = options.next() {
+                match option.as_str() {
+                    "--time" => profiler = profiler.with_timing(),
+                    "--top" => top = options.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| usage()),
+                    _ => usage(),
+                }
+            }
+            let mut machine = Machine::new();
+            machine.set_profiler(profiler);
+            let status = machine.run(read_code(args.get(2)));
+            let status = complete(&mut machine, status);
+            let profiler = machine.take_profiler().unwrap();
+            eprint!("{}", profiler.report(machine.code(), top));
+            exit_unless_halted(&machine, status);
+        }
+        Some("c") => match c::emit(&read_code(args.get(2))) {
+            Ok(source) => print!("{}", source),
+            Err(e) => {
+                eprintln!("tower: {}", e);
+                std::process::exit(1);
+            }
+        },
+        Some("wasm") => {
+            let module = wasm::emit(&read_code(args.get(2))).unwrap_or_else(|e| {
+                eprintln!("tower: {}", e);
+                std::process::exit(1);
+            });
+            let output = args.get(3).unwrap_or_else(|| usage());
+            if let Err(e) = std::fs::write(output, module) {
+                eprintln!("tower: cannot write {}: {}", output, e);
+                std::process::exit(1);
+            }
+        }
+        Some(_) => usage(),
+    }
+}
@@ -0,0 +1,85 @@
+// floating point functions for the MATH opcode, which picks one by its index in FUNCTIONS.
+// the assembler, the disassembler and the machine all go through this table
+use crate::{machine::Trap, value::Value};
+
+#[derive(Clone, Copy)]
+pub enum Math {
+    Unary(fn(f32) -> f32, fn(f64) -> f64),
+    Binary(fn(f32, f32) -> f32, fn(f64, f64) -> f64),
+    // classifies its operand, the result is a bool
+    Test(fn(f32) -> bool, fn(f64) -> bool),
+}
+
+pub const FUNCTIONS: [(&str, Math); 17] = [
+    ("sqrt", Math::Unary(f32::sqrt, f64::sqrt)),
+    ("floor", Math::Unary(f32::floor, f64::floor)),
+    ("ceil", Math::Unary(f32::ceil, f64::ceil)),
+    ("round", Math::Unary(f32::round, f64::round)),
+    ("trunc", Math::Unary(f32::trunc, f64::trunc)),
+    ("sin", Math::Unary(f32::sin, f64::sin)),
+    ("cos", Math::Unary(f32::cos, f64::cos)),
+    ("tan", Math::Unary(f32::tan, f64::tan)),
+    ("exp", Math::Unary(f32::exp, f64::exp)),
+    ("ln", Math::Unary(f32::ln, f64::ln)),
+    ("log2", Math::Unary(f32::log2, f64::log2)),
+    ("atan2", Math::Binary(f32::atan2, f64::atan2)),
+    ("pow", Math::Binary(f32::powf, f64::powf)),
+    ("min", Math::Binary(f32::min, f64::min)),
+    ("max", Math::Binary(f32::max, f64::max)),
+    ("is_nan", Math::Test(f32::is_nan, f64::is_nan)),
+    ("is_finite", Math::Test(f32::is_finite, f64::is_finite)),
+];
+
+pub fn selector(name: &str) -> Option<u8> {
+    FUNCTIONS.iter().position(|(function, _)| *function == name).map(|index| index as u8)
+}
+
+pub fn name(selector: u8) -> Option<&'static str> {
+    FUNCTIONS.get(selector as usize).map(|(name, _)| *name)
+}
+
+pub fn is_binary(selector: u8) -> bool {
+    matches!(FUNCTIONS.get(selector as usize), Some((_, Math::Binary(..))))
+}
+
+// b is only read by binary functions. verify only checks the selector, so operands of
+// any type get here and the ones that are not floats of the same width trap
+pub fn apply(selector: u8, a: Value, b: Value) -> Result<Value, Trap> {
+    let (name, function) = FUNCTIONS[selector as usize];
+    match (function, a, b) {
+        (Math::Unary(f, _), Value::F32(a), _) => Ok(Value::F32(f(a))),
+        (Math::Unary(_, f), Value::F64(a), _) => Ok(Value::F64(f(a))),
+        (Math::Test(f, _), Value::F32(a), _) => Ok(Value::Bool(f(a))),
+        (Math::Test(_, f), Value::F64(a), _) => Ok(Value::Bool(f(a))),
+        (Math::Binary(f, _), Value::F32(a), Value::F32(b)) => Ok(Value::F32(f(a, b))),
+        (Math::Binary(_, f), Value::F64(a), Value::F64(b)) => Ok(Value::F64(f(a, b))),
+        (Math::Binary(..), a, b) => Err(Trap::Type(format!("Cannot {} types {} and {}", name, a.value_type(), b.value_type()))),
+        (_, a, _) => Err(Trap::Type(format!("Cannot {} type {}", name, a.value_type()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let sqrt = selector("sqrt").unwrap();
+        assert_eq!(apply(sqrt, Value::F64(9.0), Value::U8(0)), Ok(Value::F64(3.0)));
+        assert_eq!(apply(selector("floor").unwrap(), Value::F32(-1.5), Value::U8(0)), Ok(Value::F32(-2.0)));
+        assert_eq!(apply(selector("max").unwrap(), Value::F32(1.0), Value::F32(f32::NAN)), Ok(Value::F32(1.0)));
+        assert_eq!(apply(selector("is_nan").unwrap(), Value::F64(f64::NAN), Value::U8(0)), Ok(Value::Bool(true)));
+        assert_eq!(apply(selector("is_finite").unwrap(), Value::F32(f32::INFINITY), Value::U8(0)), Ok(Value::Bool(false)));
+        assert!(is_binary(selector("atan2").unwrap()) && !is_binary(sqrt));
+        assert_eq!(name(sqrt), Some("sqrt"));
+        assert_eq!(name(FUNCTIONS.len() as u8), None);
+    }
+
+    #[test]
+    fn test_mixed_widths() {
+        let trap = Trap::Type("Cannot pow types f32 and f64".to_string());
+        assert_eq!(apply(selector("pow").unwrap(), Value::F32(2.0), Value::F64(2.0)), Err(trap));
+        let trap = Trap::Type("Cannot is_nan type i64".to_string());
+        assert_eq!(apply(selector("is_nan").unwrap(), Value::I64(1), Value::U8(0)), Err(trap));
+    }
+}
@@ -0,0 +1,110 @@
+// byte addressable linear memory that programs grow on demand up to a limit set by the host
+use crate::value::{Value, ValueType};
+
+pub const DEFAULT_MEMORY_LIMIT: usize = 1 << 24;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Memory {
+    bytes: Vec<u8>,
+    limit: usize,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Memory::new()
+    }
+}
+
+impl Memory {
+    // starts out empty, GROW makes room
+    pub fn new() -> Memory {
+        Memory::with_limit(DEFAULT_MEMORY_LIMIT)
+    }
+
+    pub fn with_limit(limit: usize) -> Memory {
+        Memory {
+            bytes: Vec::new(),
+            limit,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    // lowering the limit below the current size only stops further growth
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+
+    // adds zeroed bytes and returns the old size, None if that would pass the limit
+    pub fn grow(&mut self, delta: u64) -> Option<usize> {
+        let old = self.bytes.len();
+        let new = usize::try_from(delta).ok().and_then(|delta| old.checked_add(delta))?;
+        if new > self.limit {
+            return None;
+        }
+        self.bytes.resize(new, 0);
+        Some(old)
+    }
+
+    // undoes a grow, also used when restoring snapshots
+    pub fn resize(&mut self, size: usize) {
+        self.bytes.resize(size, 0);
+    }
+
+    fn range(&self, addr: u64, len: usize) -> Option<std::ops::Range<usize>> {
+        let start = usize::try_from(addr).ok()?;
+        let end = start.checked_add(len)?;
+        (end <= self.bytes.len()).then_some(start..end)
+    }
+
+    // None if any of the bytes is out of bounds or the type has no fixed width
+    pub fn load(&self, addr: u64, ty: ValueType) -> Option<Value> {
+        let range = self.range(addr, ty.size()?)?;
+        Some(Value::from_le_bytes(ty, &self.bytes[range]))
+    }
+
+    // writes the value in little-endian order with the width of its type
+    pub fn store(&mut self, addr: u64, value: Value) -> bool {
+        match value.value_type().size().and_then(|size| self.range(addr, size)) {
+            Some(range) => {
+                self.bytes[range].copy_from_slice(&value.to_le_bytes());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grow_load_store() {
+        let mut memory = Memory::with_limit(16);
+        assert_eq!(memory.grow(8), Some(0));
+        assert_eq!(memory.grow(9), None);
+        assert_eq!(memory.grow(8), Some(8));
+        assert!(memory.store(2, Value::I32(-2)));
+        assert_eq!(&memory.bytes()[2..6], &[0xFE, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(memory.load(2, ValueType::I32), Some(Value::I32(-2)));
+        assert_eq!(memory.load(2, ValueType::U16), Some(Value::U16(0xFFFE)));
+        assert_eq!(memory.load(9, ValueType::F64), None);
+        assert!(!memory.store(u64::MAX, Value::U8(1)));
+        assert_eq!(memory.load(8, ValueType::F64), Some(Value::F64(0.0)));
+    }
+}
@@ -1,13 +1,14 @@
 use std::str::FromStr;
 
 #[repr(u8)]
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Opcode {
     PRINT,
     MOVE,
     LOAD,
-    STORE,
-    ADD,
+    // 3 was STORE, which wrote into the constant pool, globals replace it
+    ADD = 4,
     SUB,
     MUL,
     DIV,
@@ -19,28 +20,122 @@ pub enum Opcode {
     JMP,
     HALT,
     CONST,
+    YIELD,
+    CALLHOST,
+    TPRINT,
+    JMPIF,
+    READ,
+    MLOAD,
+    MSTORE,
+    MEMSIZE,
+    GROW,
+    GETGLOBAL,
+    SETGLOBAL,
+    PUSH,
+    POP,
+    PEEK,
+    DUP,
+    SWAP,
+    SLOAD,
+    SSTORE,
+    CONCAT,
+    STRLEN,
+    SUBSTR,
+    STRCMP,
+    STREQ,
+    TOSTR,
+    PARSE,
+    NEWARRAY,
+    NEWRECORD,
+    GETELEM,
+    SETELEM,
+    LEN,
+    APPEND,
+    CLOSURE,
+    CALL,
+    RET,
+    GETUPVAL,
+    SETUPVAL,
+    NEG,
+    NOT,
+    ABS,
+    REM,
+    MOD,
+    MATH,
+    CONVERT,
+    CHARAT,
+    ISDIGIT,
+    ISALPHA,
+    ISSPACE,
 }
 
-impl ToString for Opcode {
-    fn to_string(&self) -> String {
-        match self {
-            Opcode::PRINT => "PRINT".to_string(),
-            Opcode::MOVE => "MOVE".to_string(),
-            Opcode::LOAD => "LOAD".to_string(),
-            Opcode::STORE => "STORE".to_string(),
-            Opcode::ADD => "ADD".to_string(),
-            Opcode::SUB => "SUB".to_string(),
-            Opcode::MUL => "MUL".to_string(),
-            Opcode::DIV => "DIV".to_string(),
-            Opcode::AND => "AND".to_string(),
-            Opcode::OR => "OR".to_string(),
-            Opcode::XOR => "XOR".to_string(),
-            Opcode::SHR => "SHR".to_string(),
-            Opcode::SHL => "SHL".to_string(),
-            Opcode::JMP => "JMP".to_string(),
-            Opcode::CONST => "CONST".to_string(),
-            Opcode::HALT => "HALT".to_string(),
-        }
+impl std::fmt::Display for Opcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Opcode::PRINT => "PRINT",
+            Opcode::MOVE => "MOVE",
+            Opcode::LOAD => "LOAD",
+            Opcode::ADD => "ADD",
+            Opcode::SUB => "SUB",
+            Opcode::MUL => "MUL",
+            Opcode::DIV => "DIV",
+            Opcode::AND => "AND",
+            Opcode::OR => "OR",
+            Opcode::XOR => "XOR",
+            Opcode::SHR => "SHR",
+            Opcode::SHL => "SHL",
+            Opcode::JMP => "JMP",
+            Opcode::CONST => "CONST",
+            Opcode::HALT => "HALT",
+            Opcode::YIELD => "YIELD",
+            Opcode::CALLHOST => "CALLHOST",
+            Opcode::TPRINT => "TPRINT",
+            Opcode::JMPIF => "JMPIF",
+            Opcode::READ => "READ",
+            Opcode::MLOAD => "MLOAD",
+            Opcode::MSTORE => "MSTORE",
+            Opcode::MEMSIZE => "MEMSIZE",
+            Opcode::GROW => "GROW",
+            Opcode::GETGLOBAL => "GETGLOBAL",
+            Opcode::SETGLOBAL => "SETGLOBAL",
+            Opcode::PUSH => "PUSH",
+            Opcode::POP => "POP",
+            Opcode::PEEK => "PEEK",
+            Opcode::DUP => "DUP",
+            Opcode::SWAP => "SWAP",
+            Opcode::SLOAD => "SLOAD",
+            Opcode::SSTORE => "SSTORE",
+            Opcode::CONCAT => "CONCAT",
+            Opcode::STRLEN => "STRLEN",
+            Opcode::SUBSTR => "SUBSTR",
+            Opcode::STRCMP => "STRCMP",
+            Opcode::STREQ => "STREQ",
+            Opcode::TOSTR => "TOSTR",
+            Opcode::PARSE => "PARSE",
+            Opcode::NEWARRAY => "NEWARRAY",
+            Opcode::NEWRECORD => "NEWRECORD",
+            Opcode::GETELEM => "GETELEM",
+            Opcode::SETELEM => "SETELEM",
+            Opcode::LEN => "LEN",
+            Opcode::APPEND => "APPEND",
+            Opcode::CLOSURE => "CLOSURE",
+            Opcode::CALL => "CALL",
+            Opcode::RET => "RET",
+            Opcode::GETUPVAL => "GETUPVAL",
+            Opcode::SETUPVAL => "SETUPVAL",
+            Opcode::NEG => "NEG",
+            Opcode::NOT => "NOT",
+            Opcode::ABS => "ABS",
+            Opcode::REM => "REM",
+            Opcode::MOD => "MOD",
+            Opcode::MATH => "MATH",
+            Opcode::CONVERT => "CONVERT",
+            Opcode::CHARAT => "CHARAT",
+            Opcode::ISDIGIT => "ISDIGIT",
+            Opcode::ISALPHA => "ISALPHA",
+            Opcode::ISSPACE => "ISSPACE",
+        };
+        write!(f, "{}", name)
     }
 }
 
@@ -50,7 +145,6 @@ impl Opcode {
             Opcode::PRINT => 1,
             Opcode::MOVE => 2,
             Opcode::LOAD => 2,
-            Opcode::STORE => 2,
             Opcode::ADD => 3,
             Opcode::SUB => 3,
             Opcode::MUL => 3,
@@ -63,17 +157,63 @@ impl Opcode {
             Opcode::JMP => 1,
             Opcode::CONST => 1,
             Opcode::HALT => 0,
+            Opcode::YIELD => 1,
+            Opcode::CALLHOST => 4,
+            Opcode::TPRINT => 1,
+            Opcode::JMPIF => 2,
+            Opcode::READ => 3,
+            Opcode::MLOAD => 3,
+            Opcode::MSTORE => 2,
+            Opcode::MEMSIZE => 1,
+            Opcode::GROW => 2,
+            Opcode::GETGLOBAL => 2,
+            Opcode::SETGLOBAL => 2,
+            Opcode::PUSH => 1,
+            Opcode::POP => 1,
+            Opcode::PEEK => 1,
+            Opcode::DUP => 0,
+            Opcode::SWAP => 0,
+            Opcode::SLOAD => 2,
+            Opcode::SSTORE => 2,
+            Opcode::CONCAT => 3,
+            Opcode::STRLEN => 2,
+            Opcode::SUBSTR => 4,
+            Opcode::STRCMP => 3,
+            Opcode::STREQ => 3,
+            Opcode::TOSTR => 2,
+            Opcode::PARSE => 4,
+            Opcode::NEWARRAY => 2,
+            Opcode::NEWRECORD => 2,
+            Opcode::GETELEM => 3,
+            Opcode::SETELEM => 3,
+            Opcode::LEN => 2,
+            Opcode::APPEND => 2,
+            Opcode::CLOSURE => 4,
+            Opcode::CALL => 4,
+            Opcode::RET => 1,
+            Opcode::GETUPVAL => 2,
+            Opcode::SETUPVAL => 2,
+            Opcode::NEG => 2,
+            Opcode::NOT => 2,
+            Opcode::ABS => 2,
+            Opcode::REM => 3,
+            Opcode::MOD => 3,
+            Opcode::MATH => 4,
+            Opcode::CONVERT => 4,
+            Opcode::CHARAT => 3,
+            Opcode::ISDIGIT => 2,
+            Opcode::ISALPHA => 2,
+            Opcode::ISSPACE => 2,
         }
     }
 }
 
-impl From<u8> for Opcode {
-    fn from(byte: u8) -> Self {
-        match byte {
+impl Opcode {
+    pub fn from_byte(byte: u8) -> Option<Opcode> {
+        let opcode = match byte {
             0 => Opcode::PRINT,
             1 => Opcode::MOVE,
             2 => Opcode::LOAD,
-            3 => Opcode::STORE,
             4 => Opcode::ADD,
             5 => Opcode::SUB,
             6 => Opcode::MUL,
@@ -86,8 +226,62 @@ impl From<u8> for Opcode {
             13 => Opcode::JMP,
             14 => Opcode::HALT,
             15 => Opcode::CONST,
-            _ => panic!("Invalid opcode"),
-        }
+            16 => Opcode::YIELD,
+            17 => Opcode::CALLHOST,
+            18 => Opcode::TPRINT,
+            19 => Opcode::JMPIF,
+            20 => Opcode::READ,
+            21 => Opcode::MLOAD,
+            22 => Opcode::MSTORE,
+            23 => Opcode::MEMSIZE,
+            24 => Opcode::GROW,
+            25 => Opcode::GETGLOBAL,
+            26 => Opcode::SETGLOBAL,
+            27 => Opcode::PUSH,
+            28 => Opcode::POP,
+            29 => Opcode::PEEK,
+            30 => Opcode::DUP,
+            31 => Opcode::SWAP,
+            32 => Opcode::SLOAD,
+            33 => Opcode::SSTORE,
+            34 => Opcode::CONCAT,
+            35 => Opcode::STRLEN,
+            36 => Opcode::SUBSTR,
+            37 => Opcode::STRCMP,
+            38 => Opcode::STREQ,
+            39 => Opcode::TOSTR,
+            40 => Opcode::PARSE,
+            41 => Opcode::NEWARRAY,
+            42 => Opcode::NEWRECORD,
+            43 => Opcode::GETELEM,
+            44 => Opcode::SETELEM,
+            45 => Opcode::LEN,
+            46 => Opcode::APPEND,
+            47 => Opcode::CLOSURE,
+            48 => Opcode::CALL,
+            49 => Opcode::RET,
+            50 => Opcode::GETUPVAL,
+            51 => Opcode::SETUPVAL,
+            52 => Opcode::NEG,
+            53 => Opcode::NOT,
+            54 => Opcode::ABS,
+            55 => Opcode::REM,
+            56 => Opcode::MOD,
+            57 => Opcode::MATH,
+            58 => Opcode::CONVERT,
+            59 => Opcode::CHARAT,
+            60 => Opcode::ISDIGIT,
+            61 => Opcode::ISALPHA,
+            62 => Opcode::ISSPACE,
+            _ => return None,
+        };
+        Some(opcode)
+    }
+}
+
+impl From<u8> for Opcode {
+    fn from(byte: u8) -> Self {
+        Opcode::from_byte(byte).expect("Invalid opcode")
     }
 }
 
@@ -99,7 +293,6 @@ impl FromStr for Opcode {
             "PRINT" => Ok(Opcode::PRINT),
             "MOVE" => Ok(Opcode::MOVE),
             "LOAD" => Ok(Opcode::LOAD),
-            "STORE" => Ok(Opcode::STORE),
             "ADD" => Ok(Opcode::ADD),
             "SUB" => Ok(Opcode::SUB),
             "MUL" => Ok(Opcode::MUL),
@@ -112,6 +305,53 @@ impl FromStr for Opcode {
             "JMP" => Ok(Opcode::JMP),
             "CONST" => Ok(Opcode::CONST),
             "HALT" => Ok(Opcode::HALT),
+            "YIELD" => Ok(Opcode::YIELD),
+            "CALLHOST" => Ok(Opcode::CALLHOST),
+            "TPRINT" => Ok(Opcode::TPRINT),
+            "JMPIF" => Ok(Opcode::JMPIF),
+            "READ" => Ok(Opcode::READ),
+            "MLOAD" => Ok(Opcode::MLOAD),
+            "MSTORE" => Ok(Opcode::MSTORE),
+            "MEMSIZE" => Ok(Opcode::MEMSIZE),
+            "GROW" => Ok(Opcode::GROW),
+            "GETGLOBAL" => Ok(Opcode::GETGLOBAL),
+            "SETGLOBAL" => Ok(Opcode::SETGLOBAL),
+            "PUSH" => Ok(Opcode::PUSH),
+            "POP" => Ok(Opcode::POP),
+            "PEEK" => Ok(Opcode::PEEK),
+            "DUP" => Ok(Opcode::DUP),
+            "SWAP" => Ok(Opcode::SWAP),
+            "SLOAD" => Ok(Opcode::SLOAD),
+            "SSTORE" => Ok(Opcode::SSTORE),
+            "CONCAT" => Ok(Opcode::CONCAT),
+            "STRLEN" => Ok(Opcode::STRLEN),
+            "SUBSTR" => Ok(Opcode::SUBSTR),
+            "STRCMP" => Ok(Opcode::STRCMP),
+            "STREQ" => Ok(Opcode::STREQ),
+            "TOSTR" => Ok(Opcode::TOSTR),
+            "PARSE" => Ok(Opcode::PARSE),
+            "NEWARRAY" => Ok(Opcode::NEWARRAY),
+            "NEWRECORD" => Ok(Opcode::NEWRECORD),
+            "GETELEM" => Ok(Opcode::GETELEM),
+            "SETELEM" => Ok(Opcode::SETELEM),
+            "LEN" => Ok(Opcode::LEN),
+            "APPEND" => Ok(Opcode::APPEND),
+            "CLOSURE" => Ok(Opcode::CLOSURE),
+            "CALL" => Ok(Opcode::CALL),
+            "RET" => Ok(Opcode::RET),
+            "GETUPVAL" => Ok(Opcode::GETUPVAL),
+            "SETUPVAL" => Ok(Opcode::SETUPVAL),
+            "NEG" => Ok(Opcode::NEG),
+            "NOT" => Ok(Opcode::NOT),
+            "ABS" => Ok(Opcode::ABS),
+            "REM" => Ok(Opcode::REM),
+            "MOD" => Ok(Opcode::MOD),
+            "MATH" => Ok(Opcode::MATH),
+            "CONVERT" => Ok(Opcode::CONVERT),
+            "CHARAT" => Ok(Opcode::CHARAT),
+            "ISDIGIT" => Ok(Opcode::ISDIGIT),
+            "ISALPHA" => Ok(Opcode::ISALPHA),
+            "ISSPACE" => Ok(Opcode::ISSPACE),
             _ => Err(format!("Invalid opcode: {}", s)),
         }
     }
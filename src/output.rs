@@ -0,0 +1,123 @@
+// where PRINT sends its text, stdout unless the embedder supplies a writer or a callback
+use std::io::{self, Write};
+use crate::{bigint::BigInt, heap::Heap, string, value::Value};
+
+enum Sink {
+    Writer(Box<dyn Write>),
+    Callback(Box<dyn FnMut(&str)>),
+}
+
+pub struct Output {
+    sink: Sink,
+    separator: String,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output::stdout()
+    }
+}
+
+// the value as PRINT shows it, strings, byte strings and big integers on the heap are read from it
+pub fn display(value: &Value, heap: &Heap) -> String {
+    match *value {
+        Value::Str(s) => heap.str(s).map_or(value.to_string(), str::to_string),
+        Value::Bytes(bytes) => heap.bytes(bytes).map_or(value.to_string(), |bytes| String::from_utf8_lossy(bytes).into_owned()),
+        Value::Big(big) => heap.big(big).map_or(value.to_string(), BigInt::to_string),
+        _ => value.to_string(),
+    }
+}
+
+// the value as a literal the reader accepts back, e.g. 10.5f32
+pub fn typed(value: &Value, heap: &Heap) -> String {
+    match *value {
+        Value::Bool(_) | Value::Ref(_) => value.to_string(),
+        Value::Str(s) => heap.str(s).map_or(value.to_string(), string::quote),
+        Value::Char(c) => string::quote_char(c),
+        Value::Bytes(bytes) => heap.bytes(bytes).map_or(value.to_string(), string::quote_bytes),
+        _ => format!("{}{}", display(value, heap), value.value_type()),
+    }
+}
+
+impl Output {
+    pub fn stdout() -> Output {
+        Output::new(io::stdout())
+    }
+
+    pub fn new(sink: impl Write + 'static) -> Output {
+        Output {
+            sink: Sink::Writer(Box::new(sink)),
+            separator: String::new(),
+        }
+    }
+
+    // called with the text of every PRINT, separator included
+    pub fn callback(callback: impl FnMut(&str) + 'static) -> Output {
+        Output {
+            sink: Sink::Callback(Box::new(callback)),
+            separator: String::new(),
+        }
+    }
+
+    // written after every printed value, "\n" prints one value per line
+    pub fn with_separator(mut self, separator: &str) -> Output {
+        self.separator = separator.to_string();
+        self
+    }
+
+    pub fn print(&mut self, text: &str) -> io::Result<()> {
+        match &mut self.sink {
+            Sink::Writer(writer) => write!(writer, "{}{}", text, self.separator),
+            Sink::Callback(callback) => {
+                callback(&format!("{}{}", text, self.separator));
+                Ok(())
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.sink {
+            Sink::Writer(writer) => writer.flush(),
+            Sink::Callback(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{heap::Object, string::{Bytes, Str}};
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn test_typed() {
+        let heap = Heap::new();
+        assert_eq!(typed(&Value::F32(10.5), &heap), "10.5f32");
+        assert_eq!(typed(&Value::I8(-3), &heap), "-3i8");
+        assert_eq!(typed(&Value::Bool(true), &heap), "true");
+        assert_eq!(typed(&Value::from("a\"b"), &heap), "\"a\\\"b\"");
+        assert_eq!(typed(&Value::Char('\''), &heap), "'\\''");
+        assert_eq!(typed(&Value::from(&b"a\xff"[..]), &heap), "b\"a\\xff\"");
+    }
+
+    #[test]
+    fn test_heap_strings() {
+        let mut heap = Heap::new();
+        let s = Value::Str(Str::on_heap(heap.alloc(Object::str("a\nb".to_string())).unwrap()));
+        assert_eq!((display(&s, &heap), typed(&s, &heap)), ("a\nb".to_string(), "\"a\\nb\"".to_string()));
+        let bytes = Value::Bytes(Bytes::on_heap(heap.alloc(Object::Bytes(b"a\xff".to_vec())).unwrap()));
+        assert_eq!((display(&bytes, &heap), typed(&bytes, &heap)), ("a\u{FFFD}".to_string(), "b\"a\\xff\"".to_string()));
+        heap.collect([]);
+        assert_eq!((display(&s, &heap), display(&bytes, &heap)), ("<str 0>".to_string(), "<bytes 1>".to_string()));
+    }
+
+    #[test]
+    fn test_callback_and_separator() {
+        let printed = Rc::new(RefCell::new(Vec::new()));
+        let sink = printed.clone();
+        let mut output = Output::callback(move |text| sink.borrow_mut().push(text.to_string())).with_separator("\n");
+        output.print("7").unwrap();
+        output.print("7u64").unwrap();
+        assert_eq!(*printed.borrow(), vec!["7\n", "7u64\n"]);
+    }
+}
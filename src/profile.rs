@@ -0,0 +1,190 @@
+// counts executed instructions and reports where a program spends its time
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+use crate::{code::Code, opcode::Opcode};
+
+const OPCODE_COUNT: usize = u8::MAX as usize + 1;
+
+// coarse grouping of opcodes for wall time measurements
+pub fn opcode_class(opcode: Opcode) -> &'static str {
+    match opcode {
+        Opcode::PRINT | Opcode::TPRINT | Opcode::READ | Opcode::CALLHOST => "io",
+        Opcode::MOVE | Opcode::LOAD | Opcode::CONST | Opcode::GETGLOBAL | Opcode::SETGLOBAL => "data",
+        Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV | Opcode::NEG | Opcode::ABS | Opcode::REM | Opcode::MOD | Opcode::MATH | Opcode::CONVERT => "arithmetic",
+        Opcode::AND | Opcode::OR | Opcode::XOR | Opcode::SHR | Opcode::SHL | Opcode::NOT => "bitwise",
+        Opcode::MLOAD | Opcode::MSTORE | Opcode::MEMSIZE | Opcode::GROW => "memory",
+        Opcode::PUSH | Opcode::POP | Opcode::PEEK | Opcode::DUP | Opcode::SWAP | Opcode::SLOAD | Opcode::SSTORE => "stack",
+        Opcode::CONCAT
+        | Opcode::STRLEN
+        | Opcode::SUBSTR
+        | Opcode::STRCMP
+        | Opcode::STREQ
+        | Opcode::TOSTR
+        | Opcode::PARSE
+        | Opcode::CHARAT
+        | Opcode::ISDIGIT
+        | Opcode::ISALPHA
+        | Opcode::ISSPACE => "string",
+        Opcode::NEWARRAY | Opcode::NEWRECORD | Opcode::GETELEM | Opcode::SETELEM | Opcode::LEN | Opcode::APPEND => "heap",
+        Opcode::CLOSURE | Opcode::CALL | Opcode::RET | Opcode::GETUPVAL | Opcode::SETUPVAL => "function",
+        Opcode::JMP | Opcode::JMPIF | Opcode::HALT | Opcode::YIELD => "control",
+    }
+}
+
+pub struct Profiler {
+    offsets: Vec<u64>,
+    opcodes: Vec<u64>,
+    timed: bool,
+    times: BTreeMap<&'static str, Duration>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            offsets: Vec::new(),
+            opcodes: vec![0; OPCODE_COUNT],
+            timed: false,
+            times: BTreeMap::new(),
+        }
+    }
+
+    // also measure wall time per opcode class, at the cost of reading the clock every instruction
+    pub fn with_timing(mut self) -> Profiler {
+        self.timed = true;
+        self
+    }
+
+    pub fn is_timed(&self) -> bool {
+        self.timed
+    }
+
+    pub fn count(&mut self, code: &Code, offset: usize, elapsed: Option<Duration>) {
+        if self.offsets.len() < code.raw.len() {
+            self.offsets.resize(code.raw.len(), 0);
+        }
+        self.offsets[offset] += 1;
+        let opcode = code.raw[offset];
+        self.opcodes[opcode as usize] += 1;
+        if let Some(elapsed) = elapsed {
+            *self.times.entry(opcode_class(opcode.into())).or_default() += elapsed;
+        }
+    }
+
+    pub fn offset_count(&self, offset: usize) -> u64 {
+        self.offsets.get(offset).copied().unwrap_or(0)
+    }
+
+    pub fn opcode_count(&self, opcode: Opcode) -> u64 {
+        self.opcodes[opcode as usize]
+    }
+
+    pub fn total(&self) -> u64 {
+        self.opcodes.iter().sum()
+    }
+
+    // executions per source line, highest first
+    pub fn lines(&self, code: &Code) -> Vec<(usize, u64)> {
+        let mut lines: BTreeMap<usize, u64> = BTreeMap::new();
+        for (offset, &count) in self.offsets.iter().enumerate() {
+            if count > 0 {
+                *lines.entry(code.lines[offset]).or_default() += count;
+            }
+        }
+        let mut lines: Vec<(usize, u64)> = lines.into_iter().collect();
+        lines.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        lines
+    }
+
+    pub fn times(&self) -> &BTreeMap<&'static str, Duration> {
+        &self.times
+    }
+
+    pub fn report(&self, code: &Code, top: usize) -> String {
+        let mut out = String::new();
+        let total = self.total().max(1) as f64;
+        writeln!(out, "{} instructions executed", self.total()).unwrap();
+
+        writeln!(out, "\nTop lines:").unwrap();
+        writeln!(out, "{:>8} {:>12} {:>7}", "line", "count", "%").unwrap();
+        for (line, count) in self.lines(code).into_iter().take(top) {
+            writeln!(out, "{:>8} {:>12} {:>6.1}%", line, count, count as f64 * 100.0 / total).unwrap();
+        }
+
+        writeln!(out, "\nOpcodes:").unwrap();
+        let mut opcodes: Vec<(Opcode, u64)> = (0..OPCODE_COUNT)
+            .filter(|&byte| self.opcodes[byte] > 0)
+            .map(|byte| (Opcode::from(byte as u8), self.opcodes[byte]))
+            .collect();
+        opcodes.sort_by_key(|o| std::cmp::Reverse(o.1));
+        let widest = opcodes.first().map(|o| o.1).unwrap_or(1) as f64;
+        for (opcode, count) in opcodes {
+            let bar = "#".repeat((count as f64 * 40.0 / widest).ceil() as usize);
+            writeln!(out, "{:>8} {:>12} {}", opcode.to_string(), count, bar).unwrap();
+        }
+
+        if self.timed {
+            writeln!(out, "\nTime per opcode class:").unwrap();
+            for (class, time) in &self.times {
+                writeln!(out, "{:>12} {:>12?}", class, time).unwrap();
+            }
+        }
+
+        writeln!(out, "\nListing:").unwrap();
+        let mut offset = 0;
+        while offset < code.raw.len() {
+            let count = match self.offset_count(offset) {
+                0 => String::new(),
+                count => count.to_string(),
+            };
+            writeln!(out, "{:>12} | {} {} {}", count, offset, code.lines[offset], code.format_instruction(offset)).unwrap();
+            offset = code.next_instruction(offset);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{machine::Machine, reader::assemble};
+
+    // a loop on lines 4 and 5 that never halts, stepped a fixed number of times
+    fn profile(steps: usize) -> (Machine, Profiler) {
+        let mut machine = Machine::new();
+        machine.load(assemble("CONST 1\nCONST 2\nLOAD 0 0\nADD 1 0 0\nJMP 3\n")).unwrap();
+        machine.set_profiler(Profiler::new().with_timing());
+        for _ in 0..steps {
+            machine.step();
+        }
+        let profiler = machine.take_profiler().unwrap();
+        (machine, profiler)
+    }
+
+    #[test]
+    fn test_counts() {
+        let (machine, profiler) = profile(21);
+        assert_eq!(profiler.total(), 21);
+        assert_eq!(profiler.offset_count(0), 1);
+        assert_eq!(profiler.offset_count(3), 10);
+        assert_eq!(profiler.opcode_count(Opcode::JMP), 10);
+        assert_eq!(profiler.lines(machine.code()), vec![(4, 10), (5, 10), (3, 1)]);
+        assert!(profiler.times().contains_key("arithmetic"));
+    }
+
+    #[test]
+    fn test_report() {
+        let (machine, profiler) = profile(21);
+        let report = profiler.report(machine.code(), 2);
+        assert!(report.starts_with("21 instructions executed\n"));
+        assert!(report.contains("       4           10   47.6%\n       5           10   47.6%\n\nOpcodes:"));
+        assert!(report.contains("          10 | 3 4 ADD $1 $0 $0\n"));
+        assert!(report.contains("           1 | 0 3 LOAD $0 1\n"));
+    }
+}
@@ -1,37 +1,82 @@
 // module for decoding assembly instructions into bytecode
 use std::str::FromStr;
-use crate::{Opcode, Value, Code};
+use crate::{Opcode, Value, Code, host::Hosts, value::ValueType, math};
 
 fn split_lines(src: &str) -> Vec<&str> {
     src.split('\n').collect()
 }
 
+// offset just past the closing quote of the quoted word at the start of rest, skipping escapes.
+// the word is a string, a char in single quotes or a byte string with a b before its quote
+fn quoted_end(rest: &str) -> usize {
+    let start = usize::from(rest.starts_with('b'));
+    let quote = rest[start..].chars().next().expect("quoted word starts with a quote");
+    let mut escaped = false;
+    for (i, c) in rest.char_indices().skip(start + 1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            c if c == quote => return i + 1,
+            _ => {}
+        }
+    }
+    rest.len()
+}
+
+// words are separated by whitespace or commas, a quoted word may contain either
 fn split_words(line: &str) -> Vec<&str> {
-    line.split_whitespace().collect()
+    let mut words = Vec::new();
+    let mut rest = line;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+        if rest.is_empty() {
+            return words;
+        }
+        let end = match rest.starts_with(['"', '\'']) || rest.starts_with("b\"") {
+            true => quoted_end(rest),
+            false => rest.find(|c: char| c.is_whitespace() || c == ',').unwrap_or(rest.len()),
+        };
+        words.push(&rest[..end]);
+        rest = &rest[end..];
+    }
 }
 
 #[derive(Debug, PartialEq)]
 enum Chunk {
     Byte(u8),
     Value(Value),
+    Host(String),
+    // a function declared with .function
+    Name(String),
 }
 
 // only needs to parse chunks that are not values
 fn parse_chunk(chunk: &str) -> Chunk {
     if let Ok(instr) = Opcode::from_str(chunk) {
         Chunk::Byte(instr as u8)
-    }  else if let Ok(byte) = chunk.parse::<u8>() {
+    }  else if let Ok(byte) = chunk.trim_start_matches('$').parse::<u8>() {
         Chunk::Byte(byte)
+    } else if let Ok(ty) = ValueType::from_str(chunk) {
+        Chunk::Byte(ty as u8)
+    } else if let Some(name) = chunk.strip_prefix('"').and_then(|chunk| chunk.strip_suffix('"')) {
+        Chunk::Host(name.to_string())
+    } else if is_name(chunk) {
+        Chunk::Name(chunk.to_string())
     } else {
         panic!("Invalid chunk: {}", chunk);
     }
 }
 
+fn is_name(word: &str) -> bool {
+    word.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && word.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
 type Line = (Opcode, Vec<Chunk>, usize);
 
 fn parse_line(line: &str, ln: usize) -> Line {
     let mut words = split_words(line);
-    if words.len() == 0 {
+    if words.is_empty() {
         return (Opcode::HALT, vec![], ln);
     }
     let instr = words.remove(0);
@@ -48,15 +93,123 @@ fn parse_line(line: &str, ln: usize) -> Line {
     }
 }
 
-fn parse_lines(lines: Vec<&str>) -> Vec<Line> {
-    lines.iter().enumerate().map(|line| parse_line(line.1, line.0)).collect()
+enum Statement {
+    Instruction(Line),
+    // .global <value> declares the next global with its initial value
+    Global(Value),
+    // .function <name> <arity> <registers> declares a function starting at the next instruction
+    Function(String, u8, u8),
+}
+
+fn parse_statement(line: &str, ln: usize) -> Statement {
+    match split_words(line).as_slice() {
+        [".global", value] => Statement::Global(Value::from_str(value).unwrap()),
+        [".global", ..] => panic!("Invalid global on line {}: {}", ln, line),
+        [".function", name, arity, registers] if is_name(name) => match (arity.parse(), registers.parse()) {
+            (Ok(arity), Ok(registers)) => Statement::Function(name.to_string(), arity, registers),
+            _ => panic!("Invalid function on line {}: {}", ln, line),
+        },
+        [".function", ..] => panic!("Invalid function on line {}: {}", ln, line),
+        _ => Statement::Instruction(parse_line(line, ln)),
+    }
 }
 
+fn parse_lines(lines: Vec<&str>) -> Vec<Statement> {
+    lines.iter()
+        .enumerate()
+        .filter(|line| !line.1.trim().is_empty())
+        .map(|line| parse_statement(line.1, line.0 + 1))
+        .collect()
+}
 
 
-fn assemble_line(line: Line, code: &mut Code) {
+
+fn host_index(chunk: &Chunk, hosts: &Hosts) -> u8 {
+    match chunk {
+        Chunk::Byte(index) => *index,
+        Chunk::Host(name) => hosts.index(name).unwrap_or_else(|| panic!("Unknown host function: {}", name)),
+        _ => panic!("Invalid host function: {:?}", chunk),
+    }
+}
+
+// functions are numbered in the order they are declared, so CLOSURE can name one declared later
+fn function_index(chunk: &Chunk, functions: &[String]) -> u8 {
+    match chunk {
+        Chunk::Byte(index) => *index,
+        Chunk::Name(name) => match functions.iter().position(|function| function == name) {
+            Some(index) => index as u8,
+            None => panic!("Unknown function: {}", name),
+        },
+        _ => panic!("Invalid function: {:?}", chunk),
+    }
+}
+
+// globals are numbered in the order they are declared, like functions
+fn global_index(chunk: &Chunk, globals: usize) -> u8 {
+    match chunk {
+        Chunk::Byte(index) if (*index as usize) < globals => *index,
+        Chunk::Byte(index) => panic!("Unknown global: {}", index),
+        _ => panic!("Invalid global: {:?}", chunk),
+    }
+}
+
+fn register(chunk: &Chunk) -> u8 {
+    match chunk {
+        Chunk::Byte(reg) => *reg,
+        _ => panic!("Invalid register: {:?}", chunk),
+    }
+}
+
+// CALLHOST $dst host [$first [$last]] is encoded with the argument range as first and count,
+// and so are CLOSURE $dst function and CALL $dst $closure with the same trailing range
+fn assemble_range(opcode: Opcode, callee: u8, chunks: &[Chunk], ln: usize, code: &mut Code) {
+    let (first, count) = match chunks {
+        [_, _] => (0, 0),
+        [_, _, first] => (register(first), 1),
+        [_, _, first, last] if register(last) >= register(first) => {
+            (register(first), register(last) - register(first) + 1)
+        }
+        _ => panic!("Invalid operands for {}: {:?}", opcode, chunks),
+    };
+    for byte in [opcode as u8, register(&chunks[0]), callee, first, count] {
+        code.write_code(byte, ln);
+    }
+}
+
+// MATH $dst function $a [$b], the second source only for binary functions and zero otherwise
+fn assemble_math(chunks: &[Chunk], ln: usize, code: &mut Code) {
+    let selector = match chunks.get(1) {
+        Some(Chunk::Name(name)) => math::selector(name).unwrap_or_else(|| panic!("Unknown math function: {}", name)),
+        _ => panic!("Invalid operands for MATH: {:?}", chunks),
+    };
+    let b = match (math::is_binary(selector), chunks) {
+        (false, [_, _, _]) => 0,
+        (true, [_, _, _, b]) => register(b),
+        _ => panic!("Invalid operands for MATH: {:?}", chunks),
+    };
+    for byte in [Opcode::MATH as u8, register(&chunks[0]), selector, register(&chunks[2]), b] {
+        code.write_code(byte, ln);
+    }
+}
+
+fn assemble_line(line: Line, code: &mut Code, hosts: &Hosts, functions: &[String], globals: usize) {
     let (opcode, chunks, ln) = line;
+    if chunks.len() < 2 && matches!(opcode, Opcode::CALLHOST | Opcode::CLOSURE | Opcode::CALL) {
+        panic!("Invalid operands for {}: {:?}", opcode, chunks);
+    }
     match opcode {
+        Opcode::CALLHOST => assemble_range(opcode, host_index(&chunks[1], hosts), &chunks, ln, code),
+        Opcode::CLOSURE => assemble_range(opcode, function_index(&chunks[1], functions), &chunks, ln, code),
+        Opcode::CALL => assemble_range(opcode, register(&chunks[1]), &chunks, ln, code),
+        Opcode::MATH => assemble_math(&chunks, ln, code),
+        Opcode::GETGLOBAL | Opcode::SETGLOBAL => match chunks.as_slice() {
+            [reg, global] => {
+                for byte in [opcode as u8, register(reg), global_index(global, globals)] {
+                    code.write_code(byte, ln);
+                }
+            }
+            _ => panic!("Invalid operands for {}: {:?}", opcode, chunks),
+        },
         Opcode::CONST => {
             match chunks[0] {
                 Chunk::Value(v) => code.add_const(v),
@@ -71,6 +224,8 @@ fn assemble_line(line: Line, code: &mut Code) {
             for chunk in chunks {
                 match chunk {
                     Chunk::Byte(b) => code.write_code(b, ln),
+                    Chunk::Host(name) => panic!("Unexpected host function {} for {}", name, opcode),
+                    Chunk::Name(name) => panic!("Unexpected function {} for {}", name, opcode),
                     Chunk::Value(v) => {
                         let byte = code.add_const(v) as u8;
                         code.write_code(byte, ln)
@@ -81,19 +236,45 @@ fn assemble_line(line: Line, code: &mut Code) {
     };
 }
 
-fn assemble_lines(lines: Vec<Line>, code: &mut Code) {
-    for line in lines {
-        assemble_line(line, code);
+fn assemble_lines(statements: Vec<Statement>, code: &mut Code, hosts: &Hosts) {
+    let functions: Vec<String> = statements
+        .iter()
+        .filter_map(|statement| match statement {
+            Statement::Function(name, _, _) => Some(name.clone()),
+            _ => None,
+        })
+        .collect();
+    // CLOSURE takes the index as a byte
+    if functions.len() > u8::MAX as usize + 1 {
+        panic!("Too many functions: {}", functions.len());
     }
+    let globals = statements.iter().filter(|statement| matches!(statement, Statement::Global(_))).count();
+    for statement in statements {
+        match statement {
+            Statement::Instruction(line) => assemble_line(line, code, hosts, &functions, globals),
+            Statement::Global(value) => {
+                code.add_global(value);
+            }
+            Statement::Function(name, arity, registers) => {
+                if code.function_index(&name).is_some() {
+                    panic!("Duplicate function: {}", name);
+                }
+                code.add_function(&name, arity, registers);
+            }
+        }
+    }
+}
+
+pub fn assemble(src: &str) -> Code {
+    assemble_with_hosts(src, &Hosts::new())
 }
 
-fn assemble(src: &str) -> Code {
+// host function names in CALLHOST are resolved to the indices they are registered at
+pub fn assemble_with_hosts(src: &str, hosts: &Hosts) -> Code {
     let mut code = Code::new();
     let lines = split_lines(src);
     let parsed = parse_lines(lines);
-    assemble_lines(parsed, &mut code);
-    // get rid of terminating HALT
-    code.raw.pop();
+    assemble_lines(parsed, &mut code, hosts);
     code
 }
 
@@ -116,6 +297,138 @@ mod tests {
         assert_eq!(words, vec!["LOAD", "0", "1", "ADD", "0", "1", "HALT"]);
     }
 
+    #[test]
+    fn test_split_commas_and_quotes() {
+        let words = split_words("CALLHOST $0, \"log it\",$1, $2");
+        assert_eq!(words, vec!["CALLHOST", "$0", "\"log it\"", "$1", "$2"]);
+    }
+
+    #[test]
+    fn test_assemble_strings() {
+        let words = split_words("CONST \"say \\\"hi\\\", ok\\n\"");
+        assert_eq!(words, vec!["CONST", "\"say \\\"hi\\\", ok\\n\""]);
+        let code = assemble("CONST \"a, b\\n\"\n.global \"\"\nLOAD $0 0\nPARSE $1 $2 $0 str\nHALT\n");
+        assert_eq!(code.const_pool, vec![Value::from("a, b\n")]);
+        assert_eq!(code.globals, vec![Value::from("")]);
+        assert_eq!(code.format_instruction(0), "LOAD $0 \"a, b\\n\"");
+        assert_eq!(code.raw[3..], [Opcode::PARSE as u8, 1, 2, 0, ValueType::Str as u8, Opcode::HALT as u8]);
+        assert_eq!(code.format_instruction(3), "PARSE $1 $2 $0 str");
+    }
+
+    #[test]
+    fn test_assemble_chars_and_bytes() {
+        let words = split_words("CONST ' ', b\"a, \\\"b\" 'x' bare");
+        assert_eq!(words, vec!["CONST", "' '", "b\"a, \\\"b\"", "'x'", "bare"]);
+        let code = assemble("CONST '\\''\nCONST b\"a b\"\nLOAD $0 0\nCONVERT $1 $2 $0 char\nHALT\n");
+        assert_eq!(code.const_pool, vec![Value::Char('\''), Value::from(&b"a b"[..])]);
+        assert_eq!(code.format_instruction(0), "LOAD $0 '\\''");
+        assert_eq!(code.format_instruction(3), "CONVERT $1 $2 $0 char");
+    }
+
+    #[test]
+    fn test_assemble_callhost() {
+        let mut hosts = Hosts::new();
+        hosts.register("now", |_, _| Ok(Value::U64(0)));
+        hosts.register("log", |_, _| Ok(Value::Bool(true)));
+        let code = assemble_with_hosts("CALLHOST $3, \"log\", $1, $2\nCALLHOST $0 \"now\"\nHALT\n", &hosts);
+        assert_eq!(code.raw[..5], [Opcode::CALLHOST as u8, 3, 1, 1, 2]);
+        assert_eq!(code.raw[5..10], [Opcode::CALLHOST as u8, 0, 0, 0, 0]);
+        assert_eq!(code.format_instruction(0), "CALLHOST $3 1 $1 $2");
+        assert_eq!(assemble(&code.format_instruction(0)).raw, code.raw[..5]);
+    }
+
+    #[test]
+    fn test_assemble_read_and_branch() {
+        let code = assemble("READ $0 $1 f64\nJMPIF $1 0\nHALT\n");
+        assert_eq!(code.raw, vec![Opcode::READ as u8, 0, 1, ValueType::F64 as u8, Opcode::JMPIF as u8, 1, 0, Opcode::HALT as u8]);
+        assert_eq!(code.format_instruction(0), "READ $0 $1 f64");
+        assert_eq!(code.format_instruction(4), "JMPIF $1 0");
+        assert_eq!(code.verify(), Ok(()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown host function: log")]
+    fn test_assemble_unknown_host() {
+        assemble("CALLHOST $0 \"log\"\n");
+    }
+
+    #[test]
+    fn test_assemble_globals() {
+        let code = assemble(".global 0u32\n.global true\nGETGLOBAL $0 1\nSETGLOBAL $0 0\nHALT\n");
+        assert_eq!(code.globals, vec![Value::U32(0), Value::Bool(true)]);
+        assert_eq!(code.raw[..3], [Opcode::GETGLOBAL as u8, 0, 1]);
+        assert_eq!(code.lines[0], 3);
+        assert_eq!(code.format_instruction(3), "SETGLOBAL $0 0");
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown global: 3")]
+    fn test_assemble_undeclared_global() {
+        assemble("GETGLOBAL $0 3\nHALT\n.global 1\n");
+    }
+
+    #[test]
+    fn test_assemble_functions() {
+        let code = assemble("CLOSURE $0 add $1 $2\nCALL $3 $0 $4\nHALT\n.function add 2 3\nRET $0\n");
+        assert_eq!(code.functions[0].entry, 11);
+        assert_eq!(code.raw[..10], [Opcode::CLOSURE as u8, 0, 0, 1, 2, Opcode::CALL as u8, 3, 0, 4, 1]);
+        assert_eq!(code.format_instruction(0), "CLOSURE $0 add $1 $2");
+        assert_eq!(code.format_instruction(5), "CALL $3 $0 $4 $4");
+        assert_eq!(code.format_function(11).unwrap(), ".function add 2 3");
+        assert_eq!(code.verify(), Ok(()));
+
+        let mut code = assemble("HALT\n.function f 1 0\nRET $0\n");
+        assert_eq!(code.verify().err().unwrap(), "Function f has 0 registers for 1 arguments");
+        code.functions[0].entry = 2;
+        assert_eq!(code.verify().err().unwrap(), "Function f at 2 does not start on an instruction");
+
+        let mut code = assemble("HALT\n.function f 0 0\nRET $0\n");
+        code.add_function("f", 0, 0);
+        assert_eq!(code.verify().err().unwrap(), "Duplicate function f");
+    }
+
+    #[test]
+    #[should_panic(expected = "Duplicate function: f")]
+    fn test_assemble_duplicate_function() {
+        assemble("HALT\n.function f 0 0\nRET $0\n.function f 0 0\nRET $0\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "Too many functions: 257")]
+    fn test_assemble_too_many_functions() {
+        let functions: String = (0..257).map(|i| format!(".function f{} 0 0\nRET $0\n", i)).collect();
+        assemble(&format!("HALT\n{}", functions));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown function: sub")]
+    fn test_assemble_unknown_function() {
+        assemble("CLOSURE $0 sub\nHALT\n");
+    }
+
+    #[test]
+    fn test_assemble_math() {
+        let code = assemble("MATH $1 sqrt $0\nMATH $2 atan2 $0 $1\nHALT\n");
+        let (sqrt, atan2) = (math::selector("sqrt").unwrap(), math::selector("atan2").unwrap());
+        assert_eq!(code.raw[..10], [Opcode::MATH as u8, 1, sqrt, 0, 0, Opcode::MATH as u8, 2, atan2, 0, 1]);
+        assert_eq!(code.format_instruction(0), "MATH $1 sqrt $0");
+        assert_eq!(code.format_instruction(5), "MATH $2 atan2 $0 $1");
+        assert_eq!(code.verify(), Ok(()));
+        // every function in the table round trips through the assembler and the disassembler
+        for (selector, (name, _)) in math::FUNCTIONS.iter().enumerate() {
+            let operands = if math::is_binary(selector as u8) { "$2 $3" } else { "$2" };
+            let code = assemble(&format!("MATH $1 {} {}\n", name, operands));
+            assert_eq!(code.raw[2], selector as u8);
+            assert_eq!(assemble(&code.format_instruction(0)).raw, code.raw);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid operands for MATH")]
+    fn test_assemble_math_arity() {
+        assemble("MATH $1 sqrt $0 $1\nHALT\n");
+    }
+
     #[test]
     fn test_parse_line() {
         let line = "LOAD 0 1";
@@ -150,6 +463,6 @@ mod tests {
     fn test_assemble3() {
         let src = "CONST 0\nLOAD 0 1\nHALT\n";
         let code = assemble(src);
-        assert_eq!(code.raw, vec![Opcode::LOAD as u8, 0, 1, 17]);
+        assert_eq!(code.raw, vec![Opcode::LOAD as u8, 0, 1, Opcode::HALT as u8]);
     }
 }
\ No newline at end of file
@@ -0,0 +1,239 @@
+// storage for the machine's registers. Plain keeps every register as a Value, Packed NaN-boxes the
+// common types into 8 bytes a register and spills the rest to a side table with a slot for each register
+// that needed one. the nanbox feature makes the machine use Packed, either way registers go in and out as Values
+use std::ops::Range;
+use crate::{bigint::Big, heap::Ref, machine::REGISTER_MAX, string::{Bytes, Str}, value::{Value, ValueType}};
+
+pub trait RegisterFile {
+    // every register starts as u8 0
+    fn new() -> Self;
+
+    fn get(&self, reg: usize) -> Value;
+
+    fn set(&mut self, reg: usize, value: Value);
+
+    // MOVE, which a representation can do without going through a Value
+    fn copy(&mut self, dst: usize, src: usize) {
+        self.set(dst, self.get(src));
+    }
+
+    fn read(&self, range: Range<usize>) -> Vec<Value> {
+        range.map(|reg| self.get(reg)).collect()
+    }
+
+    fn write(&mut self, start: usize, values: &[Value]) {
+        for (offset, value) in values.iter().enumerate() {
+            self.set(start + offset, *value);
+        }
+    }
+}
+
+#[cfg(not(feature = "nanbox"))]
+pub type Registers = Plain;
+
+#[cfg(feature = "nanbox")]
+pub type Registers = Packed;
+
+pub struct Plain([Value; REGISTER_MAX]);
+
+impl RegisterFile for Plain {
+    fn new() -> Plain {
+        Plain([Value::U8(0); REGISTER_MAX])
+    }
+
+    fn get(&self, reg: usize) -> Value {
+        self.0[reg]
+    }
+
+    fn set(&mut self, reg: usize, value: Value) {
+        self.0[reg] = value;
+    }
+
+    fn read(&self, range: Range<usize>) -> Vec<Value> {
+        self.0[range].to_vec()
+    }
+
+    fn write(&mut self, start: usize, values: &[Value]) {
+        self.0[start..start + values.len()].copy_from_slice(values);
+    }
+}
+
+// 8 bytes: an f64 is its own bits, any other value that fits in 32 bits is the payload of a negative
+// quiet NaN with its ValueType in the five bits above it. 64 and 128-bit integers do not fit, and
+// neither do the f64 NaNs whose bits would read back as a box
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Boxed(u64);
+
+const QUIET_NAN: u64 = 0xFFF8 << 48;
+const TAG_SHIFT: u32 = 46;
+// a tag no ValueType has, marks a register whose value is in Packed's side table at the slot in the payload
+const SPILLED: u64 = QUIET_NAN | 0x1F << TAG_SHIFT;
+
+impl Boxed {
+    pub fn new(value: Value) -> Option<Boxed> {
+        let payload = match value {
+            Value::F64(f) if f.to_bits() & QUIET_NAN == QUIET_NAN => return None,
+            Value::F64(f) => return Some(Boxed(f.to_bits())),
+            Value::Bool(b) => b as u32,
+            Value::I8(i) => i as u32,
+            Value::I16(i) => i as u32,
+            Value::I32(i) => i as u32,
+            Value::U8(u) => u as u32,
+            Value::U16(u) => u as u32,
+            Value::U32(u) => u,
+            Value::F32(f) => f.to_bits(),
+            Value::Str(s) => s.id(),
+            Value::Ref(object) => object.index(),
+            Value::Big(big) => big.id(),
+            Value::Char(c) => c as u32,
+            Value::Bytes(bytes) => bytes.id(),
+            Value::I64(_) | Value::U64(_) | Value::I128(_) | Value::U128(_) => return None,
+        };
+        Some(Boxed(QUIET_NAN | (value.value_type() as u64) << TAG_SHIFT | payload as u64))
+    }
+
+    pub fn get(self) -> Value {
+        if self.0 & QUIET_NAN != QUIET_NAN {
+            return Value::F64(f64::from_bits(self.0));
+        }
+        let payload = self.0 as u32;
+        match ValueType::from_byte((self.0 >> TAG_SHIFT & 0x1F) as u8) {
+            Some(ValueType::Bool) => Value::Bool(payload != 0),
+            Some(ValueType::I8) => Value::I8(payload as i8),
+            Some(ValueType::I16) => Value::I16(payload as i16),
+            Some(ValueType::I32) => Value::I32(payload as i32),
+            Some(ValueType::U8) => Value::U8(payload as u8),
+            Some(ValueType::U16) => Value::U16(payload as u16),
+            Some(ValueType::U32) => Value::U32(payload),
+            Some(ValueType::F32) => Value::F32(f32::from_bits(payload)),
+            Some(ValueType::Str) => Value::Str(Str::from_id(payload)),
+            Some(ValueType::Ref) => Value::Ref(Ref::new(payload)),
+            Some(ValueType::Big) => Value::Big(Big::from_id(payload)),
+            Some(ValueType::Char) => Value::Char(char::from_u32(payload).expect("boxed from a char")),
+            Some(ValueType::Bytes) => Value::Bytes(Bytes::from_id(payload)),
+            _ => unreachable!("{:#x} was not made by Boxed::new", self.0),
+        }
+    }
+
+    fn spilled(slot: usize) -> Boxed {
+        Boxed(SPILLED | slot as u64)
+    }
+
+    // the side table slot of a spilled value, None for a value in the box
+    fn slot(self) -> Option<usize> {
+        (self.0 >> 32 == SPILLED >> 32).then_some(self.0 as u32 as usize)
+    }
+}
+
+pub struct Packed {
+    boxes: [Boxed; REGISTER_MAX],
+    // the values of spilled registers. a register keeps the slot it first spilled to, so the table only
+    // grows with the registers that ever held a value too wide for a box
+    spilled: Vec<Value>,
+    // the slot of each register in spilled, NO_SLOT until it spills
+    slots: [u8; REGISTER_MAX],
+}
+
+// there are fewer registers than slot numbers, so one is left over to mark no slot
+const NO_SLOT: u8 = u8::MAX;
+
+impl Packed {
+    #[cold]
+    fn spill(&mut self, reg: usize, value: Value) {
+        if self.slots[reg] == NO_SLOT {
+            self.slots[reg] = self.spilled.len() as u8;
+            self.spilled.push(value);
+        }
+        let slot = self.slots[reg] as usize;
+        self.spilled[slot] = value;
+        self.boxes[reg] = Boxed::spilled(slot);
+    }
+}
+
+impl RegisterFile for Packed {
+    fn new() -> Packed {
+        Packed {
+            boxes: [Boxed::new(Value::U8(0)).unwrap(); REGISTER_MAX],
+            spilled: Vec::new(),
+            slots: [NO_SLOT; REGISTER_MAX],
+        }
+    }
+
+    fn get(&self, reg: usize) -> Value {
+        match self.boxes[reg].slot() {
+            Some(slot) => self.spilled[slot],
+            None => self.boxes[reg].get(),
+        }
+    }
+
+    fn set(&mut self, reg: usize, value: Value) {
+        match Boxed::new(value) {
+            Some(boxed) => self.boxes[reg] = boxed,
+            None => self.spill(reg, value),
+        }
+    }
+
+    // a spilled value is copied into the destination's own slot
+    fn copy(&mut self, dst: usize, src: usize) {
+        self.boxes[dst] = self.boxes[src];
+        if let Some(slot) = self.boxes[src].slot() {
+            self.spill(dst, self.spilled[slot]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boxed() {
+        let values = [
+            Value::Bool(true),
+            Value::I8(-1),
+            Value::I16(i16::MIN),
+            Value::I32(-5),
+            Value::U32(u32::MAX),
+            Value::F32(f32::NAN),
+            Value::F64(f64::NAN),
+            Value::F64(-0.0),
+            Value::F64(f64::NEG_INFINITY),
+            Value::from("boxed"),
+            Value::Ref(Ref::new(7)),
+            Value::Char('\u{10FFFF}'),
+            Value::from(&b"\xff"[..]),
+            "12345678901234567890123456789012345678901234567890".parse().unwrap(),
+        ];
+        for value in values {
+            let boxed = Boxed::new(value).unwrap();
+            // canonical equality, so floats come back with the same bits
+            assert_eq!(boxed.get(), value);
+        }
+        assert_eq!(std::mem::size_of::<Boxed>(), 8);
+        for value in [Value::I64(1), Value::U64(1), Value::from(1i128), Value::F64(-f64::NAN)] {
+            assert_eq!(Boxed::new(value), None);
+        }
+    }
+
+    #[test]
+    fn test_packed_spills() {
+        let mut registers = Packed::new();
+        registers.set(3, Value::I64(-2));
+        registers.copy(4, 3);
+        registers.set(3, Value::F64(-f64::NAN));
+        registers.set(3, Value::U8(9));
+        assert_eq!(registers.read(2..5), [Value::U8(0), Value::U8(9), Value::I64(-2)]);
+        registers.write(0, &[Value::F64(-f64::NAN), Value::I8(1)]);
+        assert_eq!(registers.get(0).to_le_bytes(), (-f64::NAN).to_le_bytes());
+        assert_eq!(registers.get(1), Value::I8(1));
+
+        // a register reuses its slot, so the side table only has one for each register that spilled
+        for reg in 0..REGISTER_MAX {
+            registers.set(reg % 3, Value::U64(reg as u64));
+        }
+        registers.copy(5, 4);
+        assert_eq!(registers.read(0..6), [252, 253, 254].map(Value::U64).into_iter().chain([Value::U8(9), Value::I64(-2), Value::I64(-2)]).collect::<Vec<_>>());
+        assert_eq!(registers.spilled.len(), 6);
+        assert!(size_of::<Packed>() < size_of::<Plain>() / 2);
+    }
+}
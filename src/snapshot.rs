@@ -0,0 +1,445 @@
+// versioned binary encoding of the program visible machine state, so a run can be saved and continued later
+use crate::{bigint::BigInt, code::Code, function::{Frame, Function}, heap::{Object, Ref}, machine::REGISTER_MAX, value::Value};
+
+const MAGIC: &[u8; 4] = b"TWRS";
+// version 2 added linear memory, version 3 globals, version 4 the stack, version 5 the heap
+// and version 6 functions and call frames, older snapshots restore with them empty.
+// version 7 added strings to the heap, version 8 big integers and version 9 byte strings
+pub const VERSION: u16 = 9;
+
+// everything needed to continue execution, tooling such as breakpoints and fuel is left to the host
+pub struct State {
+    pub registers: [Value; REGISTER_MAX],
+    pub pc: usize,
+    pub code: Code,
+    pub memory: Vec<u8>,
+    pub globals: Vec<Value>,
+    pub stack: Vec<Value>,
+    // heap slots by index, None for a free slot
+    pub heap: Vec<Option<Object>>,
+    pub frames: Vec<Frame>,
+}
+
+// the same state borrowed from a running machine, which is what gets written
+pub struct StateRef<'a> {
+    pub registers: &'a [Value],
+    pub pc: usize,
+    pub code: &'a Code,
+    pub memory: &'a [u8],
+    pub globals: &'a [Value],
+    pub stack: &'a [Value],
+    pub heap: &'a [Option<Object>],
+    pub frames: &'a [Frame],
+}
+
+impl State {
+    pub fn borrow(&self) -> StateRef<'_> {
+        StateRef {
+            registers: &self.registers,
+            pc: self.pc,
+            code: &self.code,
+            memory: &self.memory,
+            globals: &self.globals,
+            stack: &self.stack,
+            heap: &self.heap,
+            frames: &self.frames,
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .offset
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or(format!("Snapshot truncated at {}", self.offset))?;
+        let bytes = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn values(&mut self) -> Result<Vec<Value>, String> {
+        (0..self.u32()?).map(|_| self.value()).collect()
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        let (value, len) = Value::decode(&self.bytes[self.offset.min(self.bytes.len())..])
+            .map_err(|e| format!("{} at {}", e, self.offset))?;
+        self.offset += len;
+        Ok(value)
+    }
+}
+
+const FREE: u8 = 0;
+const ARRAY: u8 = 1;
+const RECORD: u8 = 2;
+const CLOSURE: u8 = 3;
+const STR: u8 = 4;
+const BIG: u8 = 5;
+const BYTES: u8 = 6;
+
+fn write_values(out: &mut Vec<u8>, values: &[Value]) {
+    out.extend_from_slice(&(values.len() as u32).to_le_bytes());
+    for value in values {
+        value.encode(out);
+    }
+}
+
+pub fn write(state: &StateRef) -> Vec<u8> {
+    let StateRef { registers, pc, code, memory, globals, stack, heap, frames } = *state;
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&(pc as u64).to_le_bytes());
+    write_values(&mut out, registers);
+    out.extend_from_slice(&(code.raw.len() as u32).to_le_bytes());
+    out.extend_from_slice(&code.raw);
+    for &line in &code.lines {
+        out.extend_from_slice(&(line as u64).to_le_bytes());
+    }
+    write_values(&mut out, &code.const_pool);
+    out.extend_from_slice(&(memory.len() as u64).to_le_bytes());
+    out.extend_from_slice(memory);
+    // initial values in the code, then the current ones
+    write_values(&mut out, &code.globals);
+    write_values(&mut out, globals);
+    write_values(&mut out, stack);
+    out.extend_from_slice(&(heap.len() as u32).to_le_bytes());
+    for slot in heap {
+        match slot {
+            None => out.push(FREE),
+            Some(Object::Array(values)) => {
+                out.push(ARRAY);
+                write_values(&mut out, values);
+            }
+            Some(Object::Record(values)) => {
+                out.push(RECORD);
+                write_values(&mut out, values);
+            }
+            Some(Object::Closure(function, values)) => {
+                out.push(CLOSURE);
+                out.push(*function);
+                write_values(&mut out, values);
+            }
+            Some(Object::Str(s, _)) => {
+                out.push(STR);
+                out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+                out.extend_from_slice(s.as_bytes());
+            }
+            Some(Object::Bytes(bytes)) => {
+                out.push(BYTES);
+                out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+                out.extend_from_slice(bytes);
+            }
+            Some(Object::Big(big)) => {
+                let bytes = big.to_le_bytes();
+                out.push(BIG);
+                out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+                out.extend_from_slice(&bytes);
+            }
+        }
+    }
+    out.extend_from_slice(&(code.functions.len() as u32).to_le_bytes());
+    for function in &code.functions {
+        out.extend_from_slice(&(function.name.len() as u32).to_le_bytes());
+        out.extend_from_slice(function.name.as_bytes());
+        out.extend_from_slice(&(function.entry as u64).to_le_bytes());
+        out.extend_from_slice(&[function.arity, function.registers]);
+    }
+    out.extend_from_slice(&(frames.len() as u32).to_le_bytes());
+    for frame in frames {
+        out.extend_from_slice(&(frame.return_pc as u64).to_le_bytes());
+        out.push(frame.dst);
+        out.extend_from_slice(&frame.closure.index().to_le_bytes());
+        write_values(&mut out, &frame.saved);
+    }
+    out
+}
+
+pub fn read(bytes: &[u8]) -> Result<State, String> {
+    let mut reader = Reader { bytes, offset: 0 };
+    if reader.take(4).ok() != Some(MAGIC.as_slice()) {
+        return Err("Not a tower snapshot".to_string());
+    }
+    let version = reader.u16()?;
+    if version == 0 || version > VERSION {
+        return Err(format!("Unsupported snapshot version {}", version));
+    }
+    let pc = reader.u64()? as usize;
+
+    let count = reader.u32()? as usize;
+    if count != REGISTER_MAX {
+        return Err(format!("Snapshot has {} registers, expected {}", count, REGISTER_MAX));
+    }
+    let mut registers = [Value::U8(0); REGISTER_MAX];
+    for register in registers.iter_mut() {
+        *register = reader.value()?;
+    }
+
+    let mut code = Code::new();
+    let len = reader.u32()? as usize;
+    code.raw = reader.take(len)?.to_vec();
+    for _ in 0..len {
+        code.lines.push(reader.u64()? as usize);
+    }
+    let count = reader.u32()?;
+    for _ in 0..count {
+        code.const_pool.push(reader.value()?);
+    }
+    let memory = match version {
+        1 => Vec::new(),
+        _ => {
+            let len = reader.u64()? as usize;
+            reader.take(len)?.to_vec()
+        }
+    };
+    let mut globals = Vec::new();
+    if version >= 3 {
+        for _ in 0..reader.u32()? {
+            code.globals.push(reader.value()?);
+        }
+        for _ in 0..reader.u32()? {
+            globals.push(reader.value()?);
+        }
+    }
+    let mut stack = Vec::new();
+    if version >= 4 {
+        for _ in 0..reader.u32()? {
+            stack.push(reader.value()?);
+        }
+    }
+    let mut heap = Vec::new();
+    if version >= 5 {
+        for _ in 0..reader.u32()? {
+            let offset = reader.offset;
+            heap.push(match reader.take(1)?[0] {
+                FREE => None,
+                ARRAY => Some(Object::Array(reader.values()?)),
+                RECORD => Some(Object::Record(reader.values()?)),
+                CLOSURE if version >= 6 => {
+                    let function = reader.take(1)?[0];
+                    Some(Object::Closure(function, reader.values()?))
+                }
+                STR if version >= 7 => {
+                    let len = reader.u32()? as usize;
+                    let s = String::from_utf8(reader.take(len)?.to_vec()).map_err(|_| format!("Invalid utf-8 in heap string at {}", offset))?;
+                    Some(Object::str(s))
+                }
+                BIG if version >= 8 => {
+                    let len = reader.u32()? as usize;
+                    Some(Object::Big(BigInt::from_le_bytes(reader.take(len)?)))
+                }
+                BYTES if version >= 9 => {
+                    let len = reader.u32()? as usize;
+                    Some(Object::Bytes(reader.take(len)?.to_vec()))
+                }
+                kind => return Err(format!("Invalid heap object kind {} at {}", kind, offset)),
+            });
+        }
+    }
+    let mut frames = Vec::new();
+    if version >= 6 {
+        for _ in 0..reader.u32()? {
+            let len = reader.u32()? as usize;
+            let name = String::from_utf8(reader.take(len)?.to_vec()).map_err(|_| "Invalid utf-8 in function name".to_string())?;
+            let entry = reader.u64()? as usize;
+            let (arity, registers) = (reader.take(1)?[0], reader.take(1)?[0]);
+            code.functions.push(Function { name, entry, arity, registers });
+        }
+        for _ in 0..reader.u32()? {
+            let return_pc = reader.u64()? as usize;
+            let dst = reader.take(1)?[0];
+            let closure = Ref::new(reader.u32()?);
+            frames.push(Frame { return_pc, dst, closure, saved: reader.values()? });
+        }
+    }
+    if reader.offset != bytes.len() {
+        return Err(format!("Trailing bytes in snapshot at {}", reader.offset));
+    }
+    // references may only point at live arrays, records and closures, and strings, byte strings and big
+    // integers at live objects of their own kind,
+    // and the code has neither since it outlives any heap
+    let live = |value: &Value| {
+        let object = value.as_object().map(|object| heap.get(object.index() as usize).and_then(Option::as_ref));
+        match (value, object) {
+            (_, None) => true,
+            (Value::Str(_), Some(object)) => matches!(object, Some(Object::Str(..))),
+            (Value::Bytes(_), Some(object)) => matches!(object, Some(Object::Bytes(_))),
+            (Value::Big(_), Some(object)) => matches!(object, Some(Object::Big(_))),
+            (_, Some(object)) => matches!(object, Some(Object::Array(_) | Object::Record(_) | Object::Closure(..))),
+        }
+    };
+    let frame_values = frames.iter().flat_map(|frame| frame.saved.iter());
+    let mut values = registers.iter().chain(&globals).chain(&stack).chain(heap.iter().flatten().flat_map(Object::values)).chain(frame_values);
+    if let Some(value) = values.find(|value| !live(value)) {
+        return Err(format!("Dangling reference {} in snapshot", value));
+    }
+    // closures must name a function in the code and frames must run a closure
+    for object in heap.iter().flatten() {
+        if let Object::Closure(function, _) = object {
+            if *function as usize >= code.functions.len() {
+                return Err(format!("Closure of unknown function {} in snapshot", function));
+            }
+        }
+    }
+    for frame in &frames {
+        if !matches!(heap.get(frame.closure.index() as usize), Some(Some(Object::Closure(..)))) {
+            return Err(format!("Frame without a closure {} in snapshot", Value::Ref(frame.closure)));
+        }
+    }
+    if let Some(value) = code.const_pool.iter().chain(&code.globals).find(|value| value.as_object().is_some()) {
+        return Err(format!("Reference {} in the code of a snapshot", value));
+    }
+    Ok(State { registers, pc, code, memory, globals, stack, heap, frames })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bigint::Big, string::{Bytes, Str}, value::ValueType};
+
+    fn snapshot() -> Vec<u8> {
+        let mut code = Code::new();
+        code.add_const(Value::F64(-0.5));
+        code.add_const(Value::U64(u64::MAX));
+        for (byte, line) in [(2, 1), (0, 1), (1, 1), (14, 2)] {
+            code.write_code(byte, line);
+        }
+        let mut registers = [Value::U8(0); REGISTER_MAX];
+        registers[3] = Value::I16(-300);
+        registers[254] = Value::Bool(true);
+        code.add_global(Value::I8(0));
+        code.add_function("f", 1, 2);
+        let heap = [
+            Some(Object::Array(vec![Value::I8(1)])),
+            None,
+            Some(Object::Record(vec![Value::Ref(Ref::new(0))])),
+            Some(Object::Closure(0, vec![
+                Value::I8(3),
+                Value::Str(Str::on_heap(Ref::new(4))),
+                Value::Big(Big::on_heap(Ref::new(5))),
+                Value::Bytes(Bytes::on_heap(Ref::new(6))),
+            ])),
+            Some(Object::str("hé".to_string())),
+            Some(Object::Big(BigInt::from(-5i128))),
+            Some(Object::Bytes(vec![0xFF])),
+        ];
+        let frames = [Frame {
+            return_pc: 3,
+            dst: 1,
+            closure: Ref::new(3),
+            saved: vec![Value::Ref(Ref::new(0))],
+        }];
+        write(&StateRef {
+            registers: &registers,
+            pc: 3,
+            code: &code,
+            memory: &[1, 2, 3],
+            globals: &[Value::I8(5)],
+            stack: &[Value::I8(7)],
+            heap: &heap,
+            frames: &frames,
+        })
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bytes = snapshot();
+        assert_eq!(&bytes[..6], b"TWRS\x09\x00");
+        let restored = read(&bytes).unwrap();
+        assert_eq!(restored.pc, 3);
+        assert_eq!(restored.registers[3], Value::I16(-300));
+        assert_eq!(restored.registers[254], Value::Bool(true));
+        assert_eq!(restored.code.raw, vec![2, 0, 1, 14]);
+        assert_eq!(restored.code.lines, vec![1, 1, 1, 2]);
+        assert_eq!(restored.code.const_pool, vec![Value::F64(-0.5), Value::U64(u64::MAX)]);
+        assert_eq!(restored.memory, vec![1, 2, 3]);
+        assert_eq!(restored.code.globals, vec![Value::I8(0)]);
+        assert_eq!(restored.globals, vec![Value::I8(5)]);
+        assert_eq!(restored.stack, vec![Value::I8(7)]);
+        assert_eq!(restored.heap[1], None);
+        assert_eq!(restored.heap[2], Some(Object::Record(vec![Value::Ref(Ref::new(0))])));
+        assert_eq!(restored.heap[4], Some(Object::str("hé".to_string())));
+        assert_eq!(restored.heap[3].as_ref().unwrap().values()[1], Value::Str(Str::on_heap(Ref::new(4))));
+        assert_eq!(restored.heap[5], Some(Object::Big(BigInt::from(-5i128))));
+        assert_eq!(restored.heap[6], Some(Object::Bytes(vec![0xFF])));
+        assert_eq!(restored.code.functions[0].name, "f");
+        assert_eq!(restored.frames[0].closure, Ref::new(3));
+        assert_eq!(write(&restored.borrow()), bytes);
+    }
+
+    #[test]
+    fn test_rejects_bad_snapshots() {
+        let bytes = snapshot();
+        assert_eq!(read(b"nope").err().unwrap(), "Not a tower snapshot");
+        let mut future = bytes.clone();
+        future[4] = 10;
+        assert_eq!(read(&future).err().unwrap(), "Unsupported snapshot version 10");
+        assert!(read(&bytes[..bytes.len() - 1]).err().unwrap().starts_with("Truncated ref value"));
+        // version 1 had nothing after the constant pool, version 2 nothing after memory
+        // version 3 nothing after the globals, version 4 nothing after the stack
+        // and version 5 nothing after the heap, which had no closures
+        let mut old = bytes[..bytes.len() - 45].to_vec();
+        old[4] = 5;
+        assert!(read(&old).err().unwrap().starts_with("Invalid heap object kind 3"));
+        let mut old = bytes[..bytes.len() - 122].to_vec();
+        old[4] = 4;
+        assert_eq!(read(&old).unwrap().stack, vec![Value::I8(7)]);
+        let mut old = bytes[..bytes.len() - 128].to_vec();
+        old[4] = 3;
+        assert_eq!(read(&old).unwrap().globals, vec![Value::I8(5)]);
+        let mut old = bytes[..bytes.len() - 140].to_vec();
+        old[4] = 2;
+        assert_eq!(read(&old).unwrap().memory, vec![1, 2, 3]);
+        let mut old = bytes[..bytes.len() - 151].to_vec();
+        old[4] = 1;
+        assert_eq!(read(&old).unwrap().memory, Vec::<u8>::new());
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(read(&trailing).err().unwrap().starts_with("Trailing bytes"));
+        let mut dangling = bytes.clone();
+        let last = dangling.len() - 4;
+        dangling[last] = 1;
+        assert_eq!(read(&dangling).err().unwrap(), "Dangling reference <ref 1> in snapshot");
+        // a reference to a string and string, big integer or byte string handles to other objects dangle too
+        dangling[last] = 4;
+        assert_eq!(read(&dangling).err().unwrap(), "Dangling reference <ref 4> in snapshot");
+        let mut dangling = bytes.clone();
+        let upvalue = dangling.windows(9).position(|window| window == [ValueType::Str as u8, 0xFF, 0xFF, 0xFF, 0xFF, 4, 0, 0, 0]).unwrap();
+        dangling[upvalue + 5] = 0;
+        assert_eq!(read(&dangling).err().unwrap(), "Dangling reference <str 0> in snapshot");
+        let mut dangling = bytes.clone();
+        let upvalue = dangling.windows(9).position(|window| window == [ValueType::Big as u8, 0xFF, 0xFF, 0xFF, 0xFF, 5, 0, 0, 0]).unwrap();
+        dangling[upvalue + 5] = 4;
+        assert_eq!(read(&dangling).err().unwrap(), "Dangling reference <big 4> in snapshot");
+        let mut dangling = bytes.clone();
+        let upvalue = dangling.windows(9).position(|window| window == [ValueType::Bytes as u8, 0xFF, 0xFF, 0xFF, 0xFF, 6, 0, 0, 0]).unwrap();
+        dangling[upvalue + 5] = 4;
+        assert_eq!(read(&dangling).err().unwrap(), "Dangling reference <bytes 4> in snapshot");
+        let mut huge_memory = bytes[..bytes.len() - 151].to_vec();
+        huge_memory[4] = 2;
+        huge_memory.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert!(read(&huge_memory).err().unwrap().starts_with("Snapshot truncated"));
+        let mut bad_type = bytes;
+        bad_type[18] = 99;
+        assert_eq!(read(&bad_type).err().unwrap(), "Invalid value type 99 at 18");
+    }
+}
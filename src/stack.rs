@@ -0,0 +1,110 @@
+// value stack for spilling registers and passing arguments, bounded by a depth the host sets
+use crate::value::Value;
+
+pub const DEFAULT_STACK_LIMIT: usize = 1 << 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stack {
+    values: Vec<Value>,
+    limit: usize,
+}
+
+impl Default for Stack {
+    fn default() -> Self {
+        Stack::new()
+    }
+}
+
+impl Stack {
+    pub fn new() -> Stack {
+        Stack::with_limit(DEFAULT_STACK_LIMIT)
+    }
+
+    pub fn with_limit(limit: usize) -> Stack {
+        Stack {
+            values: Vec::new(),
+            limit,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    // lowering the limit below the current depth only stops further pushes
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
+    // bottom first
+    pub fn values(&self) -> &[Value] {
+        &self.values
+    }
+
+    // used to undo instructions and restore snapshots, ignores the limit
+    pub fn values_mut(&mut self) -> &mut Vec<Value> {
+        &mut self.values
+    }
+
+    // false if the stack is already at its limit
+    pub fn push(&mut self, value: Value) -> bool {
+        if self.values.len() >= self.limit {
+            return false;
+        }
+        self.values.push(value);
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<Value> {
+        self.values.pop()
+    }
+
+    // index of the slot depth values below the top, 0 being the top
+    pub fn index(&self, depth: usize) -> Option<usize> {
+        self.values.len().checked_sub(depth + 1)
+    }
+
+    pub fn peek(&self, depth: usize) -> Option<Value> {
+        self.index(depth).map(|index| self.values[index])
+    }
+
+    pub fn set(&mut self, depth: usize, value: Value) -> bool {
+        match self.index(depth) {
+            Some(index) => {
+                self.values[index] = value;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_pop_peek() {
+        let mut stack = Stack::with_limit(2);
+        assert!(stack.push(Value::I8(1)));
+        assert!(stack.push(Value::I8(2)));
+        assert!(!stack.push(Value::I8(3)));
+        assert_eq!(stack.peek(0), Some(Value::I8(2)));
+        assert_eq!(stack.peek(1), Some(Value::I8(1)));
+        assert_eq!(stack.peek(2), None);
+        assert!(stack.set(1, Value::I8(5)));
+        assert!(!stack.set(2, Value::I8(5)));
+        assert_eq!(stack.pop(), Some(Value::I8(2)));
+        assert_eq!(stack.values(), &[Value::I8(5)]);
+        assert_eq!(stack.pop(), Some(Value::I8(5)));
+        assert_eq!(stack.pop(), None);
+    }
+}
@@ -0,0 +1,453 @@
+// interned strings and byte strings, so a string value is a small Copy handle and equal strings share
+// one handle. interned text lives for the rest of the process, so only constants are interned and
+// strings and byte strings built at run time are objects on the machine's heap that the collector frees
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Mutex, OnceLock};
+use crate::heap::Ref;
+
+// a handle with this bit set is not interned, the rest of it is the slot of its object on the heap
+pub(crate) const ON_HEAP: u32 = 1 << 31;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Str(u32);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Bytes(u32);
+
+struct Interner<T: ?Sized + 'static> {
+    ids: HashMap<&'static T, u32>,
+    values: Vec<&'static T>,
+}
+
+impl<T: ?Sized + Eq + Hash> Interner<T>
+where
+    for<'a> Box<T>: From<&'a T>,
+{
+    fn new() -> Interner<T> {
+        Interner {
+            ids: HashMap::new(),
+            values: Vec::new(),
+        }
+    }
+
+    fn intern(&mut self, value: &T) -> u32 {
+        if let Some(&id) = self.ids.get(value) {
+            return id;
+        }
+        let value: &'static T = Box::leak(Box::from(value));
+        let id = u32::try_from(self.values.len()).expect("Too many interned strings");
+        self.values.push(value);
+        self.ids.insert(value, id);
+        id
+    }
+}
+
+fn strings() -> &'static Mutex<Interner<str>> {
+    static STRINGS: OnceLock<Mutex<Interner<str>>> = OnceLock::new();
+    STRINGS.get_or_init(|| Mutex::new(Interner::new()))
+}
+
+fn byte_strings() -> &'static Mutex<Interner<[u8]>> {
+    static BYTE_STRINGS: OnceLock<Mutex<Interner<[u8]>>> = OnceLock::new();
+    BYTE_STRINGS.get_or_init(|| Mutex::new(Interner::new()))
+}
+
+// the char indexes of interned strings, made the first time a string is indexed
+fn char_indexes() -> &'static Mutex<HashMap<u32, &'static CharIndex>> {
+    static CHAR_INDEXES: OnceLock<Mutex<HashMap<u32, &'static CharIndex>>> = OnceLock::new();
+    CHAR_INDEXES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// every CHAR_STEP-th char has its byte offset in a CharIndex
+const CHAR_STEP: usize = 64;
+
+// the number of chars in a string and where some of them start, so the char at an index is found by
+// walking at most CHAR_STEP chars instead of the whole string. ascii needs no offsets
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CharIndex {
+    len: usize,
+    offsets: Vec<usize>,
+}
+
+impl CharIndex {
+    pub fn new(s: &str) -> CharIndex {
+        if s.is_ascii() {
+            return CharIndex { len: s.len(), offsets: Vec::new() };
+        }
+        let mut index = CharIndex::default();
+        for (offset, _) in s.char_indices() {
+            if index.len % CHAR_STEP == 0 {
+                index.offsets.push(offset);
+            }
+            index.len += 1;
+        }
+        index
+    }
+
+    // in chars
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // the byte offset in s, the string this indexes, of the char at the index. the index one past
+    // the last char gives the length of s, so offsets also bound substrings
+    pub fn offset(&self, s: &str, index: usize) -> Option<usize> {
+        if index >= self.len {
+            return (index == self.len).then_some(s.len());
+        }
+        if self.offsets.is_empty() {
+            return Some(index);
+        }
+        let start = self.offsets[index / CHAR_STEP];
+        s[start..].char_indices().nth(index % CHAR_STEP).map(|(offset, _)| start + offset)
+    }
+
+    // the bytes its offsets take up
+    pub(crate) fn size(&self) -> usize {
+        self.offsets.len() * size_of::<usize>()
+    }
+}
+
+impl Str {
+    pub fn intern(s: &str) -> Str {
+        Str(strings().lock().unwrap().intern(s))
+    }
+
+    pub(crate) fn on_heap(object: Ref) -> Str {
+        Str(ON_HEAP | object.index())
+    }
+
+    // the text of an interned string, None for one on the heap, which only the heap can read
+    pub fn interned(&self) -> Option<&'static str> {
+        match self.object() {
+            Some(_) => None,
+            None => Some(strings().lock().unwrap().values[self.0 as usize]),
+        }
+    }
+
+    pub fn object(&self) -> Option<Ref> {
+        (self.0 & ON_HEAP != 0).then(|| Ref::new(self.0 & !ON_HEAP))
+    }
+
+    // the char index of an interned string, None for one on the heap, which keeps its own
+    pub fn char_index(&self) -> Option<&'static CharIndex> {
+        let s = self.interned()?;
+        let mut indexes = char_indexes().lock().unwrap();
+        Some(*indexes.entry(self.0).or_insert_with(|| Box::leak(Box::new(CharIndex::new(s)))))
+    }
+
+    // the handle as a number, for register files that store it without its type
+    pub(crate) fn id(&self) -> u32 {
+        self.0
+    }
+
+    pub(crate) fn from_id(id: u32) -> Str {
+        Str(id)
+    }
+}
+
+// interned strings by their text and before strings on the heap, which compare by slot
+impl Ord for Str {
+    fn cmp(&self, other: &Str) -> Ordering {
+        match (self.interned(), other.interned()) {
+            (Some(a), Some(b)) => a.cmp(b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => self.0.cmp(&other.0),
+        }
+    }
+}
+
+impl PartialOrd for Str {
+    fn partial_cmp(&self, other: &Str) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl std::fmt::Debug for Str {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.interned(), self.object()) {
+            (Some(s), _) => write!(f, "{:?}", s),
+            (None, object) => write!(f, "<str {}>", object.map_or(0, |object| object.index())),
+        }
+    }
+}
+
+impl Bytes {
+    pub fn intern(bytes: &[u8]) -> Bytes {
+        Bytes(byte_strings().lock().unwrap().intern(bytes))
+    }
+
+    pub(crate) fn on_heap(object: Ref) -> Bytes {
+        Bytes(ON_HEAP | object.index())
+    }
+
+    // the bytes of an interned byte string, None for one on the heap, which only the heap can read
+    pub fn interned(&self) -> Option<&'static [u8]> {
+        match self.object() {
+            Some(_) => None,
+            None => Some(byte_strings().lock().unwrap().values[self.0 as usize]),
+        }
+    }
+
+    pub fn object(&self) -> Option<Ref> {
+        (self.0 & ON_HEAP != 0).then(|| Ref::new(self.0 & !ON_HEAP))
+    }
+
+    // the handle as a number, for register files that store it without its type
+    pub(crate) fn id(&self) -> u32 {
+        self.0
+    }
+
+    pub(crate) fn from_id(id: u32) -> Bytes {
+        Bytes(id)
+    }
+}
+
+// like strings, interned byte strings by their bytes and before byte strings on the heap
+impl Ord for Bytes {
+    fn cmp(&self, other: &Bytes) -> Ordering {
+        match (self.interned(), other.interned()) {
+            (Some(a), Some(b)) => a.cmp(b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => self.0.cmp(&other.0),
+        }
+    }
+}
+
+impl PartialOrd for Bytes {
+    fn partial_cmp(&self, other: &Bytes) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl std::fmt::Debug for Bytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.interned(), self.object()) {
+            (Some(bytes), _) => write!(f, "{}", quote_bytes(bytes)),
+            (None, object) => write!(f, "<bytes {}>", object.map_or(0, |object| object.index())),
+        }
+    }
+}
+
+// the string as a literal the reader accepts back, in quotes with escapes
+pub fn quote(s: &str) -> String {
+    quote_with(s, '"')
+}
+
+// a char literal such as 'a' or '\''
+pub fn quote_char(c: char) -> String {
+    quote_with(c.encode_utf8(&mut [0; 4]), '\'')
+}
+
+fn quote_with(s: &str, quote: char) -> String {
+    let mut out = String::from(quote);
+    for c in s.chars() {
+        match c {
+            c if c == quote => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\0' => out.push_str("\\0"),
+            c if c.is_control() => out.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push(quote);
+    out
+}
+
+// a byte string literal such as b"raw\xff", bytes outside printable ascii are written as \x escapes
+pub fn quote_bytes(bytes: &[u8]) -> String {
+    let mut out = String::from("b\"");
+    for &byte in bytes {
+        match byte {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            b'\0' => out.push_str("\\0"),
+            b' '..=b'~' => out.push(byte as char),
+            _ => out.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// the inverse of quote, s still has its quotes
+pub fn unquote(s: &str) -> Result<String, String> {
+    unquote_with(s, '"')
+}
+
+// the inverse of quote_char
+pub fn unquote_char(s: &str) -> Result<char, String> {
+    let text = unquote_with(s, '\'')?;
+    let mut chars = text.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => Err(format!("Invalid char literal {}", s)),
+    }
+}
+
+fn unquote_with(s: &str, quote: char) -> Result<String, String> {
+    let inner = s
+        .strip_prefix(quote)
+        .and_then(|s| s.strip_suffix(quote))
+        .filter(|_| s.len() >= 2)
+        .ok_or(format!("Unterminated literal {}", s))?;
+    let mut out = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c == quote {
+            return Err(format!("Unescaped quote in {}", s));
+        }
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        let escaped = match chars.next() {
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('0') => '\0',
+            Some('\\') => '\\',
+            Some(c) if c == quote => c,
+            Some('u') => {
+                let rest = chars.as_str();
+                let code = rest
+                    .strip_prefix('{')
+                    .and_then(|rest| rest.split_once('}'))
+                    .and_then(|(hex, _)| u32::from_str_radix(hex, 16).ok().and_then(char::from_u32).map(|c| (c, hex.len())))
+                    .ok_or(format!("Invalid unicode escape in {}", s))?;
+                chars = rest[code.1 + 2..].chars();
+                code.0
+            }
+            _ => return Err(format!("Invalid escape in {}", s)),
+        };
+        out.push(escaped);
+    }
+    Ok(out)
+}
+
+// the inverse of quote_bytes, other characters in the literal stand for their utf-8 bytes
+pub fn unquote_bytes(s: &str) -> Result<Vec<u8>, String> {
+    let inner = s
+        .strip_prefix("b\"")
+        .and_then(|s| s.strip_suffix('"'))
+        .filter(|_| s.len() >= 3)
+        .ok_or(format!("Unterminated literal {}", s))?;
+    let mut out = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c == '"' {
+            return Err(format!("Unescaped quote in {}", s));
+        }
+        if c != '\\' {
+            out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            continue;
+        }
+        let escaped = match chars.next() {
+            Some('n') => b'\n',
+            Some('r') => b'\r',
+            Some('t') => b'\t',
+            Some('0') => b'\0',
+            Some('"') => b'"',
+            Some('\\') => b'\\',
+            Some('x') => {
+                let rest = chars.as_str();
+                let byte = rest
+                    .get(..2)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or(format!("Invalid byte escape in {}", s))?;
+                chars = rest[2..].chars();
+                byte
+            }
+            _ => return Err(format!("Invalid escape in {}", s)),
+        };
+        out.push(escaped);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern() {
+        let a = Str::intern("hello");
+        assert_eq!(a, Str::intern(&(String::from("hel") + "lo")));
+        assert_ne!(a, Str::intern("world"));
+        assert_eq!(a.interned(), Some("hello"));
+        let b = Str::on_heap(Ref::new(3));
+        assert_eq!((b.interned(), b.object()), (None, Some(Ref::new(3))));
+        assert!(a < Str::intern("world") && Str::intern("world") < b);
+    }
+
+    #[test]
+    fn test_char_index() {
+        let s = "é".repeat(100) + "abc";
+        let index = CharIndex::new(&s);
+        assert_eq!((index.len(), index.offsets.len()), (103, 2));
+        assert_eq!(index.offset(&s, 0), Some(0));
+        assert_eq!(index.offset(&s, 70), Some(140));
+        assert_eq!(index.offset(&s, 101), Some(201));
+        assert_eq!((index.offset(&s, 103), index.offset(&s, 104)), (Some(203), None));
+        let ascii = CharIndex::new("abc");
+        assert_eq!((ascii.len(), ascii.offset("abc", 2), ascii.size()), (3, Some(2), 0));
+        assert!(CharIndex::new("").is_empty());
+        assert_eq!(Str::intern(&s).char_index(), Some(&index));
+        assert_eq!(Str::on_heap(Ref::new(0)).char_index(), None);
+    }
+
+    #[test]
+    fn test_quote_unquote() {
+        let text = "tab\there \"quoted\" \\ \u{1} é\n";
+        assert_eq!(quote(text), "\"tab\\there \\\"quoted\\\" \\\\ \\u{1} é\\n\"");
+        assert_eq!(unquote(&quote(text)), Ok(text.to_string()));
+        assert_eq!(unquote("\"\""), Ok(String::new()));
+        assert!(unquote("\"").is_err());
+        assert!(unquote("\"a\\q\"").is_err());
+        assert!(unquote("\"a\"b\"").is_err());
+        assert!(unquote("\"\\u{110000}\"").is_err());
+    }
+
+    #[test]
+    fn test_chars_and_bytes() {
+        for c in ['a', '\'', '"', '\\', '\n', '\u{1}', 'é'] {
+            assert_eq!(unquote_char(&quote_char(c)), Ok(c));
+        }
+        assert_eq!(quote_char('\''), "'\\''");
+        assert_eq!(unquote_char("'\\u{1F600}'"), Ok('\u{1F600}'));
+        assert!(unquote_char("''").is_err());
+        assert!(unquote_char("'ab'").is_err());
+        assert!(unquote_char("'").is_err());
+
+        let bytes = b"raw \"\\\n\x00\xff~";
+        assert_eq!(quote_bytes(bytes), "b\"raw \\\"\\\\\\n\\0\\xff~\"");
+        assert_eq!(unquote_bytes(&quote_bytes(bytes)), Ok(bytes.to_vec()));
+        assert_eq!(unquote_bytes("b\"é\""), Ok("é".as_bytes().to_vec()));
+        assert!(unquote_bytes("b\"\\x1\"").is_err());
+        assert!(unquote_bytes("b\"").is_err());
+
+        let a = Bytes::intern(b"ab");
+        assert_eq!(a, Bytes::intern(&b"abc"[..2]));
+        assert_eq!(a.interned(), Some(&b"ab"[..]));
+        let b = Bytes::on_heap(Ref::new(2));
+        assert_eq!((b.interned(), b.object(), format!("{:?}", b)), (None, Some(Ref::new(2)), "<bytes 2>".to_string()));
+        assert!(a < b && Bytes::intern(b"a") < a);
+    }
+}
@@ -0,0 +1,167 @@
+// records every executed instruction to a sink for after the fact diagnosis
+use std::io::{self, Write};
+use std::ops::Range;
+use crate::{heap::Heap, output, value::{Value, ValueType}};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Text,
+    JsonLines,
+}
+
+// one executed instruction, with the destination register before and after it ran
+pub struct TraceRecord {
+    pub pc: usize,
+    pub line: usize,
+    pub instruction: String,
+    pub register: Option<(u8, TracedValue, TracedValue)>,
+}
+
+// a register value as the trace shows it, read out before the collector can free a string it refers to
+#[derive(Debug, Clone, PartialEq)]
+pub struct TracedValue {
+    pub ty: ValueType,
+    pub text: String,
+}
+
+impl TracedValue {
+    pub fn new(value: &Value, heap: &Heap) -> TracedValue {
+        TracedValue {
+            ty: value.value_type(),
+            text: output::display(value, heap),
+        }
+    }
+}
+
+pub struct Tracer {
+    sink: Box<dyn Write>,
+    format: TraceFormat,
+    range: Range<usize>,
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// values are written as strings so that 64 bit integers and NaN survive JSON parsers
+fn json_value(value: &TracedValue) -> String {
+    format!("{{\"type\":\"{}\",\"value\":{}}}", value.ty, json_string(&value.text))
+}
+
+impl TraceRecord {
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{} {} {}", self.pc, self.line, self.instruction);
+        if let Some((reg, before, after)) = &self.register {
+            text.push_str(&format!("  ${}: {} -> {}", reg, before.text, after.text));
+        }
+        text
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"pc\":{},\"line\":{},\"instruction\":{}",
+            self.pc,
+            self.line,
+            json_string(&self.instruction)
+        );
+        if let Some((reg, before, after)) = &self.register {
+            json.push_str(&format!(
+                ",\"register\":{},\"before\":{},\"after\":{}",
+                reg,
+                json_value(before),
+                json_value(after)
+            ));
+        }
+        json.push('}');
+        json
+    }
+}
+
+impl Tracer {
+    pub fn new(sink: impl Write + 'static, format: TraceFormat) -> Tracer {
+        Tracer {
+            sink: Box::new(sink),
+            format,
+            range: 0..usize::MAX,
+        }
+    }
+
+    // only instructions whose offset falls in range are recorded
+    pub fn with_range(mut self, range: Range<usize>) -> Tracer {
+        self.range = range;
+        self
+    }
+
+    pub fn traces(&self, pc: usize) -> bool {
+        self.range.contains(&pc)
+    }
+
+    pub fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let line = match self.format {
+            TraceFormat::Text => record.to_text(),
+            TraceFormat::JsonLines => record.to_json(),
+        };
+        writeln!(self.sink, "{}", line)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn traced(value: Value) -> TracedValue {
+        TracedValue::new(&value, &Heap::new())
+    }
+
+    #[test]
+    fn test_record_text() {
+        let record = TraceRecord {
+            pc: 6,
+            line: 5,
+            instruction: "ADD $2 $0 $1".to_string(),
+            register: Some((2, traced(Value::U8(0)), traced(Value::I8(30)))),
+        };
+        assert_eq!(record.to_text(), "6 5 ADD $2 $0 $1  $2: 0 -> 30");
+    }
+
+    #[test]
+    fn test_record_json() {
+        let record = TraceRecord {
+            pc: 10,
+            line: 6,
+            instruction: "PRINT $2".to_string(),
+            register: None,
+        };
+        assert_eq!(record.to_json(), r#"{"pc":10,"line":6,"instruction":"PRINT $2"}"#);
+        let record = TraceRecord {
+            register: Some((1, traced(Value::F64(f64::NAN)), traced(Value::U64(u64::MAX)))),
+            ..record
+        };
+        assert_eq!(
+            record.to_json(),
+            r#"{"pc":10,"line":6,"instruction":"PRINT $2","register":1,"before":{"type":"f64","value":"NaN"},"after":{"type":"u64","value":"18446744073709551615"}}"#
+        );
+    }
+
+    #[test]
+    fn test_json_string_escapes() {
+        assert_eq!(json_string("a\"b\\c\n\u{1}"), r#""a\"b\\c\n\u0001""#);
+    }
+}
@@ -1,6 +1,15 @@
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
 use std::str::FromStr;
+use crate::{bigint::{Big, BigInt}, heap::Ref, machine::Trap, string::{self, Bytes, Str}};
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+// the encoded length that marks a value on the heap, which is followed by its slot instead of its payload
+const ON_HEAP_LEN: u32 = u32::MAX;
+
+// equality, ordering and hashing are canonical: values of different types are never equal,
+// floats compare by total_cmp so NaN equals itself and -0.0 differs from 0.0.
+// ieee_cmp and ieee_eq give the IEEE 754 float semantics instead
+#[derive(Debug, Clone, Copy)]
 pub enum Value {
     Bool(bool),
     I8(i8),
@@ -13,6 +22,398 @@ pub enum Value {
     U64(u64),
     F32(f32),
     F64(f64),
+    Str(Str),
+    // an array or record on the machine's heap
+    Ref(Ref),
+    I128(Bits128),
+    U128(Bits128),
+    // an arbitrary-precision integer, interned or on the heap like strings
+    Big(Big),
+    Char(char),
+    Bytes(Bytes),
+}
+
+// the bits of a 128-bit integer as two halves, low first. a u128 field would raise Value's alignment
+// to 16 and its size to 32 bytes. the halves still take Value from 16 to 24 bytes, the machine loops in
+// benches/registers.rs run no slower for it and several times faster than with the integers on the
+// heap like big integers. nanbox registers stay at 8
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Bits128([u64; 2]);
+
+impl Bits128 {
+    pub fn i128(self) -> i128 {
+        self.u128() as i128
+    }
+
+    pub fn u128(self) -> u128 {
+        self.0[0] as u128 | (self.0[1] as u128) << 64
+    }
+}
+
+impl From<u128> for Bits128 {
+    fn from(u: u128) -> Bits128 {
+        Bits128([u as u64, (u >> 64) as u64])
+    }
+}
+
+impl From<i128> for Bits128 {
+    fn from(i: i128) -> Bits128 {
+        Bits128::from(i as u128)
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ValueType {
+    Bool,
+    I8,
+    I16,
+    I32,
+    I64,
+    U8,
+    U16,
+    U32,
+    U64,
+    F32,
+    F64,
+    Str,
+    Ref,
+    I128,
+    U128,
+    Big,
+    Char,
+    Bytes,
+}
+
+impl ValueType {
+    pub fn from_byte(byte: u8) -> Option<ValueType> {
+        let ty = match byte {
+            0 => ValueType::Bool,
+            1 => ValueType::I8,
+            2 => ValueType::I16,
+            3 => ValueType::I32,
+            4 => ValueType::I64,
+            5 => ValueType::U8,
+            6 => ValueType::U16,
+            7 => ValueType::U32,
+            8 => ValueType::U64,
+            9 => ValueType::F32,
+            10 => ValueType::F64,
+            11 => ValueType::Str,
+            12 => ValueType::Ref,
+            13 => ValueType::I128,
+            14 => ValueType::U128,
+            15 => ValueType::Big,
+            16 => ValueType::Char,
+            17 => ValueType::Bytes,
+            _ => return None,
+        };
+        Some(ty)
+    }
+
+    // width in bytes of the little-endian encoding, None for types that cannot live in linear memory:
+    // strings, byte strings and big integers have no fixed width and references only mean something to the heap
+    // that made them
+    pub fn size(&self) -> Option<usize> {
+        match self {
+            ValueType::Bool | ValueType::I8 | ValueType::U8 => Some(1),
+            ValueType::I16 | ValueType::U16 => Some(2),
+            ValueType::I32 | ValueType::U32 | ValueType::F32 | ValueType::Char => Some(4),
+            ValueType::I64 | ValueType::U64 | ValueType::F64 => Some(8),
+            ValueType::I128 | ValueType::U128 => Some(16),
+            ValueType::Str | ValueType::Ref | ValueType::Big | ValueType::Bytes => None,
+        }
+    }
+
+    pub fn is_integer(&self) -> bool {
+        !matches!(
+            self,
+            ValueType::Bool | ValueType::F32 | ValueType::F64 | ValueType::Str | ValueType::Ref | ValueType::Char | ValueType::Bytes
+        )
+    }
+
+    pub fn is_float(&self) -> bool {
+        matches!(self, ValueType::F32 | ValueType::F64)
+    }
+}
+
+impl std::fmt::Display for ValueType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            ValueType::Bool => "bool",
+            ValueType::I8 => "i8",
+            ValueType::I16 => "i16",
+            ValueType::I32 => "i32",
+            ValueType::I64 => "i64",
+            ValueType::U8 => "u8",
+            ValueType::U16 => "u16",
+            ValueType::U32 => "u32",
+            ValueType::U64 => "u64",
+            ValueType::F32 => "f32",
+            ValueType::F64 => "f64",
+            ValueType::Str => "str",
+            ValueType::Ref => "ref",
+            ValueType::I128 => "i128",
+            ValueType::U128 => "u128",
+            ValueType::Big => "big",
+            ValueType::Char => "char",
+            ValueType::Bytes => "bytes",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Value {
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Value::Bool(_) => ValueType::Bool,
+            Value::I8(_) => ValueType::I8,
+            Value::I16(_) => ValueType::I16,
+            Value::I32(_) => ValueType::I32,
+            Value::I64(_) => ValueType::I64,
+            Value::U8(_) => ValueType::U8,
+            Value::U16(_) => ValueType::U16,
+            Value::U32(_) => ValueType::U32,
+            Value::U64(_) => ValueType::U64,
+            Value::F32(_) => ValueType::F32,
+            Value::F64(_) => ValueType::F64,
+            Value::Str(_) => ValueType::Str,
+            Value::Ref(_) => ValueType::Ref,
+            Value::I128(_) => ValueType::I128,
+            Value::U128(_) => ValueType::U128,
+            Value::Big(_) => ValueType::Big,
+            Value::Char(_) => ValueType::Char,
+            Value::Bytes(_) => ValueType::Bytes,
+        }
+    }
+
+    // the heap object the value keeps alive, strings built at run time included
+    pub fn as_object(&self) -> Option<Ref> {
+        match self {
+            Value::Ref(object) => Some(*object),
+            Value::Str(s) => s.object(),
+            Value::Bytes(bytes) => bytes.object(),
+            Value::Big(big) => big.object(),
+            _ => None,
+        }
+    }
+
+    // an integer that can address memory or a sequence, None for negatives and non-integers
+    pub fn as_index(&self) -> Option<u64> {
+        match *self {
+            Value::I8(i) => u64::try_from(i).ok(),
+            Value::I16(i) => u64::try_from(i).ok(),
+            Value::I32(i) => u64::try_from(i).ok(),
+            Value::I64(i) => u64::try_from(i).ok(),
+            Value::U8(u) => Some(u as u64),
+            Value::U16(u) => Some(u as u64),
+            Value::U32(u) => Some(u as u64),
+            Value::U64(u) => Some(u),
+            Value::I128(i) => u64::try_from(i.i128()).ok(),
+            Value::U128(u) => u64::try_from(u.u128()).ok(),
+            // the machine reads big integers on the heap itself
+            Value::Big(big) => big.interned()?.to_u128().and_then(|u| u64::try_from(u).ok()),
+            Value::Bool(_) | Value::F32(_) | Value::F64(_) | Value::Str(_) | Value::Ref(_) | Value::Char(_) | Value::Bytes(_) => None,
+        }
+    }
+
+    // little-endian bytes of the payload, without the type, the utf-8 text for strings
+    // and the shortest two's complement for big integers. a string, byte string or big integer on the heap gives its slot like a reference
+    pub fn to_le_bytes(&self) -> Vec<u8> {
+        match self {
+            Value::Bool(b) => vec![*b as u8],
+            Value::I8(i) => i.to_le_bytes().to_vec(),
+            Value::I16(i) => i.to_le_bytes().to_vec(),
+            Value::I32(i) => i.to_le_bytes().to_vec(),
+            Value::I64(i) => i.to_le_bytes().to_vec(),
+            Value::U8(u) => u.to_le_bytes().to_vec(),
+            Value::U16(u) => u.to_le_bytes().to_vec(),
+            Value::U32(u) => u.to_le_bytes().to_vec(),
+            Value::U64(u) => u.to_le_bytes().to_vec(),
+            Value::F32(f) => f.to_le_bytes().to_vec(),
+            Value::F64(f) => f.to_le_bytes().to_vec(),
+            Value::Str(s) => match (s.interned(), s.object()) {
+                (Some(text), _) => text.as_bytes().to_vec(),
+                (None, object) => object.map_or(0, |object| object.index()).to_le_bytes().to_vec(),
+            },
+            Value::Ref(object) => object.index().to_le_bytes().to_vec(),
+            Value::I128(i) => i.i128().to_le_bytes().to_vec(),
+            Value::U128(u) => u.u128().to_le_bytes().to_vec(),
+            Value::Big(big) => match (big.interned(), big.object()) {
+                (Some(big), _) => big.to_le_bytes(),
+                (None, object) => object.map_or(0, |object| object.index()).to_le_bytes().to_vec(),
+            },
+            Value::Char(c) => (*c as u32).to_le_bytes().to_vec(),
+            Value::Bytes(bytes) => match (bytes.interned(), bytes.object()) {
+                (Some(bytes), _) => bytes.to_vec(),
+                (None, object) => object.map_or(0, |object| object.index()).to_le_bytes().to_vec(),
+            },
+        }
+    }
+
+    // reads a payload of exactly ty.size() bytes, any number for a string or a big integer and four for a reference.
+    // like invalid utf-8 in a string, a char that is not a unicode scalar value reads as U+FFFD
+    pub fn from_le_bytes(ty: ValueType, bytes: &[u8]) -> Value {
+        match ty {
+            ValueType::Bool => Value::Bool(bytes[0] != 0),
+            ValueType::I8 => Value::I8(i8::from_le_bytes(bytes.try_into().unwrap())),
+            ValueType::I16 => Value::I16(i16::from_le_bytes(bytes.try_into().unwrap())),
+            ValueType::I32 => Value::I32(i32::from_le_bytes(bytes.try_into().unwrap())),
+            ValueType::I64 => Value::I64(i64::from_le_bytes(bytes.try_into().unwrap())),
+            ValueType::U8 => Value::U8(u8::from_le_bytes(bytes.try_into().unwrap())),
+            ValueType::U16 => Value::U16(u16::from_le_bytes(bytes.try_into().unwrap())),
+            ValueType::U32 => Value::U32(u32::from_le_bytes(bytes.try_into().unwrap())),
+            ValueType::U64 => Value::U64(u64::from_le_bytes(bytes.try_into().unwrap())),
+            ValueType::F32 => Value::F32(f32::from_le_bytes(bytes.try_into().unwrap())),
+            ValueType::F64 => Value::F64(f64::from_le_bytes(bytes.try_into().unwrap())),
+            ValueType::Str => Value::Str(Str::intern(&String::from_utf8_lossy(bytes))),
+            ValueType::Ref => Value::Ref(Ref::new(u32::from_le_bytes(bytes.try_into().unwrap()))),
+            ValueType::I128 => Value::from(i128::from_le_bytes(bytes.try_into().unwrap())),
+            ValueType::U128 => Value::from(u128::from_le_bytes(bytes.try_into().unwrap())),
+            ValueType::Big => Value::Big(Big::intern(BigInt::from_le_bytes(bytes))),
+            ValueType::Char => Value::Char(char::from_u32(u32::from_le_bytes(bytes.try_into().unwrap())).unwrap_or(char::REPLACEMENT_CHARACTER)),
+            ValueType::Bytes => Value::Bytes(Bytes::intern(bytes)),
+        }
+    }
+
+    // type tag followed by the little-endian payload, strings, byte strings and big integers put their u32 length before it.
+    // a string, byte string or big integer on the heap has u32::MAX for its length and its slot for the payload
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.push(self.value_type() as u8);
+        let payload = self.to_le_bytes();
+        match self {
+            Value::Str(_) | Value::Big(_) | Value::Bytes(_) if self.as_object().is_some() => out.extend_from_slice(&ON_HEAP_LEN.to_le_bytes()),
+            Value::Str(_) | Value::Big(_) | Value::Bytes(_) => out.extend_from_slice(&(payload.len() as u32).to_le_bytes()),
+            _ => {}
+        }
+        out.extend_from_slice(&payload);
+    }
+
+    // returns the value and the number of bytes it took up
+    pub fn decode(bytes: &[u8]) -> Result<(Value, usize), String> {
+        let tag = *bytes.first().ok_or("Missing value type")?;
+        let ty = ValueType::from_byte(tag).ok_or(format!("Invalid value type {}", tag))?;
+        let (start, size) = match ty.size() {
+            Some(size) => (1, size),
+            None if ty == ValueType::Ref => (1, 4),
+            None => {
+                let len = bytes.get(1..5).ok_or(format!("Truncated {} value", ty))?;
+                (5, u32::from_le_bytes(len.try_into().unwrap()) as usize)
+            }
+        };
+        if size == ON_HEAP_LEN as usize && matches!(ty, ValueType::Str | ValueType::Big | ValueType::Bytes) {
+            let slot = bytes.get(5..9).ok_or(format!("Truncated {} value", ty))?;
+            let object = Ref::new(u32::from_le_bytes(slot.try_into().unwrap()));
+            let value = match ty {
+                ValueType::Str => Value::Str(Str::on_heap(object)),
+                ValueType::Bytes => Value::Bytes(Bytes::on_heap(object)),
+                _ => Value::Big(Big::on_heap(object)),
+            };
+            return Ok((value, 9));
+        }
+        let payload = bytes.get(start..start + size).ok_or(format!("Truncated {} value", ty))?;
+        if ty == ValueType::Str && std::str::from_utf8(payload).is_err() {
+            return Err("Invalid utf-8 in str value".to_string());
+        }
+        if ty == ValueType::Char && char::from_u32(u32::from_le_bytes(payload.try_into().unwrap())).is_none() {
+            return Err("Invalid char value".to_string());
+        }
+        Ok((Value::from_le_bytes(ty, payload), start + size))
+    }
+}
+
+impl Value {
+    // orders by type first, in ValueType order, so an i64 sorts before any u8.
+    // within a type numbers and chars compare by value, floats by total_cmp, interned strings by their text
+    // and references, strings, byte strings and big integers on the heap by heap index
+    pub fn total_cmp(&self, other: &Value) -> Ordering {
+        match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+            (Value::I8(a), Value::I8(b)) => a.cmp(b),
+            (Value::I16(a), Value::I16(b)) => a.cmp(b),
+            (Value::I32(a), Value::I32(b)) => a.cmp(b),
+            (Value::I64(a), Value::I64(b)) => a.cmp(b),
+            (Value::U8(a), Value::U8(b)) => a.cmp(b),
+            (Value::U16(a), Value::U16(b)) => a.cmp(b),
+            (Value::U32(a), Value::U32(b)) => a.cmp(b),
+            (Value::U64(a), Value::U64(b)) => a.cmp(b),
+            (Value::I128(a), Value::I128(b)) => a.i128().cmp(&b.i128()),
+            (Value::U128(a), Value::U128(b)) => a.u128().cmp(&b.u128()),
+            (Value::Big(a), Value::Big(b)) => a.cmp(b),
+            (Value::F32(a), Value::F32(b)) => a.total_cmp(b),
+            (Value::F64(a), Value::F64(b)) => a.total_cmp(b),
+            (Value::Str(a), Value::Str(b)) => a.cmp(b),
+            (Value::Char(a), Value::Char(b)) => a.cmp(b),
+            (Value::Bytes(a), Value::Bytes(b)) => a.cmp(b),
+            (Value::Ref(a), Value::Ref(b)) => a.index().cmp(&b.index()),
+            _ => self.value_type().cmp(&other.value_type()),
+        }
+    }
+
+    // None for NaN and for values of different types, tower never converts between types implicitly
+    pub fn ieee_cmp(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::F32(a), Value::F32(b)) => a.partial_cmp(b),
+            (Value::F64(a), Value::F64(b)) => a.partial_cmp(b),
+            _ if self.value_type() != other.value_type() => None,
+            _ => Some(self.total_cmp(other)),
+        }
+    }
+
+    // false for NaN even against itself, true for -0.0 against 0.0
+    pub fn ieee_eq(&self, other: &Value) -> bool {
+        self.ieee_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        self.total_cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Value) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Value) -> Ordering {
+        self.total_cmp(other)
+    }
+}
+
+// floats hash their bits, which total_cmp treats as equal exactly when they are the same,
+// and strings, byte strings and big integers their interned handle, which is the same exactly when
+// the text, the bytes or the number is. like references, strings, byte strings and big integers on the
+// heap are equal only to themselves
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.value_type().hash(state);
+        match self {
+            Value::Bool(b) => b.hash(state),
+            Value::I8(i) => i.hash(state),
+            Value::I16(i) => i.hash(state),
+            Value::I32(i) => i.hash(state),
+            Value::I64(i) => i.hash(state),
+            Value::U8(u) => u.hash(state),
+            Value::U16(u) => u.hash(state),
+            Value::U32(u) => u.hash(state),
+            Value::U64(u) => u.hash(state),
+            Value::F32(f) => f.to_bits().hash(state),
+            Value::F64(f) => f.to_bits().hash(state),
+            Value::Str(s) => s.hash(state),
+            Value::Ref(object) => object.hash(state),
+            Value::I128(i) => i.hash(state),
+            Value::U128(u) => u.hash(state),
+            Value::Big(big) => big.hash(state),
+            Value::Char(c) => c.hash(state),
+            Value::Bytes(bytes) => bytes.hash(state),
+        }
+    }
 }
 
 impl std::fmt::Display for Value {
@@ -29,215 +430,427 @@ impl std::fmt::Display for Value {
             Value::U64(u) => write!(f, "{}", u),
             Value::F32(fl) => write!(f, "{}", fl),
             Value::F64(fl) => write!(f, "{}", fl),
+            Value::Str(s) => match s.interned() {
+                Some(text) => write!(f, "{}", text),
+                None => write!(f, "{:?}", s),
+            },
+            Value::Ref(object) => write!(f, "<ref {}>", object.index()),
+            Value::I128(i) => write!(f, "{}", i.i128()),
+            Value::U128(u) => write!(f, "{}", u.u128()),
+            Value::Big(big) => write!(f, "{:?}", big),
+            Value::Char(c) => write!(f, "{}", c),
+            Value::Bytes(bytes) => match bytes.interned() {
+                Some(bytes) => write!(f, "{}", String::from_utf8_lossy(bytes)),
+                None => write!(f, "{:?}", bytes),
+            },
         }
     }
 }
 
 impl std::ops::Add for Value {
-    type Output = Value;
-
-    fn add(self, other: Value) -> Value {
-        match (&self, &other) {
-            (Value::I8(a), Value::I8(b)) => Value::I8(a + b),
-            (Value::I16(a), Value::I16(b)) => Value::I16(a + b),
-            (Value::I32(a), Value::I32(b)) => Value::I32(a + b),
-            (Value::I64(a), Value::I64(b)) => Value::I64(a + b),
-            (Value::U8(a), Value::U8(b)) => Value::U8(a + b),
-            (Value::U16(a), Value::U16(b)) => Value::U16(a + b),
-            (Value::U32(a), Value::U32(b)) => Value::U32(a + b),
-            (Value::U64(a), Value::U64(b)) => Value::U64(a + b),
-            (Value::F32(a), Value::F32(b)) => Value::F32(a + b),
-            (Value::F64(a), Value::F64(b)) => Value::F64(a + b),
-            _ => panic!("Cannot add types {} and {}", self, other),
-        }
+    type Output = Result<Value, Trap>;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        let value = match (self, rhs) {
+            (Value::I8(a), Value::I8(b)) => a.checked_add(b).map(Value::I8),
+            (Value::I16(a), Value::I16(b)) => a.checked_add(b).map(Value::I16),
+            (Value::I32(a), Value::I32(b)) => a.checked_add(b).map(Value::I32),
+            (Value::I64(a), Value::I64(b)) => a.checked_add(b).map(Value::I64),
+            (Value::U8(a), Value::U8(b)) => a.checked_add(b).map(Value::U8),
+            (Value::U16(a), Value::U16(b)) => a.checked_add(b).map(Value::U16),
+            (Value::U32(a), Value::U32(b)) => a.checked_add(b).map(Value::U32),
+            (Value::U64(a), Value::U64(b)) => a.checked_add(b).map(Value::U64),
+            (Value::I128(a), Value::I128(b)) => a.i128().checked_add(b.i128()).map(Value::from),
+            (Value::U128(a), Value::U128(b)) => a.u128().checked_add(b.u128()).map(Value::from),
+            (Value::F32(a), Value::F32(b)) => Some(Value::F32(a + b)),
+            (Value::F64(a), Value::F64(b)) => Some(Value::F64(a + b)),
+            _ => return Err(Trap::Type(format!("Cannot add types {} and {}", self.value_type(), rhs.value_type()))),
+        };
+        value.ok_or(Trap::Overflow(self.value_type()))
     }
 }
 
 impl std::ops::Sub for Value {
-    type Output = Value;
+    type Output = Result<Value, Trap>;
 
     fn sub(self, rhs: Self) -> Self::Output {
-        match (&self, &rhs) {
-            (Value::I8(a), Value::I8(b)) => Value::I8(a - b),
-            (Value::I16(a), Value::I16(b)) => Value::I16(a - b),
-            (Value::I32(a), Value::I32(b)) => Value::I32(a - b),
-            (Value::I64(a), Value::I64(b)) => Value::I64(a - b),
-            (Value::U8(a), Value::U8(b)) => Value::U8(a - b),
-            (Value::U16(a), Value::U16(b)) => Value::U16(a - b),
-            (Value::U32(a), Value::U32(b)) => Value::U32(a - b),
-            (Value::U64(a), Value::U64(b)) => Value::U64(a - b),
-            (Value::F32(a), Value::F32(b)) => Value::F32(a - b),
-            (Value::F64(a), Value::F64(b)) => Value::F64(a - b),
-            _ => panic!("Cannot subtract types {} and {}", self, rhs),
-        }
+        let value = match (self, rhs) {
+            (Value::I8(a), Value::I8(b)) => a.checked_sub(b).map(Value::I8),
+            (Value::I16(a), Value::I16(b)) => a.checked_sub(b).map(Value::I16),
+            (Value::I32(a), Value::I32(b)) => a.checked_sub(b).map(Value::I32),
+            (Value::I64(a), Value::I64(b)) => a.checked_sub(b).map(Value::I64),
+            (Value::U8(a), Value::U8(b)) => a.checked_sub(b).map(Value::U8),
+            (Value::U16(a), Value::U16(b)) => a.checked_sub(b).map(Value::U16),
+            (Value::U32(a), Value::U32(b)) => a.checked_sub(b).map(Value::U32),
+            (Value::U64(a), Value::U64(b)) => a.checked_sub(b).map(Value::U64),
+            (Value::I128(a), Value::I128(b)) => a.i128().checked_sub(b.i128()).map(Value::from),
+            (Value::U128(a), Value::U128(b)) => a.u128().checked_sub(b.u128()).map(Value::from),
+            (Value::F32(a), Value::F32(b)) => Some(Value::F32(a - b)),
+            (Value::F64(a), Value::F64(b)) => Some(Value::F64(a - b)),
+            _ => return Err(Trap::Type(format!("Cannot subtract types {} and {}", self.value_type(), rhs.value_type()))),
+        };
+        value.ok_or(Trap::Overflow(self.value_type()))
     }
 }
 
 impl std::ops::Mul for Value {
-    type Output = Value;
+    type Output = Result<Value, Trap>;
 
     fn mul(self, rhs: Self) -> Self::Output {
-        match (&self, &rhs) {
-            (Value::I8(a), Value::I8(b)) => Value::I8(a * b),
-            (Value::I16(a), Value::I16(b)) => Value::I16(a * b),
-            (Value::I32(a), Value::I32(b)) => Value::I32(a * b),
-            (Value::I64(a), Value::I64(b)) => Value::I64(a * b),
-            (Value::U8(a), Value::U8(b)) => Value::U8(a * b),
-            (Value::U16(a), Value::U16(b)) => Value::U16(a * b),
-            (Value::U32(a), Value::U32(b)) => Value::U32(a * b),
-            (Value::U64(a), Value::U64(b)) => Value::U64(a * b),
-            (Value::F32(a), Value::F32(b)) => Value::F32(a * b),
-            (Value::F64(a), Value::F64(b)) => Value::F64(a * b),
-            _ => panic!("Cannot multiply types {} and {}", self, rhs),
-        }
+        let value = match (self, rhs) {
+            (Value::I8(a), Value::I8(b)) => a.checked_mul(b).map(Value::I8),
+            (Value::I16(a), Value::I16(b)) => a.checked_mul(b).map(Value::I16),
+            (Value::I32(a), Value::I32(b)) => a.checked_mul(b).map(Value::I32),
+            (Value::I64(a), Value::I64(b)) => a.checked_mul(b).map(Value::I64),
+            (Value::U8(a), Value::U8(b)) => a.checked_mul(b).map(Value::U8),
+            (Value::U16(a), Value::U16(b)) => a.checked_mul(b).map(Value::U16),
+            (Value::U32(a), Value::U32(b)) => a.checked_mul(b).map(Value::U32),
+            (Value::U64(a), Value::U64(b)) => a.checked_mul(b).map(Value::U64),
+            (Value::I128(a), Value::I128(b)) => a.i128().checked_mul(b.i128()).map(Value::from),
+            (Value::U128(a), Value::U128(b)) => a.u128().checked_mul(b.u128()).map(Value::from),
+            (Value::F32(a), Value::F32(b)) => Some(Value::F32(a * b)),
+            (Value::F64(a), Value::F64(b)) => Some(Value::F64(a * b)),
+            _ => return Err(Trap::Type(format!("Cannot multiply types {} and {}", self.value_type(), rhs.value_type()))),
+        };
+        value.ok_or(Trap::Overflow(self.value_type()))
     }
 }
 
 impl std::ops::Div for Value {
-    type Output = Value;
+    type Output = Result<Value, Trap>;
 
+    // integer division truncates, dividing the minimum of a signed type by -1 overflows
     fn div(self, rhs: Self) -> Self::Output {
-        match (&self, &rhs) {
-            (Value::I8(a), Value::I8(b)) => Value::I8(a / b),
-            (Value::I16(a), Value::I16(b)) => Value::I16(a / b),
-            (Value::I32(a), Value::I32(b)) => Value::I32(a / b),
-            (Value::I64(a), Value::I64(b)) => Value::I64(a / b),
-            (Value::U8(a), Value::U8(b)) => Value::U8(a / b),
-            (Value::U16(a), Value::U16(b)) => Value::U16(a / b),
-            (Value::U32(a), Value::U32(b)) => Value::U32(a / b),
-            (Value::U64(a), Value::U64(b)) => Value::U64(a / b),
-            (Value::F32(a), Value::F32(b)) => Value::F32(a / b),
-            (Value::F64(a), Value::F64(b)) => Value::F64(a / b),
-            _ => panic!("Cannot divide types {} and {}", self, rhs),
-        }
+        let value = match (self, rhs) {
+            (Value::I8(a), Value::I8(b)) => a.checked_div(b).map(Value::I8),
+            (Value::I16(a), Value::I16(b)) => a.checked_div(b).map(Value::I16),
+            (Value::I32(a), Value::I32(b)) => a.checked_div(b).map(Value::I32),
+            (Value::I64(a), Value::I64(b)) => a.checked_div(b).map(Value::I64),
+            (Value::U8(a), Value::U8(b)) => a.checked_div(b).map(Value::U8),
+            (Value::U16(a), Value::U16(b)) => a.checked_div(b).map(Value::U16),
+            (Value::U32(a), Value::U32(b)) => a.checked_div(b).map(Value::U32),
+            (Value::U64(a), Value::U64(b)) => a.checked_div(b).map(Value::U64),
+            (Value::I128(a), Value::I128(b)) => a.i128().checked_div(b.i128()).map(Value::from),
+            (Value::U128(a), Value::U128(b)) => a.u128().checked_div(b.u128()).map(Value::from),
+            (Value::F32(a), Value::F32(b)) => Some(Value::F32(a / b)),
+            (Value::F64(a), Value::F64(b)) => Some(Value::F64(a / b)),
+            _ => return Err(Trap::Type(format!("Cannot divide types {} and {}", self.value_type(), rhs.value_type()))),
+        };
+        value.ok_or_else(|| self.division_trap(rhs))
     }
 }
 
 impl std::ops::BitAnd for Value {
-    type Output = Value;
+    type Output = Result<Value, Trap>;
 
     fn bitand(self, rhs: Self) -> Self::Output {
-        match (&self, &rhs) {
-            (Value::I8(a), Value::I8(b)) => Value::I8(a & b),
-            (Value::I16(a), Value::I16(b)) => Value::I16(a & b),
-            (Value::I32(a), Value::I32(b)) => Value::I32(a & b),
-            (Value::I64(a), Value::I64(b)) => Value::I64(a & b),
-            (Value::U8(a), Value::U8(b)) => Value::U8(a & b),
-            (Value::U16(a), Value::U16(b)) => Value::U16(a & b),
-            (Value::U32(a), Value::U32(b)) => Value::U32(a & b),
-            (Value::U64(a), Value::U64(b)) => Value::U64(a & b),
-            (Value::Bool(a), Value::Bool(b)) => Value::Bool(a & b),
-            _ => panic!("Cannot bitand types {} and {}", self, rhs),
+        match (self, rhs) {
+            (Value::I8(a), Value::I8(b)) => Ok(Value::I8(a & b)),
+            (Value::I16(a), Value::I16(b)) => Ok(Value::I16(a & b)),
+            (Value::I32(a), Value::I32(b)) => Ok(Value::I32(a & b)),
+            (Value::I64(a), Value::I64(b)) => Ok(Value::I64(a & b)),
+            (Value::U8(a), Value::U8(b)) => Ok(Value::U8(a & b)),
+            (Value::U16(a), Value::U16(b)) => Ok(Value::U16(a & b)),
+            (Value::U32(a), Value::U32(b)) => Ok(Value::U32(a & b)),
+            (Value::U64(a), Value::U64(b)) => Ok(Value::U64(a & b)),
+            (Value::I128(a), Value::I128(b)) => Ok(Value::from(a.i128() & b.i128())),
+            (Value::U128(a), Value::U128(b)) => Ok(Value::from(a.u128() & b.u128())),
+            (Value::Bool(a), Value::Bool(b)) => Ok(Value::Bool(a & b)),
+            _ => Err(Trap::Type(format!("Cannot bitand types {} and {}", self.value_type(), rhs.value_type()))),
         }
     }
 }
 
 impl std::ops::BitOr for Value {
-    type Output = Value;
+    type Output = Result<Value, Trap>;
 
     fn bitor(self, rhs: Self) -> Self::Output {
-        match (&self, &rhs) {
-            (Value::I8(a), Value::I8(b)) => Value::I8(a | b),
-            (Value::I16(a), Value::I16(b)) => Value::I16(a | b),
-            (Value::I32(a), Value::I32(b)) => Value::I32(a | b),
-            (Value::I64(a), Value::I64(b)) => Value::I64(a | b),
-            (Value::U8(a), Value::U8(b)) => Value::U8(a | b),
-            (Value::U16(a), Value::U16(b)) => Value::U16(a | b),
-            (Value::U32(a), Value::U32(b)) => Value::U32(a | b),
-            (Value::U64(a), Value::U64(b)) => Value::U64(a | b),
-            (Value::Bool(a), Value::Bool(b)) => Value::Bool(a | b),
-            _ => panic!("Cannot bitor types {} and {}", self, rhs),
+        match (self, rhs) {
+            (Value::I8(a), Value::I8(b)) => Ok(Value::I8(a | b)),
+            (Value::I16(a), Value::I16(b)) => Ok(Value::I16(a | b)),
+            (Value::I32(a), Value::I32(b)) => Ok(Value::I32(a | b)),
+            (Value::I64(a), Value::I64(b)) => Ok(Value::I64(a | b)),
+            (Value::U8(a), Value::U8(b)) => Ok(Value::U8(a | b)),
+            (Value::U16(a), Value::U16(b)) => Ok(Value::U16(a | b)),
+            (Value::U32(a), Value::U32(b)) => Ok(Value::U32(a | b)),
+            (Value::U64(a), Value::U64(b)) => Ok(Value::U64(a | b)),
+            (Value::I128(a), Value::I128(b)) => Ok(Value::from(a.i128() | b.i128())),
+            (Value::U128(a), Value::U128(b)) => Ok(Value::from(a.u128() | b.u128())),
+            (Value::Bool(a), Value::Bool(b)) => Ok(Value::Bool(a | b)),
+            _ => Err(Trap::Type(format!("Cannot bitor types {} and {}", self.value_type(), rhs.value_type()))),
         }
     }
 }
 
 impl std::ops::BitXor for Value {
-    type Output = Value;
+    type Output = Result<Value, Trap>;
 
     fn bitxor(self, rhs: Self) -> Self::Output {
-        match (&self, &rhs) {
-            (Value::I8(a), Value::I8(b)) => Value::I8(a ^ b),
-            (Value::I16(a), Value::I16(b)) => Value::I16(a ^ b),
-            (Value::I32(a), Value::I32(b)) => Value::I32(a ^ b),
-            (Value::I64(a), Value::I64(b)) => Value::I64(a ^ b),
-            (Value::U8(a), Value::U8(b)) => Value::U8(a ^ b),
-            (Value::U16(a), Value::U16(b)) => Value::U16(a ^ b),
-            (Value::U32(a), Value::U32(b)) => Value::U32(a ^ b),
-            (Value::U64(a), Value::U64(b)) => Value::U64(a ^ b),
-            (Value::Bool(a), Value::Bool(b)) => Value::Bool(a ^ b),
-            _ => panic!("Cannot bitxor types {} and {}", self, rhs),
+        match (self, rhs) {
+            (Value::I8(a), Value::I8(b)) => Ok(Value::I8(a ^ b)),
+            (Value::I16(a), Value::I16(b)) => Ok(Value::I16(a ^ b)),
+            (Value::I32(a), Value::I32(b)) => Ok(Value::I32(a ^ b)),
+            (Value::I64(a), Value::I64(b)) => Ok(Value::I64(a ^ b)),
+            (Value::U8(a), Value::U8(b)) => Ok(Value::U8(a ^ b)),
+            (Value::U16(a), Value::U16(b)) => Ok(Value::U16(a ^ b)),
+            (Value::U32(a), Value::U32(b)) => Ok(Value::U32(a ^ b)),
+            (Value::U64(a), Value::U64(b)) => Ok(Value::U64(a ^ b)),
+            (Value::I128(a), Value::I128(b)) => Ok(Value::from(a.i128() ^ b.i128())),
+            (Value::U128(a), Value::U128(b)) => Ok(Value::from(a.u128() ^ b.u128())),
+            (Value::Bool(a), Value::Bool(b)) => Ok(Value::Bool(a ^ b)),
+            _ => Err(Trap::Type(format!("Cannot bitxor types {} and {}", self.value_type(), rhs.value_type()))),
         }
     }
 }
 
 impl std::ops::Shr for Value {
-    type Output = Value;
+    type Output = Result<Value, Trap>;
 
+    // the amount has to be at least 0 and less than the width of the type
     fn shr(self, rhs: Self) -> Self::Output {
-        match (&self, &rhs) {
-            (Value::I8(a), Value::I8(b)) => Value::I8(a >> b),
-            (Value::I16(a), Value::I16(b)) => Value::I16(a >> b),
-            (Value::I32(a), Value::I32(b)) => Value::I32(a >> b),
-            (Value::I64(a), Value::I64(b)) => Value::I64(a >> b),
-            (Value::U8(a), Value::U8(b)) => Value::U8(a >> b),
-            (Value::U16(a), Value::U16(b)) => Value::U16(a >> b),
-            (Value::U32(a), Value::U32(b)) => Value::U32(a >> b),
-            (Value::U64(a), Value::U64(b)) => Value::U64(a >> b),
-            _ => panic!("Cannot bitshift types {} and {}", self, rhs),
-        }
+        let value = match (self, rhs) {
+            (Value::I8(a), Value::I8(b)) => a.checked_shr(shift_amount(b)).map(Value::I8),
+            (Value::I16(a), Value::I16(b)) => a.checked_shr(shift_amount(b)).map(Value::I16),
+            (Value::I32(a), Value::I32(b)) => a.checked_shr(shift_amount(b)).map(Value::I32),
+            (Value::I64(a), Value::I64(b)) => a.checked_shr(shift_amount(b)).map(Value::I64),
+            (Value::U8(a), Value::U8(b)) => a.checked_shr(shift_amount(b)).map(Value::U8),
+            (Value::U16(a), Value::U16(b)) => a.checked_shr(shift_amount(b)).map(Value::U16),
+            (Value::U32(a), Value::U32(b)) => a.checked_shr(shift_amount(b)).map(Value::U32),
+            (Value::U64(a), Value::U64(b)) => a.checked_shr(shift_amount(b)).map(Value::U64),
+            (Value::I128(a), Value::I128(b)) => a.i128().checked_shr(shift_amount(b.i128())).map(Value::from),
+            (Value::U128(a), Value::U128(b)) => a.u128().checked_shr(shift_amount(b.u128())).map(Value::from),
+            _ => return Err(Trap::Type(format!("Cannot bitshift types {} and {}", self.value_type(), rhs.value_type()))),
+        };
+        value.ok_or_else(|| Trap::InvalidShift(rhs.to_string()))
     }
 }
 
 impl std::ops::Shl for Value {
-    type Output = Value;
+    type Output = Result<Value, Trap>;
 
     fn shl(self, rhs: Self) -> Self::Output {
-        match (&self, &rhs) {
-            (Value::I8(a), Value::I8(b)) => Value::I8(a << b),
-            (Value::I16(a), Value::I16(b)) => Value::I16(a << b),
-            (Value::I32(a), Value::I32(b)) => Value::I32(a << b),
-            (Value::I64(a), Value::I64(b)) => Value::I64(a << b),
-            (Value::U8(a), Value::U8(b)) => Value::U8(a << b),
-            (Value::U16(a), Value::U16(b)) => Value::U16(a << b),
-            (Value::U32(a), Value::U32(b)) => Value::U32(a << b),
-            (Value::U64(a), Value::U64(b)) => Value::U64(a << b),
-            _ => panic!("Cannot bitshift types {} and {}", self, rhs),
-        }
+        let value = match (self, rhs) {
+            (Value::I8(a), Value::I8(b)) => a.checked_shl(shift_amount(b)).map(Value::I8),
+            (Value::I16(a), Value::I16(b)) => a.checked_shl(shift_amount(b)).map(Value::I16),
+            (Value::I32(a), Value::I32(b)) => a.checked_shl(shift_amount(b)).map(Value::I32),
+            (Value::I64(a), Value::I64(b)) => a.checked_shl(shift_amount(b)).map(Value::I64),
+            (Value::U8(a), Value::U8(b)) => a.checked_shl(shift_amount(b)).map(Value::U8),
+            (Value::U16(a), Value::U16(b)) => a.checked_shl(shift_amount(b)).map(Value::U16),
+            (Value::U32(a), Value::U32(b)) => a.checked_shl(shift_amount(b)).map(Value::U32),
+            (Value::U64(a), Value::U64(b)) => a.checked_shl(shift_amount(b)).map(Value::U64),
+            (Value::I128(a), Value::I128(b)) => a.i128().checked_shl(shift_amount(b.i128())).map(Value::from),
+            (Value::U128(a), Value::U128(b)) => a.u128().checked_shl(shift_amount(b.u128())).map(Value::from),
+            _ => return Err(Trap::Type(format!("Cannot bitshift types {} and {}", self.value_type(), rhs.value_type()))),
+        };
+        value.ok_or_else(|| Trap::InvalidShift(rhs.to_string()))
     }
 }
 
-impl Into<u8> for Value {
-    fn into(self) -> u8 {
+// a shift amount as a u32, an amount that does not fit is as out of range as one that does
+fn shift_amount(amount: impl TryInto<u32>) -> u32 {
+    amount.try_into().unwrap_or(u32::MAX)
+}
+
+impl std::ops::Rem for Value {
+    type Output = Result<Value, Trap>;
+
+    // truncated, the result takes the sign of the dividend
+    fn rem(self, rhs: Self) -> Self::Output {
+        let value = match (self, rhs) {
+            (Value::I8(a), Value::I8(b)) => a.checked_rem(b).map(Value::I8),
+            (Value::I16(a), Value::I16(b)) => a.checked_rem(b).map(Value::I16),
+            (Value::I32(a), Value::I32(b)) => a.checked_rem(b).map(Value::I32),
+            (Value::I64(a), Value::I64(b)) => a.checked_rem(b).map(Value::I64),
+            (Value::U8(a), Value::U8(b)) => a.checked_rem(b).map(Value::U8),
+            (Value::U16(a), Value::U16(b)) => a.checked_rem(b).map(Value::U16),
+            (Value::U32(a), Value::U32(b)) => a.checked_rem(b).map(Value::U32),
+            (Value::U64(a), Value::U64(b)) => a.checked_rem(b).map(Value::U64),
+            (Value::I128(a), Value::I128(b)) => a.i128().checked_rem(b.i128()).map(Value::from),
+            (Value::U128(a), Value::U128(b)) => a.u128().checked_rem(b.u128()).map(Value::from),
+            (Value::F32(a), Value::F32(b)) => Some(Value::F32(a % b)),
+            (Value::F64(a), Value::F64(b)) => Some(Value::F64(a % b)),
+            _ => return Err(Trap::Type(format!("Cannot take the remainder of types {} and {}", self.value_type(), rhs.value_type()))),
+        };
+        value.ok_or_else(|| self.division_trap(rhs))
+    }
+}
+
+impl std::ops::Neg for Value {
+    type Output = Result<Value, Trap>;
+
+    fn neg(self) -> Self::Output {
+        let value = match self {
+            Value::I8(a) => a.checked_neg().map(Value::I8),
+            Value::I16(a) => a.checked_neg().map(Value::I16),
+            Value::I32(a) => a.checked_neg().map(Value::I32),
+            Value::I64(a) => a.checked_neg().map(Value::I64),
+            Value::I128(a) => a.i128().checked_neg().map(Value::from),
+            Value::F32(a) => Some(Value::F32(-a)),
+            Value::F64(a) => Some(Value::F64(-a)),
+            _ => return Err(Trap::Type(format!("Cannot negate type {}", self.value_type()))),
+        };
+        value.ok_or(Trap::Overflow(self.value_type()))
+    }
+}
+
+impl std::ops::Not for Value {
+    type Output = Result<Value, Trap>;
+
+    // bitwise for integers, logical for bools
+    fn not(self) -> Self::Output {
         match self {
+            Value::I8(a) => Ok(Value::I8(!a)),
+            Value::I16(a) => Ok(Value::I16(!a)),
+            Value::I32(a) => Ok(Value::I32(!a)),
+            Value::I64(a) => Ok(Value::I64(!a)),
+            Value::U8(a) => Ok(Value::U8(!a)),
+            Value::U16(a) => Ok(Value::U16(!a)),
+            Value::U32(a) => Ok(Value::U32(!a)),
+            Value::U64(a) => Ok(Value::U64(!a)),
+            Value::I128(a) => Ok(Value::from(!a.i128())),
+            Value::U128(a) => Ok(Value::from(!a.u128())),
+            Value::Bool(a) => Ok(Value::Bool(!a)),
+            _ => Err(Trap::Type(format!("Cannot not type {}", self.value_type()))),
+        }
+    }
+}
+
+impl Value {
+    // the trap for an integer division with no result, by zero or of the minimum by -1
+    fn division_trap(self, rhs: Value) -> Trap {
+        match rhs.to_big().is_ok_and(|big| big.is_zero()) {
+            true => Trap::DivideByZero,
+            false => Trap::Overflow(self.value_type()),
+        }
+    }
+
+    // Euclidean, the result is never negative
+    pub fn rem_euclid(self, rhs: Value) -> Result<Value, Trap> {
+        let value = match (self, rhs) {
+            (Value::I8(a), Value::I8(b)) => a.checked_rem_euclid(b).map(Value::I8),
+            (Value::I16(a), Value::I16(b)) => a.checked_rem_euclid(b).map(Value::I16),
+            (Value::I32(a), Value::I32(b)) => a.checked_rem_euclid(b).map(Value::I32),
+            (Value::I64(a), Value::I64(b)) => a.checked_rem_euclid(b).map(Value::I64),
+            (Value::U8(a), Value::U8(b)) => a.checked_rem_euclid(b).map(Value::U8),
+            (Value::U16(a), Value::U16(b)) => a.checked_rem_euclid(b).map(Value::U16),
+            (Value::U32(a), Value::U32(b)) => a.checked_rem_euclid(b).map(Value::U32),
+            (Value::U64(a), Value::U64(b)) => a.checked_rem_euclid(b).map(Value::U64),
+            (Value::I128(a), Value::I128(b)) => a.i128().checked_rem_euclid(b.i128()).map(Value::from),
+            (Value::U128(a), Value::U128(b)) => a.u128().checked_rem_euclid(b.u128()).map(Value::from),
+            (Value::F32(a), Value::F32(b)) => Some(Value::F32(a.rem_euclid(b))),
+            (Value::F64(a), Value::F64(b)) => Some(Value::F64(a.rem_euclid(b))),
+            _ => return Err(Trap::Type(format!("Cannot take the modulus of types {} and {}", self.value_type(), rhs.value_type()))),
+        };
+        value.ok_or_else(|| self.division_trap(rhs))
+    }
+
+    // unsigned integers are their own absolute value
+    pub fn abs(self) -> Result<Value, Trap> {
+        let value = match self {
+            Value::I8(a) => a.checked_abs().map(Value::I8),
+            Value::I16(a) => a.checked_abs().map(Value::I16),
+            Value::I32(a) => a.checked_abs().map(Value::I32),
+            Value::I64(a) => a.checked_abs().map(Value::I64),
+            Value::I128(a) => a.i128().checked_abs().map(Value::from),
+            Value::U8(_) | Value::U16(_) | Value::U32(_) | Value::U64(_) | Value::U128(_) => Some(self),
+            Value::F32(a) => Some(Value::F32(a.abs())),
+            Value::F64(a) => Some(Value::F64(a.abs())),
+            _ => return Err(Trap::Type(format!("Cannot take the absolute value of type {}", self.value_type()))),
+        };
+        value.ok_or(Trap::Overflow(self.value_type()))
+    }
+}
+
+impl Value {
+    // the same number as another integer type, None when it does not fit. this is the only way
+    // between integer types and between chars and their code points, the machine converts to and
+    // from big integers on the heap through to_big and from_big. other types trap
+    pub fn convert(&self, ty: ValueType) -> Result<Option<Value>, Trap> {
+        Value::from_big(&self.to_big()?, ty)
+    }
+
+    // an integer or a char as a big integer
+    pub fn to_big(&self) -> Result<BigInt, Trap> {
+        let big = match *self {
+            Value::I8(i) => BigInt::from(i as i128),
+            Value::I16(i) => BigInt::from(i as i128),
+            Value::I32(i) => BigInt::from(i as i128),
+            Value::I64(i) => BigInt::from(i as i128),
+            Value::I128(i) => BigInt::from(i.i128()),
+            Value::U8(u) => BigInt::from(u as u128),
+            Value::U16(u) => BigInt::from(u as u128),
+            Value::U32(u) => BigInt::from(u as u128),
+            Value::U64(u) => BigInt::from(u as u128),
+            Value::U128(u) => BigInt::from(u.u128()),
+            Value::Big(big) => big.interned().expect("big integers on the heap are read through it").clone(),
+            Value::Char(c) => BigInt::from(c as u128),
+            _ => return Err(Trap::Type(format!("Cannot convert type {} to an integer", self.value_type()))),
+        };
+        Ok(big)
+    }
+
+    // the number as an integer of a fixed width or a char, None when it does not fit
+    pub fn from_big(big: &BigInt, ty: ValueType) -> Result<Option<Value>, Trap> {
+        let (signed, unsigned) = (big.to_i128(), big.to_u128());
+        let value = match ty {
+            ValueType::I8 => signed.and_then(|i| i.try_into().ok()).map(Value::I8),
+            ValueType::I16 => signed.and_then(|i| i.try_into().ok()).map(Value::I16),
+            ValueType::I32 => signed.and_then(|i| i.try_into().ok()).map(Value::I32),
+            ValueType::I64 => signed.and_then(|i| i.try_into().ok()).map(Value::I64),
+            ValueType::I128 => signed.map(Value::from),
+            ValueType::U8 => unsigned.and_then(|u| u.try_into().ok()).map(Value::U8),
+            ValueType::U16 => unsigned.and_then(|u| u.try_into().ok()).map(Value::U16),
+            ValueType::U32 => unsigned.and_then(|u| u.try_into().ok()).map(Value::U32),
+            ValueType::U64 => unsigned.and_then(|u| u.try_into().ok()).map(Value::U64),
+            ValueType::U128 => unsigned.map(Value::from),
+            ValueType::Char => unsigned.and_then(|u| u.try_into().ok()).and_then(char::from_u32).map(Value::Char),
+            _ => return Err(Trap::Type(format!("Cannot convert an integer to type {}", ty))),
+        };
+        Ok(value)
+    }
+}
+
+impl From<Value> for u8 {
+    fn from(value: Value) -> u8 {
+        match value {
             Value::U8(u) => u,
-            _ => panic!("Cannot convert {} into u8", self),
+            _ => panic!("Cannot convert {} into u8", value),
         }
     }
 }
 
-impl Into<u16> for Value {
-    fn into(self) -> u16 {
-        match self {
+impl From<Value> for u16 {
+    fn from(value: Value) -> u16 {
+        match value {
             Value::U16(u) => u,
-            _ => panic!("Cannot convert {} into u16", self),
+            _ => panic!("Cannot convert {} into u16", value),
         }
     }
 }
 
-impl Into<u32> for Value {
-    fn into(self) -> u32 {
-        match self {
+impl From<Value> for u32 {
+    fn from(value: Value) -> u32 {
+        match value {
             Value::U32(u) => u,
-            _ => panic!("Cannot convert {} into u32", self),
+            _ => panic!("Cannot convert {} into u32", value),
         }
     }
 }
 
-impl Into<u64> for Value {
-    fn into(self) -> u64 {
-        match self {
+impl From<Value> for u64 {
+    fn from(value: Value) -> u64 {
+        match value {
             Value::U64(u) => u,
-            _ => panic!("Cannot convert {} into u64", self),
+            _ => panic!("Cannot convert {} into u64", value),
+        }
+    }
+}
+
+impl From<Value> for u128 {
+    fn from(value: Value) -> u128 {
+        match value {
+            Value::U128(u) => u.u128(),
+            _ => panic!("Cannot convert {} into u128", value),
         }
     }
 }
@@ -272,6 +885,12 @@ impl From<i64> for Value {
     }
 }
 
+impl From<i128> for Value {
+    fn from(i: i128) -> Value {
+        Value::I128(Bits128::from(i))
+    }
+}
+
 impl From<u8> for Value {
     fn from(u: u8) -> Value {
         Value::U8(u)
@@ -296,6 +915,11 @@ impl From<u64> for Value {
     }
 }
 
+impl From<u128> for Value {
+    fn from(u: u128) -> Value {
+        Value::U128(Bits128::from(u))
+    }
+}
 
 impl From<f32> for Value {
     fn from(f: f32) -> Value {
@@ -303,16 +927,111 @@ impl From<f32> for Value {
     }
 }
 
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::Str(Str::intern(s))
+    }
+}
+
+impl From<char> for Value {
+    fn from(c: char) -> Value {
+        Value::Char(c)
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(bytes: &[u8]) -> Value {
+        Value::Bytes(Bytes::intern(bytes))
+    }
+}
+
 impl From<f64> for Value {
     fn from(f: f64) -> Value {
         Value::F64(f)
     }
 }
 
+impl FromStr for ValueType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        (0..=ValueType::Bytes as u8)
+            .filter_map(ValueType::from_byte)
+            .find(|ty| ty.to_string() == s)
+            .ok_or(format!("Invalid type: {}", s))
+    }
+}
+
+impl Value {
+    // parses text as exactly the given type, with no suffix
+    pub fn parse_as(s: &str, ty: ValueType) -> Result<Value, String> {
+        parse_typed(s, ty).ok_or(format!("Cannot parse {} into {}", s, ty))
+    }
+}
+
+// parses digits as exactly the given type, for literals with a type suffix
+fn parse_typed(digits: &str, ty: ValueType) -> Option<Value> {
+    let value = match ty {
+        ValueType::Bool => Value::Bool(digits.parse().ok()?),
+        ValueType::I8 => Value::I8(digits.parse().ok()?),
+        ValueType::I16 => Value::I16(digits.parse().ok()?),
+        ValueType::I32 => Value::I32(digits.parse().ok()?),
+        ValueType::I64 => Value::I64(digits.parse().ok()?),
+        ValueType::U8 => Value::U8(digits.parse().ok()?),
+        ValueType::U16 => Value::U16(digits.parse().ok()?),
+        ValueType::U32 => Value::U32(digits.parse().ok()?),
+        ValueType::U64 => Value::U64(digits.parse().ok()?),
+        ValueType::I128 => Value::from(digits.parse::<i128>().ok()?),
+        ValueType::U128 => Value::from(digits.parse::<u128>().ok()?),
+        ValueType::Big => Value::Big(Big::intern(BigInt::from_str(digits).ok()?)),
+        ValueType::F32 => Value::F32(digits.parse().ok()?),
+        ValueType::F64 => Value::F64(digits.parse().ok()?),
+        ValueType::Str => Value::Str(Str::intern(digits)),
+        ValueType::Char => Value::Char(digits.parse().ok()?),
+        ValueType::Bytes => Value::Bytes(Bytes::intern(digits.as_bytes())),
+        // references cannot be written down, only created by the machine
+        ValueType::Ref => return None,
+    };
+    Some(value)
+}
+
+const SUFFIXES: [ValueType; 13] = [
+    ValueType::I8,
+    ValueType::I16,
+    ValueType::I32,
+    ValueType::I64,
+    ValueType::I128,
+    ValueType::U8,
+    ValueType::U16,
+    ValueType::U32,
+    ValueType::U64,
+    ValueType::U128,
+    ValueType::Big,
+    ValueType::F32,
+    ValueType::F64,
+];
+
 impl FromStr for Value {
     type Err = String;
 
+    // untyped literals take the first type they fit in, a suffix such as 10.5f32 picks the type
+    // and a quoted literal is a string, 'a' a char and b"raw" a byte string. integers too wide for 128 bits
+    // and hex literals are big
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('"') {
+            return string::unquote(s).map(|s| Value::Str(Str::intern(&s)));
+        }
+        if s.starts_with('\'') {
+            return string::unquote_char(s).map(Value::Char);
+        }
+        if s.starts_with("b\"") {
+            return string::unquote_bytes(s).map(|bytes| Value::Bytes(Bytes::intern(&bytes)));
+        }
+        for ty in SUFFIXES {
+            if let Some(digits) = s.strip_suffix(ty.to_string().as_str()) {
+                return Value::parse_as(digits, ty);
+            }
+        }
         if let Ok(i)= s.parse::<i8>() {
             Ok(Value::I8(i))
         } else if let Ok(i) = s.parse::<i16>() {
@@ -329,6 +1048,12 @@ impl FromStr for Value {
             Ok(Value::U32(u))
         } else if let Ok(u) = s.parse::<u64>() {
             Ok(Value::U64(u))
+        } else if let Ok(i) = s.parse::<i128>() {
+            Ok(Value::from(i))
+        } else if let Ok(u) = s.parse::<u128>() {
+            Ok(Value::from(u))
+        } else if let Ok(big) = BigInt::from_str(s) {
+            Ok(Value::Big(Big::intern(big)))
         } else if let Ok(f) = s.parse::<f32>() {
             Ok(Value::F32(f))
         } else if let Ok(f) = s.parse::<f64>() {
@@ -339,4 +1064,208 @@ impl FromStr for Value {
             Err(format!("Cannot parse {} into Value", s))
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_str_suffix() {
+        assert_eq!(Value::from_str("10.5f32"), Ok(Value::F32(10.5)));
+        assert_eq!(Value::from_str("1u64"), Ok(Value::U64(1)));
+        assert_eq!(Value::from_str("-3i16"), Ok(Value::I16(-3)));
+        assert_eq!(Value::from_str("1"), Ok(Value::I8(1)));
+        assert_eq!(Value::from_str("300u8"), Err("Cannot parse 300 into u8".to_string()));
+        assert_eq!(Value::from_str("\"a b\\n\""), Ok(Value::from("a b\n")));
+        assert_eq!(Value::parse_as(" 1 ", ValueType::Str), Ok(Value::from(" 1 ")));
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let mut bytes = Vec::new();
+        Value::I32(-2).encode(&mut bytes);
+        Value::Bool(true).encode(&mut bytes);
+        assert_eq!(bytes, vec![ValueType::I32 as u8, 0xFE, 0xFF, 0xFF, 0xFF, ValueType::Bool as u8, 1]);
+        assert_eq!(Value::decode(&bytes), Ok((Value::I32(-2), 5)));
+        assert_eq!(Value::decode(&bytes[5..]), Ok((Value::Bool(true), 2)));
+        assert_eq!(Value::decode(&bytes[..3]), Err("Truncated i32 value".to_string()));
+
+        let mut bytes = Vec::new();
+        Value::from("hé").encode(&mut bytes);
+        assert_eq!(bytes, vec![ValueType::Str as u8, 3, 0, 0, 0, b'h', 0xC3, 0xA9]);
+        assert_eq!(Value::decode(&bytes), Ok((Value::from("hé"), 8)));
+        assert_eq!(Value::decode(&bytes[..7]), Err("Truncated str value".to_string()));
+        assert_eq!(Value::decode(&bytes[..6]).map(|(v, _)| v), Err("Truncated str value".to_string()));
+        assert_eq!(Value::decode(&[ValueType::Ref as u8, 7, 0, 0, 0]), Ok((Value::Ref(Ref::new(7)), 5)));
+        bytes[6] = 0xFF;
+        assert_eq!(Value::decode(&bytes), Err("Invalid utf-8 in str value".to_string()));
+
+        let mut bytes = Vec::new();
+        let s = Value::Str(Str::on_heap(Ref::new(2)));
+        s.encode(&mut bytes);
+        assert_eq!(bytes, vec![ValueType::Str as u8, 0xFF, 0xFF, 0xFF, 0xFF, 2, 0, 0, 0]);
+        assert_eq!(Value::decode(&bytes), Ok((s, 9)));
+        assert_eq!(s.as_object(), Some(Ref::new(2)));
+    }
+
+    #[test]
+    fn test_unary_and_remainder() {
+        assert_eq!(-Value::I16(5), Ok(Value::I16(-5)));
+        assert!((-Value::F64(0.0)).unwrap().ieee_eq(&Value::F64(0.0)));
+        assert_eq!((-Value::F64(0.0)).unwrap().to_le_bytes(), (-0.0f64).to_le_bytes());
+        assert_eq!(!Value::U8(0b1010), Ok(Value::U8(0b1111_0101)));
+        assert_eq!(!Value::Bool(true), Ok(Value::Bool(false)));
+        assert_eq!(Value::I32(-7) % Value::I32(3), Ok(Value::I32(-1)));
+        assert_eq!(Value::I32(-7).rem_euclid(Value::I32(3)), Ok(Value::I32(2)));
+        assert_eq!(Value::I32(7).rem_euclid(Value::I32(-3)), Ok(Value::I32(1)));
+        assert_eq!(Value::F32(-7.5) % Value::F32(2.0), Ok(Value::F32(-1.5)));
+        assert_eq!(Value::F32(-7.5).rem_euclid(Value::F32(2.0)), Ok(Value::F32(0.5)));
+        assert_eq!(Value::I8(-8).abs(), Ok(Value::I8(8)));
+        assert_eq!(Value::U64(8).abs(), Ok(Value::U64(8)));
+        // results that do not fit and divisors of zero trap instead of panicking
+        assert_eq!(Value::from(i128::MIN).abs(), Err(Trap::Overflow(ValueType::I128)));
+        assert_eq!(-Value::I32(i32::MIN), Err(Trap::Overflow(ValueType::I32)));
+        assert_eq!(Value::I64(i64::MIN) % Value::I64(-1), Err(Trap::Overflow(ValueType::I64)));
+        assert_eq!(Value::U32(7).rem_euclid(Value::U32(0)), Err(Trap::DivideByZero));
+        assert_eq!(Value::from(7u128) % Value::from(0u128), Err(Trap::DivideByZero));
+    }
+
+    #[test]
+    fn test_128_bit() {
+        assert_eq!(std::mem::size_of::<Value>(), 24);
+        assert_eq!(Value::from_str("1u128"), Ok(Value::from(1u128)));
+        assert_eq!(Value::from_str("-5i128"), Ok(Value::from(-5i128)));
+        // untyped literals past 64 bits
+        assert_eq!(Value::from_str("-9223372036854775809"), Ok(Value::from(i64::MIN as i128 - 1)));
+        assert_eq!(Value::from_str(&u128::MAX.to_string()), Ok(Value::from(u128::MAX)));
+        assert_eq!(Value::from_str("-1u128"), Err("Cannot parse -1 into u128".to_string()));
+
+        let big = Value::from(1u128 << 100);
+        assert_eq!(big + Value::from(1u128), Ok(Value::from((1u128 << 100) + 1)));
+        assert_eq!(big >> Value::from(99u128), Ok(Value::from(2u128)));
+        assert_eq!(Value::from(-7i128).rem_euclid(Value::from(3i128)), Ok(Value::from(2i128)));
+        assert_eq!((-Value::from(i128::MAX)).unwrap().abs(), Ok(Value::from(i128::MAX)));
+        assert_eq!(u128::from((!Value::from(0u128)).unwrap()), u128::MAX);
+        assert!(Value::from(-1i128) < Value::from(0i128));
+        assert_eq!(big.to_string(), (1u128 << 100).to_string());
+        assert_eq!(big.as_index(), None);
+        assert_eq!(Value::from(3i128).as_index(), Some(3));
+
+        let mut bytes = Vec::new();
+        Value::from(-2i128).encode(&mut bytes);
+        assert_eq!(bytes.len(), 17);
+        assert_eq!(bytes[0], ValueType::I128 as u8);
+        assert_eq!(Value::decode(&bytes), Ok((Value::from(-2i128), 17)));
+        assert_eq!(ValueType::from_str("u128"), Ok(ValueType::U128));
+    }
+
+    #[test]
+    fn test_big() {
+        let big = Value::from_str("0x100000000000000000000000000000000big").unwrap();
+        assert_eq!(big.value_type(), ValueType::Big);
+        assert_eq!(Value::from_str("340282366920938463463374607431768211456"), Ok(big));
+        assert_eq!(Value::from_str("0x10"), Value::from_str("16big"));
+        assert_eq!(Value::from_str("1.5big"), Err("Cannot parse 1.5 into big".to_string()));
+        assert_eq!(Value::parse_as("-0xff", ValueType::Big).map(|v| v.to_string()), Ok("-255".to_string()));
+
+        let (one, minus) = (Value::from_str("1big").unwrap(), Value::from_str("-1big").unwrap());
+        assert!(minus < one && one < big);
+        assert_eq!(one.as_index(), Some(1));
+
+        // only explicit conversions between integer types, the machine converts big integers on the heap
+        assert_eq!(Value::from_big(&BigInt::from(u128::MAX), ValueType::U128), Ok(Some(Value::from(u128::MAX))));
+        assert_eq!(big.convert(ValueType::U128), Ok(None));
+        assert_eq!(minus.convert(ValueType::U8), Ok(None));
+        assert_eq!(Value::I8(-1).to_big(), Ok(BigInt::from(-1i128)));
+        assert_eq!(Value::I64(300).convert(ValueType::U16), Ok(Some(Value::U16(300))));
+        assert_eq!(Value::F32(1.0).convert(ValueType::I8), Err(Trap::Type("Cannot convert type f32 to an integer".to_string())));
+        assert_eq!(Value::I8(1).convert(ValueType::Bool), Err(Trap::Type("Cannot convert an integer to type bool".to_string())));
+        assert_ne!(Value::I8(1), one);
+
+        let mut bytes = Vec::new();
+        Value::from_str("-0x100000000000000000000000000000000big").unwrap().encode(&mut bytes);
+        assert_eq!(bytes[..5], [ValueType::Big as u8, 17, 0, 0, 0]);
+        assert_eq!(Value::decode(&bytes).map(|(value, len)| (value.to_string(), len)), Ok(("-340282366920938463463374607431768211456".to_string(), 22)));
+        let on_heap = Value::Big(Big::on_heap(Ref::new(7)));
+        bytes.clear();
+        on_heap.encode(&mut bytes);
+        assert_eq!(bytes, [ValueType::Big as u8, 0xFF, 0xFF, 0xFF, 0xFF, 7, 0, 0, 0]);
+        assert_eq!(Value::decode(&bytes), Ok((on_heap, 9)));
+        assert_eq!(on_heap.as_object(), Some(Ref::new(7)));
+        assert_eq!(ValueType::from_str("big"), Ok(ValueType::Big));
+    }
+
+    #[test]
+    fn test_chars_and_bytes() {
+        assert_eq!(Value::from_str("'a'"), Ok(Value::Char('a')));
+        assert_eq!(Value::from_str("'\\n'"), Ok(Value::Char('\n')));
+        assert_eq!(Value::from_str("b\"raw\\xff\""), Ok(Value::from(&b"raw\xff"[..])));
+        assert!(Value::from_str("'ab'").is_err());
+        assert_eq!(Value::parse_as("é", ValueType::Char), Ok(Value::Char('é')));
+        assert_eq!(Value::Char('é').to_string(), "é");
+        assert_eq!(Value::from(&b"h\xffi"[..]).to_string(), "h\u{FFFD}i");
+
+        let mut bytes = Vec::new();
+        Value::Char('é').encode(&mut bytes);
+        Value::from(&b"\x00a"[..]).encode(&mut bytes);
+        assert_eq!(bytes, vec![ValueType::Char as u8, 0xE9, 0, 0, 0, ValueType::Bytes as u8, 2, 0, 0, 0, 0, b'a']);
+        assert_eq!(Value::decode(&bytes), Ok((Value::Char('é'), 5)));
+        assert_eq!(Value::decode(&bytes[5..]), Ok((Value::from(&b"\x00a"[..]), 7)));
+        assert_eq!(Value::decode(&[ValueType::Char as u8, 0, 0xD8, 0, 0]), Err("Invalid char value".to_string()));
+
+        assert_eq!(Value::Char('a').convert(ValueType::U8), Ok(Some(Value::U8(97))));
+        assert_eq!(Value::Char('é').convert(ValueType::I8), Ok(None));
+        assert_eq!(Value::U32(0x1F600).convert(ValueType::Char), Ok(Some(Value::Char('\u{1F600}'))));
+        assert_eq!(Value::I32(0xD800).convert(ValueType::Char), Ok(None));
+        assert!(Value::Char('a') < Value::Char('b'));
+        assert!(Value::from(&b"ab"[..]) < Value::from(&b"b"[..]));
+    }
+
+    #[test]
+    fn test_ieee_semantics() {
+        let nan = Value::F64(f64::NAN);
+        assert!(!nan.ieee_eq(&nan));
+        assert_eq!(nan.ieee_cmp(&Value::F64(1.0)), None);
+        assert!(Value::F32(-0.0).ieee_eq(&Value::F32(0.0)));
+        assert_eq!(Value::F32(1.0).ieee_cmp(&Value::F32(2.0)), Some(Ordering::Less));
+        assert_eq!(Value::from("b").ieee_cmp(&Value::from("a")), Some(Ordering::Greater));
+        // no implicit conversions, even between numbers that hold the same value
+        assert_eq!(Value::I8(1).ieee_cmp(&Value::I16(1)), None);
+        assert_eq!(Value::F32(1.0).ieee_cmp(&Value::F64(1.0)), None);
+    }
+
+    #[test]
+    fn test_canonical_equality_and_ordering() {
+        use std::collections::HashSet;
+        let nan = Value::F64(f64::NAN);
+        assert_eq!(nan, nan);
+        assert_ne!(Value::F64(-0.0), Value::F64(0.0));
+        assert_ne!(Value::I8(1), Value::I16(1));
+        assert_ne!(Value::U64(1), Value::F64(1.0));
+
+        let mut values = vec![
+            Value::U8(0),
+            Value::F64(f64::NAN),
+            Value::from("b"),
+            Value::I64(-1),
+            Value::F64(0.0),
+            Value::F64(-0.0),
+            Value::F64(f64::NEG_INFINITY),
+            Value::from("a"),
+            Value::Bool(true),
+        ];
+        values.sort();
+        // types in ValueType order, so the negative i64 comes before the u8 zero
+        assert_eq!(values[..4], [Value::Bool(true), Value::I64(-1), Value::U8(0), Value::F64(f64::NEG_INFINITY)]);
+        assert!(values[4].ieee_eq(&Value::F64(0.0)) && values[4].total_cmp(&values[5]) == Ordering::Less);
+        assert_eq!(values[6..], [Value::F64(f64::NAN), Value::from("a"), Value::from("b")]);
+
+        let set: HashSet<Value> = [nan, nan, Value::F64(0.0), Value::F64(-0.0), Value::from("a"), Value::from("a")].into();
+        assert_eq!(set.len(), 4);
+    }
+
+    #[test]
+    fn test_negate_unsigned() {
+        assert_eq!(-Value::U8(1), Err(Trap::Type("Cannot negate type u8".to_string())));
+    }
+}